@@ -2,8 +2,22 @@
 
 use fast_protocol::{Server, Response, middleware::*};
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+#[derive(Deserialize)]
+struct JsonRequest {
+    name: String,
+    value: i32,
+}
+
+#[derive(Serialize)]
+struct JsonResponse {
+    message: String,
+    received: String,
+    timestamp: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
@@ -32,36 +46,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(Response::text(message))
     }).await;
 
-    // Register /json handler
-    server.on_async("/json", |ctx: Context| async {
-        use serde::{Deserialize, Serialize};
-
-        #[derive(Deserialize)]
-        struct Request {
-            name: String,
-            value: i32,
-        }
-
-        #[derive(Serialize)]
-        struct ResponseData {
-            message: String,
-            received: String,
-            timestamp: u64,
-        }
-
-        let req: Request = ctx.json()?;
+    // Register /json handler; the payload and response are encoded as JSON automatically
+    server.on_typed("/json", |req: JsonRequest| {
         println!("Received JSON: name={}, value={}", req.name, req.value);
 
-        let resp = ResponseData {
+        Ok(JsonResponse {
             message: "Received".to_string(),
             received: req.name,
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
-        };
-
-        Response::json(&resp)
+        })
     }).await;
 
     // Register /uppercase handler
@@ -0,0 +1,63 @@
+//! Job queue throughput with a large backlog of scheduled jobs
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use fast_protocol::jobs::{JobConfig, JobPriority, JobQueue};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// High priority jobs waiting for a time far in the future
+const SCHEDULED_JOBS: usize = 10_000;
+/// Ready jobs drained per iteration
+const READY_JOBS: usize = 1_000;
+
+/// Build a queue holding the scheduled backlog and the ready jobs
+async fn backlogged_queue() -> Arc<JobQueue> {
+    let queue = Arc::new(JobQueue::new(4));
+    queue.register("noop".to_string(), |_job| Ok(Bytes::new())).await;
+
+    let later = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64 + 3_600_000;
+    for _ in 0..SCHEDULED_JOBS {
+        let config = JobConfig {
+            priority: JobPriority::High,
+            scheduled_at: Some(later),
+            ..Default::default()
+        };
+        queue.enqueue("noop".to_string(), Bytes::new(), config).await;
+    }
+    for _ in 0..READY_JOBS {
+        queue.enqueue("noop".to_string(), Bytes::new(), JobConfig::default()).await;
+    }
+    queue
+}
+
+fn drain_ready_jobs(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    c.bench_function("drain 1k ready jobs behind 10k scheduled", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let queue = backlogged_queue().await;
+
+                    let start = Instant::now();
+                    queue.clone().start().await;
+                    while queue.get_completed_count().await < READY_JOBS {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                    total += start.elapsed();
+                    queue.shutdown().await;
+                }
+                total
+            })
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = drain_ready_jobs
+}
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Packet encoding and decoding cost
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fast_protocol::packet::Packet;
+
+/// Payload sizes to encode and decode
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+fn serialize_packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for size in PAYLOAD_SIZES {
+        let packet = Packet::new_data("/bench".to_string(), Bytes::from(vec![7u8; size]), 42);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &packet, |b, packet| {
+            b.iter(|| packet.serialize().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn deserialize_packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    for size in PAYLOAD_SIZES {
+        let packet = Packet::new_data("/bench".to_string(), Bytes::from(vec![7u8; size]), 42);
+        let datagram = packet.serialize().unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &datagram, |b, datagram| {
+            b.iter(|| Packet::deserialize(datagram.clone()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize_packets, deserialize_packets);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+//! Allocations made while receiving a million datagrams
+//!
+//! Run with `cargo bench --bench recv_alloc_bench`. Packets are either dropped
+//! as soon as they arrive or kept alive for a while, as a slow handler would.
+
+use bytes::Bytes;
+use fast_protocol::packet::{Packet, PacketFlags};
+use fast_protocol::transport::{Transport, TransportConfig};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::UdpSocket;
+
+/// Allocations at least this big are counted as receive buffers
+const BUFFER_ALLOCATION: usize = 4096;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BUFFER_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator that counts allocations
+struct CountingAlloc;
+
+impl CountingAlloc {
+    fn count(size: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        if size >= BUFFER_ALLOCATION {
+            BUFFER_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Datagrams received per run
+const PACKETS: usize = 1_000_000;
+/// Datagrams queued on the socket before they are drained
+const BURST: usize = 64;
+/// Packets kept alive in the retaining run
+const RETAINED: usize = 1024;
+
+/// Receive `PACKETS` datagrams, keeping the last `retain` alive
+///
+/// Returns all allocations and buffer-sized allocations made while receiving.
+async fn receive(retain: usize) -> (u64, u64) {
+    let receiver = Transport::bind(([127, 0, 0, 1], 0), TransportConfig::default()).await.unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sender.connect(receiver.local_addr().unwrap()).await.unwrap();
+
+    let mut packet = Packet::new_data("/bench".to_string(), Bytes::from(vec![7u8; 200]), 0);
+    packet.flags = PacketFlags::default();
+    let datagram = packet.serialize().unwrap();
+
+    let mut kept = VecDeque::with_capacity(retain + 1);
+    let (mut allocations, mut buffer_allocations) = (0, 0);
+    for _ in 0..PACKETS / BURST {
+        for _ in 0..BURST {
+            sender.send(&datagram).await.unwrap();
+        }
+
+        let before = (ALLOCATIONS.load(Ordering::Relaxed), BUFFER_ALLOCATIONS.load(Ordering::Relaxed));
+        for _ in 0..BURST {
+            let (packet, _) = receiver.recv().await.unwrap();
+            if retain > 0 {
+                kept.push_back(packet);
+                if kept.len() > retain {
+                    kept.pop_front();
+                }
+            }
+        }
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before.0;
+        buffer_allocations += BUFFER_ALLOCATIONS.load(Ordering::Relaxed) - before.1;
+    }
+    (allocations, buffer_allocations)
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+    for (label, retain) in [("dropped on arrival", 0), ("1024 kept alive", RETAINED)] {
+        let (allocations, buffer_allocations) = runtime.block_on(receive(retain));
+        println!(
+            "recv, packets {}: {} allocations per million packets, {} of them receive buffers",
+            label, allocations, buffer_allocations
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Transport receive throughput on loopback
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use fast_protocol::packet::{Packet, PacketFlags};
+use fast_protocol::transport::{Transport, TransportConfig};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Datagrams queued on the socket before each timed drain
+const DATAGRAMS: usize = 64;
+/// Payload size of each datagram
+const PAYLOAD_SIZE: usize = 200;
+
+fn receive_datagrams(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (receiver, sender) = runtime.block_on(async {
+        let receiver = Transport::bind(([127, 0, 0, 1], 0), TransportConfig::default()).await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.connect(receiver.local_addr().unwrap()).await.unwrap();
+        (receiver, sender)
+    });
+
+    let mut packet = Packet::new_data("/bench".to_string(), Bytes::from(vec![7u8; PAYLOAD_SIZE]), 0);
+    packet.flags = PacketFlags::default();
+    let datagram = packet.serialize().unwrap();
+
+    let mut group = c.benchmark_group("recv");
+    group.throughput(Throughput::Elements(DATAGRAMS as u64));
+    group.bench_function("64 datagrams of 200 bytes", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    for _ in 0..DATAGRAMS {
+                        sender.send(&datagram).await.unwrap();
+                    }
+
+                    let start = Instant::now();
+                    for _ in 0..DATAGRAMS {
+                        receiver.recv().await.unwrap();
+                    }
+                    total += start.elapsed();
+                }
+                total
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, receive_datagrams);
+criterion_main!(benches);
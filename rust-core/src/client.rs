@@ -1,30 +1,276 @@
 //! Client implementation
 
 use bytes::Bytes;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock, oneshot};
-use tokio::time::{timeout, Duration};
-use tracing::{info, error, debug};
-
-use crate::transport::{Transport, TransportConfig};
-use crate::packet::{Packet, PacketType};
-use crate::crypto::CryptoProvider;
-use crate::compression::CompressionProvider;
+use std::time::Instant;
+use tokio::sync::{mpsc, Mutex, RwLock, oneshot};
+use tokio::task::AbortHandle;
+use tokio::time::{self, timeout, Duration};
+use tracing::{info, error, debug, trace, warn};
+
+use crate::transport::{canonical_addr, Transport, TransportConfig, TransportStats};
+use crate::packet::{Packet, PacketType, DEFAULT_CHANNEL};
+use crate::protocol;
+use crate::crypto::{
+    verify_handshake, Crypto, CryptoProvider, KeyExchange, IDENTITY_KEY_SIZE, PUBLIC_KEY_SIZE,
+    RESUMPTION_NONCE_SIZE, SIGNATURE_SIZE,
+};
+use crate::codec::Codec;
+use crate::compression::Compression;
+use crate::middleware::{correlation_id, AsyncFnHandler, Context, Extensions, FnHandler, Handler, Response};
+use crate::stream::{ResponseStream, StreamReassembler, DEFAULT_STREAM_WINDOW};
+use crate::tasks::TaskSet;
 use crate::error::*;
+use crate::PROTOCOL_VERSION;
 
 /// Pending request waiting for response
 struct PendingRequest {
-    tx: oneshot::Sender<Bytes>,
+    tx: oneshot::Sender<Result<Bytes>>,
+}
+
+/// Everything needed to abandon a request, locally and on the server
+struct Canceller {
+    transport: Arc<Transport>,
+    server_addr: SocketAddr,
+    pending_requests: Arc<RwLock<HashMap<RequestKey, PendingRequest>>>,
+}
+
+impl Canceller {
+    /// Cancel the request sent on `channel` with `sequence`
+    ///
+    /// The cancel packet goes out on the same channel, which is how the
+    /// server tells which request it refers to.
+    async fn cancel(&self, channel: u16, sequence: u32) -> Result<()> {
+        self.pending_requests.write().await.remove(&(channel, sequence));
+        self.transport.cancel_reliable(channel, sequence).await;
+        let mut cancel = Packet::new_cancel(sequence);
+        cancel.channel_id = channel;
+        self.transport.send_reliable_packet(cancel, self.server_addr).await?;
+        debug!("Cancelled request {} on channel {}", sequence, channel);
+        Ok(())
+    }
+}
+
+/// A request waiting for its response
+///
+/// Dropping the handle before the response arrives cancels the request.
+pub struct RequestHandle {
+    channel: u16,
+    sequence: u32,
+    rx: oneshot::Receiver<Result<Bytes>>,
+    request_timeout: Duration,
+    canceller: Option<Canceller>,
+}
+
+impl RequestHandle {
+    /// Channel the request was sent on
+    pub fn channel(&self) -> u16 {
+        self.channel
+    }
+
+    /// Sequence number the request was sent with, within its channel
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Wait for responses up to `timeout` instead of the client's request timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Wait for the response, up to the request timeout
+    ///
+    /// A request that times out is cancelled.
+    pub async fn response(mut self) -> Result<Bytes> {
+        match timeout(self.request_timeout, &mut self.rx).await {
+            Ok(Ok(response)) => {
+                trace!("Received response for sequence {}", self.sequence);
+                self.canceller = None;
+                response
+            }
+            Ok(Err(_)) => {
+                self.canceller = None;
+                Err(ProtocolError::Channel("Response channel closed".to_string()))
+            }
+            Err(_) => Err(ProtocolError::Timeout),
+        }
+    }
+
+    /// Abandon the request and ask the server to abort its handler
+    pub async fn cancel(mut self) -> Result<()> {
+        match self.canceller.take() {
+            Some(canceller) => canceller.cancel(self.channel, self.sequence).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        let Some(canceller) = self.canceller.take() else {
+            return;
+        };
+        if self.rx.try_recv().is_ok() {
+            return;
+        }
+
+        // Forget the request right away when possible; the rest needs the runtime
+        let (channel, sequence) = (self.channel, self.sequence);
+        if let Ok(mut pending) = canceller.pending_requests.try_write() {
+            pending.remove(&(channel, sequence));
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = canceller.cancel(channel, sequence).await {
+                    debug!("Failed to cancel request {}: {}", sequence, e);
+                }
+            });
+        }
+    }
+}
+
+/// When `Client::request_with_retry` re-issues a request the server failed
+///
+/// Only error replies carrying one of the retryable codes are retried, with
+/// the delay between attempts doubling from the initial backoff up to the
+/// cap. Defaults to 3 attempts, retrying `Busy` and `Timeout` after 50ms.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<RemoteErrorCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            retry_on: vec![RemoteErrorCode::Busy, RemoteErrorCode::Timeout],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make at most `attempts` attempts in total, including the first
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Also retry error replies with `code`
+    pub fn retry_on(mut self, code: RemoteErrorCode) -> Self {
+        if !self.retry_on.contains(&code) {
+            self.retry_on.push(code);
+        }
+        self
+    }
+
+    /// Retry only error replies with one of `codes`
+    pub fn retry_only(mut self, codes: impl IntoIterator<Item = RemoteErrorCode>) -> Self {
+        self.retry_on = codes.into_iter().collect();
+        self
+    }
+
+    /// Whether a failed attempt is worth repeating
+    fn is_retryable(&self, error: &ProtocolError) -> bool {
+        matches!(error, ProtocolError::Remote { code, .. } if self.retry_on.contains(code))
+    }
+
+    /// Delay before the retry following attempt number `attempt`, from 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
 }
 
+/// Ticket the server issued for resuming a session, with the secret it stands for
+#[derive(Clone)]
+struct SessionTicket {
+    ticket: Bytes,
+    secret: [u8; 32],
+    expires_at: Instant,
+}
+
+/// Keys a `Connect` offers the server
+enum KeyOffer {
+    /// Nothing, for unencrypted or pre-shared key connections
+    None,
+    /// A fresh X25519 key exchange
+    Exchange(KeyExchange),
+    /// A session ticket, with a fresh nonce to key the resumed session
+    Resume {
+        nonce: [u8; RESUMPTION_NONCE_SIZE],
+        ticket: SessionTicket,
+    },
+}
+
+/// Callback invoked when the client loses its connection
+type DisconnectHandler = Arc<dyn Fn() + Send + Sync>;
+
+/// Channel a request was sent on and its sequence within that channel
+///
+/// Replies are looked up by their `reply_to`, which the server sets to the
+/// request's sequence; their own sequence comes from the server's counter.
+/// Wire formats older than `PROTOCOL_VERSION` have no `reply_to`, so requests
+/// are refused on them.
+type RequestKey = (u16, u32);
+
+/// Handler for data pushed by the server
+type PushHandler = Arc<dyn Handler>;
+
 /// Client for making requests
 pub struct Client {
     transport: Arc<Transport>,
     server_addr: SocketAddr,
-    pending_requests: Arc<RwLock<HashMap<u32, PendingRequest>>>,
+    pending_requests: Arc<RwLock<HashMap<RequestKey, PendingRequest>>>,
+    streams: Arc<RwLock<HashMap<RequestKey, Arc<Mutex<StreamReassembler>>>>>,
+    handlers: Arc<RwLock<HashMap<String, PushHandler>>>,
+    channel_handlers: Arc<RwLock<HashMap<(u16, String), PushHandler>>>,
     request_timeout: Duration,
+    liveness_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    /// Heartbeat task started by the receive loop, stopped on disconnect
+    heartbeat: std::sync::Mutex<Option<AbortHandle>>,
+    /// Liveness and rekey tasks, stopped when the client is dropped
+    tasks: TaskSet,
+    connected: AtomicBool,
+    last_seen: Arc<RwLock<Instant>>,
+    disconnect_handler: Arc<RwLock<Option<DisconnectHandler>>>,
+    auth_token: Bytes,
+    /// Server identity keys the key exchange must be signed with, if any
+    pinned_server_keys: Vec<[u8; IDENTITY_KEY_SIZE]>,
+    /// Format typed requests and pushed payloads are encoded in
+    codec: Codec,
+    /// Secret of the last exchanged session, for the ticket the server sends after it
+    resumption_secret: std::sync::Mutex<Option<[u8; 32]>>,
+    /// Latest session ticket, presented on the next connect
+    session_ticket: std::sync::Mutex<Option<SessionTicket>>,
+    /// Whether the current session was resumed from a ticket
+    resumed: AtomicBool,
+    handshake: Mutex<Option<mpsc::UnboundedSender<Packet>>>,
+    receiving: AtomicBool,
+    /// Held by whatever reads from the transport: the recv loop, or a handshake until it starts
+    reader: Mutex<()>,
+    /// Pings awaiting their pong, keyed by send time on the `epoch` clock
+    pings: Mutex<HashMap<u64, oneshot::Sender<Duration>>>,
+    epoch: Instant,
 }
 
 impl Client {
@@ -35,107 +281,899 @@ impl Client {
         config: TransportConfig,
     ) -> Result<Self> {
         let transport = Transport::bind(bind_addr, config).await?;
-        
-        let client = Self {
+        Ok(Self::with_transport(transport, server_addr))
+    }
+
+    /// Start building a client for the server at `server_addr`
+    pub fn builder(server_addr: SocketAddr) -> ClientBuilder {
+        ClientBuilder::new(server_addr)
+    }
+
+    /// Create a client for `server_addr` on an existing transport
+    ///
+    /// Use `Transport::over` to connect over a `PacketTransport` other than UDP.
+    pub fn with_transport(transport: Transport, server_addr: SocketAddr) -> Self {
+        let heartbeat_interval = transport.config().heartbeat_interval;
+        let liveness_timeout = heartbeat_interval * 3;
+        Self {
             transport: Arc::new(transport),
-            server_addr,
+            server_addr: canonical_addr(server_addr),
             pending_requests: Arc::new(RwLock::new(HashMap::new())),
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            handlers: Arc::new(RwLock::new(HashMap::new())),
+            channel_handlers: Arc::new(RwLock::new(HashMap::new())),
             request_timeout: Duration::from_secs(5),
-        };
-
-        Ok(client)
+            liveness_timeout,
+            heartbeat_interval: Some(heartbeat_interval),
+            heartbeat: std::sync::Mutex::new(None),
+            tasks: TaskSet::default(),
+            connected: AtomicBool::new(false),
+            last_seen: Arc::new(RwLock::new(Instant::now())),
+            disconnect_handler: Arc::new(RwLock::new(None)),
+            auth_token: Bytes::new(),
+            pinned_server_keys: Vec::new(),
+            codec: Codec::default(),
+            resumption_secret: std::sync::Mutex::new(None),
+            session_ticket: std::sync::Mutex::new(None),
+            resumed: AtomicBool::new(false),
+            handshake: Mutex::new(None),
+            receiving: AtomicBool::new(false),
+            reader: Mutex::new(()),
+            pings: Mutex::new(HashMap::new()),
+            epoch: Instant::now(),
+        }
     }
 
     /// Set encryption provider
-    pub fn set_crypto(&mut self, crypto: CryptoProvider) {
-        Arc::get_mut(&mut self.transport)
-            .unwrap()
-            .set_crypto(crypto);
+    ///
+    /// Safe to call at any time, including while running, to swap keys.
+    pub async fn set_crypto(&self, crypto: impl Crypto + 'static) {
+        self.transport.set_crypto(crypto).await;
     }
 
     /// Set compression provider
-    pub fn set_compression(&mut self, compression: CompressionProvider) {
-        Arc::get_mut(&mut self.transport)
-            .unwrap()
-            .set_compression(compression);
+    pub async fn set_compression(&self, compression: impl Compression + 'static) {
+        self.transport.set_compression(compression).await;
     }
 
     /// Connect to the server
+    ///
+    /// The `Connect` packet offers the transport's `max_protocol_version`,
+    /// and the server answers in the highest version both sides support,
+    /// which every later packet to the server is written in.
+    ///
+    /// When encryption is enabled without a pre-shared key, the handshake
+    /// performs an ephemeral X25519 exchange and derives a session key.
+    /// Every attempt uses a fresh key pair and the server must echo the
+    /// client's public key in its `ConnectAck`, so a replayed `ConnectAck`
+    /// from an earlier handshake is ignored. With pinned server keys, the
+    /// server must also sign the exchange with one of them, or the connect
+    /// fails with `AuthenticationFailed`.
+    ///
+    /// If the server issued a session ticket on an earlier connect, and it has
+    /// not expired, the ticket is presented instead and the session resumes
+    /// under a fresh key without an exchange. Should the server refuse the
+    /// ticket, the connect falls back to a full exchange.
     pub async fn connect(&self) -> Result<()> {
+        self.send_connect(None).await.map(|_| ())
+    }
+
+    /// Connect and send a request in the same round trip (0-RTT)
+    ///
+    /// The request rides in the `Connect` packet and its response in the
+    /// `ConnectAck`, saving the round trip that `connect` followed by
+    /// `request` takes. The request must fit in a single packet, and the
+    /// handshake waits for its handler, up to the request timeout.
+    ///
+    /// The request is sent before any key exchange, so it is neither
+    /// encrypted nor protected against replay: anyone on the path can read
+    /// it, or resend the `Connect` to run the handler again. It is therefore
+    /// refused when encryption is enabled, on either side; only use it for
+    /// idempotent requests that are fine to send in the clear.
+    pub async fn connect_with_request(&self, route: impl Into<String>, payload: Bytes) -> Result<Bytes> {
+        if self.transport.config().enable_encryption {
+            return Err(ProtocolError::Encryption(
+                "Early data cannot be sent with encryption enabled".to_string(),
+            ));
+        }
+        let max_version = self.transport.config().max_protocol_version;
+        if max_version < PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                actual: max_version,
+            });
+        }
+
+        let ack = self.send_connect(Some((route.into(), payload))).await?;
+        match ack.early_reply()? {
+            Some(reply) if reply.packet_type == PacketType::Error => Err(reply.remote_error()?),
+            Some(reply) => Ok(reply.payload),
+            None => Err(ProtocolError::InvalidPacket(
+                "Server did not answer the early request".to_string(),
+            )),
+        }
+    }
+
+    /// Send a `Connect`, carrying `early` as a request if set, and return the server's `ConnectAck`
+    async fn send_connect(&self, early: Option<(String, Bytes)>) -> Result<Packet> {
         info!("Connecting to {}", self.server_addr);
-        
-        let connect_packet = Packet::new_connect();
-        self.transport.send(connect_packet, self.server_addr).await?;
-
-        // Wait for ConnectAck
-        let start = std::time::Instant::now();
-        while start.elapsed() < Duration::from_secs(5) {
-            match timeout(Duration::from_millis(100), self.transport.recv()).await {
-                Ok(Ok((packet, _))) => {
-                    if packet.packet_type == PacketType::ConnectAck {
-                        info!("Connected to {}", self.server_addr);
-                        return Ok(());
+
+        let offer = if !self.transport.config().enable_encryption || self.transport.has_crypto().await {
+            KeyOffer::None
+        } else {
+            match self.valid_ticket() {
+                Some(ticket) => KeyOffer::Resume {
+                    nonce: CryptoProvider::generate_key(),
+                    ticket,
+                },
+                None => KeyOffer::Exchange(KeyExchange::new()),
+            }
+        };
+        if matches!(offer, KeyOffer::None) && !self.pinned_server_keys.is_empty() {
+            return Err(ProtocolError::AuthenticationFailed(
+                "Pinned server keys need the key exchange handshake".to_string(),
+            ));
+        }
+
+        if let Some(ack) = self.offer_keys(offer, early.clone()).await? {
+            return Ok(ack);
+        }
+        // Only a resumption goes unanswered, when the server refuses the ticket
+        info!("Server refused the session ticket, exchanging keys instead");
+        *self.session_ticket.lock().unwrap() = None;
+        self.offer_keys(KeyOffer::Exchange(KeyExchange::new()), early)
+            .await?
+            .ok_or_else(|| ProtocolError::Encryption("Server did not complete key exchange".to_string()))
+    }
+
+    /// Session ticket to present, unless there is none or it has expired
+    fn valid_ticket(&self) -> Option<SessionTicket> {
+        let mut ticket = self.session_ticket.lock().unwrap();
+        if ticket.as_ref().is_some_and(|ticket| ticket.expires_at <= Instant::now()) {
+            debug!("Session ticket expired");
+            *ticket = None;
+        }
+        ticket.clone()
+    }
+
+    /// Send one `Connect` offering `offer` and wait for the server's answer
+    ///
+    /// `None` when the server refused a session ticket.
+    async fn offer_keys(&self, offer: KeyOffer, early: Option<(String, Bytes)>) -> Result<Option<Packet>> {
+        let key_material = match &offer {
+            KeyOffer::None => Vec::new(),
+            KeyOffer::Exchange(exchange) => exchange.public_key().to_vec(),
+            KeyOffer::Resume { nonce, ticket } => [&nonce[..], &ticket.ticket[..]].concat(),
+        };
+        // The handshake also waits for the early request's handler
+        let mut deadline = Duration::from_secs(5);
+        let connect_packet = if let Some((route, data)) = early {
+            deadline = deadline.max(self.request_timeout);
+            Packet::new_connect_with_early_data(&key_material, &self.auth_token, route, &data)
+        } else if self.transport.config().max_protocol_version < PROTOCOL_VERSION {
+            // Older versions carry just the public key, with no room for a token
+            Packet {
+                payload: Bytes::from(key_material),
+                ..Packet::new_connect()
+            }
+        } else {
+            Packet::new_connect_with(&key_material, &self.auth_token)
+        };
+
+        let (tx, mut replies) = mpsc::unbounded_channel();
+        *self.handshake.lock().await = Some(tx);
+
+        let result = match self.transport.send(connect_packet, self.server_addr).await {
+            Ok(()) => timeout(deadline, self.complete_handshake(offer, &mut replies))
+                .await
+                .unwrap_or(Err(ProtocolError::Timeout)),
+            Err(e) => Err(e),
+        };
+        *self.handshake.lock().await = None;
+        result
+    }
+
+    /// Wait for the server's answer to the `Connect`, apply it and return it
+    ///
+    /// `None` when the server refused a session ticket.
+    async fn complete_handshake(
+        &self,
+        mut offer: KeyOffer,
+        replies: &mut mpsc::UnboundedReceiver<Packet>,
+    ) -> Result<Option<Packet>> {
+        loop {
+            let Some(packet) = self.next_handshake_reply(replies).await else {
+                return Err(ProtocolError::ConnectionClosed);
+            };
+            if packet.packet_type == PacketType::ConnectReject {
+                let reason = String::from_utf8_lossy(&packet.payload).into_owned();
+                return Err(ProtocolError::AuthenticationFailed(reason));
+            }
+            let pending = match std::mem::replace(&mut offer, KeyOffer::None) {
+                KeyOffer::None => {
+                    self.transport.set_peer_version(self.server_addr, packet.version).await;
+                    info!("Connected to {} at protocol version {}", self.server_addr, packet.version);
+                    self.resumed.store(false, Ordering::SeqCst);
+                    self.mark_connected().await;
+                    return Ok(Some(packet));
+                }
+                KeyOffer::Resume { nonce, ticket } => {
+                    if packet.payload.is_empty() {
+                        return Ok(None);
+                    }
+                    if packet.payload[..] != nonce[..] {
+                        debug!("Ignoring ConnectAck for a different handshake");
+                        offer = KeyOffer::Resume { nonce, ticket };
+                        continue;
                     }
+                    let crypto = CryptoProvider::resumed(&ticket.secret, &nonce);
+                    self.transport
+                        .set_session_crypto(self.server_addr, Arc::new(crypto))
+                        .await;
+                    self.transport.set_peer_version(self.server_addr, packet.version).await;
+                    info!(
+                        "Resumed session with {} at protocol version {}",
+                        self.server_addr, packet.version
+                    );
+                    self.resumed.store(true, Ordering::SeqCst);
+                    self.mark_connected().await;
+                    return Ok(Some(packet));
                 }
-                _ => continue,
+                KeyOffer::Exchange(pending) => pending,
+            };
+
+            let payload = &packet.payload;
+            let keys_len = 2 * PUBLIC_KEY_SIZE;
+            let signed = payload.len() == keys_len + IDENTITY_KEY_SIZE + SIGNATURE_SIZE;
+            if payload.len() != keys_len && !signed {
+                return Err(ProtocolError::Encryption(
+                    "Server did not complete key exchange".to_string(),
+                ));
+            }
+            let (server_public, client_public) = payload[..keys_len].split_at(PUBLIC_KEY_SIZE);
+            if client_public != pending.public_key() {
+                debug!("Ignoring ConnectAck for a different handshake");
+                offer = KeyOffer::Exchange(pending);
+                continue;
             }
+
+            if !self.pinned_server_keys.is_empty() {
+                if !signed {
+                    return Err(ProtocolError::AuthenticationFailed(
+                        "Server did not sign the key exchange".to_string(),
+                    ));
+                }
+                let (identity, signature) = payload[keys_len..].split_at(IDENTITY_KEY_SIZE);
+                verify_handshake(&self.pinned_server_keys, identity, signature, server_public, client_public)?;
+            }
+
+            let crypto = pending.derive(server_public, true)?;
+            *self.resumption_secret.lock().unwrap() = Some(crypto.resumption_secret());
+            self.transport
+                .set_session_crypto(self.server_addr, Arc::new(crypto))
+                .await;
+            self.transport.set_peer_version(self.server_addr, packet.version).await;
+            info!(
+                "Connected to {} with negotiated encryption at protocol version {}",
+                self.server_addr, packet.version
+            );
+            self.resumed.store(false, Ordering::SeqCst);
+            self.mark_connected().await;
+            return Ok(Some(packet));
         }
+    }
+
+    /// Next `ConnectAck` or `ConnectReject` routed to the handshake waiter
+    ///
+    /// Replies always arrive through `handle_packet`. Once the recv loop is
+    /// running it delivers them, and until then this drives the receive path
+    /// itself. A recv loop started meanwhile waits for the handshake to
+    /// finish, so a read here is only ever cut short by the handshake's own
+    /// deadline, never to hand over to the loop.
+    async fn next_handshake_reply(
+        &self,
+        replies: &mut mpsc::UnboundedReceiver<Packet>,
+    ) -> Option<Packet> {
+        loop {
+            if let Ok(packet) = replies.try_recv() {
+                return Some(packet);
+            }
+            let reader = if self.receiving.load(Ordering::SeqCst) {
+                None
+            } else {
+                self.reader.try_lock().ok()
+            };
+            let Some(_reader) = reader else {
+                return replies.recv().await;
+            };
 
-        Err(ProtocolError::Timeout)
+            match self.transport.recv().await {
+                Ok((packet, _)) => {
+                    if let Err(e) = self.handle_packet(packet).await {
+                        error!("Error handling packet: {}", e);
+                    }
+                }
+                Err(e) => error!("Error receiving packet: {}", e),
+            }
+        }
     }
 
-    /// Send a request and wait for response
-    pub async fn request(&self, route: impl Into<String>, payload: Bytes) -> Result<Bytes> {
+    /// Register a handler for data the server pushes to this client
+    ///
+    /// Pushed messages are one-way, so the handler's response is discarded.
+    pub async fn on<H>(&self, route: impl Into<String>, handler: H)
+    where
+        H: Handler + 'static,
+    {
         let route = route.into();
-        debug!("Sending request to route: {}", route);
+        trace!("Registered push route: {}", route);
+        self.handlers.write().await.insert(route, Arc::new(handler));
+    }
 
-        let sequence = self
-            .transport
-            .send_reliable(route.clone(), payload, self.server_addr)
+    /// Register a handler for data the server pushes to a route on one channel
+    ///
+    /// Takes precedence over a handler registered with `on` for the same route.
+    pub async fn on_channel<H>(&self, channel: u16, route: impl Into<String>, handler: H)
+    where
+        H: Handler + 'static,
+    {
+        let route = route.into();
+        trace!("Registered push route: {} on channel {}", route, channel);
+        self.channel_handlers
+            .write()
+            .await
+            .insert((channel, route), Arc::new(handler));
+    }
+
+    /// Register a synchronous function handler for pushed data
+    pub async fn on_fn<F>(&self, route: impl Into<String>, handler: F)
+    where
+        F: Fn(Context) -> Result<Response> + Send + Sync + 'static,
+    {
+        self.on(route, FnHandler::new(handler)).await;
+    }
+
+    /// Register an async function handler for pushed data
+    pub async fn on_async<F, Fut>(&self, route: impl Into<String>, handler: F)
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<Response>> + Send + 'static,
+    {
+        self.on(route, AsyncFnHandler::new(handler)).await;
+    }
+
+    /// Tell the server this client is going away, without waiting for anything
+    ///
+    /// This is the abortive disconnect: it does not flush, so reliable
+    /// packets still waiting for an ACK are lost once the server forgets the
+    /// connection. Use `disconnect_gracefully` to deliver them first.
+    pub async fn disconnect(&self) -> Result<()> {
+        info!("Disconnecting from {}", self.server_addr);
+        if let Some(heartbeat) = self.heartbeat.lock().unwrap().take() {
+            heartbeat.abort();
+        }
+        self.transport.send(Packet::new_disconnect(), self.server_addr).await?;
+        self.transport.remove_session_crypto(self.server_addr).await;
+        self.connected.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Wait until every reliable packet sent to the server is acknowledged
+    ///
+    /// Packets given up on after the maximum retransmissions also stop
+    /// counting, as reported to the transport's `on_drop` callback. Fails
+    /// with `Timeout` if packets are still waiting for an ACK after `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> Result<()> {
+        time::timeout(timeout, self.transport.flush(self.server_addr))
+            .await
+            .map_err(|_| ProtocolError::Timeout)
+    }
+
+    /// Flush, then disconnect once everything sent has been delivered
+    ///
+    /// If the flush times out, nothing is sent and the client stays
+    /// connected, still retransmitting; call `disconnect` to give up.
+    pub async fn disconnect_gracefully(&self, timeout: Duration) -> Result<()> {
+        self.flush(timeout).await?;
+        self.disconnect().await
+    }
+
+    /// Rotate the negotiated session key
+    ///
+    /// A `Rekey` packet carrying a fresh salt is sent under the current key,
+    /// then both sides derive the next key from the current key and the salt.
+    /// Packets with sequences before the `Rekey` are encrypted under the old
+    /// key and everything after it under the new one. Retransmissions reuse
+    /// their original ciphertext, which is why the old key keeps decrypting
+    /// for the grace window; a new-key packet that overtakes the `Rekey` is
+    /// simply accepted on retransmission.
+    pub async fn rekey(&self) -> Result<()> {
+        if self.transport.session_crypto(self.server_addr).await.is_none() {
+            return Err(ProtocolError::Encryption(
+                "No negotiated session key to rotate".to_string(),
+            ));
+        }
+
+        let salt = CryptoProvider::generate_key();
+        self.transport
+            .send_reliable_packet(Packet::new_rekey(Bytes::copy_from_slice(&salt)), self.server_addr)
             .await?;
+        self.transport.rekey_session(self.server_addr, &salt).await?;
+
+        info!("Rotated session key with {}", self.server_addr);
+        Ok(())
+    }
+
+    /// Rotate the session key whenever the configured byte or time limit is reached
+    fn start_rekey_task(self: &Arc<Self>) {
+        let config = self.transport.config();
+        if config.rekey_after_bytes.is_none() && config.rekey_interval.is_none() {
+            return;
+        }
+        let period = config
+            .rekey_interval
+            .map_or(Duration::from_secs(1), |interval| interval.min(Duration::from_secs(1)));
+
+        let weak = Arc::downgrade(self);
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(client) = weak.upgrade() else {
+                    break;
+                };
+                let Some(crypto) = client.transport.session_crypto(client.server_addr).await else {
+                    continue;
+                };
 
-        // Create a channel for the response
+                let config = client.transport.config();
+                let bytes_due = config
+                    .rekey_after_bytes
+                    .is_some_and(|limit| crypto.bytes_processed() >= limit);
+                let time_due = config
+                    .rekey_interval
+                    .is_some_and(|limit| crypto.key_age() >= limit);
+                if bytes_due || time_due {
+                    if let Err(e) = client.rekey().await {
+                        error!("Session key rotation failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Measure the round trip to the server
+    ///
+    /// Sends a `Ping` carrying its send time, which the server's transport
+    /// echoes straight back in a `Pong` without involving any handler. Unlike
+    /// the transport's RTT estimate this can be triggered at will, e.g. for a
+    /// health dashboard. The recv loop must be running for the pong to arrive.
+    pub async fn ping(&self) -> Result<Duration> {
+        let sent_at = self.epoch.elapsed().as_micros() as u64;
         let (tx, rx) = oneshot::channel();
-        self.pending_requests
-            .write()
-            .await
-            .insert(sequence, PendingRequest { tx });
+        self.pings.lock().await.insert(sent_at, tx);
 
-        // Wait for response with timeout
+        if let Err(e) = self.transport.send(Packet::new_ping(sent_at), self.server_addr).await {
+            self.pings.lock().await.remove(&sent_at);
+            return Err(e);
+        }
         match timeout(self.request_timeout, rx).await {
-            Ok(Ok(response)) => {
-                debug!("Received response for sequence {}", sequence);
-                Ok(response)
-            }
-            Ok(Err(_)) => Err(ProtocolError::Channel("Response channel closed".to_string())),
-            Err(_) => {
-                self.pending_requests.write().await.remove(&sequence);
+            Ok(Ok(rtt)) => Ok(rtt),
+            _ => {
+                self.pings.lock().await.remove(&sent_at);
                 Err(ProtocolError::Timeout)
             }
         }
     }
 
+    /// Register a callback invoked when the server stops responding
+    pub async fn on_disconnect<F>(&self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        *self.disconnect_handler.write().await = Some(Arc::new(callback));
+    }
+
+    /// Whether the client is connected and the server is still responding
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Whether the current session was resumed from a session ticket
+    pub fn is_resumed(&self) -> bool {
+        self.resumed.load(Ordering::SeqCst)
+    }
+
+    /// Wire format version negotiated with the server
+    ///
+    /// The transport's `max_protocol_version` until connected.
+    pub async fn protocol_version(&self) -> u8 {
+        self.transport.peer_version(self.server_addr).await
+    }
+
+    /// Record a successful handshake
+    async fn mark_connected(&self) {
+        *self.last_seen.write().await = Instant::now();
+        self.connected.store(true, Ordering::SeqCst);
+        self.transport.discover_path_mtu(self.server_addr).await;
+    }
+
+    /// Watch for the server going silent and surface it as a disconnect
+    ///
+    /// Any packet from the server, including heartbeat replies, counts as a
+    /// sign of life. Once nothing has arrived for `liveness_timeout` the
+    /// client is marked disconnected, pending requests fail with
+    /// `ConnectionClosed` and the disconnect callback runs.
+    fn start_liveness_monitor(self: &Arc<Self>) {
+        let weak = Arc::downgrade(self);
+        let period = (self.liveness_timeout / 4).max(Duration::from_millis(10));
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(client) = weak.upgrade() else {
+                    break;
+                };
+                if !client.is_connected() {
+                    continue;
+                }
+
+                let silent_for = client.last_seen.read().await.elapsed();
+                if silent_for <= client.liveness_timeout {
+                    continue;
+                }
+
+                warn!("No packets from {} for {:?}, disconnecting", client.server_addr, silent_for);
+                client.connected.store(false, Ordering::SeqCst);
+
+                let pending: Vec<PendingRequest> = client
+                    .pending_requests
+                    .write()
+                    .await
+                    .drain()
+                    .map(|(_, pending)| pending)
+                    .collect();
+                for request in pending {
+                    let _ = request.tx.send(Err(ProtocolError::ConnectionClosed));
+                }
+
+                let streams: Vec<_> = client.streams.write().await.drain().collect();
+                for (_, stream) in streams {
+                    stream.lock().await.fail(ProtocolError::ConnectionClosed).await;
+                }
+
+                let callback = client.disconnect_handler.read().await.clone();
+                if let Some(callback) = callback {
+                    callback();
+                }
+            }
+        });
+    }
+
+    /// Send a request and wait for response
+    ///
+    /// Needs the current wire format, which carries the sequence a reply
+    /// answers; fails with `ProtocolError::VersionMismatch` on older ones.
+    pub async fn request(&self, route: impl Into<String>, payload: Bytes) -> Result<Bytes> {
+        self.request_cancellable(route, payload).await?.response().await
+    }
+
+    /// Send a request and wait up to `timeout` for the response
+    ///
+    /// Overrides the client's request timeout for this call only, e.g. to
+    /// give a health check a short deadline and a bulk upload a long one.
+    pub async fn request_with_timeout(
+        &self,
+        route: impl Into<String>,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<Bytes> {
+        self.request_cancellable(route, payload)
+            .await?
+            .with_timeout(timeout)
+            .response()
+            .await
+    }
+
+    /// Send a request, re-issuing it while the server fails it with a retryable code
+    ///
+    /// Each attempt is a new request, so only use this on routes that are
+    /// safe to run more than once. Returns the last error once the policy
+    /// gives up.
+    pub async fn request_with_retry(
+        &self,
+        route: impl Into<String>,
+        payload: Bytes,
+        policy: RetryPolicy,
+    ) -> Result<Bytes> {
+        let route = route.into();
+        let mut attempt = 1;
+        loop {
+            match self.request(route.clone(), payload.clone()).await {
+                Err(e) if attempt < policy.max_attempts && policy.is_retryable(&e) => {
+                    let delay = policy.delay(attempt);
+                    debug!("Attempt {} on route {} failed ({}), retrying in {:?}", attempt, route, e, delay);
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a request on a channel and wait for response
+    ///
+    /// Channels are independent sequence spaces: a channel held up by loss
+    /// or a slow ordered handler does not delay requests on another.
+    pub async fn request_on(
+        &self,
+        channel: u16,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<Bytes> {
+        self.request_cancellable_on(channel, route, payload).await?.response().await
+    }
+
+    /// Send a request and return a handle for awaiting or cancelling its response
+    ///
+    /// Cancelling, or dropping the handle before the response arrives, stops
+    /// retransmitting the request and tells the server to abort its handler.
+    pub async fn request_cancellable(
+        &self,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<RequestHandle> {
+        self.request_cancellable_on(DEFAULT_CHANNEL, route, payload).await
+    }
+
+    /// Send a request on a channel and return a handle for its response
+    pub async fn request_cancellable_on(
+        &self,
+        channel: u16,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<RequestHandle> {
+        let mut packet = Packet::new_data(route.into(), payload, 0);
+        packet.channel_id = channel;
+        self.send_request_packet(packet).await
+    }
+
+    /// Send a request the server runs at most once for `key`, and wait for the response
+    ///
+    /// Sending the same key again, say after a timeout or from
+    /// `request_with_retry`-style logic of your own, gets the response the
+    /// handler gave the first time instead of running it again, for as long
+    /// as the server keeps it (`Server::set_idempotency_ttl`). Use a fresh
+    /// key, such as a UUID, for every distinct operation. Keys are 1 to 255
+    /// bytes, and are scoped to this client's identity and the route.
+    pub async fn request_idempotent(
+        &self,
+        route: impl Into<String>,
+        key: &str,
+        payload: Bytes,
+    ) -> Result<Bytes> {
+        let packet = Packet::new_idempotent_data(route.into(), key, &payload)?;
+        self.send_request_packet(packet).await?.response().await
+    }
+
+    /// Fail unless the negotiated wire format can carry replies
+    async fn check_request_version(&self) -> Result<()> {
+        let version = self.protocol_version().await;
+        if version < PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch { expected: PROTOCOL_VERSION, actual: version });
+        }
+        Ok(())
+    }
+
+    /// Send a request packet reliably and return a handle for its response
+    async fn send_request_packet(&self, packet: Packet) -> Result<RequestHandle> {
+        self.check_request_version().await?;
+        let channel = packet.channel_id;
+        trace!("Sending request to route: {} on channel {}", packet.route, channel);
+
+        // Wait for the response before sending, since it can arrive before the send returns
+        let (tx, rx) = oneshot::channel();
+        let mut registered = None;
+        let sent = self
+            .transport
+            .send_reliable_packet_with(packet, self.server_addr, |channel, sequence| {
+                registered = Some((channel, sequence));
+                let pending_requests = self.pending_requests.clone();
+                async move {
+                    pending_requests.write().await.insert((channel, sequence), PendingRequest { tx });
+                }
+            })
+            .await;
+        let sequence = match sent {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                if let Some(key) = registered {
+                    self.pending_requests.write().await.remove(&key);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(RequestHandle {
+            channel,
+            sequence,
+            rx,
+            request_timeout: self.request_timeout,
+            canceller: Some(Canceller {
+                transport: self.transport.clone(),
+                server_addr: self.server_addr,
+                pending_requests: self.pending_requests.clone(),
+            }),
+        })
+    }
+
+    /// Send many requests at once, packed into as few datagrams as possible
+    ///
+    /// The requests go out in the order given and the results come back in
+    /// the same order, each failing or succeeding on its own. The server may
+    /// still run their handlers concurrently and in any order, unless their
+    /// routes are ordered. Fails as a whole only if the requests cannot be sent.
+    pub async fn request_many(&self, requests: Vec<(String, Bytes)>) -> Result<Vec<Result<Bytes>>> {
+        self.check_request_version().await?;
+        let packets = requests
+            .into_iter()
+            .map(|(route, payload)| Packet::new_data(route, payload, 0))
+            .collect();
+        // Each request waits for its reply before it is sent, since the
+        // earliest replies can arrive while the batch waits for window room
+        let mut handles = Vec::new();
+        let sent = self
+            .transport
+            .send_reliable_batch_with(packets, self.server_addr, |channel, sequence| {
+                let (tx, rx) = oneshot::channel();
+                handles.push(RequestHandle {
+                    channel,
+                    sequence,
+                    rx,
+                    request_timeout: self.request_timeout,
+                    canceller: None,
+                });
+                let pending_requests = self.pending_requests.clone();
+                async move {
+                    pending_requests.write().await.insert((channel, sequence), PendingRequest { tx });
+                }
+            })
+            .await;
+        if let Err(e) = sent {
+            let mut pending = self.pending_requests.write().await;
+            for handle in &handles {
+                pending.remove(&(handle.channel, handle.sequence));
+            }
+            return Err(e);
+        }
+        trace!("Sent {} batched requests", handles.len());
+
+        for handle in &mut handles {
+            handle.canceller = Some(Canceller {
+                transport: self.transport.clone(),
+                server_addr: self.server_addr,
+                pending_requests: self.pending_requests.clone(),
+            });
+        }
+
+        Ok(futures::future::join_all(handles.into_iter().map(RequestHandle::response)).await)
+    }
+
+    /// Send a typed request to an `on_request` route and wait for its response
+    ///
+    /// The request is sent as-is, encoded with the client's codec, so set its
+    /// `id` to whatever should be traced. Fails with
+    /// `ProtocolError::InvalidPayload` if the response does not parse or
+    /// carries a different id; a handler error comes back as an unsuccessful
+    /// `Response` rather than an `Err`.
+    pub async fn request_typed<Req, Resp>(
+        &self,
+        route: impl Into<String>,
+        request: &protocol::Request<Req>,
+    ) -> Result<protocol::Response<Resp>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let reply = self.request(route, self.codec.encode(request)?).await?;
+        let response: protocol::Response<Resp> = self.codec.decode(&reply)?;
+        if response.id != request.id {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "Response id {} does not match request id {}",
+                response.id, request.id
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Send a request to a stream route and read the response as it arrives
+    ///
+    /// Chunks are yielded in order even if they arrive out of order. If the
+    /// reader falls behind, chunks are buffered up to the stream window.
+    pub async fn request_stream(
+        &self,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<ResponseStream> {
+        let route = route.into();
+        trace!("Opening stream on route: {}", route);
+
+        let (tx, rx) = mpsc::channel(DEFAULT_STREAM_WINDOW);
+
+        // Hold the lock while sending so the first chunk cannot arrive before the stream is registered
+        let mut streams = self.streams.write().await;
+        let sequence = self
+            .transport
+            .send_reliable(route, payload, self.server_addr)
+            .await?;
+        streams.insert((DEFAULT_CHANNEL, sequence), Arc::new(Mutex::new(StreamReassembler::new(tx))));
+
+        Ok(ResponseStream::new(rx))
+    }
+
     /// Send a request without waiting for response
+    ///
+    /// Pairs with routes answering `Response::none()`, which send nothing
+    /// back; other routes still send a reply nobody is waiting for.
     pub async fn send(&self, route: impl Into<String>, payload: Bytes) -> Result<u32> {
+        self.send_on(DEFAULT_CHANNEL, route, payload).await
+    }
+
+    /// Send a request at most once, without waiting for response
+    ///
+    /// Unlike `send`, the packet is never retransmitted and the server
+    /// neither ACKs nor answers it, so a lost or late packet is simply
+    /// dropped. Meant for real-time data that is worthless once late.
+    pub async fn send_unreliable(&self, route: impl Into<String>, payload: Bytes) -> Result<u32> {
         let route = route.into();
-        debug!("Sending fire-and-forget to route: {}", route);
+        trace!("Sending unreliable packet to route: {}", route);
+
+        self.transport
+            .send_unreliable_on(DEFAULT_CHANNEL, route, payload, self.server_addr)
+            .await
+    }
+
+    /// Send a request on a channel without waiting for response
+    ///
+    /// Returns the sequence the request was given within its channel.
+    pub async fn send_on(&self, channel: u16, route: impl Into<String>, payload: Bytes) -> Result<u32> {
+        let route = route.into();
+        trace!("Sending fire-and-forget to route: {} on channel {}", route, channel);
         
         self.transport
-            .send_reliable(route, payload, self.server_addr)
+            .send_reliable_on(channel, route, payload, self.server_addr)
             .await
     }
 
     /// Start receiving responses
+    ///
+    /// The background tasks started here only hold the client weakly: once
+    /// this loop is stopped and the client dropped, they stop too.
     pub async fn start_recv_loop(self: Arc<Self>) -> Result<()> {
         // Start retransmission task
         self.transport.clone().start_retransmission_task().await;
 
-        // Start heartbeat task
-        self.transport.clone().start_heartbeat_task(self.server_addr).await;
+        if let Some(period) = self.heartbeat_interval {
+            let heartbeat = self.transport.clone().start_heartbeat_task(self.server_addr, period).await;
+            if let Some(previous) = self.heartbeat.lock().unwrap().replace(heartbeat) {
+                previous.abort();
+            }
+        }
 
-        loop {
-            match self.transport.recv().await {
-                Ok((packet, _)) => {
+        self.start_liveness_monitor();
+        self.start_rekey_task();
+        self.receiving.store(true, Ordering::SeqCst);
+        // Wait out a handshake reading packets itself
+        let _reader = self.reader.lock().await;
+
+        let incoming = self.transport.incoming();
+        tokio::pin!(incoming);
+        while let Some(received) = incoming.next().await {
+            match received {
+                Ok((packet, addr)) => {
+                    if addr == self.server_addr {
+                        *self.last_seen.write().await = Instant::now();
+                    }
                     let client = self.clone();
                     tokio::spawn(async move {
                         if let Err(e) = client.handle_packet(packet).await {
@@ -148,27 +1186,108 @@ impl Client {
                 }
             }
         }
+        Ok(())
     }
 
     /// Handle an incoming packet
     async fn handle_packet(&self, packet: Packet) -> Result<()> {
         match packet.packet_type {
             PacketType::Data => {
-                debug!("Received data response: seq={}", packet.sequence);
-                
-                // Find pending request
-                if let Some(pending) = self.pending_requests.write().await.remove(&packet.sequence) {
-                    let _ = pending.tx.send(packet.payload);
+                trace!("Received data packet: seq={} reply_to={:?}", packet.sequence, packet.reply_to);
+
+                // Find the pending request it answers
+                if let Some(request) = packet.answered_sequence() {
+                    let key = (packet.channel_id, request);
+                    let pending = self.pending_requests.write().await.remove(&key);
+                    if let Some(pending) = pending {
+                        let _ = pending.tx.send(Ok(packet.payload));
+                        return Ok(());
+                    }
+                }
+
+                // Otherwise it is an unsolicited push from the server
+                let key = (packet.channel_id, packet.route.clone());
+                let handler = match self.channel_handlers.read().await.get(&key).cloned() {
+                    Some(handler) => Some(handler),
+                    None => self.handlers.read().await.get(&packet.route).cloned(),
+                };
+                match handler {
+                    Some(handler) => {
+                        let ctx = Context {
+                            route: packet.route.clone(),
+                            payload: packet.payload.clone(),
+                            remote_addr: self.server_addr,
+                            correlation_id: correlation_id(self.server_addr, &packet),
+                            packet,
+                            params: HashMap::new(),
+                            auth: None,
+                            codec: self.codec,
+                            extensions: Extensions::default(),
+                        };
+                        handler.handle(ctx).await?;
+                    }
+                    None => debug!("No handler for pushed route: {}", packet.route),
+                }
+            }
+            PacketType::Error => {
+                let error = packet.remote_error()?;
+                let pending = match packet.answered_sequence() {
+                    Some(request) => self.pending_requests.write().await.remove(&(packet.channel_id, request)),
+                    None => None,
+                };
+                match pending {
+                    Some(pending) => {
+                        let _ = pending.tx.send(Err(error));
+                    }
+                    None => debug!("Error reply for no pending request: {}", error),
                 }
             }
-            PacketType::Ack => {
-                self.transport.handle_ack(packet.sequence).await;
+            PacketType::Stream => {
+                let (header, chunk) = packet.stream_parts()?;
+                let key = (packet.channel_id, header.stream_id);
+                let stream = self.streams.read().await.get(&key).cloned();
+                let Some(stream) = stream else {
+                    debug!("Chunk for unknown stream {}", header.stream_id);
+                    return Ok(());
+                };
+
+                if stream.lock().await.accept(header, chunk).await {
+                    self.streams.write().await.remove(&key);
+                }
             }
-            PacketType::Nack => {
-                self.transport.handle_nack(packet.sequence).await;
+            PacketType::Ack | PacketType::Nack => {
+                // Already applied by the transport when received
             }
             PacketType::Heartbeat => {
-                debug!("Received heartbeat");
+                trace!("Received heartbeat");
+            }
+            PacketType::Pong => {
+                let sent_at = packet.ping_time()?;
+                if let Some(waiter) = self.pings.lock().await.remove(&sent_at) {
+                    let rtt = self.epoch.elapsed().saturating_sub(Duration::from_micros(sent_at));
+                    let _ = waiter.send(rtt);
+                }
+            }
+            PacketType::ConnectAck | PacketType::ConnectReject => {
+                match self.handshake.lock().await.as_ref() {
+                    Some(waiter) => {
+                        let _ = waiter.send(packet);
+                    }
+                    None => debug!("Ignoring {:?} outside a handshake", packet.packet_type),
+                }
+            }
+            PacketType::SessionTicket => {
+                let (lifetime, ticket) = packet.session_ticket_parts()?;
+                // Only the exchange that issued the ticket knows its secret
+                let Some(secret) = *self.resumption_secret.lock().unwrap() else {
+                    debug!("Ignoring session ticket without an exchanged session");
+                    return Ok(());
+                };
+                *self.session_ticket.lock().unwrap() = Some(SessionTicket {
+                    ticket,
+                    secret,
+                    expires_at: Instant::now() + lifetime,
+                });
             }
             _ => {
                 debug!("Unhandled packet type: {:?}", packet.packet_type);
@@ -178,14 +1297,647 @@ impl Client {
         Ok(())
     }
 
+    /// Set the token sent with the connection request for the server's authenticator
+    pub fn set_auth_token(&mut self, token: impl Into<Bytes>) {
+        self.auth_token = token.into();
+    }
+
+    /// Set the codec for typed requests, which must match the server's for the route
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
     /// Set request timeout
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.request_timeout = timeout;
     }
 
+    /// Set how often the receive loop sends heartbeats, or `None` to send none
+    ///
+    /// Defaults to the transport's `heartbeat_interval`. Without heartbeats the
+    /// server only hears from the client when it sends something, so keep
+    /// traffic flowing within the server's idle timeout and the client's
+    /// liveness timeout some other way. Takes effect when the receive loop starts.
+    pub fn set_heartbeat_interval(&mut self, interval: Option<Duration>) {
+        self.heartbeat_interval = interval;
+    }
+
+    /// Set how long the server may stay silent before the client treats it as gone
+    pub fn set_liveness_timeout(&mut self, timeout: Duration) {
+        self.liveness_timeout = timeout;
+    }
+
+    /// Snapshot of the underlying transport counters
+    pub async fn stats(&self) -> TransportStats {
+        self.transport.stats().await
+    }
+
     /// Get client local address
     pub fn local_addr(&self) -> Result<SocketAddr> {
         self.transport.local_addr()
     }
 }
 
+/// Builder for a client wired up with everything it needs before first use
+pub struct ClientBuilder {
+    server_addr: SocketAddr,
+    bind_addr: Option<SocketAddr>,
+    config: TransportConfig,
+    crypto: Option<Box<dyn Crypto>>,
+    compression: Option<Box<dyn Compression>>,
+    auth_token: Bytes,
+    request_timeout: Option<Duration>,
+    pinned_server_keys: Vec<[u8; IDENTITY_KEY_SIZE]>,
+    codec: Codec,
+}
+
+impl ClientBuilder {
+    /// Builder for a client of the server at `server_addr`
+    pub fn new(server_addr: SocketAddr) -> Self {
+        Self {
+            server_addr,
+            bind_addr: None,
+            config: TransportConfig::default(),
+            crypto: None,
+            compression: None,
+            auth_token: Bytes::new(),
+            request_timeout: None,
+            pinned_server_keys: Vec::new(),
+            codec: Codec::default(),
+        }
+    }
+
+    /// Local address to bind, by default any port on the server's address family
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.bind_addr = Some(addr.into());
+        self
+    }
+
+    /// Transport configuration
+    pub fn transport_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Encryption provider
+    pub fn crypto(mut self, crypto: impl Crypto + 'static) -> Self {
+        self.crypto = Some(Box::new(crypto));
+        self
+    }
+
+    /// Compression provider
+    pub fn compression(mut self, compression: impl Compression + 'static) -> Self {
+        self.compression = Some(Box::new(compression));
+        self
+    }
+
+    /// Token sent with the connection request for the server's authenticator
+    pub fn auth_token(mut self, token: impl Into<Bytes>) -> Self {
+        self.auth_token = token.into();
+        self
+    }
+
+    /// Request timeout
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Codec for typed requests, which must match the server's for the route
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Only connect to a server whose identity has this public key
+    ///
+    /// May be called more than once to allow several keys, e.g. while
+    /// rotating the server's identity. See `ServerIdentity`.
+    pub fn pin_server_key(mut self, key: [u8; IDENTITY_KEY_SIZE]) -> Self {
+        self.pinned_server_keys.push(key);
+        self
+    }
+
+    /// Bind the socket and create the client
+    pub async fn build(self) -> Result<Client> {
+        let bind_addr = self.bind_addr.unwrap_or_else(|| match self.server_addr {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+        });
+        let transport = Transport::bind(bind_addr, self.config).await?;
+        if let Some(crypto) = self.crypto {
+            transport.set_crypto(crypto).await;
+        }
+        if let Some(compression) = self.compression {
+            transport.set_compression(compression).await;
+        }
+
+        let mut client = Client::with_transport(transport, self.server_addr);
+        client.auth_token = self.auth_token;
+        client.pinned_server_keys = self.pinned_server_keys;
+        client.codec = self.codec;
+        if let Some(timeout) = self.request_timeout {
+            client.request_timeout = timeout;
+        }
+        Ok(client)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_silent_server_triggers_disconnect() {
+        // A fake server that completes the handshake and then goes quiet
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            let ack = Packet::new_connect_ack(Bytes::new()).serialize().unwrap();
+            server.send_to(&ack, from).await.unwrap();
+            // Keep the socket open but never answer again
+            while server.recv_from(&mut buf).await.is_ok() {}
+        });
+
+        let mut client = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        client.set_liveness_timeout(Duration::from_millis(200));
+        let client = Arc::new(client);
+        client.connect().await.unwrap();
+        assert!(client.is_connected());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        client
+            .on_disconnect(move || {
+                let _ = tx.send(());
+            })
+            .await;
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let request = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/never", Bytes::new()).await }
+        });
+
+        timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert!(!client.is_connected());
+
+        // The pending request fails right away instead of waiting for its timeout
+        let result = timeout(Duration::from_secs(1), request).await.unwrap().unwrap();
+        assert!(matches!(result, Err(ProtocolError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_error_reply_fails_request() {
+        let client = Client::new(([127, 0, 0, 1], 0), "127.0.0.1:9".parse().unwrap(), TransportConfig::default())
+            .await
+            .unwrap();
+        let (tx, rx) = oneshot::channel();
+        client.pending_requests.write().await.insert((0, 7), PendingRequest { tx });
+
+        let mut reply = Packet::new_error("/boom".to_string(), RemoteErrorCode::HandlerPanic, "handler panicked");
+        reply.reply_to = Some(7);
+        client.handle_packet(reply).await.unwrap();
+
+        let result = rx.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Remote { code: RemoteErrorCode::HandlerPanic, message }) if message == "handler panicked"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_per_call_timeout_overrides_client_default() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server
+            .on_async("/slow", |_ctx| async {
+                time::sleep(Duration::from_millis(300)).await;
+                Ok(Response::text("done"))
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        // The client-wide default leaves room for the slow handler
+        let response = client.request("/slow", Bytes::new()).await.unwrap();
+        assert_eq!(&response[..], b"done");
+
+        let started = Instant::now();
+        let result = client
+            .request_with_timeout("/slow", Bytes::new(), Duration::from_millis(50))
+            .await;
+        assert!(matches!(result, Err(ProtocolError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(client.request_timeout, Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_retry_retries_transient_errors() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        let calls = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = calls.clone();
+        server
+            .on_fn("/flaky", move |_ctx| {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(ProtocolError::Channel("database busy".to_string()))
+                } else {
+                    Ok(Response::text("done"))
+                }
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        // Handler errors are not retried by default
+        let result = client.request_with_retry("/flaky", Bytes::new(), RetryPolicy::default()).await;
+        assert!(matches!(result, Err(ProtocolError::Remote { code: RemoteErrorCode::HandlerError, .. })));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        calls.store(0, Ordering::SeqCst);
+        let policy = RetryPolicy::default()
+            .retry_on(RemoteErrorCode::HandlerError)
+            .backoff(Duration::from_millis(10), Duration::from_millis(20));
+        let response = client.request_with_retry("/flaky", Bytes::new(), policy.clone()).await.unwrap();
+        assert_eq!(&response[..], b"done");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Giving up after the last attempt returns its error
+        calls.store(0, Ordering::SeqCst);
+        let result = client.request_with_retry("/flaky", Bytes::new(), policy.max_attempts(2)).await;
+        assert!(matches!(result, Err(ProtocolError::Remote { code: RemoteErrorCode::HandlerError, .. })));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    /// Relay datagrams between a single client and `server`, delaying each by `delay`
+    async fn delaying_proxy(server: SocketAddr, delay: Duration) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = vec![0u8; 65536];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let dest = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                let datagram = buf[..len].to_vec();
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    time::sleep(delay).await;
+                    let _ = socket.send_to(&datagram, dest).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_early_data_saves_a_round_trip() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+        let server_addr = server.local_addr().unwrap();
+        let one_way = Duration::from_millis(50);
+
+        // Connecting, then requesting, takes two round trips
+        let proxy = delaying_proxy(server_addr, one_way).await;
+        let client = Arc::new(Client::new(([127, 0, 0, 1], 0), proxy, TransportConfig::default()).await.unwrap());
+        let started = Instant::now();
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+        assert_eq!(client.request("/echo", Bytes::from("hi")).await.unwrap(), "hi");
+        assert!(started.elapsed() >= one_way * 4);
+
+        // With early data it takes one
+        let proxy = delaying_proxy(server_addr, one_way).await;
+        let client = Client::new(([127, 0, 0, 1], 0), proxy, TransportConfig::default()).await.unwrap();
+        let started = Instant::now();
+        let reply = client.connect_with_request("/echo", Bytes::from("hi")).await.unwrap();
+        assert_eq!(reply, "hi");
+        assert!(started.elapsed() < one_way * 3, "took {:?}", started.elapsed());
+        assert!(client.is_connected());
+
+        let err = client.connect_with_request("/missing", Bytes::new()).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::RouteNotFound(route) if route == "/missing"));
+
+        // Never sent in the clear from a client that expects encryption
+        let config = TransportConfig { enable_encryption: true, ..Default::default() };
+        let client = Client::new(([127, 0, 0, 1], 0), server_addr, config).await.unwrap();
+        assert!(matches!(
+            client.connect_with_request("/echo", Bytes::new()).await,
+            Err(ProtocolError::Encryption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resumed_session_skips_key_exchange() {
+        let config = TransportConfig { enable_encryption: true, ..Default::default() };
+        let mut server = crate::server::Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap();
+        server.set_session_tickets(crate::crypto::TicketKey::generate(), Duration::from_secs(60));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+        let server_addr = server.local_addr().unwrap();
+
+        let client = Arc::new(Client::new(([127, 0, 0, 1], 0), server_addr, config).await.unwrap());
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+        assert_eq!(client.request("/echo", Bytes::from("hi")).await.unwrap(), "hi");
+        assert!(!client.is_resumed());
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while client.session_ticket.lock().unwrap().is_none() {
+            assert!(Instant::now() < deadline, "no session ticket issued");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        client.disconnect().await.unwrap();
+        client.connect().await.unwrap();
+        assert!(client.is_resumed());
+        assert_eq!(client.request("/echo", Bytes::from("again")).await.unwrap(), "again");
+
+        // A ticket the server cannot open falls back to a full exchange
+        if let Some(ticket) = client.session_ticket.lock().unwrap().as_mut() {
+            ticket.ticket = Bytes::from(vec![0; ticket.ticket.len()]);
+        }
+        client.disconnect().await.unwrap();
+        client.connect().await.unwrap();
+        assert!(!client.is_resumed());
+        assert_eq!(client.request("/echo", Bytes::from("fresh")).await.unwrap(), "fresh");
+    }
+
+    #[tokio::test]
+    async fn test_request_many_batches_requests() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server
+            .on_fn("/double", |ctx| {
+                let n: u32 = ctx.text()?.parse().map_err(|_| ProtocolError::InvalidPayload("not a number".to_string()))?;
+                Ok(Response::text((n * 2).to_string()))
+            })
+            .await;
+        // Handled one at a time, so replies leave the server in request order
+        server.set_ordered("/double", true).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let requests = (0..50u32)
+            .map(|i| ("/double".to_string(), Bytes::from(i.to_string())))
+            .collect();
+        let sent_before = client.stats().await.packets_sent;
+        let results = client.request_many(requests).await.unwrap();
+        let datagrams = client.stats().await.packets_sent - sent_before;
+
+        assert_eq!(results.len(), 50);
+        for (i, result) in results.into_iter().enumerate() {
+            assert_eq!(result.unwrap(), Bytes::from((i * 2).to_string()));
+        }
+        // One ACK per reply, plus a few datagrams carrying all the requests
+        assert!(datagrams < 50 + 10, "sent {} datagrams", datagrams);
+    }
+
+    #[tokio::test]
+    async fn test_replies_correlate_when_sequences_are_out_of_step() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+        server.on_fn("/note", |_ctx| Ok(Response::new(Bytes::new()))).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        // Push the two sides' sequence counters apart before any request
+        for _ in 0..3 {
+            client.send("/note", Bytes::new()).await.unwrap();
+        }
+        for _ in 0..7 {
+            server.broadcast("/news", Bytes::from("extra")).await.unwrap();
+        }
+
+        let requests: Vec<_> = (0..10u32)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move { client.request("/echo", Bytes::from(i.to_string())).await })
+            })
+            .collect();
+        for (i, request) in requests.into_iter().enumerate() {
+            assert_eq!(request.await.unwrap().unwrap(), Bytes::from(i.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_aborts_server_handler() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+
+        let (started_tx, mut started_rx) = mpsc::unbounded_channel();
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = finished.clone();
+        server
+            .on_async("/slow", move |_ctx| {
+                let started = started_tx.clone();
+                let done = done.clone();
+                async move {
+                    let _ = started.send(());
+                    time::sleep(Duration::from_millis(300)).await;
+                    done.store(true, Ordering::SeqCst);
+                    Ok(Response::text("done"))
+                }
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let handle = client.request_cancellable("/slow", Bytes::new()).await.unwrap();
+        timeout(Duration::from_secs(2), started_rx.recv()).await.unwrap().unwrap();
+        handle.cancel().await.unwrap();
+        assert!(client.pending_requests.read().await.is_empty());
+
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(!finished.load(Ordering::SeqCst));
+
+        // Dropping a request future forgets it without waiting for the timeout
+        let dropped = timeout(Duration::from_millis(50), client.request("/slow", Bytes::new())).await;
+        assert!(dropped.is_err());
+        assert!(client.pending_requests.read().await.is_empty());
+    }
+
+    /// Count heartbeats reaching a fake server over `window`
+    async fn heartbeats_received(server: &UdpSocket, window: Duration) -> usize {
+        let mut buf = vec![0u8; 65536];
+        let mut heartbeats = 0;
+        let deadline = time::Instant::now() + window;
+        while let Ok(Ok(len)) = time::timeout_at(deadline, server.recv(&mut buf)).await {
+            if Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap().packet_type == PacketType::Heartbeat {
+                heartbeats += 1;
+            }
+        }
+        heartbeats
+    }
+
+    #[tokio::test]
+    async fn test_flush_waits_for_pending_acks() {
+        let server = Arc::new(
+            crate::server::Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        for i in 0..20 {
+            client.send("/log", Bytes::from(format!("line {}", i))).await.unwrap();
+        }
+        client.flush(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(client.stats().await.pending_acks, 0);
+        client.disconnect_gracefully(Duration::from_secs(2)).await.unwrap();
+        assert!(!client.is_connected());
+
+        // Nothing ACKs a silent server, so the flush gives up and no Disconnect goes out
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::new(([127, 0, 0, 1], 0), silent.local_addr().unwrap(), TransportConfig::default())
+            .await
+            .unwrap();
+        client.send("/log", Bytes::from("lost")).await.unwrap();
+        let mut buf = [0u8; 1024];
+        silent.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            client.disconnect_gracefully(Duration::from_millis(50)).await,
+            Err(ProtocolError::Timeout)
+        ));
+        assert_eq!(client.stats().await.pending_acks, 1);
+
+        // The abortive disconnect goes out at once, unacknowledged packets or not
+        timeout(Duration::from_millis(50), client.disconnect()).await.unwrap().unwrap();
+        assert!(!client.is_connected());
+        loop {
+            let len = timeout(Duration::from_secs(1), silent.recv(&mut buf)).await.unwrap().unwrap();
+            if Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap().packet_type == PacketType::Disconnect {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_can_be_disabled_and_stop_on_disconnect() {
+        let config = TransportConfig {
+            heartbeat_interval: Duration::from_millis(20),
+            ..TransportConfig::default()
+        };
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let mut quiet = Client::new(([127, 0, 0, 1], 0), server_addr, config.clone()).await.unwrap();
+        quiet.set_heartbeat_interval(None);
+        let quiet = Arc::new(quiet);
+        tokio::spawn(quiet.clone().start_recv_loop());
+        assert_eq!(heartbeats_received(&server, Duration::from_millis(200)).await, 0);
+
+        let chatty = Arc::new(Client::new(([127, 0, 0, 1], 0), server_addr, config).await.unwrap());
+        tokio::spawn(chatty.clone().start_recv_loop());
+        assert!(heartbeats_received(&server, Duration::from_millis(200)).await > 0);
+
+        chatty.disconnect().await.unwrap();
+        // Let a heartbeat already on the wire land before counting
+        heartbeats_received(&server, Duration::from_millis(30)).await;
+        assert_eq!(heartbeats_received(&server, Duration::from_millis(200)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dropping_client_stops_its_tasks() {
+        let config = TransportConfig {
+            heartbeat_interval: Duration::from_millis(20),
+            rekey_interval: Some(Duration::from_secs(60)),
+            ..TransportConfig::default()
+        };
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+                .await
+                .unwrap(),
+        );
+        let recv_loop = tokio::spawn(client.clone().start_recv_loop());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.tasks.running(), 2);
+
+        recv_loop.abort();
+        let _ = recv_loop.await;
+        let transport = Arc::downgrade(&client.transport);
+        drop(client);
+
+        // Nothing left running holds the transport, and heartbeats have stopped
+        assert!(transport.upgrade().is_none());
+        heartbeats_received(&server, Duration::from_millis(30)).await;
+        assert_eq!(heartbeats_received(&server, Duration::from_millis(100)).await, 0);
+    }
+}
@@ -0,0 +1,127 @@
+//! Serialization formats for typed payloads
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::*;
+
+/// Format typed handlers decode requests and encode responses in
+///
+/// Not negotiated: both sides must be configured with the same codec, on the
+/// server per server or per route and on the client per client. JSON is
+/// always available; MessagePack and CBOR need the `msgpack` and `cbor`
+/// features.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// JSON, readable and widely supported
+    #[default]
+    Json,
+    /// MessagePack, compact and fast for binary-heavy payloads
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// CBOR, compact and self-describing
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    /// Name of the format, for error messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "JSON",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "MessagePack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "CBOR",
+        }
+    }
+
+    /// Serialize `value` in this format
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes> {
+        let encoded = match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|e| ProtocolError::JsonError(e.to_string()))?,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| self.encode_error(e))?,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| self.encode_error(e))?;
+                buf
+            }
+        };
+        Ok(Bytes::from(encoded))
+    }
+
+    /// Deserialize a value in this format
+    ///
+    /// Fails with `ProtocolError::InvalidPayload` if `data` does not parse.
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            Codec::Json => serde_json::from_slice(data).map_err(|e| self.decode_error(e)),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_slice(data).map_err(|e| self.decode_error(e)),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::from_reader(data).map_err(|e| self.decode_error(e)),
+        }
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    fn encode_error(&self, e: impl std::fmt::Display) -> ProtocolError {
+        ProtocolError::Encoding(format!("{} encode error: {}", self.name(), e))
+    }
+
+    fn decode_error(&self, e: impl std::fmt::Display) -> ProtocolError {
+        ProtocolError::InvalidPayload(format!("{} parse error: {}", self.name(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        values: Vec<f64>,
+        raw: Vec<u8>,
+        calibrated: Option<bool>,
+    }
+
+    /// Every codec built into this configuration, JSON first
+    fn enabled_codecs() -> Vec<Codec> {
+        vec![
+            Codec::Json,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor,
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip_in_every_codec() {
+        let reading = Reading {
+            sensor: "probe-7".to_string(),
+            values: vec![1.5, -2.25, 1e9],
+            raw: vec![0, 1, 254, 255],
+            calibrated: None,
+        };
+
+        let mut sizes = Vec::new();
+        for codec in enabled_codecs() {
+            let encoded = codec.encode(&reading).unwrap();
+            let decoded: Reading = codec.decode(&encoded).unwrap();
+            assert_eq!(decoded, reading, "{} roundtrip", codec.name());
+            sizes.push(encoded.len());
+
+            assert!(matches!(
+                codec.decode::<Reading>(b"\xff\x00not valid"),
+                Err(ProtocolError::InvalidPayload(msg)) if msg.starts_with(codec.name())
+            ));
+        }
+        // The binary formats are smaller than JSON for the same value
+        assert!(sizes[1..].iter().all(|&size| size < sizes[0]));
+    }
+}
@@ -1,37 +1,150 @@
 //! Compression and decompression support
 
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 use bytes::Bytes;
+#[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
 use std::io::{Read, Write};
 
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 use crate::error::*;
 
 /// Compression algorithm
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum CompressionAlgorithm {
+    #[default]
     Zstd,
     Lz4,
+    Brotli,
+    Gzip,
+    /// An application codec supplied through the `Compression` trait
+    ///
+    /// Both peers must configure the same one.
+    Custom,
 }
 
-/// Compression provider
+impl CompressionAlgorithm {
+    /// Identifier carried in the packet flags
+    pub fn id(&self) -> u8 {
+        match self {
+            CompressionAlgorithm::Zstd => 0,
+            CompressionAlgorithm::Lz4 => 1,
+            CompressionAlgorithm::Brotli => 2,
+            CompressionAlgorithm::Gzip => 3,
+            CompressionAlgorithm::Custom => 7,
+        }
+    }
+
+    /// Look up an algorithm by its identifier
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CompressionAlgorithm::Zstd),
+            1 => Some(CompressionAlgorithm::Lz4),
+            2 => Some(CompressionAlgorithm::Brotli),
+            3 => Some(CompressionAlgorithm::Gzip),
+            7 => Some(CompressionAlgorithm::Custom),
+            _ => None,
+        }
+    }
+}
+
+/// Codec for packet payloads
+///
+/// Implement this to compress with an algorithm the crate does not bundle
+/// and hand it to `set_compression` on a server, client or transport.
+/// Packets record `algorithm()` in their flags, and a receiver only runs its
+/// own codec on packets carrying the same algorithm.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub trait Compression: Send + Sync {
+    /// Algorithm recorded in the flags of packets this codec compresses
+    fn algorithm(&self) -> CompressionAlgorithm {
+        CompressionAlgorithm::Custom
+    }
+
+    /// Compress data
+    fn compress(&self, data: &[u8]) -> Result<Bytes>;
+
+    /// Decompress what `compress` produced
+    fn decompress(&self, data: &[u8]) -> Result<Bytes>;
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl<C: Compression + ?Sized> Compression for Box<C> {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        (**self).algorithm()
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Bytes> {
+        (**self).compress(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Bytes> {
+        (**self).decompress(data)
+    }
+}
+
+#[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
+/// Compression provider for the bundled algorithms
 pub struct CompressionProvider {
     algorithm: CompressionAlgorithm,
     level: i32,
+    /// Zstd dictionary shared with the peer
+    dictionary: Option<Vec<u8>>,
 }
 
+#[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
 impl CompressionProvider {
     /// Create a new compression provider with Zstd
     pub fn new_zstd(level: i32) -> Self {
         Self {
             algorithm: CompressionAlgorithm::Zstd,
             level,
+            dictionary: None,
         }
     }
 
+    /// Create a Zstd compression provider that uses a dictionary
+    ///
+    /// Small, similar messages compress far better against a dictionary of
+    /// their common content. Both peers must configure the same dictionary,
+    /// agreed out of band; one can be built with `train_zstd_dictionary`.
+    pub fn new_zstd_with_dict(level: i32, dictionary: &[u8]) -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Zstd,
+            level,
+            dictionary: Some(dictionary.to_vec()),
+        }
+    }
+
+    /// Train a Zstd dictionary of at most `max_size` bytes from sample messages
+    pub fn train_zstd_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
+            .map_err(|e| ProtocolError::Compression(format!("Zstd dictionary training failed: {}", e)))
+    }
+
     /// Create a new compression provider with LZ4
     pub fn new_lz4(level: i32) -> Self {
         Self {
             algorithm: CompressionAlgorithm::Lz4,
             level,
+            dictionary: None,
+        }
+    }
+
+    /// Create a new compression provider with Brotli (quality 0-11)
+    pub fn new_brotli(quality: i32) -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Brotli,
+            level: quality,
+            dictionary: None,
+        }
+    }
+
+    /// Create a new compression provider with Gzip (level 0-9)
+    pub fn new_gzip(level: i32) -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Gzip,
+            level,
+            dictionary: None,
         }
     }
 
@@ -39,8 +152,14 @@ impl CompressionProvider {
     pub fn compress(&self, data: &[u8]) -> Result<Bytes> {
         match self.algorithm {
             CompressionAlgorithm::Zstd => {
-                let compressed = zstd::encode_all(data, self.level)
-                    .map_err(|e| ProtocolError::Compression(format!("Zstd compression failed: {}", e)))?;
+                let compressed = match &self.dictionary {
+                    Some(dictionary) => {
+                        zstd::bulk::Compressor::with_dictionary(self.level, dictionary)
+                            .and_then(|mut compressor| compressor.compress(data))
+                    }
+                    None => zstd::encode_all(data, self.level),
+                }
+                .map_err(|e| ProtocolError::Compression(format!("Zstd compression failed: {}", e)))?;
                 Ok(Bytes::from(compressed))
             }
             CompressionAlgorithm::Lz4 => {
@@ -59,12 +178,66 @@ impl CompressionProvider {
                 
                 Ok(Bytes::from(compressed))
             }
+            CompressionAlgorithm::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        4096,
+                        self.level.clamp(0, 11) as u32,
+                        22,
+                    );
+                    encoder
+                        .write_all(data)
+                        .map_err(|e| ProtocolError::Compression(format!("Brotli compression failed: {}", e)))?;
+                }
+
+                Ok(Bytes::from(compressed))
+            }
+            CompressionAlgorithm::Gzip => {
+                let level = flate2::Compression::new(self.level.clamp(0, 9) as u32);
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder
+                    .write_all(data)
+                    .map_err(|e| ProtocolError::Compression(format!("Gzip compression failed: {}", e)))?;
+
+                let compressed = encoder
+                    .finish()
+                    .map_err(|e| ProtocolError::Compression(format!("Gzip finish failed: {}", e)))?;
+
+                Ok(Bytes::from(compressed))
+            }
+            CompressionAlgorithm::Custom => Err(ProtocolError::Compression(
+                "Custom compression needs the application's own codec".to_string(),
+            )),
         }
     }
 
+    /// Algorithm used by this provider
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
     /// Decompress data
     pub fn decompress(&self, data: &[u8]) -> Result<Bytes> {
-        match self.algorithm {
+        match (&self.dictionary, self.algorithm) {
+            (Some(dictionary), CompressionAlgorithm::Zstd) => {
+                let mut decompressed = Vec::new();
+                zstd::stream::read::Decoder::with_dictionary(data, dictionary)
+                    .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+                    .map_err(|e| ProtocolError::Compression(format!("Zstd decompression failed: {}", e)))?;
+                Ok(Bytes::from(decompressed))
+            }
+            _ => Self::decompress_with(self.algorithm, data),
+        }
+    }
+
+    /// Decompress data produced by any supported algorithm
+    ///
+    /// Decompression needs no level, so a peer can read whatever algorithm the
+    /// sender chose regardless of its own configured provider.
+    pub fn decompress_with(algorithm: CompressionAlgorithm, data: &[u8]) -> Result<Bytes> {
+        match algorithm {
             CompressionAlgorithm::Zstd => {
                 let decompressed = zstd::decode_all(data)
                     .map_err(|e| ProtocolError::Compression(format!("Zstd decompression failed: {}", e)))?;
@@ -81,11 +254,45 @@ impl CompressionProvider {
                 
                 Ok(Bytes::from(decompressed))
             }
+            CompressionAlgorithm::Brotli => {
+                let mut decompressed = Vec::new();
+                brotli::Decompressor::new(data, 4096)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| ProtocolError::Compression(format!("Brotli decompression failed: {}", e)))?;
+
+                Ok(Bytes::from(decompressed))
+            }
+            CompressionAlgorithm::Gzip => {
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| ProtocolError::Compression(format!("Gzip decompression failed: {}", e)))?;
+
+                Ok(Bytes::from(decompressed))
+            }
+            CompressionAlgorithm::Custom => Err(ProtocolError::Compression(
+                "Custom compression needs the application's own codec".to_string(),
+            )),
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
+impl Compression for CompressionProvider {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Bytes> {
+        CompressionProvider::compress(self, data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Bytes> {
+        CompressionProvider::decompress(self, data)
+    }
+}
+
+#[cfg(all(test, feature = "compression", not(target_arch = "wasm32")))]
 mod tests {
     use super::*;
 
@@ -110,5 +317,58 @@ mod tests {
         
         assert_eq!(data, &decompressed[..]);
     }
-}
 
+    #[test]
+    fn test_brotli_compression() {
+        let compressor = CompressionProvider::new_brotli(5);
+        let data = b"Hello, World! This is a test message for compression.";
+
+        let compressed = compressor.compress(data).unwrap();
+        let decompressed = compressor.decompress(&compressed).unwrap();
+
+        assert_eq!(data, &decompressed[..]);
+    }
+
+    #[test]
+    fn test_gzip_compression() {
+        let compressor = CompressionProvider::new_gzip(6);
+        let data = b"Hello, World! This is a test message for compression.";
+
+        let compressed = compressor.compress(data).unwrap();
+        let decompressed = compressor.decompress(&compressed).unwrap();
+
+        assert_eq!(data, &decompressed[..]);
+    }
+
+    #[test]
+    fn test_zstd_dictionary_shrinks_small_messages() {
+        let messages: Vec<Vec<u8>> = (0..500)
+            .map(|i| {
+                format!(
+                    r#"{{"user_id":{},"event":"page_view","path":"/products/{}","session":"s-{}","agent":"Mozilla/5.0"}}"#,
+                    i,
+                    i % 37,
+                    i * 7919
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dictionary = CompressionProvider::train_zstd_dictionary(&messages[..400], 4096).unwrap();
+
+        let plain = CompressionProvider::new_zstd(3);
+        let with_dict = CompressionProvider::new_zstd_with_dict(3, &dictionary);
+        let mut plain_total = 0;
+        let mut dict_total = 0;
+        for message in &messages[400..] {
+            plain_total += plain.compress(message).unwrap().len();
+            let compressed = with_dict.compress(message).unwrap();
+            dict_total += compressed.len();
+            assert_eq!(&with_dict.decompress(&compressed).unwrap()[..], &message[..]);
+        }
+        assert!(dict_total * 2 < plain_total, "{} vs {}", dict_total, plain_total);
+
+        // Frames compressed without the dictionary still decode
+        let compressed = plain.compress(&messages[0]).unwrap();
+        assert_eq!(&with_dict.decompress(&compressed).unwrap()[..], &messages[0][..]);
+    }
+}
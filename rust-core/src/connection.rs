@@ -0,0 +1,90 @@
+//! Per-connection session state
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::crypto::CryptoProvider;
+use crate::PROTOCOL_VERSION;
+
+/// Unique identifier for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Allocate a new, process-wide unique connection id
+    pub fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Get the raw id value
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn_{}", self.0)
+    }
+}
+
+/// Identity an authenticator established for a connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthInfo {
+    /// Who the peer authenticated as
+    pub identity: String,
+    /// Extra facts about the peer, such as roles or scopes
+    pub claims: HashMap<String, String>,
+}
+
+impl AuthInfo {
+    /// Auth info for `identity` with no claims
+    pub fn new(identity: impl Into<String>) -> Self {
+        Self {
+            identity: identity.into(),
+            claims: HashMap::new(),
+        }
+    }
+
+    /// Add a claim
+    pub fn with_claim(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.claims.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// State kept for a connected peer
+#[derive(Clone)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    pub last_seen: Instant,
+    /// Session crypto negotiated during the handshake
+    pub crypto: Option<Arc<CryptoProvider>>,
+    /// Identity established by the server's authenticator, if it has one
+    pub auth: Option<AuthInfo>,
+    /// Wire format version negotiated during the handshake
+    pub version: u8,
+}
+
+impl Connection {
+    /// Create state for a newly connected peer
+    pub fn new(addr: SocketAddr, crypto: Option<Arc<CryptoProvider>>) -> Self {
+        let now = Instant::now();
+        Self {
+            id: ConnectionId::next(),
+            addr,
+            connected_at: now,
+            last_seen: now,
+            crypto,
+            auth: None,
+            version: PROTOCOL_VERSION,
+        }
+    }
+}
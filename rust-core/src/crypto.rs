@@ -1,15 +1,48 @@
 //! Encryption and decryption support
 
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Nonce,
 };
 use chacha20poly1305::{ChaCha20Poly1305, Key};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use bytes::Bytes;
+use hkdf::Hkdf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use rand::Rng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
+use crate::clock::{RngSource, SystemClock, ThreadRng, TimeSource};
 use crate::error::*;
 
+/// Authenticated encryption of packet payloads
+///
+/// Implement this to use another AEAD, or keys held in hardware, and hand it
+/// to `set_crypto` on a server, client or transport. `aad` is authenticated
+/// but not encrypted; the transport passes the packet's route, so a payload
+/// cannot be replayed on another route. Keys negotiated in the handshake
+/// always use the built-in `CryptoProvider`.
+pub trait Crypto: Send + Sync {
+    /// Encrypt `data`, returning everything `decrypt` needs besides the key and `aad`
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes>;
+
+    /// Decrypt what `encrypt` produced for the same `aad`
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes>;
+}
+
+impl<C: Crypto + ?Sized> Crypto for Box<C> {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        (**self).encrypt(data, aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        (**self).decrypt(data, aad)
+    }
+}
+
 /// Encryption algorithm
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EncryptionAlgorithm {
@@ -17,32 +50,117 @@ pub enum EncryptionAlgorithm {
     ChaCha20Poly1305,
 }
 
+/// Size of the nonce prepended to every ciphertext
+pub const NONCE_SIZE: usize = 12;
+
+/// Number of messages a single key may encrypt before it must be replaced
+///
+/// Kept well below the 64-bit counter range so exhaustion is a clean error
+/// rather than a wrap back to an already used nonce.
+pub const MAX_MESSAGES_PER_KEY: u64 = u64::MAX - (1 << 32);
+
+/// How long the previous key keeps decrypting after a rotation by default
+pub const DEFAULT_REKEY_GRACE: Duration = Duration::from_secs(10);
+
+/// A keyed AEAD cipher
+#[derive(Clone)]
+enum Cipher {
+    Aes(Box<Aes256Gcm>),
+    ChaCha(ChaCha20Poly1305),
+}
+
+impl Cipher {
+    fn new(algorithm: EncryptionAlgorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            EncryptionAlgorithm::Aes256Gcm => Cipher::Aes(Box::new(Aes256Gcm::new(key.into()))),
+            EncryptionAlgorithm::ChaCha20Poly1305 => {
+                Cipher::ChaCha(ChaCha20Poly1305::new(Key::from_slice(key)))
+            }
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        let data = Payload { msg: data, aad };
+        match self {
+            Cipher::Aes(cipher) => cipher
+                .encrypt(nonce, data)
+                .map_err(|e| ProtocolError::Encryption(format!("AES encryption failed: {}", e))),
+            Cipher::ChaCha(cipher) => cipher
+                .encrypt(nonce, data)
+                .map_err(|e| ProtocolError::Encryption(format!("ChaCha encryption failed: {}", e))),
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        let ciphertext = Payload { msg: ciphertext, aad };
+        match self {
+            Cipher::Aes(cipher) => cipher
+                .decrypt(nonce, ciphertext)
+                .map_err(|e| ProtocolError::Encryption(format!("AES decryption failed: {}", e))),
+            Cipher::ChaCha(cipher) => cipher
+                .decrypt(nonce, ciphertext)
+                .map_err(|e| ProtocolError::Encryption(format!("ChaCha decryption failed: {}", e))),
+        }
+    }
+}
+
 /// Crypto provider for encryption and decryption
+///
+/// Nonces are a random 32-bit salt chosen when the key is set up followed by
+/// a 64-bit message counter, so a provider never repeats a nonce.
 pub struct CryptoProvider {
     algorithm: EncryptionAlgorithm,
-    aes_cipher: Option<Aes256Gcm>,
-    chacha_cipher: Option<ChaCha20Poly1305>,
+    key: [u8; 32],
+    cipher: Cipher,
+    previous: Option<(Cipher, Instant)>,
+    /// Older pre-shared keys still accepted for decryption, most recent first
+    fallbacks: Vec<Cipher>,
+    rekey_grace: Duration,
+    nonce_salt: [u8; 4],
+    nonce_counter: AtomicU64,
+    bytes_processed: AtomicU64,
+    created_at: Instant,
+    clock: Arc<dyn TimeSource>,
+    rng: Arc<dyn RngSource>,
 }
 
 impl CryptoProvider {
-    /// Create a new crypto provider with AES-256-GCM
-    pub fn new_aes(key: &[u8; 32]) -> Self {
-        let cipher = Aes256Gcm::new(key.into());
+    fn with_algorithm(algorithm: EncryptionAlgorithm, key: &[u8; 32]) -> Self {
         Self {
-            algorithm: EncryptionAlgorithm::Aes256Gcm,
-            aes_cipher: Some(cipher),
-            chacha_cipher: None,
+            algorithm,
+            key: *key,
+            cipher: Cipher::new(algorithm, key),
+            previous: None,
+            fallbacks: Vec::new(),
+            rekey_grace: DEFAULT_REKEY_GRACE,
+            nonce_salt: rand::thread_rng().gen(),
+            nonce_counter: AtomicU64::new(0),
+            bytes_processed: AtomicU64::new(0),
+            created_at: Instant::now(),
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(ThreadRng),
         }
     }
 
+    /// Create a new crypto provider with AES-256-GCM
+    pub fn new_aes(key: &[u8; 32]) -> Self {
+        Self::with_algorithm(EncryptionAlgorithm::Aes256Gcm, key)
+    }
+
     /// Create a new crypto provider with ChaCha20-Poly1305
     pub fn new_chacha(key: &[u8; 32]) -> Self {
-        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
-        Self {
-            algorithm: EncryptionAlgorithm::ChaCha20Poly1305,
-            aes_cipher: None,
-            chacha_cipher: Some(cipher),
-        }
+        Self::with_algorithm(EncryptionAlgorithm::ChaCha20Poly1305, key)
+    }
+
+    /// Create an AES-256-GCM provider keyed from a shared secret via HKDF-SHA256
+    pub fn from_shared_secret(secret: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(b"fast-protocol session key", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self::new_aes(&key)
     }
 
     /// Generate a random 256-bit key
@@ -52,67 +170,400 @@ impl CryptoProvider {
         key
     }
 
+    /// Also decrypt with `keys`, tried most recent first after the current key
+    ///
+    /// For rolling a new pre-shared key out across peers: each peer encrypts
+    /// with the new key as soon as it has it, and keeps decrypting what peers
+    /// still on an older key send. Unlike the previous key after a rotation,
+    /// these are accepted until the provider is replaced or rotated: a
+    /// rotation drops them, so they never outlive the key they were set with.
+    pub fn with_fallback_keys(mut self, keys: &[[u8; 32]]) -> Self {
+        self.fallbacks = keys.iter().map(|key| Cipher::new(self.algorithm, key)).collect();
+        self
+    }
+
+    /// Encryption algorithm in use
+    pub fn algorithm(&self) -> EncryptionAlgorithm {
+        self.algorithm
+    }
+
+    /// Set how long the previous key keeps decrypting after a rotation
+    pub fn set_rekey_grace(&mut self, grace: Duration) {
+        self.rekey_grace = grace;
+    }
+
+    /// Set the clock that ages keys and the rotation grace window
+    ///
+    /// The current key counts as set up now on the new clock.
+    pub fn set_time_source(&mut self, clock: Arc<dyn TimeSource>) {
+        self.created_at = clock.now();
+        self.clock = clock;
+    }
+
+    /// Set the generator nonce salts are drawn from
+    ///
+    /// The current key gets a fresh salt from it, so set it before encrypting.
+    pub fn set_rng(&mut self, rng: Arc<dyn RngSource>) {
+        rng.fill_bytes(&mut self.nonce_salt);
+        self.rng = rng;
+    }
+
+    /// Derive the next key from the current one and a salt both peers know
+    pub fn next_key(&self, salt: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &self.key)
+            .expand(b"fast-protocol rekey", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    /// Secret a later connection can resume this session from
+    ///
+    /// Derived from the key the session started with, so both sides can
+    /// compute it right after the key exchange.
+    pub fn resumption_secret(&self) -> [u8; 32] {
+        let mut secret = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand(b"fast-protocol resumption secret", &mut secret)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        secret
+    }
+
+    /// AES-256-GCM provider for a session resumed from `secret`
+    ///
+    /// The client's fresh `nonce` salts the key, so no two resumptions of the
+    /// same session share one.
+    pub fn resumed(secret: &[u8; 32], nonce: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(nonce), secret)
+            .expand(b"fast-protocol resumed session key", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self::new_aes(&key)
+    }
+
+    /// Switch to a new key, keeping the current one for decryption during the grace window
+    ///
+    /// The nonce salt and counter start over since they belong to the key, and
+    /// fallback keys are dropped.
+    pub fn rotate(&mut self, new_key: &[u8; 32]) {
+        let cipher = Cipher::new(self.algorithm, new_key);
+        let previous = std::mem::replace(&mut self.cipher, cipher);
+        self.previous = Some((previous, self.clock.now()));
+        self.fallbacks.clear();
+        self.key = *new_key;
+        self.rng.fill_bytes(&mut self.nonce_salt);
+        self.nonce_counter = AtomicU64::new(0);
+        self.bytes_processed = AtomicU64::new(0);
+        self.created_at = self.clock.now();
+    }
+
+    /// A provider using `new_key`, with this provider's key kept as the previous one
+    ///
+    /// Used where the current provider is shared and cannot be rotated in place.
+    pub fn rotated(&self, new_key: &[u8; 32]) -> Self {
+        let mut next = Self::with_algorithm(self.algorithm, &self.key);
+        next.rekey_grace = self.rekey_grace;
+        next.clock = self.clock.clone();
+        next.rng = self.rng.clone();
+        next.rotate(new_key);
+        next
+    }
+
+    /// Bytes encrypted and decrypted under the current key
+    pub fn bytes_processed(&self) -> u64 {
+        self.bytes_processed.load(Ordering::Relaxed)
+    }
+
+    /// Time since the current key was set up
+    pub fn key_age(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.created_at)
+    }
+
+    /// Number of messages this key can still encrypt
+    pub fn remaining_messages(&self) -> u64 {
+        MAX_MESSAGES_PER_KEY.saturating_sub(self.nonce_counter.load(Ordering::Relaxed))
+    }
+
+    /// Reserve the next nonce, failing once the key is exhausted
+    fn next_nonce(&self) -> Result<[u8; NONCE_SIZE]> {
+        let counter = self
+            .nonce_counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < MAX_MESSAGES_PER_KEY).then_some(n + 1)
+            })
+            .map_err(|_| {
+                ProtocolError::Encryption("Nonce space exhausted, key must be rotated".to_string())
+            })?;
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&self.nonce_salt);
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        Ok(nonce)
+    }
+
     /// Encrypt data
     pub fn encrypt(&self, data: &[u8]) -> Result<Bytes> {
-        // Generate random nonce (96 bits = 12 bytes)
-        let mut nonce_bytes = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-
-        let ciphertext = match self.algorithm {
-            EncryptionAlgorithm::Aes256Gcm => {
-                let cipher = self.aes_cipher.as_ref()
-                    .ok_or_else(|| ProtocolError::Encryption("AES cipher not initialized".to_string()))?;
-                cipher
-                    .encrypt(nonce, data)
-                    .map_err(|e| ProtocolError::Encryption(format!("AES encryption failed: {}", e)))?
-            }
-            EncryptionAlgorithm::ChaCha20Poly1305 => {
-                let cipher = self.chacha_cipher.as_ref()
-                    .ok_or_else(|| ProtocolError::Encryption("ChaCha cipher not initialized".to_string()))?;
-                cipher
-                    .encrypt(nonce, data)
-                    .map_err(|e| ProtocolError::Encryption(format!("ChaCha encryption failed: {}", e)))?
-            }
-        };
+        self.encrypt_with_aad(data, &[])
+    }
+
+    /// Encrypt data, authenticating `aad` along with it
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        let nonce = self.next_nonce()?;
+        let ciphertext = self.cipher.encrypt(&nonce, data, aad)?;
+        self.bytes_processed.fetch_add(data.len() as u64, Ordering::Relaxed);
 
         // Prepend nonce to ciphertext
-        let mut result = Vec::with_capacity(12 + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
+        let mut result = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        result.extend_from_slice(&nonce);
         result.extend_from_slice(&ciphertext);
 
         Ok(Bytes::from(result))
     }
 
     /// Decrypt data
+    ///
+    /// Data that fails to authenticate under the current key is retried with
+    /// the previous key while still inside the rotation grace window, then
+    /// with each fallback key.
     pub fn decrypt(&self, data: &[u8]) -> Result<Bytes> {
-        if data.len() < 12 {
+        self.decrypt_with_aad(data, &[])
+    }
+
+    /// Decrypt data encrypted with `aad`
+    pub fn decrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        if data.len() < NONCE_SIZE {
             return Err(ProtocolError::Encryption("Data too short".to_string()));
         }
 
         // Extract nonce and ciphertext
-        let nonce = Nonce::from_slice(&data[0..12]);
-        let ciphertext = &data[12..];
-
-        let plaintext = match self.algorithm {
-            EncryptionAlgorithm::Aes256Gcm => {
-                let cipher = self.aes_cipher.as_ref()
-                    .ok_or_else(|| ProtocolError::Encryption("AES cipher not initialized".to_string()))?;
-                cipher
-                    .decrypt(nonce, ciphertext)
-                    .map_err(|e| ProtocolError::Encryption(format!("AES decryption failed: {}", e)))?
-            }
-            EncryptionAlgorithm::ChaCha20Poly1305 => {
-                let cipher = self.chacha_cipher.as_ref()
-                    .ok_or_else(|| ProtocolError::Encryption("ChaCha cipher not initialized".to_string()))?;
-                cipher
-                    .decrypt(nonce, ciphertext)
-                    .map_err(|e| ProtocolError::Encryption(format!("ChaCha decryption failed: {}", e)))?
-            }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+        let plaintext = match self.cipher.decrypt(nonce, ciphertext, aad) {
+            Ok(plaintext) => plaintext,
+            Err(e) => self.decrypt_with_older_keys(nonce, ciphertext, aad).ok_or(e)?,
         };
+        self.bytes_processed.fetch_add(plaintext.len() as u64, Ordering::Relaxed);
 
         Ok(Bytes::from(plaintext))
     }
+
+    /// Try the keys from before the current one, most recent first
+    fn decrypt_with_older_keys(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let previous = self
+            .previous
+            .as_ref()
+            .filter(|(_, rotated_at)| self.clock.now().saturating_duration_since(*rotated_at) < self.rekey_grace)
+            .map(|(cipher, _)| cipher);
+        previous
+            .into_iter()
+            .chain(&self.fallbacks)
+            .find_map(|cipher| cipher.decrypt(nonce, ciphertext, aad).ok())
+    }
+}
+
+impl Crypto for CryptoProvider {
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        self.encrypt_with_aad(data, aad)
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+        self.decrypt_with_aad(data, aad)
+    }
+}
+
+/// Size of an X25519 public key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// One side of an ephemeral X25519 key exchange
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    /// Generate a fresh ephemeral key pair
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Public key to send to the peer
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    /// Complete the exchange with the peer's public key
+    ///
+    /// The session key is derived from the shared secret together with both
+    /// public keys, so it is bound to this particular exchange.
+    pub fn derive(self, peer_public: &[u8], initiator: bool) -> Result<CryptoProvider> {
+        let peer: [u8; PUBLIC_KEY_SIZE] = peer_public
+            .try_into()
+            .map_err(|_| ProtocolError::Encryption("Invalid public key length".to_string()))?;
+        let own = self.public.to_bytes();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(ProtocolError::Encryption(
+                "Key exchange produced a weak shared secret".to_string(),
+            ));
+        }
+
+        let (client, server) = if initiator { (own, peer) } else { (peer, own) };
+        let mut material = Vec::with_capacity(3 * PUBLIC_KEY_SIZE);
+        material.extend_from_slice(shared.as_bytes());
+        material.extend_from_slice(&client);
+        material.extend_from_slice(&server);
+
+        Ok(CryptoProvider::from_shared_secret(&material))
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Size of the random nonce a client sends with a session ticket
+pub const RESUMPTION_NONCE_SIZE: usize = 32;
+
+/// What a session ticket is sealed under, so it means nothing elsewhere
+const TICKET_CONTEXT: &[u8] = b"plus-protocol session ticket v1";
+
+/// Key a server seals session tickets with
+///
+/// A ticket holds a session's resumption secret and when it expires,
+/// readable only by the server, which keeps no state per ticket. Keep the
+/// key stable across restarts to honour tickets issued before one.
+pub struct TicketKey {
+    sealer: CryptoProvider,
+}
+
+impl TicketKey {
+    /// Generate a fresh ticket key
+    pub fn generate() -> Self {
+        Self::from_bytes(&CryptoProvider::generate_key())
+    }
+
+    /// Restore a ticket key from its 32 bytes
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self {
+            sealer: CryptoProvider::new_aes(key),
+        }
+    }
+
+    /// Seal a resumption secret into a ticket valid until `expires_at`, in Unix seconds
+    pub fn seal(&self, secret: &[u8; 32], expires_at: u64) -> Result<Bytes> {
+        let mut plaintext = Vec::with_capacity(40);
+        plaintext.extend_from_slice(secret);
+        plaintext.extend_from_slice(&expires_at.to_be_bytes());
+        self.sealer.encrypt_with_aad(&plaintext, TICKET_CONTEXT)
+    }
+
+    /// Resumption secret of a ticket, if it was sealed with this key and has not expired by `now`
+    pub fn open(&self, ticket: &[u8], now: u64) -> Result<[u8; 32]> {
+        let plaintext = self.sealer.decrypt_with_aad(ticket, TICKET_CONTEXT)?;
+        if plaintext.len() != 40 {
+            return Err(ProtocolError::Encryption("Malformed session ticket".to_string()));
+        }
+        let expires_at = u64::from_be_bytes(plaintext[32..].try_into().expect("8 bytes"));
+        if expires_at <= now {
+            return Err(ProtocolError::Encryption("Session ticket expired".to_string()));
+        }
+        Ok(plaintext[..32].try_into().expect("32 bytes"))
+    }
+}
+
+/// Size of an Ed25519 identity public key
+pub const IDENTITY_KEY_SIZE: usize = 32;
+
+/// Size of an Ed25519 signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Prefix of the signed handshake transcript, so the signature means nothing elsewhere
+const HANDSHAKE_CONTEXT: &[u8] = b"plus-protocol handshake v1";
+
+/// Long-term Ed25519 key a server proves its identity with
+///
+/// During the key exchange the server signs both ephemeral public keys, and
+/// a client that pins the identity's public key refuses to connect unless
+/// the signature checks out. Keep the secret key stable across restarts so
+/// pinned clients keep trusting the server.
+pub struct ServerIdentity {
+    signing: SigningKey,
+}
+
+impl ServerIdentity {
+    /// Generate a fresh identity
+    pub fn generate() -> Self {
+        Self {
+            signing: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Restore an identity from its 32-byte secret key
+    pub fn from_secret_key(secret: &[u8; 32]) -> Self {
+        Self {
+            signing: SigningKey::from_bytes(secret),
+        }
+    }
+
+    /// Secret key, to store and restore with `from_secret_key`
+    pub fn secret_key(&self) -> [u8; 32] {
+        self.signing.to_bytes()
+    }
+
+    /// Public key for clients to pin
+    pub fn public_key(&self) -> [u8; IDENTITY_KEY_SIZE] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    /// Sign the ephemeral keys of one handshake
+    pub fn sign_handshake(&self, server_public: &[u8], client_public: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.signing
+            .sign(&handshake_transcript(server_public, client_public))
+            .to_bytes()
+    }
+}
+
+/// Check that a pinned identity signed the ephemeral keys of a handshake
+pub fn verify_handshake(
+    pinned: &[[u8; IDENTITY_KEY_SIZE]],
+    identity: &[u8],
+    signature: &[u8],
+    server_public: &[u8],
+    client_public: &[u8],
+) -> Result<()> {
+    let identity: [u8; IDENTITY_KEY_SIZE] = identity
+        .try_into()
+        .map_err(|_| ProtocolError::AuthenticationFailed("Invalid server identity key".to_string()))?;
+    if !pinned.contains(&identity) {
+        return Err(ProtocolError::AuthenticationFailed(
+            "Server identity key is not pinned".to_string(),
+        ));
+    }
+
+    let signature: [u8; SIGNATURE_SIZE] = signature
+        .try_into()
+        .map_err(|_| ProtocolError::AuthenticationFailed("Invalid handshake signature".to_string()))?;
+    VerifyingKey::from_bytes(&identity)
+        .and_then(|key| {
+            key.verify_strict(
+                &handshake_transcript(server_public, client_public),
+                &Signature::from_bytes(&signature),
+            )
+        })
+        .map_err(|_| ProtocolError::AuthenticationFailed("Invalid handshake signature".to_string()))
+}
+
+/// What the server signs: a context prefix and both ephemeral keys
+fn handshake_transcript(server_public: &[u8], client_public: &[u8]) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(HANDSHAKE_CONTEXT.len() + 2 * PUBLIC_KEY_SIZE);
+    transcript.extend_from_slice(HANDSHAKE_CONTEXT);
+    transcript.extend_from_slice(server_public);
+    transcript.extend_from_slice(client_public);
+    transcript
 }
 
 #[cfg(test)]
@@ -142,5 +593,167 @@ mod tests {
 
         assert_eq!(plaintext, &decrypted[..]);
     }
-}
 
+    #[test]
+    fn test_aad_is_authenticated() {
+        let crypto = CryptoProvider::new_aes(&CryptoProvider::generate_key());
+
+        let ciphertext = Crypto::encrypt(&crypto, b"payload", b"/route").unwrap();
+        assert_eq!(&Crypto::decrypt(&crypto, &ciphertext, b"/route").unwrap()[..], b"payload");
+        assert!(Crypto::decrypt(&crypto, &ciphertext, b"/other").is_err());
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_public = client.public_key();
+        let server_public = server.public_key();
+
+        let client_crypto = client.derive(&server_public, true).unwrap();
+        let server_crypto = server.derive(&client_public, false).unwrap();
+
+        let ciphertext = client_crypto.encrypt(b"negotiated").unwrap();
+        let decrypted = server_crypto.decrypt(&ciphertext).unwrap();
+
+        assert_eq!(b"negotiated", &decrypted[..]);
+    }
+
+    #[test]
+    fn test_session_ticket() {
+        let key = TicketKey::generate();
+        let session = CryptoProvider::new_aes(&CryptoProvider::generate_key());
+        let secret = session.resumption_secret();
+        let ticket = key.seal(&secret, 1_000).unwrap();
+
+        assert_eq!(key.open(&ticket, 999).unwrap(), secret);
+        assert!(key.open(&ticket, 1_000).is_err());
+        assert!(TicketKey::generate().open(&ticket, 999).is_err());
+
+        // Each resumption gets its own key, the same on both sides
+        let client = CryptoProvider::resumed(&secret, &[1; RESUMPTION_NONCE_SIZE]);
+        let server = CryptoProvider::resumed(&key.open(&ticket, 999).unwrap(), &[1; RESUMPTION_NONCE_SIZE]);
+        let other = CryptoProvider::resumed(&secret, &[2; RESUMPTION_NONCE_SIZE]);
+        let sealed = client.encrypt(b"resumed").unwrap();
+        assert_eq!(&server.decrypt(&sealed).unwrap()[..], b"resumed");
+        assert!(other.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_handshake_signature() {
+        let identity = ServerIdentity::generate();
+        let pinned = [identity.public_key()];
+        let (server, client) = ([1u8; PUBLIC_KEY_SIZE], [2u8; PUBLIC_KEY_SIZE]);
+        let signature = identity.sign_handshake(&server, &client);
+
+        assert!(verify_handshake(&pinned, &identity.public_key(), &signature, &server, &client).is_ok());
+        // Bound to this exchange's keys
+        assert!(verify_handshake(&pinned, &identity.public_key(), &signature, &client, &server).is_err());
+
+        let other = ServerIdentity::generate();
+        let forged = other.sign_handshake(&server, &client);
+        assert!(verify_handshake(&pinned, &other.public_key(), &forged, &server, &client).is_err());
+
+        let restored = ServerIdentity::from_secret_key(&identity.secret_key());
+        assert_eq!(restored.public_key(), identity.public_key());
+    }
+
+    #[test]
+    fn test_nonces_never_repeat() {
+        let crypto = CryptoProvider::new_aes(&CryptoProvider::generate_key());
+
+        let mut nonces = std::collections::HashSet::new();
+        for _ in 0..10_000 {
+            let ciphertext = crypto.encrypt(b"same message").unwrap();
+            assert!(nonces.insert(ciphertext[..NONCE_SIZE].to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_exhausted_key_is_rejected() {
+        let crypto = CryptoProvider::new_chacha(&CryptoProvider::generate_key());
+        crypto.nonce_counter.store(MAX_MESSAGES_PER_KEY - 1, Ordering::Relaxed);
+
+        assert!(crypto.encrypt(b"last").is_ok());
+        assert_eq!(crypto.remaining_messages(), 0);
+        assert!(matches!(crypto.encrypt(b"one too many"), Err(ProtocolError::Encryption(_))));
+    }
+
+    #[test]
+    fn test_rotation_keeps_previous_key_for_grace_window() {
+        let key = CryptoProvider::generate_key();
+        let mut sender = CryptoProvider::new_aes(&key);
+        let mut receiver = CryptoProvider::new_aes(&key);
+
+        let in_flight = sender.encrypt(b"old key").unwrap();
+        let next = sender.next_key(b"salt");
+        assert_eq!(next, receiver.next_key(b"salt"));
+        sender.rotate(&next);
+        receiver.rotate(&next);
+
+        // Both keys decrypt during the grace window
+        let fresh = sender.encrypt(b"new key").unwrap();
+        assert_eq!(&receiver.decrypt(&fresh).unwrap()[..], b"new key");
+        assert_eq!(&receiver.decrypt(&in_flight).unwrap()[..], b"old key");
+
+        // Only the new key once the window has passed
+        receiver.set_rekey_grace(Duration::ZERO);
+        assert!(receiver.decrypt(&in_flight).is_err());
+        assert!(receiver.decrypt(&fresh).is_ok());
+    }
+
+    #[test]
+    fn test_injected_clock_and_rng() {
+        use crate::clock::{MockClock, SeededRng};
+
+        let key = CryptoProvider::generate_key();
+        let clock = Arc::new(MockClock::new());
+        let sealed: Vec<_> = (0..2)
+            .map(|_| {
+                let mut crypto = CryptoProvider::new_aes(&key);
+                crypto.set_rng(Arc::new(SeededRng::new(42)));
+                crypto.encrypt(b"same nonce").unwrap()
+            })
+            .collect();
+        assert_eq!(sealed[0], sealed[1]);
+
+        let mut crypto = CryptoProvider::new_aes(&key);
+        crypto.set_time_source(clock.clone());
+        let in_flight = crypto.encrypt(b"old key").unwrap();
+        let next = crypto.next_key(b"salt");
+        crypto.rotate(&next);
+        clock.advance(DEFAULT_REKEY_GRACE - Duration::from_millis(1));
+        assert!(crypto.decrypt(&in_flight).is_ok());
+        clock.advance(Duration::from_millis(1));
+        assert!(crypto.decrypt(&in_flight).is_err());
+        assert_eq!(crypto.key_age(), DEFAULT_REKEY_GRACE);
+    }
+
+    #[test]
+    fn test_fallback_keys_decrypt_during_psk_rollout() {
+        let (old, new) = (CryptoProvider::generate_key(), CryptoProvider::generate_key());
+        let lagging = CryptoProvider::new_aes(&old);
+        let updated = CryptoProvider::new_aes(&new);
+        let rolling = CryptoProvider::new_aes(&new).with_fallback_keys(&[old]);
+
+        let from_old = lagging.encrypt_with_aad(b"old psk", b"header").unwrap();
+        assert_eq!(&rolling.decrypt_with_aad(&from_old, b"header").unwrap()[..], b"old psk");
+        assert!(rolling.decrypt_with_aad(&from_old, b"tampered").is_err());
+
+        // Always encrypts with the newest key
+        let sealed = rolling.encrypt(b"new psk").unwrap();
+        assert_eq!(&updated.decrypt(&sealed).unwrap()[..], b"new psk");
+        assert!(lagging.decrypt(&sealed).is_err());
+
+        // Keys it was not given stay rejected
+        let stranger = CryptoProvider::new_aes(&CryptoProvider::generate_key());
+        assert!(rolling.decrypt(&stranger.encrypt(b"who").unwrap()).is_err());
+
+        // A rotation ends the rollout, in place or into a new provider
+        let rekeyed = rolling.rotated(&rolling.next_key(b"salt"));
+        assert!(rekeyed.decrypt_with_aad(&from_old, b"header").is_err());
+        let mut rotating = CryptoProvider::new_aes(&new).with_fallback_keys(&[old]);
+        rotating.rotate(&CryptoProvider::generate_key());
+        assert!(rotating.decrypt_with_aad(&from_old, b"header").is_err());
+    }
+}
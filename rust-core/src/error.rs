@@ -1,17 +1,22 @@
 //! Error types for the protocol
 
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::io;
 use thiserror::Error;
 
 /// Result type alias for protocol operations
-pub type Result<T> = std::result::Result<T, ProtocolError>;
+pub type Result<T> = core::result::Result<T, ProtocolError>;
 
 /// Protocol error types
 #[derive(Error, Debug)]
 pub enum ProtocolError {
+    #[cfg(feature = "std")]
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 
+    #[cfg(feature = "std")]
     #[error("Serialization error: {0}")]
     Serialization(#[from] bincode::Error),
 
@@ -33,6 +38,15 @@ pub enum ProtocolError {
     #[error("Route not found: {0}")]
     RouteNotFound(String),
 
+    #[error("Connection not found: {0}")]
+    ConnectionNotFound(String),
+
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+
+    #[error("Stream error: {0}")]
+    Stream(String),
+
     #[error("Protocol version mismatch: expected {expected}, got {actual}")]
     VersionMismatch { expected: u8, actual: u8 },
 
@@ -45,7 +59,155 @@ pub enum ProtocolError {
     #[error("Channel error: {0}")]
     Channel(String),
 
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("Remote error ({code}): {message}")]
+    Remote { code: RemoteErrorCode, message: String },
+
+    #[error("JSON serialization error: {0}")]
+    JsonError(String),
+
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+
+    #[error("No handler for job: {0}")]
+    HandlerNotFound(String),
+
+    #[error("Unknown job: {0}")]
+    JobNotFound(String),
+
+    #[error("Job failed: {0}")]
+    JobFailed(String),
+
+    #[error("Job cancelled")]
+    JobCancelled,
+
     #[error("Other error: {0}")]
     Other(String),
 }
 
+impl ProtocolError {
+    /// Kind of error, for branching on without matching its message
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "std")]
+            ProtocolError::Io(_) => ErrorCode::Io,
+            #[cfg(feature = "std")]
+            ProtocolError::Serialization(_) => ErrorCode::Serialization,
+            ProtocolError::Encryption(_) => ErrorCode::Encryption,
+            ProtocolError::Compression(_) => ErrorCode::Compression,
+            ProtocolError::Timeout => ErrorCode::Timeout,
+            ProtocolError::InvalidPacket(_) => ErrorCode::InvalidPacket,
+            ProtocolError::ConnectionClosed => ErrorCode::ConnectionClosed,
+            ProtocolError::RouteNotFound(_) => ErrorCode::RouteNotFound,
+            ProtocolError::ConnectionNotFound(_) => ErrorCode::ConnectionNotFound,
+            ProtocolError::InvalidPayload(_) => ErrorCode::InvalidPayload,
+            ProtocolError::Stream(_) => ErrorCode::Stream,
+            ProtocolError::VersionMismatch { .. } => ErrorCode::VersionMismatch,
+            ProtocolError::MaxRetransmitReached => ErrorCode::MaxRetransmitReached,
+            ProtocolError::InvalidAddress(_) => ErrorCode::InvalidAddress,
+            ProtocolError::Channel(_) => ErrorCode::Channel,
+            ProtocolError::AuthenticationFailed(_) => ErrorCode::AuthenticationFailed,
+            ProtocolError::Remote { code, .. } => ErrorCode::Remote(*code),
+            ProtocolError::JsonError(_) => ErrorCode::Json,
+            ProtocolError::Encoding(_) => ErrorCode::Encoding,
+            ProtocolError::HandlerNotFound(_) => ErrorCode::HandlerNotFound,
+            ProtocolError::JobNotFound(_) => ErrorCode::JobNotFound,
+            ProtocolError::JobFailed(_) => ErrorCode::JobFailed,
+            ProtocolError::JobCancelled => ErrorCode::JobCancelled,
+            ProtocolError::Other(_) => ErrorCode::Other,
+        }
+    }
+}
+
+/// Kind of a `ProtocolError`, without the details it carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Io,
+    Serialization,
+    Encryption,
+    Compression,
+    Timeout,
+    InvalidPacket,
+    ConnectionClosed,
+    RouteNotFound,
+    ConnectionNotFound,
+    InvalidPayload,
+    Stream,
+    VersionMismatch,
+    MaxRetransmitReached,
+    InvalidAddress,
+    Channel,
+    AuthenticationFailed,
+    /// A request failed on the remote side, for the reason given
+    Remote(RemoteErrorCode),
+    Json,
+    Encoding,
+    HandlerNotFound,
+    JobNotFound,
+    JobFailed,
+    JobCancelled,
+    Other,
+}
+
+
+/// Code carried by an error reply, saying why the remote side failed a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteErrorCode {
+    /// The handler returned an error
+    HandlerError,
+    /// No handler is registered for the route
+    RouteNotFound,
+    /// The handler panicked
+    HandlerPanic,
+    /// The handler did not finish within the server's handler timeout
+    Timeout,
+    /// The sender has not authenticated its connection
+    Unauthenticated,
+    /// The server is at its concurrency limit and turned the request away
+    Busy,
+    /// A code this build does not know
+    Unknown(u16),
+}
+
+impl RemoteErrorCode {
+    /// Code as sent on the wire
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RemoteErrorCode::HandlerError => 1,
+            RemoteErrorCode::RouteNotFound => 2,
+            RemoteErrorCode::HandlerPanic => 3,
+            RemoteErrorCode::Timeout => 4,
+            RemoteErrorCode::Unauthenticated => 5,
+            RemoteErrorCode::Busy => 6,
+            RemoteErrorCode::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            1 => RemoteErrorCode::HandlerError,
+            2 => RemoteErrorCode::RouteNotFound,
+            3 => RemoteErrorCode::HandlerPanic,
+            4 => RemoteErrorCode::Timeout,
+            5 => RemoteErrorCode::Unauthenticated,
+            6 => RemoteErrorCode::Busy,
+            code => RemoteErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for RemoteErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteErrorCode::HandlerError => f.write_str("handler error"),
+            RemoteErrorCode::RouteNotFound => f.write_str("route not found"),
+            RemoteErrorCode::HandlerPanic => f.write_str("handler panic"),
+            RemoteErrorCode::Timeout => f.write_str("timeout"),
+            RemoteErrorCode::Unauthenticated => f.write_str("unauthenticated"),
+            RemoteErrorCode::Busy => f.write_str("busy"),
+            RemoteErrorCode::Unknown(code) => write!(f, "code {}", code),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Simulated network faults, for testing over loopback
+//!
+//! `ImpairedTransport` wraps another `PacketTransport` and drops, duplicates,
+//! delays and reorders the datagrams sent through it. It sits below
+//! `Transport`, so ACKs, retransmission and ordering can be tested against a
+//! lossy network, and with a `SeededRng` the same faults happen on every run.
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+use crate::clock::RngSource;
+use crate::error::*;
+use crate::tasks::TaskSet;
+use crate::transport::PacketTransport;
+
+/// How long a reordered datagram is held back, on top of its jitter
+pub const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// Network faults to inject into what a transport sends
+///
+/// Probabilities run from 0 to 1; `ImpairedTransport::new` rejects any other.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImpairmentConfig {
+    /// Chance a datagram is dropped
+    pub loss: f64,
+    /// Chance a datagram is sent twice
+    pub duplicate: f64,
+    /// Chance a datagram is held back by `REORDER_DELAY`, so later ones overtake it
+    pub reorder: f64,
+    /// Each datagram is delayed by a random time up to this
+    pub latency_jitter: Duration,
+}
+
+impl ImpairmentConfig {
+    /// Check every probability is a number from 0 to 1
+    pub fn validate(&self) -> Result<()> {
+        for (name, chance) in [("loss", self.loss), ("duplicate", self.duplicate), ("reorder", self.reorder)] {
+            if !(0.0..=1.0).contains(&chance) {
+                return Err(ProtocolError::Other(format!(
+                    "Impairment {} must be between 0 and 1, got {}",
+                    name, chance
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Delays after which to send each copy of a datagram; none if it is lost
+    fn plan(&self, rng: &dyn RngSource) -> Vec<Duration> {
+        if chance(rng) < self.loss {
+            return Vec::new();
+        }
+        let copies = if chance(rng) < self.duplicate { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let delay = self.latency_jitter.mul_f64(chance(rng));
+                if chance(rng) < self.reorder {
+                    delay + REORDER_DELAY
+                } else {
+                    delay
+                }
+            })
+            .collect()
+    }
+}
+
+/// Uniform random number in `[0, 1)`
+fn chance(rng: &dyn RngSource) -> f64 {
+    let mut bytes = [0u8; 8];
+    rng.fill_bytes(&mut bytes);
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Transport that injects faults into the datagrams sent through `inner`
+///
+/// Received datagrams pass through untouched; impair both ends to affect
+/// traffic in both directions.
+pub struct ImpairedTransport<T: PacketTransport> {
+    inner: Arc<T>,
+    config: ImpairmentConfig,
+    rng: Arc<dyn RngSource>,
+    /// Delayed sends, dropped along with the transport
+    tasks: TaskSet,
+}
+
+impl<T: PacketTransport + 'static> ImpairedTransport<T> {
+    /// Impair what is sent through `inner`, drawing faults from `rng`
+    pub fn new(inner: T, config: ImpairmentConfig, rng: impl RngSource + 'static) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            inner: Arc::new(inner),
+            config,
+            rng: Arc::new(rng),
+            tasks: TaskSet::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl<T: PacketTransport + 'static> PacketTransport for ImpairedTransport<T> {
+    async fn send_to(&self, datagram: &[u8], dest: SocketAddr) -> Result<()> {
+        for delay in self.config.plan(&*self.rng) {
+            if delay.is_zero() {
+                self.inner.send_to(datagram, dest).await?;
+                continue;
+            }
+            let inner = self.inner.clone();
+            let datagram = datagram.to_vec();
+            self.tasks.spawn(async move {
+                time::sleep(delay).await;
+                let _ = inner.send_to(&datagram, dest).await;
+            });
+        }
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut BytesMut, limit: usize) -> Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf, limit).await
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SeededRng;
+
+    #[test]
+    fn test_probabilities_outside_unit_range_are_rejected() {
+        let valid = ImpairmentConfig { loss: 1.0, duplicate: 0.0, reorder: 0.5, ..Default::default() };
+        assert!(valid.validate().is_ok());
+        for config in [
+            ImpairmentConfig { loss: 1.5, ..Default::default() },
+            ImpairmentConfig { duplicate: -0.1, ..Default::default() },
+            ImpairmentConfig { reorder: f64::NAN, ..Default::default() },
+        ] {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_seeded_faults_repeat() {
+        let config = ImpairmentConfig {
+            loss: 0.3,
+            duplicate: 0.2,
+            reorder: 0.2,
+            latency_jitter: Duration::from_millis(5),
+        };
+        let plans = |seed| {
+            let rng = SeededRng::new(seed);
+            (0..200).map(|_| config.plan(&rng)).collect::<Vec<_>>()
+        };
+        let plans_a = plans(7);
+        assert_eq!(plans_a, plans(7));
+        let lost = plans_a.iter().filter(|plan| plan.is_empty()).count();
+        assert!((30..90).contains(&lost), "lost {} of 200", lost);
+        assert!(plans_a.iter().any(|plan| plan.len() == 2));
+    }
+}
@@ -2,16 +2,21 @@
 //!
 //! Provides async job queue with retry, scheduling, and priority support
 
+use async_trait::async_trait;
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::collections::{BinaryHeap, HashMap};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::{mpsc, RwLock, Mutex};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{oneshot, Notify, OwnedSemaphorePermit, RwLock, Mutex, Semaphore};
 use tokio::time;
-use tracing::{info, warn, error, debug};
+use tracing::{info, warn, error, debug, trace};
 
 use crate::error::*;
+use crate::metrics::LATENCY_BUCKETS;
+use crate::tasks::TaskSet;
 
 /// Job ID type
 pub type JobId = String;
@@ -25,6 +30,7 @@ pub enum JobStatus {
     Failed,
     Retrying,
     Scheduled,
+    Cancelled,
 }
 
 /// Job priority
@@ -76,6 +82,8 @@ pub struct Job {
     pub started_at: Option<u64>,
     pub completed_at: Option<u64>,
     pub error: Option<String>,
+    /// Payload returned by the handler once the job has completed
+    pub result: Option<Bytes>,
 }
 
 impl Job {
@@ -98,6 +106,7 @@ impl Job {
             started_at: None,
             completed_at: None,
             error: None,
+            result: None,
         }
     }
     
@@ -109,6 +118,25 @@ impl Job {
             true
         }
     }
+
+    /// Parse JSON payload
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.payload)
+            .map_err(|e| ProtocolError::InvalidPayload(format!("JSON parse error: {}", e)))
+    }
+
+    /// Get payload as string
+    pub fn text(&self) -> Result<String> {
+        String::from_utf8(self.payload.to_vec())
+            .map_err(|e| ProtocolError::InvalidPayload(format!("UTF-8 error: {}", e)))
+    }
+
+    /// Which attempt at the job is running: 1 the first time, 2 on the first retry
+    ///
+    /// Zero before the job has been picked up by a worker.
+    pub fn attempt_number(&self) -> u32 {
+        self.attempts
+    }
 }
 
 impl PartialEq for Job {
@@ -127,42 +155,584 @@ impl PartialOrd for Job {
 
 impl Ord for Job {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // Higher priority jobs come first
+        // Higher priority jobs come first, then older jobs within a priority
         self.config.priority.cmp(&other.config.priority)
-            .then_with(|| self.created_at.cmp(&other.created_at))
+            .then_with(|| other.created_at.cmp(&self.created_at))
+    }
+}
+
+/// Share of picks each priority gets when scheduling is weighted-fair
+///
+/// A weight of zero counts as one, so every priority keeps running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityWeights {
+    pub low: u32,
+    pub normal: u32,
+    pub high: u32,
+    pub critical: u32,
+}
+
+impl Default for PriorityWeights {
+    fn default() -> Self {
+        Self { low: 1, normal: 2, high: 4, critical: 8 }
+    }
+}
+
+impl PriorityWeights {
+    /// Weights indexed by `JobPriority`
+    fn by_priority(&self) -> [i64; PRIORITIES] {
+        [self.low, self.normal, self.high, self.critical].map(|weight| weight.max(1) as i64)
+    }
+}
+
+/// Number of `JobPriority` classes
+const PRIORITIES: usize = 4;
+
+/// Jobs ready to run, in one heap per priority
+///
+/// Without weights the highest priority with a job always goes first. With
+/// them, priorities take turns by smooth weighted round robin: each pick
+/// goes to the priority with the most credit, among those with jobs.
+///
+/// Jobs whose name is at its concurrency limit wait outside the heaps, in
+/// the order they were picked, until a job with that name finishes.
+#[derive(Default)]
+struct ReadyQueue {
+    classes: [BinaryHeap<Job>; PRIORITIES],
+    weights: Option<PriorityWeights>,
+    credit: [i64; PRIORITIES],
+    held: HashMap<String, VecDeque<Job>>,
+}
+
+impl ReadyQueue {
+    fn push(&mut self, job: Job) {
+        self.classes[job.config.priority as usize].push(job);
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        let class = match self.weights {
+            None => (0..PRIORITIES).rev().find(|&class| !self.classes[class].is_empty())?,
+            Some(weights) => {
+                let weights = weights.by_priority();
+                let mut total = 0;
+                let mut best: Option<usize> = None;
+                // Higher priorities win ties
+                for class in (0..PRIORITIES).rev() {
+                    if self.classes[class].is_empty() {
+                        continue;
+                    }
+                    self.credit[class] += weights[class];
+                    total += weights[class];
+                    if !matches!(best, Some(best) if self.credit[best] >= self.credit[class]) {
+                        best = Some(class);
+                    }
+                }
+                let best = best?;
+                self.credit[best] -= total;
+                best
+            }
+        };
+        self.classes[class].pop()
+    }
+
+    /// Keep a job out of the heaps until `release` is called for its name
+    fn hold(&mut self, job: Job) {
+        self.held.entry(job.name.clone()).or_default().push_back(job);
+    }
+
+    /// Return the longest held job named `name` to the heaps, as a slot for it opened up
+    fn release(&mut self, name: &str) {
+        let Some(held) = self.held.get_mut(name) else {
+            return;
+        };
+        let job = held.pop_front();
+        if held.is_empty() {
+            self.held.remove(name);
+        }
+        if let Some(job) = job {
+            self.push(job);
+        }
+    }
+
+    /// Return every held job named `name` to the heaps
+    fn release_all(&mut self, name: &str) {
+        if let Some(held) = self.held.remove(name) {
+            self.extend(held);
+        }
+    }
+
+    fn set_weights(&mut self, weights: Option<PriorityWeights>) {
+        self.weights = weights;
+        self.credit = [0; PRIORITIES];
+    }
+
+    fn len(&self) -> usize {
+        self.classes.iter().map(BinaryHeap::len).sum::<usize>() + self.held.values().map(VecDeque::len).sum::<usize>()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.classes
+            .iter()
+            .flat_map(BinaryHeap::iter)
+            .chain(self.held.values().flatten())
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Job) -> bool) {
+        for class in &mut self.classes {
+            class.retain(&mut keep);
+        }
+        for held in self.held.values_mut() {
+            held.retain(&mut keep);
+        }
+        self.held.retain(|_, held| !held.is_empty());
+    }
+}
+
+impl Extend<Job> for ReadyQueue {
+    fn extend<I: IntoIterator<Item = Job>>(&mut self, jobs: I) {
+        for job in jobs {
+            self.push(job);
+        }
+    }
+}
+
+/// Finished jobs, trimmed oldest first to the queue's retention policy
+#[derive(Default)]
+struct JobHistory {
+    /// Jobs by id, with the number of the entry that recorded them
+    jobs: HashMap<JobId, (u64, Job)>,
+    /// Entries in the order they were recorded; a job recorded again leaves a stale entry behind
+    order: VecDeque<(u64, Instant, JobId)>,
+    next_entry: u64,
+    config: JobQueueConfig,
+}
+
+impl JobHistory {
+    fn insert(&mut self, job: Job) {
+        let entry = self.next_entry;
+        self.next_entry += 1;
+        self.order.push_back((entry, Instant::now(), job.id.clone()));
+        self.jobs.insert(job.id.clone(), (entry, job));
+        self.evict();
+    }
+
+    fn get(&self, job_id: &str) -> Option<&Job> {
+        self.jobs.get(job_id).map(|(_, job)| job)
+    }
+
+    fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    fn clear(&mut self) {
+        self.jobs.clear();
+        self.order.clear();
+    }
+
+    fn set_config(&mut self, config: JobQueueConfig) {
+        self.config = config;
+        self.evict();
+    }
+
+    /// Drop the oldest jobs while there are too many or they are too old
+    fn evict(&mut self) {
+        let now = Instant::now();
+        while let Some((entry, recorded_at, job_id)) = self.order.front() {
+            let live = matches!(self.jobs.get(job_id), Some((current, _)) if current == entry);
+            let expired = matches!(self.config.history_ttl, Some(ttl) if now.duration_since(*recorded_at) > ttl);
+            let over = matches!(self.config.max_history, Some(max) if self.jobs.len() > max);
+            if live && !expired && !over {
+                break;
+            }
+            if let Some((_, _, job_id)) = self.order.pop_front() {
+                if live {
+                    debug!("Evicting job {} from the completed history", job_id);
+                    self.jobs.remove(&job_id);
+                }
+            }
+        }
     }
 }
 
 /// Job handler function
-pub type JobHandler = Arc<dyn Fn(Job) -> Result<Bytes> + Send + Sync>;
+pub type JobHandler = Arc<dyn Fn(Job, CancelToken) -> Result<Bytes> + Send + Sync>;
+
+/// Hooks run around every job, for logging, metrics or tracing
+///
+/// Every hook does nothing by default. `on_failure` runs for each failed
+/// attempt, including ones that are retried.
+#[async_trait]
+pub trait JobMiddleware: Send + Sync {
+    /// Called just before the job's handler runs
+    async fn before(&self, _job: &Job) {}
+
+    /// Called after the handler returned `result`
+    async fn after(&self, _job: &Job, _result: &Bytes) {}
+
+    /// Called after the handler failed, timed out or was not found
+    async fn on_failure(&self, _job: &Job, _error: &ProtocolError) {}
+}
+
+/// Lets a running job notice that it has been cancelled
+///
+/// Cancellation is cooperative: a long-running handler should check
+/// `is_cancelled` now and then and return early once it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Whether the job has been asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Durable storage for jobs that have not finished yet
+///
+/// The queue saves a job when it is added, updates it whenever its status
+/// changes, and removes it once it has completed or failed for good.
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    /// Persist a newly added job
+    async fn save(&self, job: &Job) -> Result<()>;
+
+    /// Persist a change to a stored job
+    async fn update(&self, job: &Job) -> Result<()>;
+
+    /// Forget a job that has finished
+    async fn remove(&self, job_id: &str) -> Result<()>;
+
+    /// Load every stored job
+    async fn load(&self) -> Result<Vec<Job>>;
+}
+
+/// Keeps jobs in memory only, so they do not survive a restart
+#[derive(Default)]
+pub struct MemoryJobStore {
+    jobs: RwLock<HashMap<JobId, Job>>,
+}
+
+#[async_trait]
+impl JobStore for MemoryJobStore {
+    async fn save(&self, job: &Job) -> Result<()> {
+        self.jobs.write().await.insert(job.id.clone(), job.clone());
+        Ok(())
+    }
+
+    async fn update(&self, job: &Job) -> Result<()> {
+        self.save(job).await
+    }
+
+    async fn remove(&self, job_id: &str) -> Result<()> {
+        self.jobs.write().await.remove(job_id);
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<Vec<Job>> {
+        Ok(self.jobs.read().await.values().cloned().collect())
+    }
+}
+
+/// Keeps each job in its own file in a directory
+///
+/// Files are written to a temporary name and renamed into place, so a crash
+/// mid-write leaves the previous version intact.
+pub struct FileJobStore {
+    dir: PathBuf,
+}
+
+impl FileJobStore {
+    /// Open a store in `dir`, creating the directory if needed
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, job_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.job", job_id))
+    }
+}
+
+#[async_trait]
+impl JobStore for FileJobStore {
+    async fn save(&self, job: &Job) -> Result<()> {
+        let data = bincode::serialize(job)?;
+        let path = self.path(&job.id);
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn update(&self, job: &Job) -> Result<()> {
+        self.save(job).await
+    }
+
+    async fn remove(&self, job_id: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(job_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("job") {
+                continue;
+            }
+            let data = tokio::fs::read(&path).await?;
+            match bincode::deserialize::<Job>(&data) {
+                Ok(job) => jobs.push(job),
+                Err(e) => warn!("Skipping unreadable job file {}: {}", path.display(), e),
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+/// Settings for a job queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JobQueueConfig {
+    /// Most finished jobs kept in the history, or None for no limit
+    pub max_history: Option<usize>,
+    /// How long a finished job is kept in the history, or None for no limit
+    pub history_ttl: Option<Duration>,
+}
+
+/// Jobs of one name that have run, and how long their attempts took
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobMetrics {
+    /// Jobs that completed
+    pub completed: u64,
+    /// Jobs that failed for good, having used up their retries
+    pub failed: u64,
+    /// Failed attempts that were scheduled to run again
+    pub retried: u64,
+    /// Jobs that stopped after being cancelled while running
+    pub cancelled: u64,
+    /// Attempts per latency bucket, the last counting those slower than every bound
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    /// Total time across all attempts
+    pub latency_total: Duration,
+    /// Slowest attempt
+    pub latency_max: Duration,
+}
+
+impl JobMetrics {
+    /// Count one attempt that ended with the job in `status`
+    fn record(&mut self, latency: Duration, status: JobStatus) {
+        match status {
+            JobStatus::Completed => self.completed += 1,
+            JobStatus::Failed => self.failed += 1,
+            JobStatus::Cancelled => self.cancelled += 1,
+            _ => self.retried += 1,
+        }
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket] += 1;
+        self.latency_total += latency;
+        self.latency_max = self.latency_max.max(latency);
+    }
+
+    /// Add the counts of `other` to these
+    fn merge(&mut self, other: &JobMetrics) {
+        self.completed += other.completed;
+        self.failed += other.failed;
+        self.retried += other.retried;
+        self.cancelled += other.cancelled;
+        for (bucket, count) in self.latency_buckets.iter_mut().zip(&other.latency_buckets) {
+            *bucket += count;
+        }
+        self.latency_total += other.latency_total;
+        self.latency_max = self.latency_max.max(other.latency_max);
+    }
+
+    /// Attempts counted, whatever their outcome
+    pub fn attempts(&self) -> u64 {
+        self.completed + self.failed + self.retried + self.cancelled
+    }
+
+    /// Mean time an attempt took
+    pub fn average_latency(&self) -> Duration {
+        match self.attempts() {
+            0 => Duration::ZERO,
+            attempts => Duration::from_secs_f64(self.latency_total.as_secs_f64() / attempts as f64),
+        }
+    }
+
+    /// Time within which 99% of attempts finished
+    ///
+    /// Rounded up to the bound of the latency bucket it falls in, but never
+    /// more than the slowest attempt.
+    pub fn p99_latency(&self) -> Duration {
+        let attempts = self.attempts();
+        if attempts == 0 {
+            return Duration::ZERO;
+        }
+        let rank = (attempts as f64 * 0.99).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_secs_f64(*bound).min(self.latency_max);
+            }
+        }
+        self.latency_max
+    }
+}
+
+/// Snapshot of a job queue's load and of the jobs it has run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobQueueStats {
+    /// Jobs waiting to run, including scheduled ones
+    pub pending: usize,
+    /// Jobs running now
+    pub processing: usize,
+    /// Workers the queue was started with
+    pub workers: usize,
+    /// Workers busy with a job
+    pub busy_workers: usize,
+    /// Jobs of every name together
+    pub total: JobMetrics,
+    /// Jobs by name
+    pub by_name: HashMap<String, JobMetrics>,
+}
+
+impl JobQueueStats {
+    /// Share of workers busy with a job, from 0 to 1
+    pub fn utilization(&self) -> f64 {
+        if self.workers == 0 {
+            0.0
+        } else {
+            self.busy_workers as f64 / self.workers as f64
+        }
+    }
+}
+
+/// Receives the outcome of a job someone is waiting on
+type JobWaiter = oneshot::Sender<Result<Bytes>>;
 
 /// Job queue manager
 pub struct JobQueue {
-    /// Pending jobs (priority queue)
-    pending: Arc<RwLock<BinaryHeap<Job>>>,
+    /// Jobs ready to run, by priority
+    pending: Arc<RwLock<ReadyQueue>>,
+    /// Jobs waiting for their scheduled time, ordered by when they are due
+    scheduled: Arc<RwLock<BTreeMap<(u64, JobId), Job>>>,
+    /// Wakes an idle worker when a job becomes ready
+    job_ready: Arc<Notify>,
+    /// Wakes the scheduler when a job is scheduled
+    schedule_changed: Arc<Notify>,
     /// Processing jobs
     processing: Arc<RwLock<HashMap<JobId, Job>>>,
+    /// Cancellation tokens of processing jobs
+    cancel_tokens: Arc<RwLock<HashMap<JobId, CancelToken>>>,
     /// Completed jobs (history)
-    completed: Arc<RwLock<HashMap<JobId, Job>>>,
+    completed: Arc<RwLock<JobHistory>>,
+    /// Callers waiting for a job to finish
+    waiters: Arc<Mutex<HashMap<JobId, Vec<JobWaiter>>>>,
+    /// Durable copy of unfinished jobs
+    store: Arc<dyn JobStore>,
     /// Job handlers
     handlers: Arc<RwLock<HashMap<String, JobHandler>>>,
+    /// Hooks run around every job, in the order they were added
+    middleware: Arc<RwLock<Vec<Arc<dyn JobMiddleware>>>>,
+    /// Caps on how many jobs with a given name run at once
+    concurrency_limits: Arc<RwLock<HashMap<String, Arc<Semaphore>>>>,
+    /// Outcomes and latencies of the jobs run so far, by name
+    job_metrics: Arc<Mutex<HashMap<String, JobMetrics>>>,
     /// Worker count
     worker_count: usize,
     /// Shutdown signal
     shutdown: Arc<RwLock<bool>>,
+    /// Jobs taken by a worker whose outcome is not recorded yet
+    active: AtomicUsize,
+    /// Wakes a graceful shutdown when a worker finishes a job
+    job_finished: Notify,
+    /// Scheduler and worker tasks, stopped when the queue is dropped
+    tasks: TaskSet,
 }
 
 impl JobQueue {
-    /// Create a new job queue
+    /// Create a new job queue that keeps jobs in memory only
     pub fn new(worker_count: usize) -> Self {
+        Self::with_store(Arc::new(MemoryJobStore::default()), worker_count)
+    }
+
+    /// Create a job queue backed by `store`, picking up the jobs it already holds
+    ///
+    /// Pending and scheduled jobs are queued again as they were. A job that was
+    /// `Processing` when the previous process stopped may or may not have run
+    /// to completion, so it is re-queued as pending and runs again; its attempt
+    /// count is kept, so it still respects `max_retries`. Handlers should
+    /// therefore be safe to run more than once.
+    pub async fn new_with_store(store: Arc<dyn JobStore>, worker_count: usize) -> Result<Self> {
+        let queue = Self::with_store(store, worker_count);
+        queue.rehydrate().await?;
+        Ok(queue)
+    }
+
+    fn with_store(store: Arc<dyn JobStore>, worker_count: usize) -> Self {
         Self {
-            pending: Arc::new(RwLock::new(BinaryHeap::new())),
+            pending: Arc::new(RwLock::new(ReadyQueue::default())),
+            scheduled: Arc::new(RwLock::new(BTreeMap::new())),
+            job_ready: Arc::new(Notify::new()),
+            schedule_changed: Arc::new(Notify::new()),
             processing: Arc::new(RwLock::new(HashMap::new())),
-            completed: Arc::new(RwLock::new(HashMap::new())),
+            cancel_tokens: Arc::new(RwLock::new(HashMap::new())),
+            completed: Arc::new(RwLock::new(JobHistory::default())),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+            store,
             handlers: Arc::new(RwLock::new(HashMap::new())),
+            middleware: Arc::new(RwLock::new(Vec::new())),
+            concurrency_limits: Arc::new(RwLock::new(HashMap::new())),
+            job_metrics: Arc::new(Mutex::new(HashMap::new())),
             worker_count,
             shutdown: Arc::new(RwLock::new(false)),
+            active: AtomicUsize::new(0),
+            job_finished: Notify::new(),
+            tasks: TaskSet::default(),
+        }
+    }
+
+    /// Queue the jobs held in the store
+    async fn rehydrate(&self) -> Result<()> {
+        let jobs = self.store.load().await?;
+        let mut restored = 0;
+        for mut job in jobs {
+            match job.status {
+                JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled => {
+                    log_store_error(&job.id, self.store.remove(&job.id).await);
+                    continue;
+                }
+                JobStatus::Processing => {
+                    warn!("Re-queueing job {} that was interrupted while processing", job.id);
+                    job.status = JobStatus::Pending;
+                    job.started_at = None;
+                    job.config.scheduled_at = None;
+                    log_store_error(&job.id, self.store.update(&job).await);
+                }
+                _ => {}
+            }
+            self.queue_job(job).await;
+            restored += 1;
+        }
+        if restored > 0 {
+            info!("Restored {} jobs from the job store", restored);
         }
+        Ok(())
     }
 
     /// Register a job handler
@@ -170,19 +740,85 @@ impl JobQueue {
     where
         F: Fn(Job) -> Result<Bytes> + Send + Sync + 'static,
     {
-        info!("Registering job handler: {}", job_name);
+        self.register_cancellable(job_name, move |job, _token| handler(job)).await;
+    }
+
+    /// Register a job handler that is told when its job is cancelled
+    pub async fn register_cancellable<F>(&self, job_name: String, handler: F)
+    where
+        F: Fn(Job, CancelToken) -> Result<Bytes> + Send + Sync + 'static,
+    {
+        trace!("Registering job handler: {}", job_name);
         self.handlers.write().await.insert(job_name, Arc::new(handler));
     }
 
+    /// Share workers between priorities by weight instead of strictly (None restores strict)
+    ///
+    /// Strict priority runs a lower priority job only once no higher one is
+    /// ready, so a steady stream of urgent jobs can hold the rest back
+    /// indefinitely. With weights each priority that has jobs ready gets its
+    /// share of picks, so every job eventually runs.
+    pub async fn set_priority_weights(&self, weights: Option<PriorityWeights>) {
+        self.pending.write().await.set_weights(weights);
+    }
+
+    /// Apply `config` to the queue
+    ///
+    /// The completed history is trimmed right away, then again every time a
+    /// job finishes, dropping the jobs that finished first. Once a job has
+    /// left the history, `get_job`, `get_result` and `wait_for` no longer
+    /// know about it.
+    pub async fn set_config(&self, config: JobQueueConfig) {
+        self.completed.write().await.set_config(config);
+    }
+
+    /// Add hooks that run around every job
+    pub async fn use_middleware(&self, middleware: impl JobMiddleware + 'static) {
+        self.middleware.write().await.push(Arc::new(middleware));
+    }
+
+    /// Run at most `limit` jobs named `job_name` at once
+    ///
+    /// Jobs over the limit wait in the queue while workers pick up other
+    /// jobs. Applies to jobs started after the call.
+    pub async fn set_concurrency(&self, job_name: impl Into<String>, limit: usize) {
+        let limit = Arc::new(Semaphore::new(limit.max(1)));
+        let job_name = job_name.into();
+        self.concurrency_limits.write().await.insert(job_name.clone(), limit);
+        // Jobs held back by the old limit get another chance under the new one
+        self.pending.write().await.release_all(&job_name);
+        self.job_ready.notify_waiters();
+    }
+
     /// Add a job to the queue
     pub async fn add_job(&self, job: Job) -> JobId {
         let job_id = job.id.clone();
-        info!("Adding job: {} ({})", job.name, job_id);
-        
-        self.pending.write().await.push(job);
+        trace!("Adding job: {} ({})", job.name, job_id);
+
+        log_store_error(&job_id, self.store.save(&job).await);
+        self.queue_job(job).await;
         job_id
     }
 
+    /// Put a job in the ready heap or hold it until its scheduled time
+    async fn queue_job(&self, job: Job) {
+        if job.should_execute() {
+            let mut job = job;
+            job.status = JobStatus::Pending;
+            self.pending.write().await.push(job);
+            self.job_ready.notify_one();
+        } else {
+            self.push_scheduled(job).await;
+        }
+    }
+
+    /// Hold a job until its scheduled time
+    async fn push_scheduled(&self, job: Job) {
+        let at = job.config.scheduled_at.unwrap_or_default();
+        self.scheduled.write().await.insert((at, job.id.clone()), job);
+        self.schedule_changed.notify_one();
+    }
+
     /// Create and add a job
     pub async fn enqueue(&self, name: String, payload: Bytes, config: JobConfig) -> JobId {
         let job = Job::new(name, payload, config);
@@ -207,29 +843,71 @@ impl JobQueue {
 
     /// Get job status
     pub async fn get_job(&self, job_id: &str) -> Option<Job> {
-        // Check processing
-        if let Some(job) = self.processing.read().await.get(job_id) {
-            return Some(job.clone());
-        }
-        
-        // Check completed
-        if let Some(job) = self.completed.read().await.get(job_id) {
-            return Some(job.clone());
-        }
-        
-        // Check pending
-        for job in self.pending.read().await.iter() {
-            if job.id == job_id {
+        {
+            // A job moves from pending to processing under the pending lock,
+            // and from processing to completed under the processing lock, so
+            // holding each while checking the next never misses one in between
+            let pending = self.pending.read().await;
+            if let Some(job) = pending.iter().find(|job| job.id == job_id) {
+                return Some(job.clone());
+            }
+            let processing = self.processing.read().await;
+            if let Some(job) = processing.get(job_id) {
+                return Some(job.clone());
+            }
+            if let Some(job) = self.completed.read().await.get(job_id) {
                 return Some(job.clone());
             }
         }
-        
-        None
+
+        // Check scheduled
+        self.scheduled
+            .read()
+            .await
+            .values()
+            .find(|job| job.id == job_id)
+            .cloned()
+    }
+
+    /// Get the payload a completed job produced
+    pub async fn get_result(&self, job_id: &str) -> Option<Bytes> {
+        self.completed
+            .read()
+            .await
+            .get(job_id)
+            .filter(|job| job.status == JobStatus::Completed)
+            .and_then(|job| job.result.clone())
+    }
+
+    /// Wait for a job to finish, returning its result
+    ///
+    /// A job that is retried resolves once it either completes or has used up
+    /// its retries, in which case its last error is returned.
+    pub async fn wait_for(&self, job_id: &str) -> Result<Bytes> {
+        let rx = {
+            // Check under the waiters lock so a job finishing meanwhile cannot be missed
+            let mut waiters = self.waiters.lock().await;
+            if let Some(job) = self.completed.read().await.get(job_id) {
+                if let Some(outcome) = job_outcome(job) {
+                    return outcome;
+                }
+            }
+            if self.get_job(job_id).await.is_none() {
+                return Err(ProtocolError::JobNotFound(job_id.to_string()));
+            }
+
+            let (tx, rx) = oneshot::channel();
+            waiters.entry(job_id.to_string()).or_default().push(tx);
+            rx
+        };
+
+        rx.await
+            .map_err(|_| ProtocolError::Channel("Job queue dropped the waiter".to_string()))?
     }
 
-    /// Get all pending jobs
+    /// Get all pending jobs, including scheduled ones
     pub async fn get_pending_count(&self) -> usize {
-        self.pending.read().await.len()
+        self.pending.read().await.len() + self.scheduled.read().await.len()
     }
 
     /// Get all processing jobs
@@ -242,164 +920,337 @@ impl JobQueue {
         self.completed.read().await.len()
     }
 
+    /// Snapshot of the queue's depth, worker use and the jobs it has run
+    pub async fn stats(&self) -> JobQueueStats {
+        let by_name = self.job_metrics.lock().await.clone();
+        let mut total = JobMetrics::default();
+        for metrics in by_name.values() {
+            total.merge(metrics);
+        }
+        JobQueueStats {
+            pending: self.get_pending_count().await,
+            processing: self.get_processing_count().await,
+            workers: self.worker_count,
+            busy_workers: self.active.load(Ordering::SeqCst),
+            total,
+            by_name,
+        }
+    }
+
     /// Start processing jobs
     pub async fn start(self: Arc<Self>) {
         info!("Starting job queue with {} workers", self.worker_count);
 
         // Start scheduler
-        let queue = self.clone();
-        tokio::spawn(async move {
-            queue.run_scheduler().await;
-        });
+        self.tasks.spawn(Self::run_scheduler(Arc::downgrade(&self)));
 
         // Start workers
         for i in 0..self.worker_count {
-            let queue = self.clone();
-            tokio::spawn(async move {
+            let queue = Arc::downgrade(&self);
+            self.tasks.spawn(async move {
                 info!("Starting worker {}", i);
-                queue.run_worker(i).await;
+                Self::run_worker(queue, i).await;
             });
         }
     }
 
     /// Run scheduler (for delayed jobs)
-    async fn run_scheduler(&self) {
-        let mut interval = time::interval(Duration::from_millis(100));
-        
+    ///
+    /// Sleeps until the earliest scheduled job is due, or until a new job is scheduled.
+    async fn run_scheduler(queue: Weak<Self>) {
         loop {
-            interval.tick().await;
-            
-            if *self.shutdown.read().await {
+            let Some(this) = queue.upgrade() else {
                 break;
-            }
+            };
 
-            // Check for scheduled jobs that are ready
-            let mut pending = self.pending.write().await;
-            let mut ready_jobs = Vec::new();
-            let mut temp = BinaryHeap::new();
+            // Register before checking so a job scheduled in between still wakes us
+            let schedule_changed = this.schedule_changed.clone();
+            let changed = schedule_changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
 
-            while let Some(job) = pending.pop() {
-                if job.status == JobStatus::Scheduled && job.should_execute() {
-                    let mut ready_job = job;
-                    ready_job.status = JobStatus::Pending;
-                    ready_jobs.push(ready_job);
-                } else {
-                    temp.push(job);
-                }
+            if *this.shutdown.read().await {
+                break;
             }
 
-            // Put back non-ready jobs
-            for job in temp.into_iter() {
-                pending.push(job);
+            // Take every job that is due
+            let now = current_timestamp();
+            let (ready, next_due) = {
+                let mut scheduled = this.scheduled.write().await;
+                let later = scheduled.split_off(&(now + 1, JobId::new()));
+                let ready = std::mem::replace(&mut *scheduled, later);
+                (ready, scheduled.keys().next().map(|(at, _)| *at))
+            };
+
+            if !ready.is_empty() {
+                let mut pending = this.pending.write().await;
+                for (_, mut job) in ready {
+                    trace!("Scheduled job {} is now ready", job.id);
+                    job.status = JobStatus::Pending;
+                    pending.push(job);
+                    this.job_ready.notify_one();
+                }
             }
 
-            // Add ready jobs back
-            for job in ready_jobs {
-                debug!("Scheduled job {} is now ready", job.id);
-                pending.push(job);
+            // Only hold the queue weakly while asleep, so dropping it stops the scheduler
+            drop(this);
+            match next_due {
+                Some(at) => {
+                    let delay = Duration::from_millis(at.saturating_sub(current_timestamp()));
+                    tokio::select! {
+                        _ = time::sleep(delay) => {}
+                        _ = changed => {}
+                    }
+                }
+                None => changed.await,
             }
         }
     }
 
     /// Run worker
-    async fn run_worker(&self, worker_id: usize) {
+    ///
+    /// Parks while there is nothing to do instead of polling.
+    async fn run_worker(queue: Weak<Self>, worker_id: usize) {
         loop {
-            if *self.shutdown.read().await {
-                info!("Worker {} shutting down", worker_id);
+            let Some(this) = queue.upgrade() else {
                 break;
-            }
+            };
+
+            // Register before checking so a job added in between still wakes us
+            let job_ready = this.job_ready.clone();
+            let ready = job_ready.notified();
+            tokio::pin!(ready);
+            ready.as_mut().enable();
 
-            // Get next job
+            // Take the job under the shutdown lock, so a graceful shutdown waits for it
             let job = {
-                let mut pending = self.pending.write().await;
-                
-                // Find first non-scheduled pending job
-                let mut temp = BinaryHeap::new();
-                let mut found_job = None;
-
-                while let Some(job) = pending.pop() {
-                    if job.status == JobStatus::Pending && job.should_execute() {
-                        found_job = Some(job);
-                        break;
-                    } else {
-                        temp.push(job);
-                    }
+                let shutdown = this.shutdown.read().await;
+                if *shutdown {
+                    info!("Worker {} shutting down", worker_id);
+                    break;
+                }
+                let job = this.take_next_job().await;
+                if job.is_some() {
+                    this.active.fetch_add(1, Ordering::SeqCst);
                 }
+                job
+            };
+            let Some((mut job, token, permit)) = job else {
+                // Only hold the queue weakly while parked, so dropping it stops the worker
+                drop(this);
+                ready.await;
+                continue;
+            };
 
-                // Put back jobs we didn't process
-                for job in temp.into_iter() {
-                    pending.push(job);
+            trace!("Worker {} processing job {}", worker_id, job.id);
+
+            log_store_error(&job.id, this.store.update(&job).await);
+
+            // Process job
+            let started = Instant::now();
+            let result = this.run_job(&job, token.clone()).await;
+            let latency = started.elapsed();
+
+            this.cancel_tokens.write().await.remove(&job.id);
+
+            match result {
+                Ok(output) => {
+                    job.status = JobStatus::Completed;
+                    job.completed_at = Some(current_timestamp());
+                    job.result = Some(output);
+                    trace!("Job {} completed successfully", job.id);
                 }
+                Err(e) if token.is_cancelled() => {
+                    info!("Job {} stopped after being cancelled", job.id);
+                    job.status = JobStatus::Cancelled;
+                    job.completed_at = Some(current_timestamp());
+                    job.error = Some(e.to_string());
+                }
+                Err(e) => {
+                    error!("Job {} failed: {}", job.id, e);
+                    job.error = Some(e.to_string());
 
-                found_job
-            };
+                    // Retry logic
+                    if job.attempts < job.config.max_retries {
+                        warn!("Retrying job {} (attempt {}/{})",
+                            job.id, job.attempts + 1, job.config.max_retries);
+
+                        // Schedule retry
+                        let scheduled_at = current_timestamp() + job.config.retry_delay;
+                        job.config.scheduled_at = Some(scheduled_at);
+                        job.status = JobStatus::Scheduled;
 
-            if let Some(mut job) = job {
-                debug!("Worker {} processing job {}", worker_id, job.id);
-                
-                // Mark as processing
-                job.status = JobStatus::Processing;
-                job.started_at = Some(current_timestamp());
-                job.attempts += 1;
-                
-                self.processing.write().await.insert(job.id.clone(), job.clone());
-
-                // Process job
-                let result = self.process_job(job.clone()).await;
-
-                // Remove from processing
-                self.processing.write().await.remove(&job.id);
-
-                match result {
-                    Ok(_) => {
-                        job.status = JobStatus::Completed;
-                        job.completed_at = Some(current_timestamp());
-                        info!("Job {} completed successfully", job.id);
+                        log_store_error(&job.id, this.store.update(&job).await);
+                        this.push_scheduled(job.clone()).await;
+                    } else {
+                        job.status = JobStatus::Failed;
+                        error!("Job {} failed after {} attempts", job.id, job.attempts);
                     }
-                    Err(e) => {
-                        error!("Job {} failed: {}", job.id, e);
-                        job.error = Some(e.to_string());
-
-                        // Retry logic
-                        if job.attempts < job.config.max_retries {
-                            job.status = JobStatus::Retrying;
-                            warn!("Retrying job {} (attempt {}/{})", 
-                                job.id, job.attempts + 1, job.config.max_retries);
-                            
-                            // Schedule retry
-                            let scheduled_at = current_timestamp() + job.config.retry_delay;
-                            job.config.scheduled_at = Some(scheduled_at);
-                            job.status = JobStatus::Scheduled;
-                            
-                            self.pending.write().await.push(job.clone());
-                        } else {
-                            job.status = JobStatus::Failed;
-                            error!("Job {} failed after {} attempts", job.id, job.attempts);
-                        }
+                }
+            }
+
+            this.job_metrics
+                .lock()
+                .await
+                .entry(job.name.clone())
+                .or_default()
+                .record(latency, job.status);
+            let job_name = job.name.clone();
+            this.record_finished(job).await;
+            this.active.fetch_sub(1, Ordering::SeqCst);
+            this.job_finished.notify_waiters();
+            if permit.is_some() {
+                // A job held back by the limit can run now
+                drop(permit);
+                this.pending.write().await.release(&job_name);
+                this.job_ready.notify_one();
+            }
+        }
+    }
+
+    /// Pop the next job whose name is under its concurrency limit and mark it processing
+    ///
+    /// Only ready jobs are in the queue, so the first one allowed to run is
+    /// always the next to run. Jobs popped on the way are held aside until a
+    /// job with their name finishes, so each is passed over once rather than
+    /// on every pick. The job moves into `processing` before the queue is
+    /// unlocked, so `get_job` and `wait_for` never miss it.
+    async fn take_next_job(&self) -> Option<(Job, CancelToken, Option<OwnedSemaphorePermit>)> {
+        let limits = self.concurrency_limits.read().await;
+        let mut pending = self.pending.write().await;
+        let mut taken = None;
+        while let Some(job) = pending.pop() {
+            match limits.get(&job.name) {
+                Some(limit) => match limit.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        taken = Some((job, Some(permit)));
+                        break;
                     }
+                    Err(_) => pending.hold(job),
+                },
+                None => {
+                    taken = Some((job, None));
+                    break;
                 }
+            }
+        }
+        let (mut job, permit) = taken?;
+
+        job.status = JobStatus::Processing;
+        job.started_at = Some(current_timestamp());
+        job.attempts += 1;
+        let token = CancelToken::default();
+        self.cancel_tokens.write().await.insert(job.id.clone(), token.clone());
+        self.processing.write().await.insert(job.id.clone(), job.clone());
+        Some((job, token, permit))
+    }
+
+    /// Move a job from processing to the completed history, then wake anyone waiting on a final outcome
+    ///
+    /// Both change under the processing lock, so `get_job` always finds the job in one of them.
+    async fn record_finished(&self, job: Job) {
+        let outcome = job_outcome(&job);
+        let (job_id, attempts) = (job.id.clone(), job.attempts);
+        if outcome.is_some() {
+            log_store_error(&job_id, self.store.remove(&job_id).await);
+        }
+        {
+            let mut processing = self.processing.write().await;
+            self.completed.write().await.insert(job);
+            // A retry may already be running as a newer attempt
+            if processing.get(&job_id).is_some_and(|job| job.attempts == attempts) {
+                processing.remove(&job_id);
+            }
+        }
+        if let Some(outcome) = outcome {
+            self.notify_waiters(&job_id, outcome).await;
+        }
+    }
 
-                // Store in completed history
-                self.completed.write().await.insert(job.id.clone(), job);
+    /// Cancel a job, returning false if it is unknown or already finished
+    ///
+    /// A pending or scheduled job is removed from the queue and marked
+    /// `Cancelled` right away. A job that is already running is signalled
+    /// through its `CancelToken`; it becomes `Cancelled` if its handler then
+    /// returns an error, and keeps its result if it completes anyway.
+    pub async fn cancel(&self, job_id: &str) -> bool {
+        let mut removed = None;
+        self.pending.write().await.retain(|job| {
+            if job.id == job_id {
+                removed = Some(job.clone());
+                false
             } else {
-                // No jobs available, sleep
-                time::sleep(Duration::from_millis(100)).await;
+                true
+            }
+        });
+        if removed.is_none() {
+            let mut scheduled = self.scheduled.write().await;
+            let key = scheduled.iter().find(|(_, job)| job.id == job_id).map(|(key, _)| key.clone());
+            removed = key.and_then(|key| scheduled.remove(&key));
+        }
+
+        if let Some(mut job) = removed {
+            info!("Cancelled job {}", job_id);
+            job.status = JobStatus::Cancelled;
+            job.completed_at = Some(current_timestamp());
+            self.record_finished(job).await;
+            return true;
+        }
+
+        match self.cancel_tokens.read().await.get(job_id) {
+            Some(token) => {
+                info!("Signalling running job {} to stop", job_id);
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand a finished job's outcome to everyone waiting on it
+    async fn notify_waiters(&self, job_id: &str, outcome: Result<Bytes>) {
+        let Some(waiters) = self.waiters.lock().await.remove(job_id) else {
+            return;
+        };
+        for tx in waiters {
+            let outcome = match &outcome {
+                Ok(output) => Ok(output.clone()),
+                Err(ProtocolError::JobCancelled) => Err(ProtocolError::JobCancelled),
+                Err(ProtocolError::JobFailed(message)) => Err(ProtocolError::JobFailed(message.clone())),
+                Err(e) => Err(ProtocolError::JobFailed(e.to_string())),
+            };
+            let _ = tx.send(outcome);
+        }
+    }
+
+    /// Process a job with the middleware hooks around it
+    async fn run_job(&self, job: &Job, token: CancelToken) -> Result<Bytes> {
+        let middleware = self.middleware.read().await.clone();
+        for hooks in &middleware {
+            hooks.before(job).await;
+        }
+        let result = self.process_job(job.clone(), token).await;
+        for hooks in &middleware {
+            match &result {
+                Ok(output) => hooks.after(job, output).await,
+                Err(e) => hooks.on_failure(job, e).await,
             }
         }
+        result
     }
 
     /// Process a single job
-    async fn process_job(&self, job: Job) -> Result<Bytes> {
+    async fn process_job(&self, job: Job, token: CancelToken) -> Result<Bytes> {
         let handlers = self.handlers.read().await;
         
         let handler = handlers.get(&job.name)
-            .ok_or_else(|| ProtocolError::Other(format!("No handler for job: {}", job.name)))?;
+            .ok_or_else(|| ProtocolError::HandlerNotFound(job.name.clone()))?;
 
         // Execute with timeout
         let timeout_duration = Duration::from_millis(job.config.timeout);
         
         tokio::time::timeout(timeout_duration, async {
-            handler(job)
+            handler(job, token)
         })
         .await
         .map_err(|_| ProtocolError::Timeout)?
@@ -409,6 +1260,42 @@ impl JobQueue {
     pub async fn shutdown(&self) {
         info!("Shutting down job queue");
         *self.shutdown.write().await = true;
+        self.job_ready.notify_waiters();
+        self.schedule_changed.notify_waiters();
+    }
+
+    /// Stop taking new jobs and wait up to `timeout` for running ones to finish
+    ///
+    /// Jobs that finish while draining are recorded and persisted as usual
+    /// before this returns. Jobs not yet started stay queued, and in the store.
+    /// Fails with `ProtocolError::Timeout` if jobs are still running when
+    /// the timeout expires; they are left to finish in the background.
+    pub async fn shutdown_graceful(&self, timeout: Duration) -> Result<()> {
+        self.shutdown().await;
+        let drained = time::timeout(timeout, async {
+            loop {
+                // Register before checking so a job finishing in between still wakes us
+                let finished = self.job_finished.notified();
+                tokio::pin!(finished);
+                finished.as_mut().enable();
+                if self.active.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                finished.await;
+            }
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "{} jobs still running after draining for {:?}",
+                self.active.load(Ordering::SeqCst),
+                timeout
+            );
+            return Err(ProtocolError::Timeout);
+        }
+        info!("Job queue drained");
+        Ok(())
     }
 
     /// Clear completed jobs (cleanup)
@@ -417,17 +1304,37 @@ impl JobQueue {
     }
 }
 
+/// Log a failed store operation; the in-memory queue carries on regardless
+fn log_store_error(job_id: &str, result: Result<()>) {
+    if let Err(e) = result {
+        error!("Failed to persist job {}: {}", job_id, e);
+    }
+}
+
+/// Final outcome of a job, or `None` while it may still run
+fn job_outcome(job: &Job) -> Option<Result<Bytes>> {
+    match job.status {
+        JobStatus::Completed => Some(Ok(job.result.clone().unwrap_or_default())),
+        JobStatus::Failed => Some(Err(ProtocolError::JobFailed(
+            job.error.clone().unwrap_or_else(|| "unknown error".to_string()),
+        ))),
+        JobStatus::Cancelled => Some(Err(ProtocolError::JobCancelled)),
+        _ => None,
+    }
+}
+
 /// Generate a unique job ID
 fn generate_job_id() -> JobId {
     format!("job_{}", uuid::Uuid::new_v4())
 }
 
 /// Get current timestamp in milliseconds
+///
+/// Zero if the clock is set before the Unix epoch.
 fn current_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
 }
 
 #[cfg(test)]
@@ -439,7 +1346,7 @@ mod tests {
         let queue = Arc::new(JobQueue::new(2));
 
         // Register handler
-        queue.register("test_job".to_string(), |job| {
+        queue.register("test_job".to_string(), |_job| {
             Ok(Bytes::from("result"))
         }).await;
 
@@ -460,5 +1367,470 @@ mod tests {
         let job = queue.get_job(&job_id).await;
         assert!(job.is_some());
     }
-}
 
+    #[tokio::test]
+    async fn test_dropping_queue_stops_its_tasks() {
+        let queue = Arc::new(JobQueue::new(2));
+        queue.schedule("never".to_string(), Bytes::new(), 60_000).await;
+        queue.clone().start().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(queue.tasks.running(), 3);
+
+        // The scheduler and workers only hold the queue while busy
+        let weak = Arc::downgrade(&queue);
+        drop(queue);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_priority_order_ignores_scheduled_jobs() {
+        let queue = Arc::new(JobQueue::new(1));
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = order.clone();
+        queue.register("record".to_string(), move |job| {
+            seen.lock().unwrap().push(job.payload.clone());
+            Ok(Bytes::new())
+        }).await;
+
+        // A job far in the future must not hold up ready jobs
+        queue.schedule("record".to_string(), Bytes::from("later"), 60_000).await;
+        for (name, priority) in [
+            ("low", JobPriority::Low),
+            ("normal-1", JobPriority::Normal),
+            ("high", JobPriority::High),
+            ("normal-2", JobPriority::Normal),
+        ] {
+            let config = JobConfig { priority, ..Default::default() };
+            queue.enqueue("record".to_string(), Bytes::from(name), config).await;
+            // Distinct creation times keep the FIFO order within a priority deterministic
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        queue.clone().start().await;
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while order.lock().unwrap().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high", "normal-1", "normal-2", "low"]
+        );
+        assert_eq!(queue.get_pending_count().await, 1);
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_result_after_completion() {
+        let queue = Arc::new(JobQueue::new(1));
+        queue.register("upper".to_string(), |job| {
+            Ok(Bytes::from(String::from_utf8_lossy(&job.payload).to_uppercase()))
+        }).await;
+
+        let job_id = queue.enqueue("upper".to_string(), Bytes::from("hi"), Default::default()).await;
+        assert_eq!(queue.get_result(&job_id).await, None);
+        queue.clone().start().await;
+
+        let result = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(result) = queue.get_result(&job_id).await {
+                    return result;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, Bytes::from("HI"));
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_resolves_on_success_and_failure() {
+        let queue = Arc::new(JobQueue::new(2));
+        queue.register("ok".to_string(), |_job| Ok(Bytes::from("done"))).await;
+        queue.register("broken".to_string(), |_job| {
+            Err(ProtocolError::Other("boom".to_string()))
+        }).await;
+        queue.clone().start().await;
+
+        let ok = queue.enqueue("ok".to_string(), Bytes::new(), Default::default()).await;
+        let config = JobConfig { max_retries: 2, retry_delay: 10, ..Default::default() };
+        let broken = queue.enqueue("broken".to_string(), Bytes::new(), config).await;
+
+        let wait = Duration::from_secs(2);
+        let result = tokio::time::timeout(wait, queue.wait_for(&ok)).await.unwrap();
+        assert_eq!(result.unwrap(), Bytes::from("done"));
+        // Waiting after completion returns the stored result
+        assert_eq!(queue.wait_for(&ok).await.unwrap(), Bytes::from("done"));
+
+        let failed = tokio::time::timeout(wait, queue.wait_for(&broken)).await.unwrap().unwrap_err();
+        assert_eq!(failed.code(), ErrorCode::JobFailed);
+        assert!(failed.to_string().contains("boom"));
+        assert_eq!(queue.get_job(&broken).await.unwrap().attempts, 2);
+
+        let missing = queue.wait_for("job_missing").await.unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::JobNotFound);
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("fast-protocol-jobs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn JobStore> = Arc::new(FileJobStore::open(&dir).await.unwrap());
+
+        // First run: queue jobs but never start the workers
+        let (pending, scheduled) = {
+            let queue = JobQueue::new_with_store(store.clone(), 1).await.unwrap();
+            let pending = queue.enqueue("echo".to_string(), Bytes::from("a"), Default::default()).await;
+            let scheduled = queue.schedule("echo".to_string(), Bytes::from("b"), 60_000).await;
+            (pending, scheduled)
+        };
+        // A job the previous process was in the middle of running
+        let mut interrupted = Job::new("echo".to_string(), Bytes::from("c"), Default::default());
+        interrupted.status = JobStatus::Processing;
+        interrupted.attempts = 1;
+        store.save(&interrupted).await.unwrap();
+
+        let queue = Arc::new(JobQueue::new_with_store(store.clone(), 1).await.unwrap());
+        assert_eq!(queue.get_pending_count().await, 3);
+        assert_eq!(queue.get_job(&scheduled).await.unwrap().status, JobStatus::Scheduled);
+        assert_eq!(queue.get_job(&interrupted.id).await.unwrap().status, JobStatus::Pending);
+
+        queue.register("echo".to_string(), |job| Ok(job.payload)).await;
+        queue.clone().start().await;
+        let wait = Duration::from_secs(2);
+        assert_eq!(tokio::time::timeout(wait, queue.wait_for(&pending)).await.unwrap().unwrap(), "a");
+        assert_eq!(
+            tokio::time::timeout(wait, queue.wait_for(&interrupted.id)).await.unwrap().unwrap(),
+            "c"
+        );
+
+        // Finished jobs leave the store; the scheduled one is still there
+        let stored: Vec<JobId> = store.load().await.unwrap().into_iter().map(|job| job.id).collect();
+        assert_eq!(stored, vec![scheduled]);
+
+        queue.shutdown().await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_scheduled_job_before_it_fires() {
+        let queue = Arc::new(JobQueue::new(1));
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        queue.register("mark".to_string(), move |_job| {
+            flag.store(true, Ordering::SeqCst);
+            Ok(Bytes::new())
+        }).await;
+        queue.clone().start().await;
+
+        let job_id = queue.schedule("mark".to_string(), Bytes::new(), 100).await;
+        assert!(queue.cancel(&job_id).await);
+        assert_eq!(queue.get_job(&job_id).await.unwrap().status, JobStatus::Cancelled);
+        assert_eq!(queue.wait_for(&job_id).await.unwrap_err().code(), ErrorCode::JobCancelled);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(queue.get_pending_count().await, 0);
+        assert!(!queue.cancel(&job_id).await);
+        queue.shutdown().await;
+    }
+
+    // The handler blocks its thread while it runs, so the test needs a second one
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_graceful_shutdown_finishes_running_jobs() {
+        let store = Arc::new(MemoryJobStore::default());
+        let queue = Arc::new(JobQueue::new_with_store(store.clone(), 1).await.unwrap());
+        queue.register("slow".to_string(), |_job| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(Bytes::from("done"))
+        }).await;
+
+        let running = queue.enqueue("slow".to_string(), Bytes::new(), Default::default()).await;
+        queue.clone().start().await;
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while queue.get_processing_count().await == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let waiting = queue.enqueue("slow".to_string(), Bytes::new(), Default::default()).await;
+
+        queue.shutdown_graceful(Duration::from_secs(2)).await.unwrap();
+        let job = queue.get_job(&running).await.unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.result, Some(Bytes::from("done")));
+
+        // The finished job left the store; the one never started is still in it
+        let stored = store.load().await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, waiting);
+        assert_eq!(stored[0].status, JobStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_priorities_do_not_starve_low_jobs() {
+        let queue = Arc::new(JobQueue::new(1));
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = order.clone();
+        queue.register("record".to_string(), move |job| {
+            seen.lock().unwrap().push(job.config.priority);
+            Ok(Bytes::new())
+        }).await;
+        queue.set_priority_weights(Some(PriorityWeights::default())).await;
+
+        let critical = JobConfig { priority: JobPriority::Critical, ..Default::default() };
+        let low = JobConfig { priority: JobPriority::Low, ..Default::default() };
+        let mut lows = Vec::new();
+        for i in 0..200 {
+            queue.enqueue("record".to_string(), Bytes::new(), critical.clone()).await;
+            if i % 50 == 0 {
+                lows.push(queue.enqueue("record".to_string(), Bytes::new(), low.clone()).await);
+            }
+        }
+        queue.clone().start().await;
+        for id in &lows {
+            tokio::time::timeout(Duration::from_secs(2), queue.wait_for(id)).await.unwrap().unwrap();
+        }
+
+        // Critical gets 8 picks for every one of Low's, so all four run early
+        let order = order.lock().unwrap().clone();
+        let positions: Vec<_> = order
+            .iter()
+            .enumerate()
+            .filter(|(_, priority)| **priority == JobPriority::Low)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(positions.len(), 4);
+        assert!(positions[3] < 4 * 9, "low jobs ran at {:?}", positions);
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_middleware_runs_around_every_job() {
+        #[derive(Default)]
+        struct Counting {
+            before: AtomicUsize,
+            after: AtomicUsize,
+            failed: std::sync::Mutex<Vec<String>>,
+        }
+
+        #[async_trait]
+        impl JobMiddleware for Arc<Counting> {
+            async fn before(&self, _job: &Job) {
+                self.before.fetch_add(1, Ordering::SeqCst);
+            }
+
+            async fn after(&self, _job: &Job, _result: &Bytes) {
+                self.after.fetch_add(1, Ordering::SeqCst);
+            }
+
+            async fn on_failure(&self, job: &Job, error: &ProtocolError) {
+                self.failed.lock().unwrap().push(format!("{}: {}", job.name, error));
+            }
+        }
+
+        let queue = Arc::new(JobQueue::new(1));
+        let counts = Arc::new(Counting::default());
+        queue.use_middleware(counts.clone()).await;
+        queue.register("ok".to_string(), |_job| Ok(Bytes::from("done"))).await;
+        queue.register("fail".to_string(), |_job| Err(ProtocolError::Other("broken".to_string()))).await;
+
+        let config = JobConfig { max_retries: 2, retry_delay: 10, ..Default::default() };
+        let ok = queue.enqueue("ok".to_string(), Bytes::new(), Default::default()).await;
+        let fail = queue.enqueue("fail".to_string(), Bytes::new(), config).await;
+        queue.clone().start().await;
+
+        queue.wait_for(&ok).await.unwrap();
+        assert!(queue.wait_for(&fail).await.is_err());
+
+        // One success, then a failure and its retry
+        assert_eq!(counts.before.load(Ordering::SeqCst), 3);
+        assert_eq!(counts.after.load(Ordering::SeqCst), 1);
+        let failed = counts.failed.lock().unwrap().clone();
+        assert_eq!(failed.len(), 2);
+        assert!(failed.iter().all(|entry| entry.starts_with("fail: ") && entry.contains("broken")));
+        queue.shutdown().await;
+    }
+
+    // Handlers block their thread while they run, so give each worker one
+    #[tokio::test(flavor = "multi_thread", worker_threads = 5)]
+    async fn test_concurrency_limit_caps_one_job_name() {
+        let queue = Arc::new(JobQueue::new(4));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (now, max) = (running.clone(), peak.clone());
+        queue.register("send_email".to_string(), move |_job| {
+            let current = now.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(current, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            now.fetch_sub(1, Ordering::SeqCst);
+            Ok(Bytes::new())
+        }).await;
+        queue.register("resize".to_string(), |_job| Ok(Bytes::new())).await;
+        queue.set_concurrency("send_email", 2).await;
+
+        let mut emails = Vec::new();
+        for _ in 0..6 {
+            emails.push(queue.enqueue("send_email".to_string(), Bytes::new(), Default::default()).await);
+        }
+        let mut others = Vec::new();
+        for _ in 0..2 {
+            others.push(queue.enqueue("resize".to_string(), Bytes::new(), Default::default()).await);
+        }
+        queue.clone().start().await;
+
+        // An email waiting its turn is still pending, and can be cancelled
+        let last = emails.pop().unwrap();
+        assert_eq!(queue.get_job(&last).await.unwrap().status, JobStatus::Pending);
+        assert!(queue.cancel(&last).await);
+        assert!(matches!(queue.wait_for(&last).await, Err(ProtocolError::JobCancelled)));
+
+        // Other job types run while the emails wait their turn
+        for id in &others {
+            tokio::time::timeout(Duration::from_secs(1), queue.wait_for(id)).await.unwrap().unwrap();
+        }
+        assert!(queue.get_completed_count().await < 8);
+
+        for id in &emails {
+            tokio::time::timeout(Duration::from_secs(2), queue.wait_for(id)).await.unwrap().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(queue.get_pending_count().await, 0);
+        queue.shutdown().await;
+    }
+
+    // The handler blocks its thread while it runs, so the test needs a second one
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cancel_pending_and_running_jobs() {
+        let queue = Arc::new(JobQueue::new(1));
+        queue.register_cancellable("spin".to_string(), |_job, token| {
+            while !token.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Err(ProtocolError::Other("stopped".to_string()))
+        }).await;
+
+        // Cancelled before any worker exists to pick it up
+        let pending = queue.enqueue("spin".to_string(), Bytes::new(), Default::default()).await;
+        assert!(queue.cancel(&pending).await);
+        assert_eq!(queue.get_job(&pending).await.unwrap().status, JobStatus::Cancelled);
+
+        let running = queue.enqueue("spin".to_string(), Bytes::new(), Default::default()).await;
+        queue.clone().start().await;
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while queue.get_processing_count().await == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(queue.cancel(&running).await);
+        assert!(tokio::time::timeout(Duration::from_secs(2), queue.wait_for(&running)).await.unwrap().is_err());
+        let job = queue.get_job(&running).await.unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.attempts, 1);
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_completed_history_evicts_oldest_jobs() {
+        let queue = Arc::new(JobQueue::new(1));
+        queue.register("ok".to_string(), |_job| Ok(Bytes::new())).await;
+        queue.set_config(JobQueueConfig { max_history: Some(3), history_ttl: None }).await;
+        queue.clone().start().await;
+
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let id = queue.enqueue("ok".to_string(), Bytes::new(), Default::default()).await;
+            tokio::time::timeout(Duration::from_secs(2), queue.wait_for(&id)).await.unwrap().unwrap();
+            ids.push(id);
+        }
+        assert_eq!(queue.get_completed_count().await, 3);
+        assert!(queue.get_job(&ids[0]).await.is_none());
+        assert!(queue.get_job(&ids[1]).await.is_none());
+        for id in &ids[2..] {
+            assert_eq!(queue.get_job(id).await.unwrap().status, JobStatus::Completed);
+        }
+
+        // Jobs older than the TTL go once the next one finishes
+        queue.set_config(JobQueueConfig { max_history: None, history_ttl: Some(Duration::from_millis(20)) }).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let id = queue.enqueue("ok".to_string(), Bytes::new(), Default::default()).await;
+        tokio::time::timeout(Duration::from_secs(2), queue.wait_for(&id)).await.unwrap().unwrap();
+        assert_eq!(queue.get_completed_count().await, 1);
+        assert!(queue.get_job(&id).await.is_some());
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_outcomes_per_job_name() {
+        let queue = Arc::new(JobQueue::new(2));
+        queue.register("ok".to_string(), |_job| Ok(Bytes::new())).await;
+        queue.register("broken".to_string(), |_job| {
+            Err(ProtocolError::Other("boom".to_string()))
+        }).await;
+        queue.clone().start().await;
+
+        let mut ids = Vec::new();
+        for _ in 0..6 {
+            ids.push(queue.enqueue("ok".to_string(), Bytes::new(), Default::default()).await);
+        }
+        let config = JobConfig { max_retries: 2, retry_delay: 10, ..Default::default() };
+        for _ in 0..2 {
+            ids.push(queue.enqueue("broken".to_string(), Bytes::new(), config.clone()).await);
+        }
+        for id in &ids {
+            let _ = tokio::time::timeout(Duration::from_secs(2), queue.wait_for(id)).await.unwrap();
+        }
+
+        let stats = queue.stats().await;
+        assert_eq!((stats.pending, stats.processing), (0, 0));
+        assert_eq!(stats.workers, 2);
+        assert!(stats.utilization() <= 1.0);
+        assert_eq!(stats.total.completed, 6);
+        assert_eq!(stats.total.failed, 2);
+        assert_eq!(stats.total.retried, 2);
+        assert_eq!(stats.total.attempts(), 10);
+        assert_eq!(stats.total.latency_buckets.iter().sum::<u64>(), 10);
+        assert!(stats.total.average_latency() <= stats.total.latency_max);
+        assert!(stats.total.p99_latency() <= stats.total.latency_max);
+        assert_eq!(stats.by_name["ok"].completed, 6);
+        assert_eq!(stats.by_name["ok"].attempts(), 6);
+        assert_eq!(stats.by_name["broken"].failed, 2);
+        assert_eq!(stats.by_name["broken"].retried, 2);
+        queue.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_job_decode_helpers_and_attempt_number() {
+        let job = Job::new("sum".to_string(), Bytes::from(r#"{"a":2,"b":3}"#), Default::default());
+        assert_eq!(job.attempt_number(), 0);
+        let value: HashMap<String, u32> = job.json().unwrap();
+        assert_eq!(value["a"] + value["b"], 5);
+        assert_eq!(job.text().unwrap(), r#"{"a":2,"b":3}"#);
+
+        let garbled = Job::new("sum".to_string(), Bytes::from_static(&[0xff, 0xfe]), Default::default());
+        assert!(matches!(garbled.text(), Err(ProtocolError::InvalidPayload(_))));
+        assert!(matches!(garbled.json::<u32>(), Err(ProtocolError::InvalidPayload(_))));
+
+        // Fails the first time, then succeeds knowing it is a retry
+        let queue = Arc::new(JobQueue::new(1));
+        queue.register("flaky".to_string(), |job| match job.attempt_number() {
+            1 => Err(ProtocolError::Other("first try".to_string())),
+            n => Ok(Bytes::from(format!("{}:{}", job.text()?, n))),
+        }).await;
+        queue.clone().start().await;
+        let config = JobConfig { retry_delay: 10, ..Default::default() };
+        let id = queue.enqueue("flaky".to_string(), Bytes::from("hi"), config).await;
+        let result = tokio::time::timeout(Duration::from_secs(2), queue.wait_for(&id)).await.unwrap();
+        assert_eq!(result.unwrap(), Bytes::from("hi:2"));
+        queue.shutdown().await;
+    }
+}
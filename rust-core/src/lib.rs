@@ -2,17 +2,72 @@
 //!
 //! This library provides a reliable, encrypted, and compressed UDP-based
 //! network protocol with cross-platform support.
+//!
+//! On `wasm32` only the packet format and the browser client are built; the
+//! UDP transport and everything on top of it are native only.
+//!
+//! Without the default `std` feature the crate is `no_std` (it still needs
+//! `alloc`) and only the wire format core is built, for embedded targets
+//! that produce and consume packets with their own I/O:
+//!
+//! - `packet`: `Packet`, `PacketType`, `PacketFlags`, `PacketLimits`,
+//!   `StreamHeader` and `FragmentHeader`, with encoding and decoding
+//! - `compression::CompressionAlgorithm`, as recorded in packet flags
+//! - `error`: `ProtocolError`, `ErrorCode` and `RemoteErrorCode`
+//! - the protocol constants below
+//!
+//! There is no clock without `std`, so packets built there are stamped with
+//! a zero timestamp; set `Packet::timestamp` yourself where it matters.
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod protocol;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod codec;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod transport;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod server;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod client;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod peer;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod clock;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod crypto;
 pub mod compression;
 pub mod packet;
 pub mod error;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod middleware;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod jobs;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod connection;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod metrics;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod impairment;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod stream;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod mtu;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod ordering;
+#[cfg(all(feature = "quic", not(target_arch = "wasm32")))]
+pub mod quic;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod pacing;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod pool;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod router;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod tasks;
 
 #[cfg(feature = "nodejs")]
 pub mod node_bridge;
@@ -20,16 +75,37 @@ pub mod node_bridge;
 #[cfg(feature = "wasm")]
 pub mod wasm_bridge;
 
-pub use error::{ProtocolError, Result};
-pub use server::Server;
-pub use client::Client;
+pub use error::{ErrorCode, ProtocolError, RemoteErrorCode, Result};
 pub use packet::{Packet, PacketType};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use codec::Codec;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use server::{BroadcastReport, OverflowPolicy, Server, ServerBuilder};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use client::{Client, ClientBuilder, RequestHandle, RetryPolicy};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use peer::Peer;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub use middleware::{Middleware, Handler, HandlerFn};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use connection::{AuthInfo, ConnectionId};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use metrics::RouteMetrics;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use stream::{ResponseStream, StreamSink};
 
 /// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+///
+/// Version 3 adds a channel id to the header. Version 2 encodes the
+/// sequence number and length fields as varints, and version 1 uses fixed
+/// widths; packets in either are still accepted, on the default channel.
+/// Each connection settles on a version during the handshake, within the
+/// range set by the transport config.
+pub const PROTOCOL_VERSION: u8 = 3;
 
 /// Maximum packet size (64KB)
+///
+/// The largest UDP payload over IPv4; IPv6's smaller header leaves it room to spare.
 pub const MAX_PACKET_SIZE: usize = 65507;
 
 /// Default timeout for acknowledgments (milliseconds)
@@ -38,3 +114,26 @@ pub const DEFAULT_ACK_TIMEOUT_MS: u64 = 1000;
 /// Maximum retransmission attempts
 pub const MAX_RETRANSMIT_ATTEMPTS: u8 = 3;
 
+
+/// Log filter for this crate, letting the rest through at `info`
+///
+/// Routine operation (each packet sent, received or acknowledged, each
+/// route, push route or job handler registered, each job run) logs at
+/// `trace`, so an `info` or `debug` log only shows connections, errors and
+/// other notable events. Pass `verbose` to see the routine ones too:
+///
+/// ```ignore
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(tracing_subscriber::fmt::layer())
+///     .with(fast_protocol::log_filter(true))
+///     .init();
+/// ```
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub fn log_filter(verbose: bool) -> tracing_subscriber::filter::Targets {
+    let level = if verbose { tracing::Level::TRACE } else { tracing::Level::INFO };
+    tracing_subscriber::filter::Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(tracing::Level::INFO)
+}
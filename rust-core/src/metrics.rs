@@ -0,0 +1,133 @@
+//! Per-route request metrics
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Label requests for routes with no handler are counted under
+pub const UNMATCHED_ROUTE: &str = "<unmatched>";
+
+/// Requests handled on one route, and how long their handlers took
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteMetrics {
+    /// Requests that reached a handler
+    pub requests: u64,
+    /// Requests whose handler failed, panicked or timed out
+    pub errors: u64,
+    /// Requests per latency bucket, the last counting those slower than every bound
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    /// Total handler time across all requests
+    pub latency_total: Duration,
+}
+
+impl RouteMetrics {
+    /// Count one handled request
+    pub(crate) fn record(&mut self, latency: Duration, ok: bool) {
+        self.requests += 1;
+        if !ok {
+            self.errors += 1;
+        }
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket] += 1;
+        self.latency_total += latency;
+    }
+
+    /// Share of requests that failed, from 0 to 1
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.requests as f64
+        }
+    }
+}
+
+/// Format route metrics in the Prometheus text exposition format
+///
+/// Routes are labelled with the registered route or pattern that matched,
+/// so the number of series stays bounded by the number of routes.
+pub fn prometheus_text(metrics: &HashMap<String, RouteMetrics>) -> String {
+    let mut routes: Vec<_> = metrics.iter().collect();
+    routes.sort_by(|a, b| a.0.cmp(b.0));
+    let mut out = String::new();
+
+    out.push_str("# HELP fast_protocol_route_requests_total Requests handled per route\n");
+    out.push_str("# TYPE fast_protocol_route_requests_total counter\n");
+    for (route, m) in &routes {
+        let _ = writeln!(out, "fast_protocol_route_requests_total{{route=\"{}\"}} {}", escape(route), m.requests);
+    }
+
+    out.push_str("# HELP fast_protocol_route_errors_total Failed requests per route\n");
+    out.push_str("# TYPE fast_protocol_route_errors_total counter\n");
+    for (route, m) in &routes {
+        let _ = writeln!(out, "fast_protocol_route_errors_total{{route=\"{}\"}} {}", escape(route), m.errors);
+    }
+
+    out.push_str("# HELP fast_protocol_route_latency_seconds Handler latency per route\n");
+    out.push_str("# TYPE fast_protocol_route_latency_seconds histogram\n");
+    for (route, m) in &routes {
+        let route = escape(route);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&m.latency_buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "fast_protocol_route_latency_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                route, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "fast_protocol_route_latency_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+            route, m.requests
+        );
+        let _ = writeln!(
+            out,
+            "fast_protocol_route_latency_seconds_sum{{route=\"{}\"}} {}",
+            route,
+            m.latency_total.as_secs_f64()
+        );
+        let _ = writeln!(out, "fast_protocol_route_latency_seconds_count{{route=\"{}\"}} {}", route, m.requests);
+    }
+    out
+}
+
+/// Escape a label value for the text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut metrics = RouteMetrics::default();
+        metrics.record(Duration::from_micros(500), true);
+        metrics.record(Duration::from_millis(20), false);
+        metrics.record(Duration::from_secs(10), true);
+        assert_eq!(metrics.requests, 3);
+        assert_eq!(metrics.errors, 1);
+        assert_eq!(metrics.latency_buckets[0], 1);
+        assert_eq!(metrics.latency_buckets[4], 1);
+        assert_eq!(metrics.latency_buckets[LATENCY_BUCKETS.len()], 1);
+
+        let text = prometheus_text(&HashMap::from([("/say \"hi\"".to_string(), metrics)]));
+        assert!(text.contains("fast_protocol_route_requests_total{route=\"/say \\\"hi\\\"\"} 3\n"));
+        assert!(text.contains("fast_protocol_route_errors_total{route=\"/say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("le=\"0.001\"} 1\n"));
+        assert!(text.contains("le=\"0.025\"} 2\n"));
+        assert!(text.contains("le=\"5\"} 2\n"));
+        assert!(text.contains("le=\"+Inf\"} 3\n"));
+    }
+}
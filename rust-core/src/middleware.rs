@@ -2,9 +2,14 @@
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use crate::codec::Codec;
+use crate::connection::AuthInfo;
 use crate::error::*;
 use crate::packet::Packet;
 
@@ -15,19 +20,120 @@ pub struct Context {
     pub payload: Bytes,
     pub remote_addr: SocketAddr,
     pub packet: Packet,
+    /// Values captured by `:param` and `*wildcard` segments of the matched route
+    pub params: HashMap<String, String>,
+    /// Identity the sender authenticated as when it connected
+    pub auth: Option<AuthInfo>,
+    /// Id of the request in logs, shared by every span and event it causes
+    pub correlation_id: String,
+    /// Format `decode` and typed handlers read and write payloads in
+    pub codec: Codec,
+    /// Typed values middleware attached for the handlers after it
+    pub extensions: Extensions,
+}
+
+/// Values of any type, at most one per type, carried along with a request
+///
+/// Middleware inserts into the context it is given before running the rest
+/// of the chain with it, and everything after reads the value back by type.
+/// Cloning is cheap since values are shared, not copied.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Store `value`, replacing any earlier value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// The value of type `T`, if one was inserted
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// Remove the value of type `T`, returning whether there was one
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> bool {
+        self.map.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    /// Number of values stored
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether no values are stored
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+/// Id identifying a request from `remote_addr` in logs
+///
+/// Made of the sender's address, channel and sequence, which together are
+/// unique among the requests in flight.
+pub fn correlation_id(remote_addr: SocketAddr, packet: &Packet) -> String {
+    format!("{}/{}/{}", remote_addr, packet.channel_id, packet.sequence)
 }
 
 impl Context {
     /// Parse JSON payload
     pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
         serde_json::from_slice(&self.payload)
-            .map_err(|e| ProtocolError::Other(format!("JSON parse error: {}", e)))
+            .map_err(|e| ProtocolError::InvalidPayload(format!("JSON parse error: {}", e)))
+    }
+
+    /// Parse the payload with the context's codec
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        self.codec.decode(&self.payload)
+    }
+
+    /// Get a path parameter captured from the route
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
     }
 
     /// Get payload as string
     pub fn text(&self) -> Result<String> {
         String::from_utf8(self.payload.to_vec())
-            .map_err(|e| ProtocolError::Other(format!("UTF-8 error: {}", e)))
+            .map_err(|e| ProtocolError::InvalidPayload(format!("UTF-8 error: {}", e)))
+    }
+
+    /// Whether the request arrived encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.packet.flags.encrypted
+    }
+
+    /// Whether the request arrived compressed
+    pub fn is_compressed(&self) -> bool {
+        self.packet.flags.compressed
+    }
+
+    /// Sequence of the request within its channel
+    pub fn sequence(&self) -> u32 {
+        self.packet.sequence
+    }
+
+    /// When the sender stamped the request, in milliseconds since the Unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.packet.timestamp
+    }
+
+    /// Attach a value for the rest of the chain, see `Extensions`
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.extensions.insert(value);
+    }
+
+    /// A value attached earlier in the chain
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get()
     }
 }
 
@@ -35,28 +141,48 @@ impl Context {
 #[derive(Debug, Clone)]
 pub struct Response {
     pub data: Bytes,
+    /// Whether to compress the response, in place of the transport's
+    /// `enable_compression` (None follows the transport)
+    pub compress: Option<bool>,
+    /// Whether the request is one-way, so no reply packet is sent at all
+    pub one_way: bool,
 }
 
 impl Response {
     /// Create a new response with bytes
     pub fn new(data: Bytes) -> Self {
-        Self { data }
+        Self { data, compress: None, one_way: false }
+    }
+
+    /// Answer nothing, for notifications sent with `Client::send`
+    ///
+    /// The transport still ACKs a reliable request, but no reply packet
+    /// goes back to the sender.
+    pub fn none() -> Self {
+        Self { one_way: true, ..Self::new(Bytes::new()) }
     }
 
     /// Create a response from string
     pub fn text(text: impl Into<String>) -> Self {
-        Self {
-            data: Bytes::from(text.into().into_bytes()),
-        }
+        Self::new(Bytes::from(text.into().into_bytes()))
     }
 
     /// Create a JSON response
     pub fn json<T: serde::Serialize>(value: &T) -> Result<Self> {
         let json = serde_json::to_vec(value)
-            .map_err(|e| ProtocolError::Other(format!("JSON serialization error: {}", e)))?;
-        Ok(Self {
-            data: Bytes::from(json),
-        })
+            .map_err(|e| ProtocolError::JsonError(e.to_string()))?;
+        Ok(Self::new(Bytes::from(json)))
+    }
+
+    /// Create a response encoded with `codec`
+    pub fn encode<T: serde::Serialize>(codec: Codec, value: &T) -> Result<Self> {
+        Ok(Self::new(codec.encode(value)?))
+    }
+
+    /// Compress the response or not, whatever the transport's setting
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = Some(compress);
+        self
     }
 }
 
@@ -134,12 +260,25 @@ pub trait Middleware: Send + Sync {
 
 /// Next middleware in chain
 pub struct Next<'a> {
+    pub(crate) middleware: &'a [Arc<dyn Middleware>],
     pub(crate) handler: &'a dyn Handler,
 }
 
 impl<'a> Next<'a> {
-    pub async fn run(self, ctx: Context) -> Result<Response> {
-        self.handler.handle(ctx).await
+    /// Build a chain that runs `middleware` in order, then `handler`
+    pub(crate) fn new(middleware: &'a [Arc<dyn Middleware>], handler: &'a dyn Handler) -> Self {
+        Self { middleware, handler }
+    }
+
+    /// Run the rest of the chain
+    pub async fn run(self, mut ctx: Context) -> Result<Response> {
+        match self.middleware.split_first() {
+            Some((current, rest)) => {
+                let next = Next::new(rest, self.handler);
+                current.process(&mut ctx, next).await
+            }
+            None => self.handler.handle(ctx).await,
+        }
     }
 }
 
@@ -156,3 +295,205 @@ impl Middleware for LoggingMiddleware {
     }
 }
 
+/// Middleware deciding per response whether it is compressed
+///
+/// Responses on a route under a `never` prefix are sent as-is and those
+/// under an `always` prefix are compressed; any other response is compressed
+/// once it reaches `min_size` bytes. The transport still needs a compression
+/// provider, and still sends a payload as-is if compressing it does not pay off.
+///
+/// ```ignore
+/// server
+///     .use_middleware(
+///         CompressionMiddleware::new(1024)
+///             .always("/download")
+///             .never("/ping"),
+///     )
+///     .await;
+/// ```
+pub struct CompressionMiddleware {
+    min_size: usize,
+    always: Vec<String>,
+    never: Vec<String>,
+}
+
+impl CompressionMiddleware {
+    /// Compress responses of at least `min_size` bytes
+    pub fn new(min_size: usize) -> Self {
+        Self {
+            min_size,
+            always: Vec::new(),
+            never: Vec::new(),
+        }
+    }
+
+    /// Always compress responses on routes starting with `prefix`
+    pub fn always(mut self, prefix: impl Into<String>) -> Self {
+        self.always.push(prefix.into());
+        self
+    }
+
+    /// Never compress responses on routes starting with `prefix`
+    pub fn never(mut self, prefix: impl Into<String>) -> Self {
+        self.never.push(prefix.into());
+        self
+    }
+
+    /// Whether a response of `len` bytes on `route` should be compressed
+    fn should_compress(&self, route: &str, len: usize) -> bool {
+        let matches = |prefixes: &[String]| {
+            prefixes.iter().any(|prefix| route.starts_with(prefix.as_str()))
+        };
+        if matches(&self.never) {
+            false
+        } else {
+            matches(&self.always) || len >= self.min_size
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for CompressionMiddleware {
+    async fn process(&self, ctx: &mut Context, next: Next<'_>) -> Result<Response> {
+        let response = next.run(ctx.clone()).await?;
+        if response.compress.is_some() {
+            // The handler decided for itself
+            return Ok(response);
+        }
+        let compress = self.should_compress(&ctx.route, response.data.len());
+        Ok(response.with_compression(compress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Recording {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+        short_circuit: bool,
+    }
+
+    #[async_trait]
+    impl Middleware for Recording {
+        async fn process(&self, ctx: &mut Context, next: Next<'_>) -> Result<Response> {
+            self.log.lock().unwrap().push(self.name);
+            if self.short_circuit {
+                return Ok(Response::text(self.name));
+            }
+            next.run(ctx.clone()).await
+        }
+    }
+
+    fn context() -> Context {
+        let packet = Packet::new_data("/test".to_string(), Bytes::new(), 0);
+        Context {
+            route: packet.route.clone(),
+            payload: packet.payload.clone(),
+            remote_addr: "127.0.0.1:9000".parse().unwrap(),
+            packet,
+            params: HashMap::new(),
+            auth: None,
+            correlation_id: String::new(),
+            codec: Codec::default(),
+            extensions: Extensions::default(),
+        }
+    }
+
+    fn chain(log: &Arc<Mutex<Vec<&'static str>>>, short_circuit: bool) -> Vec<Arc<dyn Middleware>> {
+        vec![
+            Arc::new(Recording {
+                name: "first",
+                log: log.clone(),
+                short_circuit: false,
+            }),
+            Arc::new(Recording {
+                name: "second",
+                log: log.clone(),
+                short_circuit,
+            }),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_middleware_order_and_short_circuit() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler_log = log.clone();
+        let handler = FnHandler::new(move |_ctx| {
+            handler_log.lock().unwrap().push("handler");
+            Ok(Response::text("handled"))
+        });
+
+        let middleware = chain(&log, false);
+        let response = Next::new(&middleware, &handler).run(context()).await.unwrap();
+        assert_eq!(response.data, Bytes::from("handled"));
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second", "handler"]);
+
+        log.lock().unwrap().clear();
+        let middleware = chain(&log, true);
+        let response = Next::new(&middleware, &handler).run(context()).await.unwrap();
+        assert_eq!(response.data, Bytes::from("second"));
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_compression_middleware_decides_per_route_and_size() {
+        let middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(
+            CompressionMiddleware::new(16).always("/download").never("/ping"),
+        )];
+        let handler = FnHandler::new(|ctx| match ctx.route.as_str() {
+            "/forced" => Ok(Response::text("tiny").with_compression(true)),
+            "/ping" => Ok(Response::new(Bytes::from(vec![0u8; 64]))),
+            _ => Ok(Response::text("tiny")),
+        });
+
+        let run = |route: &str| {
+            let mut ctx = context();
+            ctx.route = route.to_string();
+            let next = Next::new(&middleware, &handler);
+            async move { next.run(ctx).await.unwrap().compress }
+        };
+        assert_eq!(run("/download/file").await, Some(true));
+        assert_eq!(run("/ping").await, Some(false));
+        assert_eq!(run("/other").await, Some(false));
+        assert_eq!(run("/forced").await, Some(true));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct UserId(u64);
+
+    struct Auth;
+
+    #[async_trait]
+    impl Middleware for Auth {
+        async fn process(&self, ctx: &mut Context, next: Next<'_>) -> Result<Response> {
+            let user = ctx
+                .text()?
+                .parse()
+                .map_err(|_| ProtocolError::AuthenticationFailed("bad token".to_string()))?;
+            ctx.insert(UserId(user));
+            next.run(ctx.clone()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_passes_extensions_to_handler() {
+        let middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(Auth)];
+        let handler = FnHandler::new(|ctx| match ctx.get::<UserId>() {
+            Some(UserId(id)) => Ok(Response::text(format!("user {}", id))),
+            None => Ok(Response::text("anonymous")),
+        });
+
+        let mut ctx = context();
+        ctx.payload = Bytes::from("42");
+        let response = Next::new(&middleware, &handler).run(ctx).await.unwrap();
+        assert_eq!(response.data, Bytes::from("user 42"));
+
+        // Without the middleware nothing was attached
+        let response = Next::new(&[], &handler).run(context()).await.unwrap();
+        assert_eq!(response.data, Bytes::from("anonymous"));
+        assert!(context().get::<UserId>().is_none());
+    }
+}
@@ -0,0 +1,196 @@
+//! Path MTU discovery
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+/// Datagram sizes tried in turn when probing a path upward
+const PROBE_SIZES: &[usize] = &[1200, 1280, 1400, 1452, 1472, 4096, 8972, 16384, 32768, 65507];
+
+/// Unanswered probes of one size before that size is considered too large
+const PROBE_ATTEMPTS: u8 = 3;
+
+/// How long a failed size is remembered before probing past it again
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A probe waiting for its reply
+struct Probe {
+    size: usize,
+    sent_at: Instant,
+    attempts: u8,
+}
+
+/// Discovered datagram size limit for one destination
+///
+/// Starts at a conservative size and probes upward one step at a time. A
+/// size that goes unanswered `PROBE_ATTEMPTS` times, or that the socket
+/// refuses outright, becomes a ceiling that is not probed again until
+/// `RAISE_INTERVAL` has passed.
+pub(crate) struct PathMtu {
+    confirmed: usize,
+    probe: Option<Probe>,
+    ceiling: Option<(usize, Instant)>,
+}
+
+impl PathMtu {
+    pub(crate) fn new(initial: usize) -> Self {
+        Self {
+            confirmed: initial,
+            probe: None,
+            ceiling: None,
+        }
+    }
+
+    /// Largest datagram known to reach the destination
+    pub(crate) fn mtu(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Size of the probe to send now, if one is due
+    ///
+    /// `timeout` is how long to wait for a reply before sending the probe
+    /// again; `max` bounds the sizes that are tried.
+    pub(crate) fn next_probe(&mut self, timeout: Duration, max: usize) -> Option<usize> {
+        if let Some(probe) = &mut self.probe {
+            if probe.sent_at.elapsed() < timeout {
+                return None;
+            }
+            if probe.attempts < PROBE_ATTEMPTS {
+                probe.attempts += 1;
+                probe.sent_at = Instant::now();
+                return Some(probe.size);
+            }
+            let size = probe.size;
+            self.fail(size);
+        }
+
+        if self
+            .ceiling
+            .is_some_and(|(_, since)| since.elapsed() >= RAISE_INTERVAL)
+        {
+            self.ceiling = None;
+        }
+
+        let size = PROBE_SIZES.iter().copied().find(|&size| {
+            size > self.confirmed
+                && size <= max
+                && self.ceiling.is_none_or(|(ceiling, _)| size < ceiling)
+        })?;
+        self.probe = Some(Probe {
+            size,
+            sent_at: Instant::now(),
+            attempts: 1,
+        });
+        Some(size)
+    }
+
+    /// A probe of `size` bytes reached the destination
+    ///
+    /// Only a reply for the outstanding probe counts; any other size is
+    /// ignored and false returned, so a forged reply cannot raise the MTU
+    /// past what the path carries.
+    pub(crate) fn confirm(&mut self, size: usize) -> bool {
+        if self.probe.as_ref().is_none_or(|probe| probe.size != size) {
+            return false;
+        }
+        self.probe = None;
+        self.confirmed = self.confirmed.max(size);
+        true
+    }
+
+    /// Datagrams of `size` bytes do not get through
+    pub(crate) fn fail(&mut self, size: usize) {
+        let ceiling = self.ceiling.map_or(size, |(ceiling, _)| ceiling.min(size));
+        self.ceiling = Some((ceiling, Instant::now()));
+        if self.probe.as_ref().is_some_and(|probe| probe.size >= size) {
+            self.probe = None;
+        }
+    }
+}
+
+/// Set the don't-fragment bit on every datagram the socket sends
+///
+/// Oversized datagrams are then dropped (or refused by the socket) instead of
+/// being fragmented at the IP layer, which is what lets probes find the path
+/// MTU. Only supported on Linux; elsewhere this does nothing.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, option, value) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    };
+    // SAFETY: the descriptor is owned by `socket` and the option value is a valid c_int
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether a send failed because the datagram is larger than the path allows
+pub(crate) fn is_too_large(err: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        err.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes_upward_and_stops_at_failure() {
+        let mut path = PathMtu::new(1200);
+        assert_eq!(path.next_probe(Duration::ZERO, 65507), Some(1280));
+        assert!(path.confirm(1280));
+        assert_eq!(path.mtu(), 1280);
+
+        // 1400 never gets a reply
+        for _ in 0..PROBE_ATTEMPTS {
+            assert_eq!(path.next_probe(Duration::ZERO, 65507), Some(1400));
+        }
+        assert_eq!(path.next_probe(Duration::ZERO, 65507), None);
+        assert_eq!(path.mtu(), 1280);
+    }
+
+    #[test]
+    fn test_only_the_outstanding_probe_is_confirmed() {
+        let mut path = PathMtu::new(1200);
+
+        // Nothing has been probed yet
+        assert!(!path.confirm(65507));
+        assert_eq!(path.mtu(), 1200);
+
+        // A reply claiming a larger size than was probed is ignored too
+        assert_eq!(path.next_probe(Duration::ZERO, 65507), Some(1280));
+        assert!(!path.confirm(65507));
+        assert_eq!(path.mtu(), 1200);
+        assert!(path.confirm(1280));
+        assert!(!path.confirm(1280));
+        assert_eq!(path.mtu(), 1280);
+    }
+}
@@ -3,25 +3,89 @@
 #[cfg(feature = "nodejs")]
 use neon::prelude::*;
 use neon::types::buffer::TypedArray;
-use std::sync::Arc;
-use tokio::runtime::Runtime;
+use neon::types::JsFuture;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::runtime::{Builder, Runtime};
 
 use crate::{
     server::Server,
     client::Client,
     transport::TransportConfig,
     crypto::CryptoProvider,
-    compression::CompressionProvider,
-    middleware::{Context, Response},
+    middleware::Response,
+    error::{ProtocolError, Result},
 };
 
+/// Environment variable setting the worker thread count of the shared runtime
+const WORKER_THREADS_ENV: &str = "FAST_PROTOCOL_WORKER_THREADS";
+
+/// Worker thread count set through `init`, or 0 to use the environment or tokio's default
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Runtime shared by every server and client, alive while any wrapper holds it
+static RUNTIME: Mutex<Weak<Runtime>> = Mutex::new(Weak::new());
+
+/// Worker thread count for the next runtime
+fn worker_threads() -> Option<usize> {
+    match WORKER_THREADS.load(Ordering::Relaxed) {
+        0 => parse_worker_threads(std::env::var(WORKER_THREADS_ENV).ok().as_deref()),
+        threads => Some(threads),
+    }
+}
+
+fn parse_worker_threads(value: Option<&str>) -> Option<usize> {
+    value?.trim().parse().ok().filter(|&threads| threads > 0)
+}
+
+/// Parse a socket address passed in from JS
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse().map_err(|_| ProtocolError::InvalidAddress(addr.to_string()))
+}
+
+/// The shared runtime, started on first use
+///
+/// Every wrapper holds a reference; once the last one is finalized the
+/// runtime shuts down, and the next wrapper created starts a fresh one.
+fn shared_runtime() -> std::io::Result<Arc<Runtime>> {
+    let mut shared = RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(runtime) = shared.upgrade() {
+        return Ok(runtime);
+    }
+
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("fast-protocol");
+    if let Some(threads) = worker_threads() {
+        builder.worker_threads(threads);
+    }
+    let runtime = Arc::new(builder.build()?);
+    *shared = Arc::downgrade(&runtime);
+    Ok(runtime)
+}
+
+/// Let go of a wrapper's runtime, shutting it down if no other wrapper uses it
+///
+/// Shutdown does not wait for tasks, since finalizers run on the JS thread.
+fn release_runtime(runtime: Arc<Runtime>) {
+    if let Ok(runtime) = Arc::try_unwrap(runtime) {
+        runtime.shutdown_background();
+    }
+}
+
 /// Wrapper for Server that can be stored in JS
 struct ServerWrapper {
     server: Arc<Server>,
     runtime: Arc<Runtime>,
 }
 
-impl Finalize for ServerWrapper {}
+impl Finalize for ServerWrapper {
+    fn finalize<'a, C: Context<'a>>(self, _cx: &mut C) {
+        drop(self.server);
+        release_runtime(self.runtime);
+    }
+}
 
 /// Wrapper for Client that can be stored in JS
 struct ClientWrapper {
@@ -29,19 +93,40 @@ struct ClientWrapper {
     runtime: Arc<Runtime>,
 }
 
-impl Finalize for ClientWrapper {}
+impl Finalize for ClientWrapper {
+    fn finalize<'a, C: Context<'a>>(self, _cx: &mut C) {
+        drop(self.client);
+        release_runtime(self.runtime);
+    }
+}
+
+/// Set the worker thread count of the shared runtime
+///
+/// Takes precedence over `FAST_PROTOCOL_WORKER_THREADS`. Throws if the
+/// runtime is already running, since its thread count cannot change.
+fn init(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let threads = cx.argument::<JsNumber>(0)?.value(&mut cx);
+    if !(threads >= 1.0 && threads.fract() == 0.0) {
+        return cx.throw_range_error("worker thread count must be a positive integer");
+    }
+    if RUNTIME.lock().unwrap_or_else(|e| e.into_inner()).strong_count() > 0 {
+        return cx.throw_error("init must be called before any server or client is created");
+    }
+    WORKER_THREADS.store(threads as usize, Ordering::Relaxed);
+    Ok(cx.undefined())
+}
 
 /// Create a new server
 fn create_server(mut cx: FunctionContext) -> JsResult<JsBox<ServerWrapper>> {
     let addr = cx.argument::<JsString>(0)?.value(&mut cx);
-    
-    let runtime = Arc::new(
-        Runtime::new().or_else(|e| cx.throw_error(format!("Failed to create runtime: {}", e)))?
-    );
+    let addr = parse_addr(&addr).or_else(|e| cx.throw_error(e.to_string()))?;
+
+    let runtime = shared_runtime()
+        .or_else(|e| cx.throw_error(format!("Failed to create runtime: {}", e)))?;
 
     let server = runtime.block_on(async {
         let config = TransportConfig::default();
-        Server::new(addr.parse().unwrap(), config).await
+        Server::new(addr, config).await
     }).or_else(|e| cx.throw_error(format!("Failed to create server: {}", e)))?;
 
     let wrapper = ServerWrapper {
@@ -52,23 +137,126 @@ fn create_server(mut cx: FunctionContext) -> JsResult<JsBox<ServerWrapper>> {
     Ok(cx.boxed(wrapper))
 }
 
+/// What a JS handler produced when it was called
+enum JsReply {
+    /// The handler returned a value, or threw
+    Ready(std::result::Result<Bytes, String>),
+    /// The handler returned a promise that settles later
+    Pending(JsFuture<std::result::Result<Bytes, String>>),
+}
+
+/// A payload handed over from JS
+///
+/// Buffers and other `Uint8Array`s are copied byte for byte. Strings are
+/// accepted as a convenience and sent as UTF-8.
+#[derive(Debug)]
+enum JsPayload {
+    Binary(Vec<u8>),
+    Text(String),
+    Empty,
+}
+
+impl JsPayload {
+    /// Read a payload from a JS value, or `None` if it has no byte form
+    fn from_js(cx: &mut Cx, value: Handle<JsValue>) -> Option<Self> {
+        if let Ok(bytes) = value.downcast::<JsUint8Array, _>(cx) {
+            return Some(JsPayload::Binary(bytes.as_slice(cx).to_vec()));
+        }
+        if let Ok(text) = value.downcast::<JsString, _>(cx) {
+            return Some(JsPayload::Text(text.value(cx)));
+        }
+        if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
+            return Some(JsPayload::Empty);
+        }
+        None
+    }
+
+    fn into_bytes(self) -> Bytes {
+        match self {
+            JsPayload::Binary(data) => Bytes::from(data),
+            JsPayload::Text(text) => Bytes::from(text),
+            JsPayload::Empty => Bytes::new(),
+        }
+    }
+}
+
+/// Turn a value returned by a JS handler into response bytes
+fn js_to_bytes(cx: &mut Cx, value: Handle<JsValue>) -> std::result::Result<Bytes, String> {
+    JsPayload::from_js(cx, value)
+        .map(JsPayload::into_bytes)
+        .ok_or_else(|| "handler must return a string, Buffer or Uint8Array".to_string())
+}
+
+/// Describe a thrown value or rejection reason
+fn js_error_message(cx: &mut Cx, error: Handle<JsValue>) -> String {
+    match error.to_string(cx) {
+        Ok(message) => message.value(cx),
+        Err(_) => "unknown JavaScript error".to_string(),
+    }
+}
+
+/// Call a JS route handler on the JS thread with the payload as a `Buffer`
+///
+/// The handler may return its response directly or as a promise. A thrown
+/// exception or rejected promise becomes a protocol error.
+async fn call_js_handler(
+    channel: Channel,
+    callback: Arc<Root<JsFunction>>,
+    payload: Bytes,
+) -> Result<Response> {
+    let reply = channel
+        .send(move |mut cx| {
+            let callback = callback.to_inner(&mut cx);
+            let buffer = JsBuffer::from_slice(&mut cx, &payload)?;
+            let this = cx.undefined();
+
+            let returned = match cx.try_catch(|cx| callback.call(cx, this, [buffer.upcast()])) {
+                Ok(returned) => returned,
+                Err(error) => return Ok(JsReply::Ready(Err(js_error_message(&mut cx, error)))),
+            };
+
+            if let Ok(promise) = returned.downcast::<JsPromise, _>(&mut cx) {
+                let settled = promise.to_future(&mut cx, |mut cx, result| {
+                    Ok(match result {
+                        Ok(value) => js_to_bytes(&mut cx, value),
+                        Err(error) => Err(js_error_message(&mut cx, error)),
+                    })
+                })?;
+                return Ok(JsReply::Pending(settled));
+            }
+            Ok(JsReply::Ready(js_to_bytes(&mut cx, returned)))
+        })
+        .await
+        .map_err(|e| ProtocolError::Channel(format!("JS handler did not run: {}", e)))?;
+
+    let outcome = match reply {
+        JsReply::Ready(outcome) => outcome,
+        JsReply::Pending(settled) => settled
+            .await
+            .map_err(|e| ProtocolError::Channel(format!("JS handler did not settle: {}", e)))?,
+    };
+
+    outcome
+        .map(Response::new)
+        .map_err(|message| ProtocolError::Other(format!("JS handler failed: {}", message)))
+}
+
 /// Register a route handler
+///
+/// The JS callback receives the request payload as a `Buffer` and returns a
+/// string, `Buffer` or `Uint8Array`, or a promise of one.
 fn server_on(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let wrapper = cx.argument::<JsBox<ServerWrapper>>(0)?;
     let route = cx.argument::<JsString>(1)?.value(&mut cx);
-    let callback = cx.argument::<JsFunction>(2)?.root(&mut cx);
+    let callback = Arc::new(cx.argument::<JsFunction>(2)?.root(&mut cx));
+    let channel = cx.channel();
 
     let server = wrapper.server.clone();
     let runtime = wrapper.runtime.clone();
 
     runtime.spawn(async move {
-        server.on_async(route, move |ctx: Context| {
-            let callback = callback.clone();
-            async move {
-                // For now, return a simple response
-                // In a full implementation, we'd call the JS callback here
-                Ok(Response::text("OK"))
-            }
+        server.on_async(route, move |ctx| {
+            call_js_handler(channel.clone(), callback.clone(), ctx.payload)
         }).await;
     });
 
@@ -95,18 +283,15 @@ fn server_listen(mut cx: FunctionContext) -> JsResult<JsUndefined> {
 fn create_client(mut cx: FunctionContext) -> JsResult<JsBox<ClientWrapper>> {
     let bind_addr = cx.argument::<JsString>(0)?.value(&mut cx);
     let server_addr = cx.argument::<JsString>(1)?.value(&mut cx);
-    
-    let runtime = Arc::new(
-        Runtime::new().or_else(|e| cx.throw_error(format!("Failed to create runtime: {}", e)))?
-    );
+    let bind_addr = parse_addr(&bind_addr).or_else(|e| cx.throw_error(e.to_string()))?;
+    let server_addr = parse_addr(&server_addr).or_else(|e| cx.throw_error(e.to_string()))?;
+
+    let runtime = shared_runtime()
+        .or_else(|e| cx.throw_error(format!("Failed to create runtime: {}", e)))?;
 
     let client = runtime.block_on(async {
         let config = TransportConfig::default();
-        Client::new(
-            bind_addr.parse().unwrap(),
-            server_addr.parse().unwrap(),
-            config,
-        ).await
+        Client::new(bind_addr, server_addr, config).await
     }).or_else(|e| cx.throw_error(format!("Failed to create client: {}", e)))?;
 
     let wrapper = ClientWrapper {
@@ -142,10 +327,17 @@ fn client_connect(mut cx: FunctionContext) -> JsResult<JsPromise> {
 }
 
 /// Send a request
+///
+/// The payload may be a `Buffer`, `Uint8Array` or string; the promise
+/// resolves with the response as a `Buffer`.
 fn client_request(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let wrapper = cx.argument::<JsBox<ClientWrapper>>(0)?;
     let route = cx.argument::<JsString>(1)?.value(&mut cx);
-    let data = cx.argument::<JsString>(2)?.value(&mut cx);
+    let data = cx.argument::<JsValue>(2)?;
+    let data = match JsPayload::from_js(&mut cx, data) {
+        Some(payload) => payload.into_bytes(),
+        None => return cx.throw_type_error("payload must be a Buffer, Uint8Array or string"),
+    };
     let channel = cx.channel();
 
     let client = wrapper.client.clone();
@@ -154,14 +346,11 @@ fn client_request(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let (deferred, promise) = cx.promise();
 
     runtime.spawn(async move {
-        let result = client.request(route, data.into()).await;
+        let result = client.request(route, data).await;
         
         deferred.settle_with(&channel, move |mut cx| {
             match result {
-                Ok(bytes) => {
-                    let s = String::from_utf8_lossy(&bytes);
-                    Ok(cx.string(s))
-                }
+                Ok(bytes) => JsBuffer::from_slice(&mut cx, &bytes),
                 Err(e) => cx.throw_error(format!("Request failed: {}", e)),
             }
         });
@@ -173,6 +362,7 @@ fn client_request(mut cx: FunctionContext) -> JsResult<JsPromise> {
 /// Export all functions to Node.js
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    cx.export_function("init", init)?;
     cx.export_function("createServer", create_server)?;
     cx.export_function("serverOn", server_on)?;
     cx.export_function("serverListen", server_listen)?;
@@ -182,3 +372,40 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_keeps_embedded_nulls() {
+        let data = vec![0x00, b'a', 0x00, 0xff, 0xfe, 0x00];
+        assert_eq!(JsPayload::Binary(data.clone()).into_bytes(), Bytes::from(data));
+
+        let text = JsPayload::Text("a\0b\0".to_string()).into_bytes();
+        assert_eq!(&text[..], b"a\0b\0");
+        assert!(JsPayload::Empty.into_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_wrappers_share_one_runtime() {
+        let first = shared_runtime().unwrap();
+        let second = shared_runtime().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        release_runtime(first);
+        release_runtime(second);
+        assert_eq!(RUNTIME.lock().unwrap().strong_count(), 0);
+
+        assert_eq!(parse_worker_threads(Some(" 4 ")), Some(4));
+        assert_eq!(parse_worker_threads(Some("0")), None);
+        assert_eq!(parse_worker_threads(Some("many")), None);
+        assert_eq!(parse_worker_threads(None), None);
+    }
+
+    #[test]
+    fn test_bad_address_is_an_error() {
+        assert_eq!(parse_addr("127.0.0.1:8080").unwrap(), SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert!(matches!(parse_addr("localhost"), Err(ProtocolError::InvalidAddress(addr)) if addr == "localhost"));
+        assert!(matches!(parse_addr("127.0.0.1:99999"), Err(ProtocolError::InvalidAddress(_))));
+    }
+}
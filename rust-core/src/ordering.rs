@@ -0,0 +1,142 @@
+//! In-order delivery of reliable packets
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::packet::Packet;
+
+/// A sequence received ahead of the next expected one
+enum Held {
+    /// Packet for an ordered route, delivered once the gap before it fills
+    Packet(Packet),
+    /// Sequence used by an unordered packet, already delivered; it only fills the gap
+    Passed,
+}
+
+/// Ordering state for one channel of one source
+#[derive(Default)]
+struct SourceOrder {
+    next: u32,
+    held: BTreeMap<u32, Held>,
+    waiting_since: Option<Instant>,
+}
+
+impl SourceOrder {
+    /// Deliver everything that is now contiguous with the next expected sequence
+    fn drain(&mut self, ready: &mut Vec<Packet>) {
+        while let Some(held) = self.held.remove(&self.next) {
+            if let Held::Packet(packet) = held {
+                ready.push(packet);
+            }
+            self.next = self.next.wrapping_add(1);
+        }
+        self.waiting_since = if self.held.is_empty() {
+            None
+        } else {
+            self.waiting_since.or(Some(Instant::now()))
+        };
+    }
+
+    /// Give up on the gap and move on to the first held sequence
+    fn skip_gap(&mut self, ready: &mut Vec<Packet>) {
+        if let Some(&first) = self.held.keys().next() {
+            self.next = first;
+            self.waiting_since = None;
+            self.drain(ready);
+        }
+    }
+
+    fn is_late(&self, sequence: u32) -> bool {
+        (sequence.wrapping_sub(self.next) as i32) < 0
+    }
+}
+
+/// Puts reliable packets from each source back into sequence order
+///
+/// Each channel of a source has its own sequence space and is ordered on its
+/// own, so a gap on one channel never holds back another. Every reliable
+/// packet on a channel takes part so that sequences used by
+/// unordered routes still close gaps, but only packets for ordered routes are
+/// held back. A gap is normally filled by the retransmission of the missing
+/// packet; if it stays open longer than `timeout`, delivery skips past it and
+/// the missing packet is delivered on its own if it shows up later.
+pub(crate) struct ReorderBuffer {
+    sources: HashMap<(SocketAddr, u16), SourceOrder>,
+    timeout: Duration,
+}
+
+impl ReorderBuffer {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            sources: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Record a reliable packet, returning the ordered packets now ready for delivery
+    ///
+    /// Pass `ordered = false` for packets that are delivered right away.
+    pub(crate) fn accept(&mut self, addr: SocketAddr, packet: Packet, ordered: bool) -> Vec<Packet> {
+        let source = self.sources.entry((addr, packet.channel_id)).or_default();
+        let sequence = packet.sequence;
+        let mut ready = Vec::new();
+
+        if source.is_late(sequence) {
+            if ordered {
+                ready.push(packet);
+            }
+            return ready;
+        }
+
+        let held = if ordered { Held::Packet(packet) } else { Held::Passed };
+        source.held.insert(sequence, held);
+        source.drain(&mut ready);
+
+        if source.waiting_since.is_some_and(|since| since.elapsed() >= self.timeout) {
+            source.skip_gap(&mut ready);
+        }
+        ready
+    }
+
+    /// Skip gaps that have been open longer than the timeout
+    pub(crate) fn flush_expired(&mut self) -> Vec<(SocketAddr, Packet)> {
+        let mut flushed = Vec::new();
+        for ((addr, _), source) in self.sources.iter_mut() {
+            if source.waiting_since.is_some_and(|since| since.elapsed() >= self.timeout) {
+                let mut ready = Vec::new();
+                source.skip_gap(&mut ready);
+                flushed.extend(ready.into_iter().map(|packet| (*addr, packet)));
+            }
+        }
+        flushed
+    }
+
+    /// Forget every channel of a source, e.g. when it reconnects and its sequences start over
+    pub(crate) fn reset(&mut self, addr: SocketAddr) {
+        self.sources.retain(|(source, _), _| *source != addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn packet(sequence: u32) -> Packet {
+        Packet::new_data("/r".to_string(), Bytes::new(), sequence)
+    }
+
+    #[test]
+    fn test_unordered_packets_fill_gaps() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(60));
+
+        assert!(buffer.accept(addr, packet(2), true).is_empty());
+        assert_eq!(buffer.accept(addr, packet(0), true).len(), 1);
+
+        // Sequence 1 belongs to an unordered route but still releases 2
+        let ready = buffer.accept(addr, packet(1), false);
+        assert_eq!(ready.iter().map(|p| p.sequence).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,78 @@
+//! Send rate pacing
+
+use std::time::{Duration, Instant};
+
+/// Time's worth of tokens the bucket holds at most, letting short bursts through
+const BURST: Duration = Duration::from_millis(10);
+
+/// Token bucket limiting how many bytes per second are sent
+///
+/// Each datagram takes tokens for its size. When the bucket runs dry the
+/// datagram still goes out, but only after its sender has waited for the
+/// borrowed tokens to refill, so the long-run rate stays under the limit
+/// however sends and retransmissions are bunched up.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket allowing `rate` bytes per second
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        let capacity = (rate * BURST.as_secs_f64()).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take tokens for `bytes`, returning how long to wait before sending them
+    pub(crate) fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Whole tokens available right now
+    pub(crate) fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens.max(0.0) as u64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_waits_for_borrowed_tokens() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = Instant::now();
+        bucket.refilled_at = start;
+
+        // The 10 byte burst allowance goes first, then each byte costs a millisecond
+        assert_eq!(bucket.reserve(10, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(100, start), Duration::from_millis(100));
+        assert_eq!(bucket.available(start), 0);
+
+        // Refilling pays the debt off before any tokens become available
+        let later = start + Duration::from_millis(105);
+        assert_eq!(bucket.available(later), 5);
+        assert_eq!(bucket.reserve(5, later), Duration::ZERO);
+    }
+}
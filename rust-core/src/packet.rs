@@ -1,9 +1,27 @@
 //! Packet definitions and serialization
+//!
+//! Packets go on the wire through the hand-rolled serializer in this module,
+//! which also lays out the payloads of the existing control packets. New
+//! control packets can instead carry a serde struct encoded with bincode,
+//! through `Packet::serialize_bincode` and `Packet::deserialize_bincode`
+//! (with the `std` feature).
 
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use bincode::Options;
 use bytes::{Bytes, BytesMut, Buf, BufMut};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::compression::CompressionAlgorithm;
 use crate::{error::*, PROTOCOL_VERSION};
 
 /// Packet types
@@ -25,6 +43,28 @@ pub enum PacketType {
     Disconnect = 6,
     /// Batch of packets
     Batch = 7,
+    /// Fragment of a packet too large for a single datagram
+    Fragment = 8,
+    /// Chunk of a streamed response
+    Stream = 9,
+    /// Switch the session to a key derived from the current one
+    Rekey = 10,
+    /// Abandon the request sent with the sequence carried in the payload
+    Cancel = 11,
+    /// Padded packet testing whether datagrams of its size reach the peer
+    Probe = 12,
+    /// Reply to a probe, carrying the size of the probe that arrived
+    ProbeAck = 13,
+    /// Reply to a request that failed, carrying an error code and message
+    Error = 14,
+    /// Refusal of a connection request, carrying the reason
+    ConnectReject = 15,
+    /// Latency probe carrying the sender's send time
+    Ping = 16,
+    /// Reply to a ping, echoing its payload
+    Pong = 17,
+    /// Ticket the client can present to resume its session without a key exchange
+    SessionTicket = 18,
 }
 
 impl TryFrom<u8> for PacketType {
@@ -40,6 +80,17 @@ impl TryFrom<u8> for PacketType {
             5 => Ok(PacketType::ConnectAck),
             6 => Ok(PacketType::Disconnect),
             7 => Ok(PacketType::Batch),
+            8 => Ok(PacketType::Fragment),
+            9 => Ok(PacketType::Stream),
+            10 => Ok(PacketType::Rekey),
+            11 => Ok(PacketType::Cancel),
+            12 => Ok(PacketType::Probe),
+            13 => Ok(PacketType::ProbeAck),
+            14 => Ok(PacketType::Error),
+            15 => Ok(PacketType::ConnectReject),
+            16 => Ok(PacketType::Ping),
+            17 => Ok(PacketType::Pong),
+            18 => Ok(PacketType::SessionTicket),
             _ => Err(ProtocolError::InvalidPacket(format!(
                 "Unknown packet type: {}",
                 value
@@ -48,12 +99,22 @@ impl TryFrom<u8> for PacketType {
     }
 }
 
+/// Bits of the flags byte holding the compression algorithm id
+const COMPRESSION_ALGORITHM_MASK: u8 = 0b0011_1000;
+const COMPRESSION_ALGORITHM_SHIFT: u8 = 3;
+
 /// Packet flags
 #[derive(Debug, Clone, Copy, Default)]
 pub struct PacketFlags {
     pub encrypted: bool,
     pub compressed: bool,
     pub requires_ack: bool,
+    /// Sent at most once: never ACKed, retransmitted or answered
+    pub unreliable: bool,
+    /// The payload starts with an idempotency key, see `Packet::new_idempotent_data`
+    pub idempotent: bool,
+    /// Algorithm the payload was compressed with, meaningful when `compressed` is set
+    pub compression: CompressionAlgorithm,
 }
 
 impl PacketFlags {
@@ -68,16 +129,135 @@ impl PacketFlags {
         if self.requires_ack {
             byte |= 0b0000_0100;
         }
+        if self.unreliable {
+            byte |= 0b0100_0000;
+        }
+        if self.idempotent {
+            byte |= 0b1000_0000;
+        }
+        if self.compressed {
+            byte |= (self.compression.id() << COMPRESSION_ALGORITHM_SHIFT) & COMPRESSION_ALGORITHM_MASK;
+        }
         byte
     }
 
     pub fn from_byte(byte: u8) -> Self {
+        let algorithm_id = (byte & COMPRESSION_ALGORITHM_MASK) >> COMPRESSION_ALGORITHM_SHIFT;
         Self {
             encrypted: (byte & 0b0000_0001) != 0,
             compressed: (byte & 0b0000_0010) != 0,
             requires_ack: (byte & 0b0000_0100) != 0,
+            unreliable: (byte & 0b0100_0000) != 0,
+            idempotent: (byte & 0b1000_0000) != 0,
+            compression: CompressionAlgorithm::from_id(algorithm_id).unwrap_or_default(),
+        }
+    }
+}
+
+/// Wire format with fixed-width sequence and length fields
+///
+/// Still read, and written back for packets that arrived in it.
+pub const FIXED_WIDTH_VERSION: u8 = 1;
+
+/// Wire format with varint sequence and length fields but no channel id
+///
+/// Still read, and written back for packets that arrived in it. Like version
+/// 1 it can only carry the default channel.
+pub const VARINT_VERSION: u8 = 2;
+
+/// Channel used by packets that do not name one
+pub const DEFAULT_CHANNEL: u16 = 0;
+
+/// Longest header in any supported wire format
+const MAX_HEADER_LEN: usize = 34;
+
+/// Bit of the type byte marking a reply, whose header carries the request's sequence
+const REPLY_TYPE_BIT: u8 = 0x80;
+
+/// Bounds on incoming packets, checked before their fields are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketLimits {
+    pub max_route_len: usize,
+    pub max_payload_len: usize,
+}
+
+impl PacketLimits {
+    /// No bounds beyond what the wire format can express
+    pub const NONE: Self = Self {
+        max_route_len: usize::MAX,
+        max_payload_len: usize::MAX,
+    };
+
+    /// Largest serialized packet within these limits
+    pub fn max_packet_len(&self) -> usize {
+        MAX_HEADER_LEN
+            .saturating_add(self.max_route_len)
+            .saturating_add(self.max_payload_len)
+    }
+}
+
+/// Append `value` as an LEB128 varint
+fn put_varint(buf: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Number of bytes `value` takes as an LEB128 varint
+fn varint_len(value: u32) -> usize {
+    (32 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+/// Read an LEB128 varint, naming `field` in any error
+fn get_varint(data: &mut Bytes, field: &str) -> Result<u32> {
+    let mut value = 0u64;
+    for shift in (0..35).step_by(7) {
+        if !data.has_remaining() {
+            return Err(ProtocolError::InvalidPacket(format!("Truncated {}", field)));
+        }
+        let byte = data.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return u32::try_from(value)
+                .map_err(|_| ProtocolError::InvalidPacket(format!("Invalid {}", field)));
         }
     }
+    Err(ProtocolError::InvalidPacket(format!("Invalid {}", field)))
+}
+
+/// Size of the fragment header at the start of a fragment payload
+pub const FRAGMENT_HEADER_SIZE: usize = 8;
+
+/// Fragment metadata carried at the start of a `Fragment` packet payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// Identifier shared by all fragments of one original packet
+    pub fragment_id: u32,
+    /// Position of this fragment within the original packet
+    pub fragment_index: u16,
+    /// Total number of fragments making up the original packet
+    pub fragment_count: u16,
+}
+
+/// Size of the stream header at the start of a stream chunk payload
+pub const STREAM_HEADER_SIZE: usize = 9;
+
+const STREAM_FLAG_END: u8 = 0b0000_0001;
+const STREAM_FLAG_ERROR: u8 = 0b0000_0010;
+
+/// Stream metadata carried at the start of a `Stream` packet payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// Identifier shared by all chunks of one stream (the sequence of the request)
+    pub stream_id: u32,
+    /// Position of this chunk within the stream
+    pub index: u32,
+    /// Last chunk of the stream
+    pub end: bool,
+    /// The chunk carries an error message instead of data
+    pub error: bool,
 }
 
 /// Main packet structure
@@ -86,7 +266,17 @@ pub struct Packet {
     pub version: u8,
     pub packet_type: PacketType,
     pub flags: PacketFlags,
+    /// Logical channel the packet belongs to
+    ///
+    /// Each channel has its own sequence space, so reliability, ordering and
+    /// congestion are tracked separately per channel.
+    pub channel_id: u16,
     pub sequence: u32,
+    /// Sequence of the request this packet answers, set on replies
+    ///
+    /// Only carried by the current wire format; older formats drop it, so
+    /// requests cannot be answered in them.
+    pub reply_to: Option<u32>,
     pub timestamp: u64,
     pub route: String,
     pub payload: Bytes,
@@ -102,20 +292,66 @@ impl Packet {
                 requires_ack: true,
                 ..Default::default()
             },
+            channel_id: DEFAULT_CHANNEL,
             sequence,
+            reply_to: None,
             timestamp: Self::current_timestamp(),
             route,
             payload,
         }
     }
 
+    /// Create a data packet carrying an idempotency key
+    ///
+    /// The payload is the key's length as a u8, the key, then `payload`. A
+    /// server runs the handler once per key and answers repeats with the
+    /// response it cached. Fails if the key is empty or longer than 255 bytes.
+    pub fn new_idempotent_data(route: String, key: &str, payload: &[u8]) -> Result<Self> {
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "Idempotency key must be 1 to 255 bytes, got {}",
+                key.len()
+            )));
+        }
+        let mut data = BytesMut::with_capacity(1 + key.len() + payload.len());
+        data.put_u8(key.len() as u8);
+        data.put_slice(key.as_bytes());
+        data.put_slice(payload);
+
+        let mut packet = Self::new_data(route, data.freeze(), 0);
+        packet.flags.idempotent = true;
+        Ok(packet)
+    }
+
+    /// Idempotency key and request payload of an idempotent data packet
+    ///
+    /// `None` for packets sent without a key.
+    pub fn idempotency_parts(&self) -> Result<Option<(String, Bytes)>> {
+        if !self.flags.idempotent {
+            return Ok(None);
+        }
+        let key_len = match self.payload.first() {
+            Some(&len) if len > 0 && self.payload.len() > len as usize => len as usize,
+            _ => {
+                return Err(ProtocolError::InvalidPacket(
+                    "Payload shorter than its idempotency key".to_string(),
+                ))
+            }
+        };
+        let key = core::str::from_utf8(&self.payload[1..1 + key_len])
+            .map_err(|_| ProtocolError::InvalidPacket("Idempotency key is not UTF-8".to_string()))?;
+        Ok(Some((key.to_string(), self.payload.slice(1 + key_len..))))
+    }
+
     /// Create an acknowledgment packet
     pub fn new_ack(sequence: u32) -> Self {
         Self {
             version: PROTOCOL_VERSION,
             packet_type: PacketType::Ack,
             flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
             sequence,
+            reply_to: None,
             timestamp: Self::current_timestamp(),
             route: String::new(),
             payload: Bytes::new(),
@@ -128,7 +364,9 @@ impl Packet {
             version: PROTOCOL_VERSION,
             packet_type: PacketType::Nack,
             flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
             sequence,
+            reply_to: None,
             timestamp: Self::current_timestamp(),
             route: String::new(),
             payload: Bytes::new(),
@@ -141,7 +379,9 @@ impl Packet {
             version: PROTOCOL_VERSION,
             packet_type: PacketType::Heartbeat,
             flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
             sequence: 0,
+            reply_to: None,
             timestamp: Self::current_timestamp(),
             route: String::new(),
             payload: Bytes::new(),
@@ -154,61 +394,747 @@ impl Packet {
             version: PROTOCOL_VERSION,
             packet_type: PacketType::Connect,
             flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::new(),
+        }
+    }
+
+    /// Create a connection request carrying a key exchange public key and an auth token
+    ///
+    /// The payload is the key length as a u8, the key, then the token. Either
+    /// may be empty.
+    pub fn new_connect_with(public_key: &[u8], token: &[u8]) -> Self {
+        let mut payload = BytesMut::with_capacity(1 + public_key.len() + token.len());
+        payload.put_u8(public_key.len() as u8);
+        payload.put_slice(public_key);
+        payload.put_slice(token);
+
+        Self {
+            payload: payload.freeze(),
+            ..Self::new_connect()
+        }
+    }
+
+    /// Create a connection request that also carries a request for `route` (0-RTT)
+    ///
+    /// The payload is the key length as a u8, the key, the token length as a
+    /// u16, the token, then the request payload. A non-empty route is what
+    /// marks a connection request as carrying one.
+    pub fn new_connect_with_early_data(public_key: &[u8], token: &[u8], route: String, data: &[u8]) -> Self {
+        let mut payload = BytesMut::with_capacity(3 + public_key.len() + token.len() + data.len());
+        payload.put_u8(public_key.len() as u8);
+        payload.put_slice(public_key);
+        payload.put_u16(token.len() as u16);
+        payload.put_slice(token);
+        payload.put_slice(data);
+
+        Self {
+            route,
+            payload: payload.freeze(),
+            ..Self::new_connect()
+        }
+    }
+
+    /// Public key and auth token a connection request carries
+    ///
+    /// Before protocol version 3 the whole payload was the public key.
+    pub fn connect_parts(&self) -> Result<(Bytes, Bytes)> {
+        let (public_key, token, _) = self.connect_fields()?;
+        Ok((public_key, token))
+    }
+
+    /// Payload of the request a connection request carries for its route, if any
+    pub fn connect_early_data(&self) -> Result<Option<Bytes>> {
+        if self.route.is_empty() || self.version < PROTOCOL_VERSION {
+            return Ok(None);
+        }
+        let (_, _, data) = self.connect_fields()?;
+        Ok(Some(data))
+    }
+
+    /// Public key, auth token and early request payload of a connection request
+    fn connect_fields(&self) -> Result<(Bytes, Bytes, Bytes)> {
+        if self.version < PROTOCOL_VERSION || self.payload.is_empty() {
+            return Ok((self.payload.clone(), Bytes::new(), Bytes::new()));
+        }
+        let key_len = self.payload[0] as usize;
+        if self.payload.len() < 1 + key_len {
+            return Err(ProtocolError::InvalidPacket(
+                "Connect payload shorter than its public key".to_string(),
+            ));
+        }
+        let public_key = self.payload.slice(1..1 + key_len);
+        let rest = self.payload.slice(1 + key_len..);
+        if self.route.is_empty() {
+            return Ok((public_key, rest, Bytes::new()));
+        }
+
+        let token_len = match rest.get(..2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => 0,
+        };
+        if rest.len() < 2 + token_len {
+            return Err(ProtocolError::InvalidPacket(
+                "Connect payload shorter than its auth token".to_string(),
+            ));
+        }
+        Ok((public_key, rest.slice(2..2 + token_len), rest.slice(2 + token_len..)))
+    }
+
+    /// Create a refusal of a connection request
+    pub fn new_connect_reject(reason: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::ConnectReject,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::copy_from_slice(reason.as_bytes()),
+        }
+    }
+
+    /// Create a disconnection packet
+    pub fn new_disconnect() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Disconnect,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::new(),
+        }
+    }
+
+    /// Create a connection response packet
+    pub fn new_connect_ack(payload: Bytes) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::ConnectAck,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload,
+        }
+    }
+
+    /// Create a connection acceptance answering the request its `Connect` carried
+    ///
+    /// `reply` is the `Data` or `Error` packet answering the request. The
+    /// acceptance takes its route, and its payload is the reply's packet type
+    /// as a u8 followed by the reply's payload.
+    pub fn new_connect_ack_with_reply(reply: Packet) -> Self {
+        let mut payload = BytesMut::with_capacity(1 + reply.payload.len());
+        payload.put_u8(reply.packet_type as u8);
+        payload.put_slice(&reply.payload);
+
+        Self {
+            route: reply.route,
+            ..Self::new_connect_ack(payload.freeze())
+        }
+    }
+
+    /// Reply to the early request a connection acceptance answers, if any
+    ///
+    /// Returned as the `Data` or `Error` packet it was built from.
+    pub fn early_reply(&self) -> Result<Option<Packet>> {
+        if self.packet_type != PacketType::ConnectAck || self.route.is_empty() {
+            return Ok(None);
+        }
+        let Some(&packet_type) = self.payload.first() else {
+            return Err(ProtocolError::InvalidPacket("Empty early reply".to_string()));
+        };
+        let packet_type = PacketType::try_from(packet_type)?;
+        if packet_type != PacketType::Data && packet_type != PacketType::Error {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Early reply of type {:?}",
+                packet_type
+            )));
+        }
+        Ok(Some(Packet {
+            packet_type,
+            route: self.route.clone(),
+            payload: self.payload.slice(1..),
+            ..self.clone()
+        }))
+    }
+
+    /// Create a rekey packet carrying the salt for deriving the next session key
+    pub fn new_rekey(salt: Bytes) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Rekey,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: salt,
+        }
+    }
+
+    /// Create a cancel packet for the request sent with `sequence`
+    pub fn new_cancel(sequence: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Cancel,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::copy_from_slice(&sequence.to_be_bytes()),
+        }
+    }
+
+    /// Sequence of the request a cancel packet refers to
+    pub fn cancelled_sequence(&self) -> Result<u32> {
+        let bytes: [u8; 4] = self.payload[..].try_into().map_err(|_| {
+            ProtocolError::InvalidPacket("Cancel payload must be a 4-byte sequence".to_string())
+        })?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    /// Create a path MTU probe padded to serialize to exactly `size` bytes
+    pub fn new_probe(size: usize) -> Self {
+        Self::new_probe_in(size, PROTOCOL_VERSION)
+    }
+
+    /// Create a path MTU probe written in wire format `version`
+    pub fn new_probe_in(size: usize, version: u8) -> Self {
+        let mut probe = Self {
+            version,
+            packet_type: PacketType::Probe,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
             sequence: 0,
+            reply_to: None,
             timestamp: Self::current_timestamp(),
             route: String::new(),
             payload: Bytes::new(),
+        };
+        let header = probe.encoded_len();
+        let mut padding = size.saturating_sub(header);
+        if probe.version != FIXED_WIDTH_VERSION {
+            // The payload length prefix grows with the padding
+            while padding > 0 && header - 1 + varint_len(padding as u32) + padding > size {
+                padding -= 1;
+            }
         }
+        probe.payload = Bytes::from(vec![0u8; padding]);
+        probe
+    }
+
+    /// Create the reply to a probe that arrived with `size` bytes
+    pub fn new_probe_ack(size: usize) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::ProbeAck,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::copy_from_slice(&(size as u32).to_be_bytes()),
+        }
+    }
+
+    /// Probe size a probe ack reports as having arrived
+    pub fn probed_size(&self) -> Result<usize> {
+        let bytes: [u8; 4] = self.payload[..].try_into().map_err(|_| {
+            ProtocolError::InvalidPacket("Probe ack payload must be a 4-byte size".to_string())
+        })?;
+        Ok(u32::from_be_bytes(bytes) as usize)
+    }
+
+    /// Create a latency probe stamped with the sender's clock, in microseconds
+    ///
+    /// The clock is the sender's own; only the sender reads it back.
+    pub fn new_ping(sent_at_micros: u64) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Ping,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: Bytes::copy_from_slice(&sent_at_micros.to_be_bytes()),
+        }
+    }
+
+    /// Create the reply to a ping, echoing its payload
+    pub fn new_pong(ping: &Packet) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Pong,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: ping.payload.clone(),
+        }
+    }
+
+    /// Create a session ticket, valid for `lifetime`
+    ///
+    /// The payload is the lifetime in seconds as a u32, then the ticket.
+    pub fn new_session_ticket(lifetime: Duration, ticket: &[u8]) -> Self {
+        let mut payload = BytesMut::with_capacity(4 + ticket.len());
+        payload.put_u32(lifetime.as_secs().min(u32::MAX as u64) as u32);
+        payload.put_slice(ticket);
+
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::SessionTicket,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Lifetime and ticket a session ticket packet carries
+    pub fn session_ticket_parts(&self) -> Result<(Duration, Bytes)> {
+        if self.packet_type != PacketType::SessionTicket || self.payload.len() < 4 {
+            return Err(ProtocolError::InvalidPacket("Not a session ticket".to_string()));
+        }
+        let lifetime = u32::from_be_bytes([self.payload[0], self.payload[1], self.payload[2], self.payload[3]]);
+        Ok((Duration::from_secs(lifetime as u64), self.payload.slice(4..)))
+    }
+
+    /// Send time a ping or pong carries, in microseconds of the pinging side's clock
+    pub fn ping_time(&self) -> Result<u64> {
+        let bytes: [u8; 8] = self.payload[..].try_into().map_err(|_| {
+            ProtocolError::InvalidPacket("Ping payload must be an 8-byte timestamp".to_string())
+        })?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Encode a control payload with bincode
+    ///
+    /// Only the payload is bincode; the packet around it is still written by
+    /// `serialize`. Read it back with `deserialize_bincode`.
+    #[cfg(feature = "std")]
+    pub fn serialize_bincode<T: Serialize>(value: &T) -> Result<Bytes> {
+        Ok(Bytes::from(bincode::DefaultOptions::new().serialize(value)?))
+    }
+
+    /// Decode a control payload written by `serialize_bincode`
+    ///
+    /// Lengths inside the payload cannot claim more bytes than it holds, so
+    /// a hostile payload cannot force a large allocation.
+    #[cfg(feature = "std")]
+    pub fn deserialize_bincode<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(bincode::DefaultOptions::new()
+            .with_limit(self.payload.len() as u64)
+            .deserialize(&self.payload)?)
+    }
+
+    /// Create an error reply to a request on `route`
+    ///
+    /// The payload is the code as a big-endian u16 followed by the UTF-8 message.
+    pub fn new_error(route: String, code: RemoteErrorCode, message: &str) -> Self {
+        let mut payload = BytesMut::with_capacity(2 + message.len());
+        payload.put_u16(code.to_u16());
+        payload.put_slice(message.as_bytes());
+
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Error,
+            flags: PacketFlags {
+                requires_ack: true,
+                ..Default::default()
+            },
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route,
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Sequence of the request this packet answers
+    ///
+    /// `reply_to` where the wire format carries it. Older formats have no
+    /// room for it, and a packet's own sequence says nothing about which
+    /// request it answers, so nothing sent in them is taken as a reply.
+    pub fn answered_sequence(&self) -> Option<u32> {
+        self.reply_to.filter(|_| self.version == PROTOCOL_VERSION)
+    }
+
+    /// Error an error reply carries
+    ///
+    /// A missing route comes back as `ProtocolError::RouteNotFound` naming
+    /// the route; every other code as `ProtocolError::Remote`.
+    pub fn remote_error(&self) -> Result<ProtocolError> {
+        if self.packet_type != PacketType::Error || self.payload.len() < 2 {
+            return Err(ProtocolError::InvalidPacket(
+                "Not an error reply".to_string(),
+            ));
+        }
+        let code = RemoteErrorCode::from_u16(u16::from_be_bytes([self.payload[0], self.payload[1]]));
+        if code == RemoteErrorCode::RouteNotFound {
+            return Ok(ProtocolError::RouteNotFound(self.route.clone()));
+        }
+        Ok(ProtocolError::Remote {
+            code,
+            message: String::from_utf8_lossy(&self.payload[2..]).into_owned(),
+        })
+    }
+
+    /// Create a fragment packet carrying one chunk of a larger serialized packet
+    pub fn new_fragment(header: FragmentHeader, chunk: &[u8]) -> Self {
+        let mut payload = BytesMut::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+        payload.put_u32(header.fragment_id);
+        payload.put_u16(header.fragment_index);
+        payload.put_u16(header.fragment_count);
+        payload.put_slice(chunk);
+
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Fragment,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Split a fragment packet into its header and chunk
+    pub fn fragment_parts(&self) -> Result<(FragmentHeader, Bytes)> {
+        if self.packet_type != PacketType::Fragment {
+            return Err(ProtocolError::InvalidPacket(
+                "Not a fragment packet".to_string(),
+            ));
+        }
+        if self.payload.len() < FRAGMENT_HEADER_SIZE {
+            return Err(ProtocolError::InvalidPacket(
+                "Fragment too small".to_string(),
+            ));
+        }
+
+        let mut payload = self.payload.clone();
+        let header = FragmentHeader {
+            fragment_id: payload.get_u32(),
+            fragment_index: payload.get_u16(),
+            fragment_count: payload.get_u16(),
+        };
+        if header.fragment_count == 0 || header.fragment_index >= header.fragment_count {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Invalid fragment index {} of {}",
+                header.fragment_index, header.fragment_count
+            )));
+        }
+
+        Ok((header, payload))
+    }
+
+    /// Create a stream chunk packet
+    ///
+    /// The sequence is assigned when the packet is sent reliably.
+    pub fn new_stream(route: String, header: StreamHeader, chunk: &[u8]) -> Self {
+        let mut flags = 0u8;
+        if header.end {
+            flags |= STREAM_FLAG_END;
+        }
+        if header.error {
+            flags |= STREAM_FLAG_ERROR;
+        }
+
+        let mut payload = BytesMut::with_capacity(STREAM_HEADER_SIZE + chunk.len());
+        payload.put_u32(header.stream_id);
+        payload.put_u32(header.index);
+        payload.put_u8(flags);
+        payload.put_slice(chunk);
+
+        Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Stream,
+            flags: PacketFlags {
+                requires_ack: true,
+                ..Default::default()
+            },
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route,
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Split a stream packet into its header and chunk
+    pub fn stream_parts(&self) -> Result<(StreamHeader, Bytes)> {
+        if self.packet_type != PacketType::Stream {
+            return Err(ProtocolError::InvalidPacket(
+                "Not a stream packet".to_string(),
+            ));
+        }
+        if self.payload.len() < STREAM_HEADER_SIZE {
+            return Err(ProtocolError::InvalidPacket(
+                "Stream chunk too small".to_string(),
+            ));
+        }
+
+        let mut payload = self.payload.clone();
+        let stream_id = payload.get_u32();
+        let index = payload.get_u32();
+        let flags = payload.get_u8();
+        let header = StreamHeader {
+            stream_id,
+            index,
+            end: flags & STREAM_FLAG_END != 0,
+            error: flags & STREAM_FLAG_ERROR != 0,
+        };
+
+        Ok((header, payload))
+    }
+
+    /// Create a batch packet carrying several serialized packets
+    ///
+    /// The payload is a `u16` packet count followed by each packet as a
+    /// `u32` length prefix and its serialized bytes.
+    pub fn new_batch(packets: &[Packet]) -> Result<Self> {
+        if packets.len() > u16::MAX as usize {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Too many packets for one batch: {}",
+                packets.len()
+            )));
+        }
+
+        let size = 2 + packets.iter().map(|p| 4 + p.encoded_len()).sum::<usize>();
+        let mut payload = BytesMut::with_capacity(size);
+        payload.put_u16(packets.len() as u16);
+        for packet in packets {
+            if packet.packet_type == PacketType::Batch {
+                return Err(ProtocolError::InvalidPacket(
+                    "Batches cannot be nested".to_string(),
+                ));
+            }
+            let data = packet.serialize()?;
+            payload.put_u32(data.len() as u32);
+            payload.put_slice(&data);
+        }
+
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::Batch,
+            flags: PacketFlags::default(),
+            channel_id: DEFAULT_CHANNEL,
+            sequence: 0,
+            reply_to: None,
+            timestamp: Self::current_timestamp(),
+            route: String::new(),
+            payload: payload.freeze(),
+        })
+    }
+
+    /// Extra bytes a packet adds when placed in a batch
+    pub fn batch_entry_len(&self) -> usize {
+        4 + self.encoded_len()
+    }
+
+    /// Split a batch packet back into the packets it carries
+    pub fn split_batch(&self) -> Result<Vec<Packet>> {
+        self.split_batch_with_limits(&PacketLimits::NONE)
+    }
+
+    /// Split a batch packet, rejecting entries that exceed `limits`
+    pub fn split_batch_with_limits(&self, limits: &PacketLimits) -> Result<Vec<Packet>> {
+        if self.packet_type != PacketType::Batch {
+            return Err(ProtocolError::InvalidPacket(
+                "Not a batch packet".to_string(),
+            ));
+        }
+
+        let mut payload = self.payload.clone();
+        if payload.remaining() < 2 {
+            return Err(ProtocolError::InvalidPacket(
+                "Batch too small".to_string(),
+            ));
+        }
+        let count = payload.get_u16() as usize;
+
+        // Each entry takes at least its 4-byte length, so a bogus count cannot force a big allocation
+        let mut packets = Vec::with_capacity(count.min(payload.remaining() / 4));
+        for _ in 0..count {
+            if payload.remaining() < 4 {
+                return Err(ProtocolError::InvalidPacket(
+                    "Invalid batch entry length".to_string(),
+                ));
+            }
+            let len = payload.get_u32() as usize;
+            if payload.remaining() < len {
+                return Err(ProtocolError::InvalidPacket(
+                    "Invalid batch entry data".to_string(),
+                ));
+            }
+            let packet = Packet::deserialize_with_limits(payload.split_to(len), limits)?;
+            if packet.packet_type == PacketType::Batch {
+                return Err(ProtocolError::InvalidPacket(
+                    "Batches cannot be nested".to_string(),
+                ));
+            }
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+
+    /// Time since the sender stamped the packet, by the local clock
+    ///
+    /// Zero if the timestamp is ahead of the local clock.
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(Self::current_timestamp().saturating_sub(self.timestamp))
     }
 
     /// Get current timestamp in milliseconds
+    ///
+    /// Zero if the clock is set before the Unix epoch.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
     fn current_timestamp() -> u64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
     }
 
-    /// Serialize packet to bytes
-    pub fn serialize(&self) -> Result<Bytes> {
-        let route_bytes = self.route.as_bytes();
-        let route_len = route_bytes.len() as u16;
-        let payload_len = self.payload.len() as u32;
+    /// Get current timestamp in milliseconds
+    ///
+    /// There is no clock without `std`, so packets are left unstamped.
+    #[cfg(all(not(feature = "std"), not(target_arch = "wasm32")))]
+    fn current_timestamp() -> u64 {
+        0
+    }
+
+    /// Get current timestamp in milliseconds
+    ///
+    /// `SystemTime` is unavailable in the browser, so ask JS for the time.
+    #[cfg(target_arch = "wasm32")]
+    fn current_timestamp() -> u64 {
+        js_sys::Date::now() as u64
+    }
+
+    /// Size of the packet once serialized
+    pub fn encoded_len(&self) -> usize {
+        if self.version == FIXED_WIDTH_VERSION {
+            return 1 + // version
+                1 + // packet_type
+                1 + // flags
+                4 + // sequence
+                8 + // timestamp
+                2 + // route_len
+                self.route.len() +
+                4 + // payload_len
+                self.payload.len();
+        }
 
-        // Calculate total size
-        let total_size = 1 + // version
+        let (channel_len, reply_len) = match (self.version, self.reply_to) {
+            (VARINT_VERSION, _) => (0, 0),
+            (_, Some(request)) => (varint_len(self.channel_id as u32), varint_len(request)),
+            (_, None) => (varint_len(self.channel_id as u32), 0),
+        };
+        1 + // version
             1 + // packet_type
             1 + // flags
-            4 + // sequence
+            channel_len +
+            varint_len(self.sequence) +
+            reply_len +
             8 + // timestamp
-            2 + // route_len
-            route_len as usize +
-            4 + // payload_len
-            payload_len as usize;
+            varint_len(self.route.len() as u32) +
+            self.route.len() +
+            varint_len(self.payload.len() as u32) +
+            self.payload.len()
+    }
+
+    /// Serialize packet to bytes
+    ///
+    /// Written in the wire format of the packet's `version`: fixed-width
+    /// fields for version 1, varint sequence and lengths otherwise, and a
+    /// channel id and the sequence a reply answers from version 3 on.
+    pub fn serialize(&self) -> Result<Bytes> {
+        check_version(self.version)?;
+        if self.version != PROTOCOL_VERSION && self.channel_id != DEFAULT_CHANNEL {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Channel {} needs protocol version {}",
+                self.channel_id, PROTOCOL_VERSION
+            )));
+        }
+        let route_bytes = self.route.as_bytes();
 
-        let mut buf = BytesMut::with_capacity(total_size);
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
 
         // Write header
+        let reply_to = self.reply_to.filter(|_| self.version == PROTOCOL_VERSION);
         buf.put_u8(self.version);
-        buf.put_u8(self.packet_type as u8);
+        buf.put_u8(match reply_to {
+            Some(_) => self.packet_type as u8 | REPLY_TYPE_BIT,
+            None => self.packet_type as u8,
+        });
         buf.put_u8(self.flags.to_byte());
-        buf.put_u32(self.sequence);
-        buf.put_u64(self.timestamp);
 
-        // Write route
-        buf.put_u16(route_len);
-        buf.put_slice(route_bytes);
-
-        // Write payload
-        buf.put_u32(payload_len);
+        if self.version == FIXED_WIDTH_VERSION {
+            buf.put_u32(self.sequence);
+            buf.put_u64(self.timestamp);
+            buf.put_u16(route_bytes.len() as u16);
+            buf.put_slice(route_bytes);
+            buf.put_u32(self.payload.len() as u32);
+        } else {
+            if self.version == PROTOCOL_VERSION {
+                put_varint(&mut buf, self.channel_id as u32);
+            }
+            put_varint(&mut buf, self.sequence);
+            if let Some(request) = reply_to {
+                put_varint(&mut buf, request);
+            }
+            buf.put_u64(self.timestamp);
+            put_varint(&mut buf, route_bytes.len() as u32);
+            buf.put_slice(route_bytes);
+            put_varint(&mut buf, self.payload.len() as u32);
+        }
         buf.put_slice(&self.payload);
 
         Ok(buf.freeze())
     }
 
     /// Deserialize packet from bytes
-    pub fn deserialize(mut data: Bytes) -> Result<Self> {
-        if data.remaining() < 20 {
+    ///
+    /// The payload is a slice of `data` rather than a copy.
+    pub fn deserialize(data: Bytes) -> Result<Self> {
+        Self::deserialize_with_limits(data, &PacketLimits::NONE)
+    }
+
+    /// Deserialize packet from bytes, rejecting routes or payloads over `limits`
+    ///
+    /// Lengths are checked as soon as they are read, before anything is
+    /// allocated for the field.
+    pub fn deserialize_with_limits(mut data: Bytes, limits: &PacketLimits) -> Result<Self> {
+        if data.remaining() < 3 {
             return Err(ProtocolError::InvalidPacket(
                 "Packet too small".to_string(),
             ));
@@ -216,48 +1142,98 @@ impl Packet {
 
         // Read header
         let version = data.get_u8();
-        if version != PROTOCOL_VERSION {
-            return Err(ProtocolError::VersionMismatch {
-                expected: PROTOCOL_VERSION,
-                actual: version,
-            });
-        }
+        check_version(version)?;
 
-        let packet_type = PacketType::try_from(data.get_u8())?;
+        let type_byte = data.get_u8();
+        let is_reply = type_byte & REPLY_TYPE_BIT != 0;
+        if is_reply && version != PROTOCOL_VERSION {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Reply marker needs protocol version {}",
+                PROTOCOL_VERSION
+            )));
+        }
+        let packet_type = PacketType::try_from(type_byte & !REPLY_TYPE_BIT)?;
         let flags = PacketFlags::from_byte(data.get_u8());
-        let sequence = data.get_u32();
-        let timestamp = data.get_u64();
+
+        let channel_id = if version == PROTOCOL_VERSION {
+            u16::try_from(get_varint(&mut data, "channel id")?).map_err(|_| {
+                ProtocolError::InvalidPacket("Invalid channel id".to_string())
+            })?
+        } else {
+            DEFAULT_CHANNEL
+        };
+
+        let (sequence, reply_to, timestamp, route_len) = if version == FIXED_WIDTH_VERSION {
+            if data.remaining() < 14 {
+                return Err(ProtocolError::InvalidPacket(
+                    "Packet too small".to_string(),
+                ));
+            }
+            (data.get_u32(), None, data.get_u64(), data.get_u16() as usize)
+        } else {
+            let sequence = get_varint(&mut data, "sequence")?;
+            let reply_to = if is_reply {
+                Some(get_varint(&mut data, "reply sequence")?)
+            } else {
+                None
+            };
+            if data.remaining() < 8 {
+                return Err(ProtocolError::InvalidPacket(
+                    "Packet too small".to_string(),
+                ));
+            }
+            let timestamp = data.get_u64();
+            (sequence, reply_to, timestamp, get_varint(&mut data, "route length")? as usize)
+        };
 
         // Read route
-        let route_len = data.get_u16() as usize;
+        if route_len > limits.max_route_len {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Route length {} exceeds limit of {}",
+                route_len, limits.max_route_len
+            )));
+        }
         if data.remaining() < route_len {
             return Err(ProtocolError::InvalidPacket(
                 "Invalid route length".to_string(),
             ));
         }
-        let route_bytes = data.copy_to_bytes(route_len);
-        let route = String::from_utf8(route_bytes.to_vec())
-            .map_err(|e| ProtocolError::InvalidPacket(format!("Invalid route UTF-8: {}", e)))?;
+        let route = core::str::from_utf8(&data[..route_len])
+            .map_err(|e| ProtocolError::InvalidPacket(format!("Invalid route UTF-8: {}", e)))?
+            .to_owned();
+        data.advance(route_len);
 
         // Read payload
-        if data.remaining() < 4 {
-            return Err(ProtocolError::InvalidPacket(
-                "Invalid payload length".to_string(),
-            ));
+        let payload_len = if version == FIXED_WIDTH_VERSION {
+            if data.remaining() < 4 {
+                return Err(ProtocolError::InvalidPacket(
+                    "Invalid payload length".to_string(),
+                ));
+            }
+            data.get_u32() as usize
+        } else {
+            get_varint(&mut data, "payload length")? as usize
+        };
+        if payload_len > limits.max_payload_len {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Payload length {} exceeds limit of {}",
+                payload_len, limits.max_payload_len
+            )));
         }
-        let payload_len = data.get_u32() as usize;
         if data.remaining() < payload_len {
             return Err(ProtocolError::InvalidPacket(
                 "Invalid payload data".to_string(),
             ));
         }
-        let payload = data.copy_to_bytes(payload_len);
+        let payload = data.split_to(payload_len);
 
         Ok(Self {
             version,
             packet_type,
             flags,
+            channel_id,
             sequence,
+            reply_to,
             timestamp,
             route,
             payload,
@@ -265,6 +1241,17 @@ impl Packet {
     }
 }
 
+/// Reject wire format versions this build cannot read or write
+fn check_version(version: u8) -> Result<()> {
+    if matches!(version, PROTOCOL_VERSION | VARINT_VERSION | FIXED_WIDTH_VERSION) {
+        return Ok(());
+    }
+    Err(ProtocolError::VersionMismatch {
+        expected: PROTOCOL_VERSION,
+        actual: version,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,5 +1273,300 @@ mod tests {
         assert_eq!(packet.route, deserialized.route);
         assert_eq!(packet.payload, deserialized.payload);
     }
+
+    #[test]
+    fn test_fragment_roundtrip() {
+        let header = FragmentHeader {
+            fragment_id: 7,
+            fragment_index: 2,
+            fragment_count: 3,
+        };
+        let packet = Packet::new_fragment(header, b"chunk");
+
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        let (parsed, chunk) = deserialized.fragment_parts().unwrap();
+
+        assert_eq!(parsed, header);
+        assert_eq!(&chunk[..], b"chunk");
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let packets = vec![
+            Packet::new_data("/a".to_string(), Bytes::from("one"), 1),
+            Packet::new_ack(9),
+            Packet::new_data("/b".to_string(), Bytes::from("two"), 2),
+        ];
+
+        let batch = Packet::new_batch(&packets).unwrap();
+        let deserialized = Packet::deserialize(batch.serialize().unwrap()).unwrap();
+        let split = deserialized.split_batch().unwrap();
+
+        assert_eq!(split.len(), 3);
+        assert_eq!(split[0].route, "/a");
+        assert_eq!(split[1].packet_type, PacketType::Ack);
+        assert_eq!(split[1].sequence, 9);
+        assert_eq!(split[2].payload, Bytes::from("two"));
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let header = StreamHeader {
+            stream_id: 12,
+            index: 3,
+            end: true,
+            error: false,
+        };
+        let packet = Packet::new_stream("/s".to_string(), header, b"chunk");
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+
+        let (parsed, chunk) = deserialized.stream_parts().unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(chunk, Bytes::from("chunk"));
+        assert!(deserialized.flags.requires_ack);
+    }
+
+    #[test]
+    fn test_varint_header_is_smaller_and_reads_version_1() {
+        let mut packet = Packet::new_ack(5);
+        let compact = packet.serialize().unwrap();
+        packet.version = FIXED_WIDTH_VERSION;
+        let fixed = packet.serialize().unwrap();
+        assert_eq!(compact.len(), 15);
+        assert_eq!(fixed.len(), 21);
+
+        let legacy = Packet::deserialize(fixed).unwrap();
+        assert_eq!(legacy.version, FIXED_WIDTH_VERSION);
+        assert_eq!(legacy.sequence, 5);
+        assert_eq!(legacy.encoded_len(), 21);
+
+        assert!(matches!(
+            Packet::deserialize(Bytes::from_static(&[9, 0, 0])),
+            Err(ProtocolError::VersionMismatch { actual: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn test_channel_id_roundtrip_and_older_versions() {
+        let mut packet = Packet::new_data("/bulk".to_string(), Bytes::from("x"), 3);
+        packet.channel_id = 300;
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.channel_id, 300);
+        assert_eq!(deserialized.sequence, 3);
+
+        // Older formats have no room for a channel id
+        packet.version = VARINT_VERSION;
+        assert!(packet.serialize().is_err());
+        packet.channel_id = DEFAULT_CHANNEL;
+        let legacy = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        assert_eq!(legacy.version, VARINT_VERSION);
+        assert_eq!(legacy.channel_id, DEFAULT_CHANNEL);
+        assert_eq!(legacy.route, "/bulk");
+    }
+
+    #[test]
+    fn test_error_reply_roundtrip() {
+        for code in [
+            RemoteErrorCode::HandlerError,
+            RemoteErrorCode::HandlerPanic,
+            RemoteErrorCode::Timeout,
+            RemoteErrorCode::Unauthenticated,
+            RemoteErrorCode::Busy,
+            RemoteErrorCode::Unknown(900),
+        ] {
+            let packet = Packet::new_error("/r".to_string(), code, "went wrong");
+            let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+            assert_eq!(deserialized.packet_type, PacketType::Error);
+            assert!(matches!(
+                deserialized.remote_error().unwrap(),
+                ProtocolError::Remote { code: c, message } if c == code && message == "went wrong"
+            ));
+        }
+
+        let mut packet = Packet::new_error("/r".to_string(), RemoteErrorCode::RouteNotFound, "no route");
+        packet.reply_to = Some(42);
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.reply_to, Some(42));
+        assert_eq!(deserialized.answered_sequence(), Some(42));
+        assert!(matches!(deserialized.remote_error().unwrap(), ProtocolError::RouteNotFound(route) if route == "/r"));
+
+        // Older formats drop `reply_to`, and answer no request
+        packet.version = VARINT_VERSION;
+        packet.sequence = 42;
+        let legacy = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        assert_eq!(legacy.reply_to, None);
+        assert_eq!(legacy.answered_sequence(), None);
+        assert!(Packet::new_ack(1).remote_error().is_err());
+    }
+
+    #[test]
+    fn test_connect_parts() {
+        let key = [7u8; 32];
+        let packet = Packet::new_connect_with(&key, b"secret");
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        let (public_key, token) = deserialized.connect_parts().unwrap();
+        assert_eq!(&public_key[..], &key);
+        assert_eq!(&token[..], b"secret");
+
+        // Older clients send the bare key
+        let mut legacy = Packet::new_connect();
+        legacy.version = VARINT_VERSION;
+        legacy.payload = Bytes::copy_from_slice(&key);
+        let (public_key, token) = legacy.connect_parts().unwrap();
+        assert_eq!(&public_key[..], &key);
+        assert!(token.is_empty());
+
+        let mut truncated = Packet::new_connect();
+        truncated.payload = Bytes::from_static(&[32, 1, 2]);
+        assert!(truncated.connect_parts().is_err());
+    }
+
+    #[test]
+    fn test_connect_early_data_roundtrip() {
+        let packet = Packet::new_connect_with_early_data(&[], b"secret", "/echo".to_string(), b"hi");
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        let (public_key, token) = deserialized.connect_parts().unwrap();
+        assert!(public_key.is_empty());
+        assert_eq!(&token[..], b"secret");
+        assert_eq!(deserialized.connect_early_data().unwrap().unwrap(), Bytes::from("hi"));
+        assert_eq!(Packet::new_connect_with(&[], b"secret").connect_early_data().unwrap(), None);
+
+        let reply = Packet::new_error("/echo".to_string(), RemoteErrorCode::Busy, "later");
+        let ack = Packet::new_connect_ack_with_reply(reply);
+        let ack = Packet::deserialize(ack.serialize().unwrap()).unwrap();
+        let reply = ack.early_reply().unwrap().unwrap();
+        assert_eq!(reply.route, "/echo");
+        assert!(matches!(
+            reply.remote_error().unwrap(),
+            ProtocolError::Remote { code: RemoteErrorCode::Busy, message } if message == "later"
+        ));
+        assert!(Packet::new_connect_ack(Bytes::new()).early_reply().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_lengths_are_rejected() {
+        let limits = PacketLimits {
+            max_route_len: 16,
+            max_payload_len: 64,
+        };
+        let header = |buf: &mut BytesMut| {
+            buf.put_u8(PROTOCOL_VERSION);
+            buf.put_u8(PacketType::Data as u8);
+            buf.put_u8(0);
+            put_varint(buf, 0);
+            put_varint(buf, 1);
+            buf.put_u64(0);
+        };
+
+        // A megabyte route claimed by a tiny datagram
+        let mut buf = BytesMut::new();
+        header(&mut buf);
+        put_varint(&mut buf, 1 << 20);
+        let err = Packet::deserialize_with_limits(buf.freeze(), &limits).unwrap_err();
+        assert!(matches!(err, ProtocolError::InvalidPacket(msg) if msg == "Route length 1048576 exceeds limit of 16"));
+
+        // A short route followed by a 4 GB payload length
+        let mut buf = BytesMut::new();
+        header(&mut buf);
+        put_varint(&mut buf, 5);
+        buf.put_slice(b"/test");
+        put_varint(&mut buf, u32::MAX);
+        let err = Packet::deserialize_with_limits(buf.freeze(), &limits).unwrap_err();
+        assert!(matches!(err, ProtocolError::InvalidPacket(msg) if msg == "Payload length 4294967295 exceeds limit of 64"));
+
+        let packet = Packet::new_data("/test".to_string(), Bytes::from(vec![0u8; 64]), 1);
+        assert!(Packet::deserialize_with_limits(packet.serialize().unwrap(), &limits).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_bincode_payload_roundtrip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Handshake {
+            name: String,
+            public_key: Vec<u8>,
+            window: u32,
+        }
+
+        let handshake = Handshake {
+            name: "node-1".to_string(),
+            public_key: vec![7; 32],
+            window: 64,
+        };
+        let packet = Packet {
+            payload: Packet::serialize_bincode(&handshake).unwrap(),
+            ..Packet::new_connect()
+        };
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.deserialize_bincode::<Handshake>().unwrap(), handshake);
+
+        // A length prefix claiming far more than the payload holds is refused
+        let hostile = Packet {
+            payload: Packet::serialize_bincode(&u64::MAX).unwrap(),
+            ..Packet::new_connect()
+        };
+        let err = hostile.deserialize_bincode::<Vec<u8>>().unwrap_err();
+        assert!(matches!(err, ProtocolError::Serialization(_)));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    proptest::proptest! {
+        #[test]
+        fn prop_random_packets_roundtrip(
+            version in proptest::sample::select(vec![FIXED_WIDTH_VERSION, VARINT_VERSION, PROTOCOL_VERSION]),
+            packet_type in 0u8..=15,
+            flags in proptest::num::u8::ANY,
+            channel_id in proptest::num::u16::ANY,
+            sequence in proptest::num::u32::ANY,
+            reply_to in proptest::option::of(proptest::num::u32::ANY),
+            timestamp in proptest::num::u64::ANY,
+            route in ".{0,64}",
+            payload in proptest::collection::vec(proptest::num::u8::ANY, 0..20_000),
+        ) {
+            let packet = Packet {
+                version,
+                packet_type: PacketType::try_from(packet_type).unwrap(),
+                flags: PacketFlags::from_byte(flags),
+                channel_id: if version == PROTOCOL_VERSION { channel_id } else { DEFAULT_CHANNEL },
+                sequence,
+                reply_to: if version == PROTOCOL_VERSION { reply_to } else { None },
+                timestamp,
+                route,
+                payload: Bytes::from(payload),
+            };
+
+            let serialized = packet.serialize().unwrap();
+            proptest::prop_assert_eq!(serialized.len(), packet.encoded_len());
+
+            let deserialized = Packet::deserialize(serialized).unwrap();
+            proptest::prop_assert_eq!(deserialized.version, packet.version);
+            proptest::prop_assert_eq!(deserialized.packet_type, packet.packet_type);
+            proptest::prop_assert_eq!(deserialized.flags.to_byte(), packet.flags.to_byte());
+            proptest::prop_assert_eq!(deserialized.channel_id, packet.channel_id);
+            proptest::prop_assert_eq!(deserialized.sequence, packet.sequence);
+            proptest::prop_assert_eq!(deserialized.reply_to, packet.reply_to);
+            proptest::prop_assert_eq!(deserialized.timestamp, packet.timestamp);
+            proptest::prop_assert_eq!(deserialized.route, packet.route);
+            proptest::prop_assert_eq!(deserialized.payload, packet.payload);
+        }
+    }
 }
 
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm_tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    #[wasm_bindgen_test]
+    fn test_serialization_roundtrip_on_wasm() {
+        let packet = Packet::new_data("/echo".to_string(), Bytes::from_static(b"\0binary\xff"), 9);
+
+        let deserialized = Packet::deserialize(packet.serialize().unwrap()).unwrap();
+
+        assert_eq!(deserialized.packet_type, PacketType::Data);
+        assert_eq!(deserialized.sequence, 9);
+        assert_eq!(deserialized.timestamp, packet.timestamp);
+        assert_eq!(deserialized.route, "/echo");
+        assert_eq!(deserialized.payload, packet.payload);
+    }
+}
@@ -0,0 +1,211 @@
+//! Peer implementation, serving routes and making requests on one socket
+
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+use tracing::trace;
+
+use crate::packet::DEFAULT_CHANNEL;
+use crate::server::Server;
+use crate::transport::TransportConfig;
+use crate::error::*;
+
+/// Endpoint that both answers requests and sends them, for meshes where either side initiates
+///
+/// A peer is a `Server`, and everything for serving routes, such as
+/// registering handlers and middleware, is reached through it. On top of
+/// that it can send requests to any other peer from the same socket. A
+/// reply is matched to its request by peer, channel and sequence; every
+/// other packet is handled as a request, so `listen` must be running for
+/// replies to arrive.
+pub struct Peer {
+    server: Arc<Server>,
+    request_timeout: Duration,
+}
+
+impl Peer {
+    /// Create a new peer
+    pub async fn new(addr: impl Into<SocketAddr>, config: TransportConfig) -> Result<Self> {
+        let server = Server::new(addr, config).await?;
+        Ok(Self::from_server(server))
+    }
+
+    /// Turn a configured server into a peer that can also make requests
+    pub fn from_server(server: Server) -> Self {
+        Self {
+            server: Arc::new(server),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Start serving requests and receiving replies
+    pub async fn listen(&self) -> Result<()> {
+        self.server.clone().listen().await
+    }
+
+    /// Send a request to another peer and wait for the response
+    pub async fn request(
+        &self,
+        dest: SocketAddr,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<Bytes> {
+        self.request_on(dest, DEFAULT_CHANNEL, route, payload).await
+    }
+
+    /// Send a request to another peer on a channel and wait for the response
+    pub async fn request_on(
+        &self,
+        dest: SocketAddr,
+        channel: u16,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<Bytes> {
+        let route = route.into();
+        trace!("Sending request to {} on route: {} on channel {}", dest, route, channel);
+
+        let (key, rx) = self.server.send_request(dest, channel, route, payload).await?;
+        match timeout(self.request_timeout, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(ProtocolError::ConnectionClosed),
+            Err(_) => {
+                self.server.forget_request(key).await;
+                Err(ProtocolError::Timeout)
+            }
+        }
+    }
+
+    /// Set request timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+}
+
+impl Deref for Peer {
+    type Target = Server;
+
+    fn deref(&self) -> &Server {
+        &self.server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Response;
+
+    async fn start_peer(name: &'static str) -> Arc<Peer> {
+        let peer = Arc::new(
+            Peer::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        peer.on_fn("/name", move |_ctx| Ok(Response::text(name))).await;
+        tokio::spawn({
+            let peer = peer.clone();
+            async move { peer.listen().await }
+        });
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_peers_call_each_other() {
+        let alice = start_peer("alice").await;
+        let bob = start_peer("bob").await;
+        let alice_addr = alice.local_addr().unwrap();
+        let bob_addr = bob.local_addr().unwrap();
+
+        let reply = alice.request(bob_addr, "/name", Bytes::new()).await.unwrap();
+        assert_eq!(reply, Bytes::from("bob"));
+
+        let reply = bob.request(alice_addr, "/name", Bytes::new()).await.unwrap();
+        assert_eq!(reply, Bytes::from("alice"));
+
+        let err = bob.request(alice_addr, "/missing", Bytes::new()).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::RouteNotFound(route) if route == "/missing"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_survive_heavy_loss() {
+        use crate::clock::SeededRng;
+        use crate::impairment::{ImpairedTransport, ImpairmentConfig};
+        use crate::server::Server;
+        use crate::transport::{Transport, UdpTransport};
+
+        let impairment = ImpairmentConfig {
+            loss: 0.3,
+            duplicate: 0.1,
+            reorder: 0.1,
+            latency_jitter: Duration::from_millis(5),
+        };
+        let config = TransportConfig {
+            max_retransmit: 15,
+            ack_timeout: Duration::from_millis(50),
+            min_rto: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut peers = Vec::new();
+        for (seed, name) in [(1, "alice"), (2, "bob")] {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let link = UdpTransport::new(socket).unwrap();
+            let link = ImpairedTransport::new(link, impairment, SeededRng::new(seed)).unwrap();
+            let server = Server::with_transport(Transport::over(link, config.clone()));
+            let peer = Arc::new(Peer::from_server(server));
+            peer.on_fn("/name", move |_ctx| Ok(Response::text(name))).await;
+            tokio::spawn({
+                let peer = peer.clone();
+                async move { peer.listen().await }
+            });
+            peers.push(peer);
+        }
+
+        // Requests, ACKs and replies are each lost 30% of the time
+        let bob_addr = peers[1].local_addr().unwrap();
+        for _ in 0..10 {
+            let reply = peers[0].request(bob_addr, "/name", Bytes::new()).await.unwrap();
+            assert_eq!(reply, Bytes::from("bob"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_old_version_push_is_not_taken_as_a_reply() {
+        use crate::packet::{Packet, PacketType, VARINT_VERSION};
+        use tokio::net::UdpSocket;
+
+        async fn next_data(socket: &UdpSocket, route: &str) -> Packet {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let len = socket.recv(&mut buf).await.unwrap();
+                let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+                if packet.packet_type == PacketType::Data && packet.route == route {
+                    return packet;
+                }
+            }
+        }
+
+        let alice = start_peer("alice").await;
+        let alice_addr = alice.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = socket.local_addr().unwrap();
+
+        let request = tokio::spawn({
+            let alice = alice.clone();
+            async move { alice.request(remote_addr, "/work", Bytes::new()).await }
+        });
+        let sequence = next_data(&socket, "/work").await.sequence;
+
+        // A v2 packet reusing the request's sequence is a request of its own
+        let mut push = Packet::new_data("/name".to_string(), Bytes::new(), sequence);
+        push.version = VARINT_VERSION;
+        socket.send_to(&push.serialize().unwrap(), alice_addr).await.unwrap();
+        assert_eq!(next_data(&socket, "/name").await.payload, Bytes::from("alice"));
+        assert!(!request.is_finished());
+
+        let mut reply = Packet::new_data("/work".to_string(), Bytes::from("done"), sequence + 1);
+        reply.reply_to = Some(sequence);
+        socket.send_to(&reply.serialize().unwrap(), alice_addr).await.unwrap();
+        assert_eq!(request.await.unwrap().unwrap(), Bytes::from("done"));
+    }
+}
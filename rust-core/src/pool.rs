@@ -0,0 +1,104 @@
+//! Receive buffer pool
+
+use bytes::BytesMut;
+use std::collections::VecDeque;
+
+/// Room left in a block before each datagram is read
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Datagrams of the path MTU that fit in a block besides the read room
+const BLOCK_DATAGRAMS: usize = 64;
+
+/// Largest block allocated, however large the path MTU
+const MAX_BLOCK_SIZE: usize = 16 * MAX_DATAGRAM_SIZE;
+
+/// Retired blocks kept until the packets carved from them are dropped
+const MAX_RETIRED: usize = 8;
+
+/// Blocks that received datagrams are carved out of
+///
+/// Each datagram is read into the current block and split off it, so packet
+/// payloads are slices of the block rather than copies. A block that runs low
+/// on room is retired, and reused once every packet carved from it has been
+/// dropped. Blocks are sized to hold `BLOCK_DATAGRAMS` datagrams of the
+/// largest path MTU seen.
+pub(crate) struct RecvPool {
+    current: BytesMut,
+    retired: VecDeque<BytesMut>,
+}
+
+impl RecvPool {
+    pub(crate) fn new() -> Self {
+        Self {
+            current: BytesMut::new(),
+            retired: VecDeque::new(),
+        }
+    }
+
+    /// Buffer with room for the next datagram
+    ///
+    /// Split the datagram off the front of the returned buffer once it is read.
+    pub(crate) fn buffer(&mut self, mtu: usize) -> &mut BytesMut {
+        if self.current.capacity() < MAX_DATAGRAM_SIZE {
+            let block = self.take_block(block_size(mtu));
+            let full = std::mem::replace(&mut self.current, block);
+            if full.capacity() > 0 {
+                if self.retired.len() == MAX_RETIRED {
+                    self.retired.pop_front();
+                }
+                self.retired.push_back(full);
+            }
+        }
+        &mut self.current
+    }
+
+    /// Reuse a retired block nothing points into any more, or allocate one
+    fn take_block(&mut self, size: usize) -> BytesMut {
+        let reusable = self
+            .retired
+            .iter_mut()
+            .position(|block| block.try_reclaim(size));
+        match reusable {
+            Some(index) => self.retired.remove(index).expect("index is in range"),
+            None => BytesMut::with_capacity(size),
+        }
+    }
+}
+
+/// Block size for a path MTU
+fn block_size(mtu: usize) -> usize {
+    (MAX_DATAGRAM_SIZE + BLOCK_DATAGRAMS * mtu).min(MAX_BLOCK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    /// Carve `len` bytes off the pool as a received datagram would be
+    fn receive(pool: &mut RecvPool, mtu: usize, len: usize) -> bytes::Bytes {
+        let buf = pool.buffer(mtu);
+        buf.put_bytes(7, len);
+        buf.split().freeze()
+    }
+
+    #[test]
+    fn test_blocks_are_reused_once_packets_drop() {
+        let mut pool = RecvPool::new();
+        let first = receive(&mut pool, 1200, 1200);
+        let block = first.as_ptr();
+
+        // Fill the first block while holding on to its packets
+        let held: Vec<_> = (0..BLOCK_DATAGRAMS).map(|_| receive(&mut pool, 1200, 1200)).collect();
+        let second = receive(&mut pool, 1200, 1200);
+        assert_ne!(second.as_ptr(), block);
+
+        // Once they are gone the first block comes back instead of a new one
+        drop((first, held));
+        let mut reused = false;
+        for _ in 0..2 * BLOCK_DATAGRAMS {
+            reused |= receive(&mut pool, 1200, 1200).as_ptr() == block;
+        }
+        assert!(reused);
+    }
+}
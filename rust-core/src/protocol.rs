@@ -4,12 +4,25 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 /// Standard request format
+///
+/// The `id` is chosen by the caller and echoed in the `Response`, so a
+/// request can be traced end to end independently of transport sequences.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request<T> {
     pub id: String,
     pub data: T,
 }
 
+impl<T> Request<T> {
+    /// Wrap `data` in a request with a fresh random id
+    pub fn new(data: T) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            data,
+        }
+    }
+}
+
 /// Standard response format
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Response<T> {
@@ -0,0 +1,350 @@
+//! QUIC transport, as an alternative to raw UDP
+//!
+//! `QuicTransport` carries `Transport`'s datagrams over QUIC connections
+//! made with `quinn`, as a `PacketTransport`. A datagram that fits the
+//! connection's datagram limit goes as a QUIC datagram; a larger one goes on
+//! a unidirectional stream of its own. Browsers reach the same kind of
+//! endpoint through WebTransport, which runs on QUIC.
+//!
+//! `Server` and `Client` run over it through `Transport::over` and
+//! `with_transport`, keeping their own ACKs and session encryption on top.
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use quinn::rustls::RootCertStore;
+use quinn::{
+    ClientConfig, ConnectError, Connection, ConnectionError, Endpoint, SendDatagramError, ServerConfig, VarInt,
+    WriteError,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::{debug, warn};
+
+use crate::error::*;
+use crate::tasks::TaskSet;
+use crate::transport::PacketTransport;
+use crate::MAX_PACKET_SIZE;
+
+/// Certificate chain and private key a QUIC server presents
+pub struct Identity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Generate a self-signed certificate for `names`, for tests and development
+    ///
+    /// Clients have to be told to trust it, see `QuicTransport::client`.
+    pub fn self_signed(names: Vec<String>) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(names).map_err(tls_error)?;
+        Ok(Self {
+            cert_chain: vec![certified.cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der())),
+        })
+    }
+}
+
+/// State shared with the tasks reading connections
+struct Shared {
+    /// Open connections by the peer's address
+    connections: RwLock<HashMap<SocketAddr, Connection>>,
+    /// Where datagrams read from any connection go
+    inbox: mpsc::UnboundedSender<(Bytes, SocketAddr)>,
+    /// Accept loop and connection readers, stopped when the transport is dropped
+    tasks: TaskSet,
+}
+
+impl Shared {
+    /// Start reading datagrams from a new connection
+    async fn track(self: &Arc<Self>, connection: Connection) {
+        debug!("QUIC connection to {} established", connection.remote_address());
+        self.connections
+            .write()
+            .await
+            .insert(connection.remote_address(), connection.clone());
+        self.tasks.spawn(read_connection(Arc::downgrade(self), connection));
+    }
+}
+
+/// Datagram transport over QUIC connections
+///
+/// A server accepts connections on its own; a client opens one per server
+/// with `connect`. Datagrams can only be sent to a peer with an open connection.
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    shared: Arc<Shared>,
+    inbox: Mutex<mpsc::UnboundedReceiver<(Bytes, SocketAddr)>>,
+}
+
+impl QuicTransport {
+    /// Listen on `addr`, presenting `identity` to connecting clients
+    pub async fn server(addr: impl Into<SocketAddr>, identity: Identity) -> Result<Self> {
+        let config = ServerConfig::with_single_cert(identity.cert_chain, identity.key).map_err(tls_error)?;
+        let endpoint = Endpoint::server(config, addr.into())?;
+        let transport = Self::new(endpoint.clone());
+        transport
+            .shared
+            .tasks
+            .spawn(accept_connections(endpoint, Arc::downgrade(&transport.shared)));
+        Ok(transport)
+    }
+
+    /// Bind a client on `addr` that trusts servers certified by `roots`
+    pub async fn client(addr: impl Into<SocketAddr>, roots: &[CertificateDer<'static>]) -> Result<Self> {
+        let mut store = RootCertStore::empty();
+        for cert in roots {
+            store.add(cert.clone()).map_err(tls_error)?;
+        }
+        let config = ClientConfig::with_root_certificates(Arc::new(store)).map_err(tls_error)?;
+        let mut endpoint = Endpoint::client(addr.into())?;
+        endpoint.set_default_client_config(config);
+        Ok(Self::new(endpoint))
+    }
+
+    fn new(endpoint: Endpoint) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            endpoint,
+            shared: Arc::new(Shared {
+                connections: RwLock::new(HashMap::new()),
+                inbox: tx,
+                tasks: TaskSet::default(),
+            }),
+            inbox: Mutex::new(rx),
+        }
+    }
+
+    /// Open a connection to the server at `dest`, whose certificate names `server_name`
+    pub async fn connect(&self, dest: SocketAddr, server_name: &str) -> Result<()> {
+        let connection = self
+            .endpoint
+            .connect(dest, server_name)
+            .map_err(connect_error)?
+            .await
+            .map_err(connection_error)?;
+        self.shared.track(connection).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PacketTransport for QuicTransport {
+    /// Send a datagram on the connection to `dest`
+    async fn send_to(&self, datagram: &[u8], dest: SocketAddr) -> Result<()> {
+        let connection = self
+            .shared
+            .connections
+            .read()
+            .await
+            .get(&dest)
+            .cloned()
+            .ok_or_else(|| ProtocolError::ConnectionNotFound(dest.to_string()))?;
+        if connection.max_datagram_size().is_some_and(|max| datagram.len() <= max) {
+            return connection
+                .send_datagram(Bytes::copy_from_slice(datagram))
+                .map_err(send_datagram_error);
+        }
+        let mut stream = connection.open_uni().await.map_err(connection_error)?;
+        stream.write_all(datagram).await.map_err(write_error)?;
+        stream
+            .finish()
+            .map_err(|e| ProtocolError::Stream(format!("QUIC stream to {}: {}", dest, e)))?;
+        Ok(())
+    }
+
+    /// Take the next datagram read from any connection
+    ///
+    /// Unlike a UDP socket, the whole datagram is always at hand, so one
+    /// longer than `limit` has only `limit` bytes appended but its full
+    /// length returned, telling the caller it was cut short.
+    async fn recv_from(&self, buf: &mut BytesMut, limit: usize) -> Result<(usize, SocketAddr)> {
+        let (data, addr) = self.inbox.lock().await.recv().await.ok_or(ProtocolError::ConnectionClosed)?;
+        buf.put_slice(&data[..data.len().min(limit)]);
+        Ok((data.len(), addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.endpoint.local_addr().map_err(Into::into)
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.endpoint.close(VarInt::from_u32(0), b"");
+    }
+}
+
+/// Accept incoming connections until the endpoint closes
+async fn accept_connections(endpoint: Endpoint, shared: Weak<Shared>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let Some(tasks) = shared.upgrade() else {
+            break;
+        };
+        // Handshakes run on their own, so a slow one holds up no other
+        let shared = shared.clone();
+        tasks.tasks.spawn(async move {
+            match incoming.await {
+                Ok(connection) => {
+                    if let Some(shared) = shared.upgrade() {
+                        shared.track(connection).await;
+                    }
+                }
+                Err(e) => debug!("QUIC handshake failed: {}", e),
+            }
+        });
+    }
+}
+
+/// Hand every datagram arriving on `connection` to the inbox until it closes
+async fn read_connection(shared: Weak<Shared>, connection: Connection) {
+    let addr = connection.remote_address();
+    loop {
+        let data = tokio::select! {
+            datagram = connection.read_datagram() => datagram,
+            stream = connection.accept_uni() => match stream {
+                Ok(mut stream) => match stream.read_to_end(MAX_PACKET_SIZE).await {
+                    Ok(data) => Ok(Bytes::from(data)),
+                    Err(e) => {
+                        warn!("Dropping unreadable stream from {}: {}", addr, e);
+                        continue;
+                    }
+                },
+                Err(e) => Err(e),
+            },
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("QUIC connection to {} closed: {}", addr, e);
+                break;
+            }
+        };
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let _ = shared.inbox.send((data, addr));
+    }
+
+    // Forget the connection, unless it has been replaced already
+    if let Some(shared) = shared.upgrade() {
+        let mut connections = shared.connections.write().await;
+        if connections.get(&addr).is_some_and(|c| c.stable_id() == connection.stable_id()) {
+            connections.remove(&addr);
+        }
+    }
+}
+
+/// Wrap a certificate or TLS configuration error from `rustls` or `rcgen`
+fn tls_error(e: impl std::fmt::Display) -> ProtocolError {
+    ProtocolError::Encryption(format!("QUIC TLS setup failed: {}", e))
+}
+
+/// Map a connection that could not be started
+fn connect_error(e: ConnectError) -> ProtocolError {
+    match e {
+        ConnectError::InvalidRemoteAddress(addr) => ProtocolError::InvalidAddress(addr.to_string()),
+        ConnectError::InvalidServerName(name) => ProtocolError::InvalidAddress(format!("Invalid server name: {}", name)),
+        ConnectError::EndpointStopping => ProtocolError::ConnectionClosed,
+        e => ProtocolError::Other(format!("QUIC connect failed: {}", e)),
+    }
+}
+
+/// Map a connection that failed or was lost
+fn connection_error(e: ConnectionError) -> ProtocolError {
+    match e {
+        ConnectionError::TimedOut => ProtocolError::Timeout,
+        ConnectionError::ApplicationClosed(_)
+        | ConnectionError::ConnectionClosed(_)
+        | ConnectionError::Reset
+        | ConnectionError::LocallyClosed => ProtocolError::ConnectionClosed,
+        ConnectionError::TransportError(e) => ProtocolError::InvalidPacket(format!("QUIC transport error: {}", e)),
+        e => ProtocolError::Other(format!("QUIC connection failed: {}", e)),
+    }
+}
+
+/// Map a datagram the connection would not take
+fn send_datagram_error(e: SendDatagramError) -> ProtocolError {
+    match e {
+        SendDatagramError::ConnectionLost(e) => connection_error(e),
+        SendDatagramError::TooLarge => ProtocolError::InvalidPacket("Datagram too large for the QUIC connection".to_string()),
+        e => ProtocolError::Other(format!("QUIC datagram not sent: {}", e)),
+    }
+}
+
+/// Map a failed write to a stream
+fn write_error(e: WriteError) -> ProtocolError {
+    match e {
+        WriteError::ConnectionLost(e) => connection_error(e),
+        e => ProtocolError::Stream(format!("QUIC stream write failed: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::middleware::Response;
+    use crate::server::Server;
+    use crate::transport::{Transport, TransportConfig};
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn test_datagrams_round_trip_over_quic() {
+        let identity = Identity::self_signed(vec!["localhost".to_string()]).unwrap();
+        let roots = identity.cert_chain.clone();
+        let server = QuicTransport::server(([127, 0, 0, 1], 0), identity).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = QuicTransport::client(([127, 0, 0, 1], 0), &roots).await.unwrap();
+        client.connect(server_addr, "localhost").await.unwrap();
+
+        // A small datagram goes as a QUIC datagram
+        client.send_to(b"hi", server_addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        let (len, from) = timeout(Duration::from_secs(2), server.recv_from(&mut buf, 64)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"hi");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        // One past the datagram limit goes on a stream; past `limit` it is cut
+        // short, but its full length is reported
+        let large = vec![7u8; 4096];
+        server.send_to(&large, from).await.unwrap();
+        let mut buf = BytesMut::new();
+        let (len, from) = timeout(Duration::from_secs(2), client.recv_from(&mut buf, 1024)).await.unwrap().unwrap();
+        assert_eq!(len, 4096);
+        assert_eq!(&buf[..], &large[..1024]);
+        assert_eq!(from, server_addr);
+
+        // Nothing can be sent to a peer without a connection
+        let stranger = "127.0.0.1:9".parse().unwrap();
+        assert!(matches!(client.send_to(b"", stranger).await, Err(ProtocolError::ConnectionNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_server_and_client_over_quic() {
+        let identity = Identity::self_signed(vec!["localhost".to_string()]).unwrap();
+        let roots = identity.cert_chain.clone();
+        let config = TransportConfig::default();
+
+        let link = QuicTransport::server(([127, 0, 0, 1], 0), identity).await.unwrap();
+        let server_addr = link.local_addr().unwrap();
+        let server = Arc::new(Server::with_transport(Transport::over(link, config.clone())));
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+
+        let link = QuicTransport::client(([127, 0, 0, 1], 0), &roots).await.unwrap();
+        link.connect(server_addr, "localhost").await.unwrap();
+        let client = Arc::new(Client::with_transport(Transport::over(link, config), server_addr));
+        timeout(Duration::from_secs(5), client.connect()).await.unwrap().unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let reply = timeout(Duration::from_secs(5), client.request("/echo", Bytes::from("over quic")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, Bytes::from("over quic"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Route table with path parameters
+
+use std::collections::HashMap;
+
+/// One segment of a route pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Must match exactly
+    Literal(String),
+    /// `:name` matches any single segment
+    Param(String),
+    /// `*name` matches the rest of the route, possibly empty
+    Wildcard(String),
+}
+
+/// A parsed route pattern such as `/user/:id/profile` or `/files/*path`
+#[derive(Debug, Clone)]
+struct RoutePattern {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    fn parse(route: &str) -> Self {
+        let segments = split(route)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+
+        Self {
+            raw: route.to_string(),
+            segments,
+        }
+    }
+
+    fn is_literal(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    /// Ordering key: patterns without a wildcard and with more literal segments are tried first
+    fn specificity(&self) -> (bool, usize) {
+        let has_wildcard = self
+            .segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Wildcard(_)));
+        let literals = self
+            .segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count();
+        (!has_wildcard, literals)
+    }
+
+    /// Match a route, returning the captured parameters
+    fn matches(&self, route: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = split(route).collect();
+        let mut params = HashMap::new();
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.get(index) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = parts.get(index)?;
+                    params.insert(name.clone(), value.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    let rest = parts.get(index..).unwrap_or_default().join("/");
+                    params.insert(name.clone(), rest);
+                    return Some(params);
+                }
+            }
+        }
+
+        (parts.len() == self.segments.len()).then_some(params)
+    }
+}
+
+fn split(route: &str) -> impl Iterator<Item = &str> {
+    route.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Maps routes to values, supporting `:param` and `*wildcard` segments
+///
+/// An exact match always wins; otherwise the most specific matching pattern
+/// is used.
+pub(crate) struct Router<T> {
+    exact: HashMap<String, T>,
+    patterns: Vec<(RoutePattern, T)>,
+}
+
+impl<T: Clone> Router<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Register a value for a route or route pattern, replacing any previous one
+    pub(crate) fn insert(&mut self, route: impl Into<String>, value: T) {
+        let route = route.into();
+        let pattern = RoutePattern::parse(&route);
+        if pattern.is_literal() {
+            self.exact.insert(route, value);
+            return;
+        }
+
+        self.patterns.retain(|(existing, _)| existing.raw != route);
+        self.patterns.push((pattern, value));
+        self.patterns
+            .sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.specificity()));
+    }
+
+    /// Remove a route or route pattern
+    pub(crate) fn remove(&mut self, route: &str) {
+        self.exact.remove(route);
+        self.patterns.retain(|(pattern, _)| pattern.raw != route);
+    }
+
+    /// Find the value for an incoming route along with any captured parameters
+    pub(crate) fn find(&self, route: &str) -> Option<(T, HashMap<String, String>)> {
+        self.find_matched(route).map(|(_, value, params)| (value, params))
+    }
+
+    /// Like `find`, also returning the registered route or pattern that matched
+    pub(crate) fn find_matched(&self, route: &str) -> Option<(String, T, HashMap<String, String>)> {
+        if let Some(value) = self.exact.get(route) {
+            return Some((route.to_string(), value.clone(), HashMap::new()));
+        }
+
+        self.patterns.iter().find_map(|(pattern, value)| {
+            pattern
+                .matches(route)
+                .map(|params| (pattern.raw.clone(), value.clone(), params))
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty()
+    }
+}
+
+impl<T: Clone> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match_wins_over_params() {
+        let mut router = Router::new();
+        router.insert("/user/:id", "param");
+        router.insert("/user/me", "exact");
+
+        assert_eq!(router.find("/user/me").unwrap().0, "exact");
+        let (value, params) = router.find("/user/42").unwrap();
+        assert_eq!(value, "param");
+        assert_eq!(params["id"], "42");
+        assert!(router.find("/user/42/extra").is_none());
+        assert_eq!(router.find_matched("/user/42").unwrap().0, "/user/:id");
+        assert_eq!(router.find_matched("/user/me").unwrap().0, "/user/me");
+    }
+
+    #[test]
+    fn test_multiple_params_and_wildcard() {
+        let mut router = Router::new();
+        router.insert("/files/*path", "files");
+        router.insert("/org/:org/repo/:repo", "repo");
+
+        let (value, params) = router.find("/org/acme/repo/widgets").unwrap();
+        assert_eq!(value, "repo");
+        assert_eq!(params["org"], "acme");
+        assert_eq!(params["repo"], "widgets");
+
+        let (value, params) = router.find("/files/docs/2024/report.pdf").unwrap();
+        assert_eq!(value, "files");
+        assert_eq!(params["path"], "docs/2024/report.pdf");
+        assert_eq!(router.find("/files").unwrap().1["path"], "");
+    }
+}
@@ -1,70 +1,400 @@
 //! Server implementation
 
+use async_trait::async_trait;
 use bytes::Bytes;
-use std::collections::HashMap;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{info, error, debug};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify, OnceCell, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::AbortHandle;
+use tracing::{info, error, debug, trace, info_span, warn, Instrument};
 
-use crate::transport::{Transport, TransportConfig};
-use crate::middleware::{Context, Response, Handler, AsyncFnHandler};
+use crate::transport::{canonical_addr, Transport, TransportConfig, TransportStats};
+use crate::middleware::{correlation_id, Context, Extensions, Response, Handler, AsyncFnHandler, Middleware, Next};
 use crate::packet::{Packet, PacketType};
-use crate::crypto::CryptoProvider;
-use crate::compression::CompressionProvider;
+use crate::protocol;
+use crate::crypto::{
+    Crypto, CryptoProvider, KeyExchange, ServerIdentity, TicketKey, IDENTITY_KEY_SIZE, PUBLIC_KEY_SIZE,
+    RESUMPTION_NONCE_SIZE, SIGNATURE_SIZE,
+};
+use crate::codec::Codec;
+use crate::metrics::{self, RouteMetrics};
+use crate::compression::Compression;
+use crate::connection::{AuthInfo, Connection, ConnectionId};
+use crate::ordering::ReorderBuffer;
+use crate::router::Router;
+use crate::stream::{StreamSink, DEFAULT_STREAM_WINDOW};
+use crate::tasks::TaskSet;
+use crate::PROTOCOL_VERSION;
 use crate::error::*;
 
+/// Wrap a typed handler's result in a response echoing the request id
+fn envelope<T>(id: String, result: Result<T>) -> protocol::Response<T> {
+    match result {
+        Ok(data) => protocol::Response::success(id, data),
+        Err(e) => protocol::Response::error(id, e.to_string()),
+    }
+}
+
 /// Route handler type
 type RouteHandler = Arc<dyn Handler>;
 
+/// Stream route handler type
+type StreamHandler = Arc<dyn Fn(Context, StreamSink) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Adapts a stream handler to `Handler` so it runs behind the middleware chain
+struct StreamRoute {
+    handler: StreamHandler,
+    sink: StreamSink,
+}
+
+#[async_trait]
+impl Handler for StreamRoute {
+    async fn handle(&self, ctx: Context) -> Result<Response> {
+        (self.handler)(ctx, self.sink.clone()).await?;
+        Ok(Response::new(Bytes::new()))
+    }
+}
+
+/// What happens to a request for a route already running its maximum number of handlers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for a running handler to finish
+    Queue,
+    /// Answer at once with a `Busy` error reply
+    Reject,
+}
+
+/// Outcome of a broadcast
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastReport {
+    /// Number of connections the message was sent to
+    pub sent: usize,
+    /// Connections the message could not be sent to in time
+    pub failed: Vec<ConnectionId>,
+}
+
+/// Cap on how many handlers for a route run at once
+#[derive(Clone)]
+struct RouteLimit {
+    permits: Arc<Semaphore>,
+    policy: OverflowPolicy,
+}
+
+impl RouteLimit {
+    /// Take a slot, or `None` if the route is full and turns requests away
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match self.policy {
+            OverflowPolicy::Queue => self.permits.clone().acquire_owned().await.ok(),
+            OverflowPolicy::Reject => self.permits.clone().try_acquire_owned().ok(),
+        }
+    }
+}
+
+/// Number of packets currently being handled, so shutdown can wait for them
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    /// Count a packet as in flight until the returned guard is dropped
+    fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    /// Count a packet as in flight unless `limit` packets already are
+    fn try_enter(self: &Arc<Self>, limit: usize) -> Option<InFlightGuard> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < limit).then_some(count + 1))
+            .ok()?;
+        Some(InFlightGuard(self.clone()))
+    }
+
+    /// Wait until no packets are being handled
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Keeps a packet counted as in flight while it is alive
+struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Feeds ordered packets from one channel of a source to its delivery task
+type OrderedQueue = mpsc::UnboundedSender<(Packet, InFlightGuard)>;
+
+/// Reliable request from a peer: its address, channel and sequence
+pub(crate) type RequestKey = (SocketAddr, u16, u32);
+
+/// Waits for the reply to a request sent to a peer
+type ReplySender = oneshot::Sender<Result<Bytes>>;
+
+/// Callback invoked when a connection is torn down
+type DisconnectHandler = Arc<dyn Fn(ConnectionId, SocketAddr) + Send + Sync>;
+
+/// Callback deciding whether a connection request may connect
+type Authenticator = Arc<dyn Fn(Context) -> Result<AuthInfo> + Send + Sync>;
+
+/// Idempotent request: who sent it, as identity or address, its route and its key
+type IdempotencyKey = (String, String, String);
+
+/// How long the response to an idempotent request is kept by default
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
+
+/// Most idempotency keys remembered at once by default
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 10_000;
+
+/// Most resumption nonces remembered at once; resumptions are refused beyond it
+const MAX_RESUMPTION_NONCES: usize = 65_536;
+
+/// Response to an idempotent request, filled in once its handler succeeds
+struct IdempotentReply {
+    created: Instant,
+    response: Arc<OnceCell<Response>>,
+}
+
+/// Responses to idempotent requests, dropped oldest first once expired or over capacity
+#[derive(Default)]
+struct IdempotencyCache {
+    replies: HashMap<IdempotencyKey, IdempotentReply>,
+    /// Keys in the order they were added, so the oldest are found without a scan
+    order: VecDeque<IdempotencyKey>,
+}
+
+impl IdempotencyCache {
+    /// The response slot for `key`, added if new
+    ///
+    /// Only the expired keys at the front are looked at, so each key costs
+    /// constant time to drop over its lifetime.
+    fn get_or_insert(&mut self, key: IdempotencyKey, now: Instant, ttl: Duration, capacity: usize) -> Arc<OnceCell<Response>> {
+        while self
+            .order
+            .front()
+            .and_then(|oldest| self.replies.get(oldest))
+            .is_some_and(|reply| now.duration_since(reply.created) >= ttl)
+        {
+            self.drop_oldest();
+        }
+        if let Some(reply) = self.replies.get(&key) {
+            return reply.response.clone();
+        }
+
+        while self.replies.len() >= capacity.max(1) {
+            self.drop_oldest();
+        }
+        let response = Arc::new(OnceCell::new());
+        self.order.push_back(key.clone());
+        self.replies.insert(key, IdempotentReply { created: now, response: response.clone() });
+        response
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(key) = self.order.pop_front() {
+            self.replies.remove(&key);
+        }
+    }
+}
+
 /// Server for handling incoming connections
 pub struct Server {
     transport: Arc<Transport>,
-    routes: Arc<RwLock<HashMap<String, RouteHandler>>>,
+    routes: Arc<RwLock<Router<RouteHandler>>>,
+    channel_routes: Arc<RwLock<HashMap<u16, Router<RouteHandler>>>>,
+    stream_routes: Arc<RwLock<Router<StreamHandler>>>,
+    ordered_routes: Arc<RwLock<Router<()>>>,
+    reorder: Arc<Mutex<ReorderBuffer>>,
+    ordered_queues: Arc<Mutex<HashMap<(SocketAddr, u16), OrderedQueue>>>,
+    middleware: Arc<RwLock<Vec<Arc<dyn Middleware>>>>,
+    connections: Arc<RwLock<HashMap<SocketAddr, Connection>>>,
+    disconnect_handler: Arc<RwLock<Option<DisconnectHandler>>>,
+    authenticator: Arc<RwLock<Option<Authenticator>>>,
+    in_flight: Arc<InFlight>,
+    /// Data packets being handled, counted against `max_concurrent_requests`
+    requests: Arc<InFlight>,
+    max_concurrent_requests: Option<usize>,
+    /// Handler tasks for reliable requests, so the sender can cancel them
+    running: Arc<Mutex<HashMap<RequestKey, AbortHandle>>>,
+    /// Requests this server sent to peers, keyed by peer, channel and sequence
+    outgoing: Arc<RwLock<HashMap<RequestKey, ReplySender>>>,
+    shutdown: watch::Sender<bool>,
+    shutdown_grace: Duration,
+    handler_timeout: Option<Duration>,
+    broadcast_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_packet_age: Option<Duration>,
+    route_packet_ages: Arc<RwLock<Router<Duration>>>,
+    route_limits: Arc<RwLock<Router<RouteLimit>>>,
+    codec: Codec,
+    route_codecs: Arc<RwLock<Router<Codec>>>,
+    /// Request counts and handler latency, keyed by the route requests were sent to
+    route_metrics: Arc<Mutex<HashMap<String, RouteMetrics>>>,
+    /// Responses to idempotent requests, answered again for repeats of their key
+    idempotent_replies: Arc<Mutex<IdempotencyCache>>,
+    idempotency_ttl: Option<Duration>,
+    idempotency_capacity: usize,
+    clock_skew_tolerance: Duration,
+    /// Long-term key the key exchange is signed with, for clients that pin it
+    identity: Option<Arc<ServerIdentity>>,
+    /// Key session tickets are sealed with, and how long they stay valid
+    session_tickets: Option<(Arc<TicketKey>, Duration)>,
+    /// Client nonces sessions were resumed with, kept until their ticket has surely expired
+    resumption_nonces: Arc<Mutex<HashMap<[u8; RESUMPTION_NONCE_SIZE], Instant>>>,
+    /// Reorder flush, reaper and ordered delivery tasks, stopped when the server is dropped
+    tasks: TaskSet,
 }
 
 impl Server {
     /// Create a new server
     pub async fn new(addr: impl Into<SocketAddr>, config: TransportConfig) -> Result<Self> {
         let transport = Transport::bind(addr, config).await?;
-        
-        Ok(Self {
+        Ok(Self::with_transport(transport))
+    }
+
+    /// Start building a server
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Create a server on `[::]:port` reachable over both IPv6 and IPv4
+    ///
+    /// IPv4 peers are identified by their plain IPv4 address, not the
+    /// v4-mapped form the socket reports.
+    pub async fn bind_dual_stack(port: u16, config: TransportConfig) -> Result<Self> {
+        let transport = Transport::bind_dual_stack(port, config).await?;
+        Ok(Self::with_transport(transport))
+    }
+
+    /// Create a server sharing `addr` with other servers through `SO_REUSEPORT`
+    ///
+    /// The kernel spreads incoming datagrams across every server bound this
+    /// way, see `Transport::bind_reuseport` for where that is supported.
+    pub async fn bind_reuseport(addr: impl Into<SocketAddr>, config: TransportConfig) -> Result<Self> {
+        let transport = Transport::bind_reuseport(addr, config).await?;
+        Ok(Self::with_transport(transport))
+    }
+
+    /// Create a server on an existing transport
+    ///
+    /// Use `Transport::over` to serve over a `PacketTransport` other than UDP.
+    pub fn with_transport(transport: Transport) -> Self {
+        let reorder = ReorderBuffer::new(transport.config().ordered_delivery_timeout);
+        let idle_timeout = transport.config().heartbeat_interval * 3;
+        Self {
             transport: Arc::new(transport),
-            routes: Arc::new(RwLock::new(HashMap::new())),
-        })
+            routes: Arc::new(RwLock::new(Router::new())),
+            channel_routes: Arc::new(RwLock::new(HashMap::new())),
+            stream_routes: Arc::new(RwLock::new(Router::new())),
+            ordered_routes: Arc::new(RwLock::new(Router::new())),
+            reorder: Arc::new(Mutex::new(reorder)),
+            ordered_queues: Arc::new(Mutex::new(HashMap::new())),
+            middleware: Arc::new(RwLock::new(Vec::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            disconnect_handler: Arc::new(RwLock::new(None)),
+            authenticator: Arc::new(RwLock::new(None)),
+            in_flight: Arc::new(InFlight::default()),
+            requests: Arc::new(InFlight::default()),
+            max_concurrent_requests: None,
+            running: Arc::new(Mutex::new(HashMap::new())),
+            outgoing: Arc::new(RwLock::new(HashMap::new())),
+            shutdown: watch::channel(false).0,
+            shutdown_grace: Duration::from_secs(30),
+            handler_timeout: None,
+            broadcast_timeout: Duration::from_secs(1),
+            idle_timeout: Some(idle_timeout),
+            max_packet_age: None,
+            route_packet_ages: Arc::new(RwLock::new(Router::new())),
+            route_limits: Arc::new(RwLock::new(Router::new())),
+            codec: Codec::default(),
+            route_codecs: Arc::new(RwLock::new(Router::new())),
+            route_metrics: Arc::new(Mutex::new(HashMap::new())),
+            idempotent_replies: Arc::new(Mutex::new(IdempotencyCache::default())),
+            idempotency_ttl: Some(DEFAULT_IDEMPOTENCY_TTL),
+            idempotency_capacity: DEFAULT_IDEMPOTENCY_CAPACITY,
+            clock_skew_tolerance: Duration::from_secs(1),
+            identity: None,
+            session_tickets: None,
+            resumption_nonces: Arc::new(Mutex::new(HashMap::new())),
+            tasks: TaskSet::default(),
+        }
     }
 
     /// Set encryption provider
-    pub fn set_crypto(&mut self, crypto: CryptoProvider) {
-        Arc::get_mut(&mut self.transport)
-            .unwrap()
-            .set_crypto(crypto);
+    ///
+    /// Safe to call at any time, including while running, to swap keys.
+    pub async fn set_crypto(&self, crypto: impl Crypto + 'static) {
+        self.transport.set_crypto(crypto).await;
     }
 
     /// Set compression provider
-    pub fn set_compression(&mut self, compression: CompressionProvider) {
-        Arc::get_mut(&mut self.transport)
-            .unwrap()
-            .set_compression(compression);
+    pub async fn set_compression(&self, compression: impl Compression + 'static) {
+        self.transport.set_compression(compression).await;
     }
 
     /// Register a route handler
+    ///
+    /// Routes may contain `:param` segments and a trailing `*rest` wildcard,
+    /// whose values are available through `Context::param`. An exact route
+    /// always takes precedence over a pattern.
     pub async fn on<H>(&self, route: impl Into<String>, handler: H)
     where
         H: Handler + 'static,
     {
         let route = route.into();
-        info!("Registered route: {}", route);
+        trace!("Registered route: {}", route);
         self.routes.write().await.insert(route, Arc::new(handler));
     }
 
+    /// Register a route handler for packets on one channel
+    ///
+    /// A channel handler takes precedence over one registered with `on` for
+    /// the same route. Its response goes back on the same channel, as does
+    /// every response.
+    pub async fn on_channel<H>(&self, channel: u16, route: impl Into<String>, handler: H)
+    where
+        H: Handler + 'static,
+    {
+        let route = route.into();
+        trace!("Registered route: {} on channel {}", route, channel);
+        self.channel_routes
+            .write()
+            .await
+            .entry(channel)
+            .or_default()
+            .insert(route, Arc::new(handler));
+    }
+
     /// Register a synchronous function handler
     pub async fn on_fn<F>(&self, route: impl Into<String>, handler: F)
     where
         F: Fn(Context) -> Result<Response> + Send + Sync + 'static,
     {
         let route = route.into();
-        info!("Registered route: {}", route);
+        trace!("Registered route: {}", route);
         let handler = crate::middleware::FnHandler::new(handler);
         self.routes.write().await.insert(route, Arc::new(handler));
     }
@@ -76,118 +406,2889 @@ impl Server {
         Fut: std::future::Future<Output = Result<Response>> + Send + 'static,
     {
         let route = route.into();
-        info!("Registered route: {}", route);
+        trace!("Registered route: {}", route);
         let handler = AsyncFnHandler::new(handler);
         self.routes.write().await.insert(route, Arc::new(handler));
     }
 
-    /// Start listening for incoming packets
-    pub async fn listen(self: Arc<Self>) -> Result<()> {
-        let addr = self.transport.local_addr()?;
-        info!("Server listening on {}", addr);
+    /// Register a handler that takes and returns typed values
+    ///
+    /// The payload is decoded into `Req` with the route's codec before the
+    /// handler runs, failing with `ProtocolError::InvalidPayload` if it does
+    /// not parse, and the returned `Resp` is encoded with the same codec.
+    pub async fn on_typed<Req, Resp, F>(&self, route: impl Into<String>, handler: F)
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(Req) -> Result<Resp> + Send + Sync + 'static,
+    {
+        self.on_fn(route, move |ctx| Response::encode(ctx.codec, &handler(ctx.decode()?)?))
+            .await;
+    }
 
-        // Start retransmission task
-        self.transport.clone().start_retransmission_task().await;
+    /// Register an async handler that takes and returns typed values
+    ///
+    /// See `on_typed` for how the payload and response are encoded.
+    pub async fn on_typed_async<Req, Resp, F, Fut>(&self, route: impl Into<String>, handler: F)
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.on_async(route, move |ctx| {
+            let handler = handler.clone();
+            async move { Response::encode(ctx.codec, &handler(ctx.decode()?).await?) }
+        })
+        .await;
+    }
 
-        loop {
-            match self.transport.recv().await {
-                Ok((packet, remote_addr)) => {
-                    let server = self.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = server.handle_packet(packet, remote_addr).await {
-                            error!("Error handling packet: {}", e);
-                        }
-                    });
+    /// Register a typed handler that speaks the `protocol::Request`/`Response` envelope
+    ///
+    /// Like `on_typed`, but the payload is a `Request<Req>` and the reply a
+    /// `Response<Resp>` carrying the same `id`, so callers can correlate and
+    /// trace requests at the application layer. A handler error is returned
+    /// as an unsuccessful response with that id rather than as a remote error.
+    pub async fn on_request<Req, Resp, F>(&self, route: impl Into<String>, handler: F)
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(Req) -> Result<Resp> + Send + Sync + 'static,
+    {
+        self.on_fn(route, move |ctx| {
+            let request: protocol::Request<Req> = ctx.decode()?;
+            Response::encode(ctx.codec, &envelope(request.id, handler(request.data)))
+        })
+        .await;
+    }
+
+    /// Register an async typed handler that speaks the request/response envelope
+    ///
+    /// See `on_request` for how the id is echoed.
+    pub async fn on_request_async<Req, Resp, F, Fut>(&self, route: impl Into<String>, handler: F)
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.on_async(route, move |ctx| {
+            let handler = handler.clone();
+            async move {
+                let request: protocol::Request<Req> = ctx.decode()?;
+                Response::encode(ctx.codec, &envelope(request.id, handler(request.data).await))
+            }
+        })
+        .await;
+    }
+
+    /// Register a streaming route handler
+    ///
+    /// The handler writes chunks to the `StreamSink` it is given; the stream is
+    /// ended when the handler returns, with its error passed on to the client.
+    pub async fn on_stream<F, Fut>(&self, route: impl Into<String>, handler: F)
+    where
+        F: Fn(Context, StreamSink) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        let route = route.into();
+        trace!("Registered stream route: {}", route);
+        let handler: StreamHandler = Arc::new(move |ctx, sink| Box::pin(handler(ctx, sink)));
+        self.stream_routes.write().await.insert(route, handler);
+    }
+
+    /// Choose whether packets for a route are delivered strictly in sequence order
+    ///
+    /// Packets for an ordered route are held back until every earlier reliable
+    /// packet from the same source has arrived, then handled one at a time. A
+    /// gap is normally closed by the retransmission of the missing packet; if
+    /// that takes longer than `ordered_delivery_timeout`, delivery moves past
+    /// the gap and the missing packet is handled on its own if it arrives later.
+    pub async fn set_ordered(&self, route: impl Into<String>, ordered: bool) {
+        let route = route.into();
+        let mut routes = self.ordered_routes.write().await;
+        if ordered {
+            routes.insert(route, ());
+        } else {
+            routes.remove(&route);
+        }
+    }
+
+    /// Drop data packets older than `max_age` before they reach a handler
+    ///
+    /// A packet's age is measured from the timestamp its sender stamped it
+    /// with, so this assumes peer clocks agree to within the clock skew
+    /// tolerance. `None` disables the check for routes without their own limit.
+    pub fn set_max_packet_age(&mut self, max_age: Option<Duration>) {
+        self.max_packet_age = max_age;
+    }
+
+    /// Set the codec typed handlers use for routes without their own
+    ///
+    /// Defaults to JSON. Clients must be configured with the same codec.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Override the codec for a route
+    pub async fn set_route_codec(&self, route: impl Into<String>, codec: Codec) {
+        self.route_codecs.write().await.insert(route.into(), codec);
+    }
+
+    /// Codec for payloads on a route
+    async fn codec_for(&self, route: &str) -> Codec {
+        match self.route_codecs.read().await.find(route) {
+            Some((codec, _)) => codec,
+            None => self.codec,
+        }
+    }
+
+    /// Request count, error count and handler latency for each route
+    ///
+    /// Keyed by the registered route or pattern, so `/user/1` and `/user/2`
+    /// both count towards `/user/:id`. Requests for a route with no handler
+    /// are counted as failures under `metrics::UNMATCHED_ROUTE`.
+    pub async fn route_metrics(&self) -> HashMap<String, RouteMetrics> {
+        self.route_metrics.lock().await.clone()
+    }
+
+    /// Route metrics in the Prometheus text exposition format
+    pub async fn prometheus_metrics(&self) -> String {
+        metrics::prometheus_text(&*self.route_metrics.lock().await)
+    }
+
+    /// Count a handled request towards its route's metrics
+    async fn record_route(&self, route: &str, latency: Duration, ok: bool) {
+        let mut metrics = self.route_metrics.lock().await;
+        match metrics.get_mut(route) {
+            Some(route) => route.record(latency, ok),
+            None => metrics.entry(route.to_string()).or_default().record(latency, ok),
+        }
+    }
+
+    /// Override the maximum packet age for a route
+    ///
+    /// Takes precedence over `set_max_packet_age`; pass `Duration::MAX` to
+    /// exempt a route from the global limit.
+    pub async fn set_route_max_packet_age(&self, route: impl Into<String>, max_age: Duration) {
+        self.route_packet_ages.write().await.insert(route.into(), max_age);
+    }
+
+    /// Let at most `limit` handlers for a route run at once
+    ///
+    /// Requests beyond the limit wait for a running handler to finish or are
+    /// turned away with a `Busy` error reply, as `policy` says. A pattern
+    /// route shares one limit among every route it matches. Routes without a
+    /// limit are not throttled.
+    pub async fn set_route_concurrency(
+        &self,
+        route: impl Into<String>,
+        limit: usize,
+        policy: OverflowPolicy,
+    ) {
+        let limit = RouteLimit {
+            permits: Arc::new(Semaphore::new(limit)),
+            policy,
+        };
+        self.route_limits.write().await.insert(route.into(), limit);
+    }
+
+    /// Set how far a peer's clock may run behind ours before its packets look stale
+    ///
+    /// The tolerance is added to every packet age limit. Defaults to one second.
+    pub fn set_clock_skew_tolerance(&mut self, tolerance: Duration) {
+        self.clock_skew_tolerance = tolerance;
+    }
+
+    /// Add a middleware to the request chain
+    ///
+    /// Middleware runs in registration order before the route handler.
+    pub async fn use_middleware(&self, middleware: impl Middleware + 'static) {
+        self.middleware.write().await.push(Arc::new(middleware));
+    }
+
+    /// Register a callback invoked whenever a connection is torn down
+    pub async fn on_disconnect<F>(&self, callback: F)
+    where
+        F: Fn(ConnectionId, SocketAddr) + Send + Sync + 'static,
+    {
+        *self.disconnect_handler.write().await = Some(Arc::new(callback));
+    }
+
+    /// Require connecting peers to authenticate
+    ///
+    /// The authenticator sees each `Connect` with the token the client sent
+    /// as its payload. A peer it fails is refused with a `ConnectReject` and
+    /// never added to the connections; requests from peers that have not
+    /// authenticated get an `Unauthenticated` error reply. The returned
+    /// `AuthInfo` is available to handlers as `Context::auth`.
+    pub async fn set_authenticator<F>(&self, authenticator: F)
+    where
+        F: Fn(Context) -> Result<AuthInfo> + Send + Sync + 'static,
+    {
+        *self.authenticator.write().await = Some(Arc::new(authenticator));
+    }
+
+    /// Ids of all currently connected peers
+    pub async fn connections(&self) -> Vec<ConnectionId> {
+        self.connections.read().await.values().map(|c| c.id).collect()
+    }
+
+    /// Look up the state of a connection by id
+    pub async fn connection(&self, id: ConnectionId) -> Option<Connection> {
+        self.connections
+            .read()
+            .await
+            .values()
+            .find(|c| c.id == id)
+            .cloned()
+    }
+
+    /// Send a data packet to every connected peer
+    ///
+    /// The sends run concurrently, each given up on after the broadcast
+    /// timeout, so a slow or unreachable peer cannot hold up the rest. The
+    /// report lists the connections that failed, e.g. to `evict` them.
+    pub async fn broadcast(&self, route: impl Into<String>, payload: Bytes) -> Result<BroadcastReport> {
+        let route = route.into();
+        let targets: Vec<(ConnectionId, SocketAddr)> = self
+            .connections
+            .read()
+            .await
+            .values()
+            .map(|c| (c.id, c.addr))
+            .collect();
+
+        let mut sends: FuturesUnordered<_> = targets
+            .into_iter()
+            .map(|(id, addr)| {
+                let send = self.transport.send_reliable(route.clone(), payload.clone(), addr);
+                async move {
+                    let result = match tokio::time::timeout(self.broadcast_timeout, send).await {
+                        Ok(result) => result.map(|_| ()),
+                        Err(_) => Err(ProtocolError::Timeout),
+                    };
+                    (id, addr, result)
                 }
+            })
+            .collect();
+
+        let mut report = BroadcastReport::default();
+        while let Some((id, addr, result)) = sends.next().await {
+            match result {
+                Ok(()) => report.sent += 1,
                 Err(e) => {
-                    error!("Error receiving packet: {}", e);
+                    error!("Broadcast to {} failed: {}", addr, e);
+                    report.failed.push(id);
                 }
             }
         }
+
+        debug!("Broadcast {} to {} connections, {} failed", route, report.sent, report.failed.len());
+        Ok(report)
     }
 
-    /// Handle an incoming packet
-    async fn handle_packet(&self, packet: Packet, remote_addr: SocketAddr) -> Result<()> {
-        match packet.packet_type {
-            PacketType::Data => {
-                debug!("Received data packet: route={}, seq={}", packet.route, packet.sequence);
-                
-                let ctx = Context {
-                    route: packet.route.clone(),
-                    payload: packet.payload.clone(),
-                    remote_addr,
-                    packet: packet.clone(),
-                };
+    /// Set how long `broadcast` waits on any one connection before counting it as failed
+    pub fn set_broadcast_timeout(&mut self, timeout: Duration) {
+        self.broadcast_timeout = timeout;
+    }
 
-                let routes = self.routes.read().await;
-                if let Some(handler) = routes.get(&packet.route) {
-                    match handler.handle(ctx).await {
-                        Ok(response) => {
-                            // Send response back
-                            self.transport
-                                .send_reliable(packet.route, response.data, remote_addr)
-                                .await?;
-                        }
-                        Err(e) => {
-                            error!("Handler error: {}", e);
-                            // Send error response
-                            let error_msg = format!("Error: {}", e);
-                            self.transport
-                                .send_reliable(
-                                    packet.route,
-                                    Bytes::from(error_msg),
-                                    remote_addr,
-                                )
-                                .await?;
-                        }
-                    }
-                } else {
-                    error!("Route not found: {}", packet.route);
-                    let error_msg = format!("Route not found: {}", packet.route);
-                    self.transport
-                        .send_reliable(packet.route, Bytes::from(error_msg), remote_addr)
-                        .await?;
-                }
-            }
-            PacketType::Ack => {
-                self.transport.handle_ack(packet.sequence).await;
-            }
-            PacketType::Nack => {
-                self.transport.handle_nack(packet.sequence).await;
+    /// Drop a connection, as if it had timed out
+    ///
+    /// Returns whether the connection existed.
+    pub async fn evict(&self, id: ConnectionId) -> bool {
+        let addr = self.connection(id).await.map(|c| c.addr);
+        match addr {
+            Some(addr) => self.remove_connection(addr).await.is_some(),
+            None => false,
+        }
+    }
+
+    /// Send a data packet to a single connected peer
+    pub async fn push(
+        &self,
+        id: ConnectionId,
+        route: impl Into<String>,
+        payload: Bytes,
+    ) -> Result<u32> {
+        let addr = self
+            .connection(id)
+            .await
+            .map(|c| c.addr)
+            .ok_or_else(|| ProtocolError::ConnectionNotFound(id.to_string()))?;
+
+        self.transport.send_reliable(route.into(), payload, addr).await
+    }
+
+    /// Tear down every connection that has been silent for longer than `idle`
+    ///
+    /// Any packet from a peer, including heartbeats, counts as activity.
+    pub async fn reap_idle_connections(&self, idle: Duration) -> Vec<ConnectionId> {
+        let now = Instant::now();
+        let stale: Vec<SocketAddr> = self
+            .connections
+            .read()
+            .await
+            .values()
+            .filter(|c| now.duration_since(c.last_seen) > idle)
+            .map(|c| c.addr)
+            .collect();
+
+        let mut reaped = Vec::with_capacity(stale.len());
+        for addr in stale {
+            if let Some(connection) = self.remove_connection(addr).await {
+                info!("Connection {} from {} timed out", connection.id, addr);
+                reaped.push(connection.id);
             }
-            PacketType::Heartbeat => {
-                debug!("Received heartbeat from {}", remote_addr);
-                // Send heartbeat response
-                let heartbeat = Packet::new_heartbeat();
-                self.transport.send(heartbeat, remote_addr).await?;
+        }
+        reaped
+    }
+
+    /// Record activity from a peer
+    async fn touch_connection(&self, addr: SocketAddr) {
+        if let Some(connection) = self.connections.write().await.get_mut(&addr) {
+            connection.last_seen = Instant::now();
+        }
+    }
+
+    /// Remove a connection, drop its session state and notify the disconnect callback
+    async fn remove_connection(&self, addr: SocketAddr) -> Option<Connection> {
+        let connection = self.connections.write().await.remove(&addr)?;
+        self.transport.forget_peer(addr).await;
+        self.reorder.lock().await.reset(addr);
+        self.ordered_queues.lock().await.retain(|(source, _), _| *source != addr);
+
+        let callback = self.disconnect_handler.read().await.clone();
+        if let Some(callback) = callback {
+            callback(connection.id, addr);
+        }
+        Some(connection)
+    }
+
+    /// Run the middleware chain followed by the route handler
+    async fn run_handler(&self, handler: &dyn Handler, ctx: Context) -> Result<Response> {
+        let middleware = self.middleware.read().await.clone();
+        Next::new(&middleware, handler).run(ctx).await
+    }
+
+    /// Run a route handler behind the middleware chain, failing with the code to reply with
+    ///
+    /// A handler that panics or outlives the handler timeout fails its
+    /// request too. Every call counts towards the metrics of `pattern`, the
+    /// registered route that matched.
+    async fn invoke(
+        &self,
+        handler: &dyn Handler,
+        pattern: &str,
+        ctx: Context,
+    ) -> std::result::Result<Response, (RemoteErrorCode, String)> {
+        let route = ctx.route.clone();
+        let remote_addr = ctx.remote_addr;
+        // A panicking handler fails its request instead of silently killing the task
+        let handled = AssertUnwindSafe(self.run_handler(handler, ctx)).catch_unwind();
+        let started = Instant::now();
+        let result = match self.handler_timeout {
+            Some(limit) => match tokio::time::timeout(limit, handled).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.record_route(pattern, started.elapsed(), false).await;
+                    warn!("Handler for {} timed out after {:?}", route, limit);
+                    let message = format!("Handler timed out after {:?}", limit);
+                    return Err((RemoteErrorCode::Timeout, message));
+                }
+            },
+            None => handled.await,
+        };
+        self.record_route(pattern, started.elapsed(), matches!(result, Ok(Ok(_)))).await;
+        match result {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                error!("Handler error: {}", e);
+                Err((RemoteErrorCode::HandlerError, e.to_string()))
             }
-            PacketType::Connect => {
-                info!("Connection request from {}", remote_addr);
-                let response = Packet {
-                    version: crate::PROTOCOL_VERSION,
-                    packet_type: PacketType::ConnectAck,
-                    flags: Default::default(),
-                    sequence: 0,
-                    timestamp: 0,
-                    route: String::new(),
-                    payload: Bytes::new(),
-                };
-                self.transport.send(response, remote_addr).await?;
+            Err(panic) => {
+                let message = format!("Handler panicked: {}", panic_message(panic.as_ref()));
+                error!("{} (route={}, from {})", message, route, remote_addr);
+                Err((RemoteErrorCode::HandlerPanic, message))
             }
-            PacketType::Disconnect => {
-                info!("Disconnect from {}", remote_addr);
+        }
+    }
+
+    /// Run a handler once per idempotency key, answering repeats with its response
+    async fn invoke_once(
+        &self,
+        handler: &dyn Handler,
+        pattern: &str,
+        ctx: Context,
+        key: String,
+    ) -> std::result::Result<Response, (RemoteErrorCode, String)> {
+        let Some(ttl) = self.idempotency_ttl else {
+            return self.invoke(handler, pattern, ctx).await;
+        };
+        let sender = match &ctx.auth {
+            Some(auth) => auth.identity.clone(),
+            None => ctx.remote_addr.to_string(),
+        };
+        let response = self.idempotent_replies.lock().await.get_or_insert(
+            (sender, ctx.route.clone(), key),
+            Instant::now(),
+            ttl,
+            self.idempotency_capacity,
+        );
+        response.get_or_try_init(|| self.invoke(handler, pattern, ctx)).await.cloned()
+    }
+
+    /// Answer a request on a stream route
+    async fn run_stream(
+        &self,
+        handler: StreamHandler,
+        ctx: Context,
+        stream_id: u32,
+        remote_addr: SocketAddr,
+    ) -> Result<()> {
+        let sink = StreamSink::new(
+            self.transport.clone(),
+            remote_addr,
+            ctx.packet.channel_id,
+            ctx.route.clone(),
+            stream_id,
+            DEFAULT_STREAM_WINDOW,
+        );
+        let route = StreamRoute {
+            handler,
+            sink: sink.clone(),
+        };
+
+        let result = self.run_handler(&route, ctx).await.map(|_| ());
+        if let Err(e) = &result {
+            error!("Stream handler error: {}", e);
+        }
+        sink.finish(result).await
+    }
+
+    /// Sign every key exchange with `identity`, so clients can pin its public key
+    ///
+    /// The signature rides along in the `ConnectAck`, which clients from
+    /// before identity pinning do not accept.
+    pub fn set_identity(&mut self, identity: ServerIdentity) {
+        self.identity = Some(Arc::new(identity));
+    }
+
+    /// Issue session tickets sealed with `key`, valid for `lifetime`
+    ///
+    /// After each key exchange the client gets a ticket over the new
+    /// session. Presenting it in a later `Connect` resumes the session under
+    /// a fresh key without another exchange, until the ticket expires. A ticket
+    /// can be presented more than once while it is valid, but each time with
+    /// a fresh client nonce: a replayed `Connect` is refused, and so is a
+    /// resumption while the session it would replace is still established.
+    pub fn set_session_tickets(&mut self, key: TicketKey, lifetime: Duration) {
+        self.session_tickets = Some((Arc::new(key), lifetime));
+    }
+
+    /// Set how long shutdown waits for in-flight handlers to finish
+    pub fn set_shutdown_grace(&mut self, grace: Duration) {
+        self.shutdown_grace = grace;
+    }
+
+    /// Abort route handlers that run longer than `timeout` (None disables)
+    ///
+    /// The client gets a `Timeout` error reply instead of a response.
+    pub fn set_handler_timeout(&mut self, timeout: Option<Duration>) {
+        self.handler_timeout = timeout;
+    }
+
+    /// Keep responses to idempotent requests for `ttl` (None runs every request)
+    ///
+    /// A request carrying an idempotency key runs its handler once; repeats
+    /// of the key from the same sender, on the same route and within `ttl`,
+    /// get the first response again, including a repeat that arrives while
+    /// the handler is still running. A failed request is not remembered, so
+    /// it can be retried. Senders are told apart by authenticated identity,
+    /// or by address when connections are not authenticated.
+    pub fn set_idempotency_ttl(&mut self, ttl: Option<Duration>) {
+        self.idempotency_ttl = ttl;
+    }
+
+    /// Remember at most `capacity` idempotency keys at once
+    ///
+    /// Once full, the oldest key is forgotten to make room, even if its
+    /// `ttl` has not run out, so a repeat of it runs the handler again.
+    /// Bounds the memory senders can take up with fresh keys.
+    pub fn set_idempotency_capacity(&mut self, capacity: usize) {
+        self.idempotency_capacity = capacity;
+    }
+
+    /// Shed load once `limit` requests are being handled at once (None disables)
+    ///
+    /// Data packets arriving while the server is at the limit are answered at
+    /// once with a `Busy` error reply instead of being queued. Requests
+    /// waiting on a route's concurrency limit count towards it.
+    pub fn set_max_concurrent_requests(&mut self, limit: Option<usize>) {
+        self.max_concurrent_requests = limit;
+    }
+
+    /// Tear down connections silent for longer than `timeout` while listening (None disables)
+    ///
+    /// Defaults to three heartbeat intervals. Any packet from a peer,
+    /// including heartbeats, counts as activity.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Ask a running `listen` to stop accepting packets and drain its handlers
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Start listening for incoming packets
+    ///
+    /// Runs until `shutdown` is called.
+    pub async fn listen(self: Arc<Self>) -> Result<()> {
+        self.listen_with_shutdown(std::future::pending()).await
+    }
+
+    /// Start listening for incoming packets until `shutdown` resolves or `Server::shutdown` is called
+    ///
+    /// Once stopped, no new packets are accepted and handlers that are already
+    /// running get up to the shutdown grace period to finish before this returns.
+    /// Background tasks started here only hold the server weakly, so they
+    /// stop once it is dropped even if this future was dropped before finishing.
+    pub async fn listen_with_shutdown(self: Arc<Self>, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let addr = self.transport.local_addr()?;
+        info!("Server listening on {}", addr);
+
+        // Start retransmission task
+        self.transport.clone().start_retransmission_task().await;
+        let reorder_flush = self.start_reorder_flush_task();
+        let reaper = self.idle_timeout.map(|idle| self.start_reaper_task(idle));
+
+        let mut stop = self.shutdown.subscribe();
+        let incoming = self.transport.incoming();
+        tokio::pin!(shutdown, incoming);
+        loop {
+            if *stop.borrow_and_update() {
+                break;
             }
-            _ => {
-                debug!("Unhandled packet type: {:?}", packet.packet_type);
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = stop.changed() => continue,
+                Some(received) = incoming.next() => match received {
+                    Ok((packet, remote_addr)) => {
+                        if let Some(packet) = self.take_reply(packet, remote_addr).await {
+                            self.route_packet(packet, remote_addr).await;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error receiving packet: {}", e);
+                    }
+                },
             }
         }
 
+        info!("Server on {} shutting down, draining in-flight handlers", addr);
+        reorder_flush.abort();
+        if let Some(reaper) = reaper {
+            reaper.abort();
+        }
+        if tokio::time::timeout(self.shutdown_grace, self.in_flight.wait_idle())
+            .await
+            .is_err()
+        {
+            warn!("Shutdown grace period elapsed with handlers still running");
+        }
         Ok(())
     }
 
-    /// Get server local address
-    pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.transport.local_addr()
+    /// Send a request to a peer and return where its reply will arrive
+    ///
+    /// The reply is told apart from the peer's own requests by its channel
+    /// and `reply_to`, the way a client matches responses, so peers that
+    /// negotiated an older wire format cannot be sent requests.
+    pub(crate) async fn send_request(
+        &self,
+        dest: SocketAddr,
+        channel: u16,
+        route: String,
+        payload: Bytes,
+    ) -> Result<(RequestKey, oneshot::Receiver<Result<Bytes>>)> {
+        let dest = canonical_addr(dest);
+        let version = self.transport.peer_version(dest).await;
+        if version < PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch { expected: PROTOCOL_VERSION, actual: version });
+        }
+        let mut packet = Packet::new_data(route, payload, 0);
+        packet.channel_id = channel;
+
+        // Wait for the reply before sending, since it can arrive before the send returns
+        let (tx, rx) = oneshot::channel();
+        let mut registered = None;
+        let sent = self
+            .transport
+            .send_reliable_packet_with(packet, dest, |channel, sequence| {
+                let key = (dest, channel, sequence);
+                registered = Some(key);
+                let outgoing = self.outgoing.clone();
+                async move {
+                    outgoing.write().await.insert(key, tx);
+                }
+            })
+            .await;
+        match sent {
+            Ok(sequence) => Ok(((dest, channel, sequence), rx)),
+            Err(e) => {
+                if let Some(key) = registered {
+                    self.outgoing.write().await.remove(&key);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop waiting for the reply to a request sent to a peer
+    pub(crate) async fn forget_request(&self, key: RequestKey) {
+        self.outgoing.write().await.remove(&key);
+    }
+
+    /// Hand a reply to the request it answers
+    ///
+    /// Returns the packet when it answers no request this server sent.
+    async fn take_reply(&self, packet: Packet, remote_addr: SocketAddr) -> Option<Packet> {
+        if !matches!(packet.packet_type, PacketType::Data | PacketType::Error) {
+            return Some(packet);
+        }
+
+        let Some(request) = packet.answered_sequence() else {
+            return Some(packet);
+        };
+        let key = (remote_addr, packet.channel_id, request);
+        let Some(tx) = self.outgoing.write().await.remove(&key) else {
+            return Some(packet);
+        };
+        let reply = match packet.packet_type {
+            PacketType::Error => packet.remote_error().and_then(Err),
+            _ => Ok(packet.payload),
+        };
+        let _ = tx.send(reply);
+        None
+    }
+
+    /// Hand a received packet to a handler task, holding it back if its route is ordered
+    async fn route_packet(self: &Arc<Self>, packet: Packet, remote_addr: SocketAddr) {
+        if packet.packet_type == PacketType::Connect {
+            // A reconnecting client starts its sequences over
+            self.reorder.lock().await.reset(remote_addr);
+        }
+
+        let ordered_routes = self.ordered_routes.read().await;
+        if !packet.flags.requires_ack || ordered_routes.is_empty() {
+            drop(ordered_routes);
+            self.spawn_packet(packet, remote_addr).await;
+            return;
+        }
+
+        // Reliable control packets use sequences too, so they still take part in ordering
+        let ordered = packet.packet_type == PacketType::Data && ordered_routes.find(&packet.route).is_some();
+        drop(ordered_routes);
+        if !ordered {
+            self.reorder.lock().await.accept(remote_addr, packet.clone(), false);
+            self.spawn_packet(packet, remote_addr).await;
+            return;
+        }
+
+        let ready = self.reorder.lock().await.accept(remote_addr, packet, true);
+        self.deliver_ordered(remote_addr, ready).await;
+    }
+
+    /// Handle a packet on its own task
+    async fn spawn_packet(self: &Arc<Self>, packet: Packet, remote_addr: SocketAddr) {
+        let server = self.clone();
+        let guard = self.in_flight.enter();
+        let request = (packet.packet_type == PacketType::Data && packet.flags.requires_ack)
+            .then_some((remote_addr, packet.channel_id, packet.sequence));
+
+        // Hold the lock until the task is registered so it cannot unregister first
+        let mut running = self.running.lock().await;
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            if let Err(e) = server.handle_packet(packet, remote_addr).await {
+                error!("Error handling packet: {}", e);
+            }
+            if let Some(request) = request {
+                server.running.lock().await.remove(&request);
+            }
+        });
+        if let Some(request) = request {
+            running.insert(request, task.abort_handle());
+        }
+    }
+
+    /// Queue ordered packets on their channel's worker, which handles them one at a time
+    ///
+    /// Each channel of a source has its own worker, so a slow handler on one
+    /// channel does not hold up ordered packets on another.
+    async fn deliver_ordered(self: &Arc<Self>, remote_addr: SocketAddr, packets: Vec<Packet>) {
+        if packets.is_empty() {
+            return;
+        }
+
+        let mut queues = self.ordered_queues.lock().await;
+        for packet in packets {
+            let queue = queues.entry((remote_addr, packet.channel_id)).or_insert_with(|| {
+                let (tx, mut rx) = mpsc::unbounded_channel::<(Packet, InFlightGuard)>();
+                let weak = Arc::downgrade(self);
+                self.tasks.spawn(async move {
+                    // Ends once the server, which holds the sender, is dropped
+                    while let Some((packet, _guard)) = rx.recv().await {
+                        let Some(server) = weak.upgrade() else {
+                            break;
+                        };
+                        if let Err(e) = server.handle_packet(packet, remote_addr).await {
+                            error!("Error handling packet: {}", e);
+                        }
+                    }
+                });
+                tx
+            });
+            let _ = queue.send((packet, self.in_flight.enter()));
+        }
     }
+
+    /// Periodically release ordered packets stuck behind a gap that timed out
+    fn start_reorder_flush_task(self: &Arc<Self>) -> AbortHandle {
+        let period = (self.transport.config().ordered_delivery_timeout / 2)
+            .max(Duration::from_millis(10));
+        let weak = Arc::downgrade(self);
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else {
+                    break;
+                };
+                let flushed = server.reorder.lock().await.flush_expired();
+                let mut by_source: HashMap<SocketAddr, Vec<Packet>> = HashMap::new();
+                for (addr, packet) in flushed {
+                    by_source.entry(addr).or_default().push(packet);
+                }
+                for (addr, packets) in by_source {
+                    warn!("Skipped a sequence gap from {}", addr);
+                    server.deliver_ordered(addr, packets).await;
+                }
+            }
+        })
+    }
+
+    /// Periodically tear down connections that have been silent for longer than `idle`
+    fn start_reaper_task(self: &Arc<Self>, idle: Duration) -> AbortHandle {
+        let period = (idle / 2).max(Duration::from_millis(10));
+        let weak = Arc::downgrade(self);
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else {
+                    break;
+                };
+                server.reap_idle_connections(idle).await;
+            }
+        })
+    }
+
+    /// Whether a data packet is older than its route allows
+    async fn is_stale(&self, packet: &Packet) -> bool {
+        let max_age = match self.route_packet_ages.read().await.find(&packet.route) {
+            Some((max_age, _)) => Some(max_age),
+            None => self.max_packet_age,
+        };
+        max_age.is_some_and(|max_age| {
+            packet.age() > max_age.saturating_add(self.clock_skew_tolerance)
+        })
+    }
+
+    /// Handle an incoming packet inside a span carrying its correlation id
+    ///
+    /// Everything logged while the packet is handled, by middleware, the
+    /// handler and the transport sending the reply, is recorded in the span.
+    async fn handle_packet(&self, packet: Packet, remote_addr: SocketAddr) -> Result<()> {
+        let id = correlation_id(remote_addr, &packet);
+        let span = info_span!("request", id = %id, kind = ?packet.packet_type, route = %packet.route);
+        self.process_packet(packet, remote_addr, id).instrument(span).await
+    }
+
+    /// Handle an incoming packet
+    async fn process_packet(&self, packet: Packet, remote_addr: SocketAddr, id: String) -> Result<()> {
+        self.touch_connection(remote_addr).await;
+
+        match packet.packet_type {
+            PacketType::Data => {
+                trace!("Received data packet: route={}, seq={}", packet.route, packet.sequence);
+
+                if self.is_stale(&packet).await {
+                    warn!(
+                        "Dropping stale packet: route={}, seq={}, age={:?}",
+                        packet.route, packet.sequence, packet.age()
+                    );
+                    return Ok(());
+                }
+                
+                let auth = match self.connections.read().await.get(&remote_addr) {
+                    Some(connection) => connection.auth.clone(),
+                    None => None,
+                };
+                if auth.is_none() && self.authenticator.read().await.is_some() {
+                    warn!("Rejecting request from unauthenticated {}", remote_addr);
+                    let message = "Connection is not authenticated";
+                    return self
+                        .send_error(&packet, RemoteErrorCode::Unauthenticated, message, remote_addr)
+                        .await;
+                }
+
+                let (payload, idempotency_key) = match packet.idempotency_parts()? {
+                    Some((key, payload)) => (payload, Some(key)),
+                    None => (packet.payload.clone(), None),
+                };
+                let mut ctx = Context {
+                    route: packet.route.clone(),
+                    payload,
+                    remote_addr,
+                    packet: packet.clone(),
+                    params: HashMap::new(),
+                    auth,
+                    correlation_id: id,
+                    codec: self.codec_for(&packet.route).await,
+                    extensions: Extensions::default(),
+                };
+
+                let _request = match self.max_concurrent_requests {
+                    Some(limit) => match self.requests.try_enter(limit) {
+                        Some(request) => request,
+                        None => {
+                            warn!("Shedding request from {}: {} requests in flight", remote_addr, limit);
+                            return self
+                                .send_error(&packet, RemoteErrorCode::Busy, "Server busy", remote_addr)
+                                .await;
+                        }
+                    },
+                    None => self.requests.enter(),
+                };
+
+                let limit = self.route_limits.read().await.find(&packet.route);
+                let _permit = match limit {
+                    Some((limit, _)) => match limit.acquire().await {
+                        Some(permit) => Some(permit),
+                        None => {
+                            warn!("Route {} is at its concurrency limit", packet.route);
+                            let message = format!("Route busy: {}", packet.route);
+                            return self
+                                .send_error(&packet, RemoteErrorCode::Busy, &message, remote_addr)
+                                .await;
+                        }
+                    },
+                    None => None,
+                };
+
+                let stream_handler = self.stream_routes.read().await.find(&packet.route);
+                if let Some((stream_handler, params)) = stream_handler {
+                    ctx.params = params;
+                    return self
+                        .run_stream(stream_handler, ctx, packet.sequence, remote_addr)
+                        .await;
+                }
+
+                let channel = packet.channel_id;
+                let handler = match self.channel_routes.read().await.get(&channel) {
+                    Some(routes) => routes.find_matched(&packet.route),
+                    None => None,
+                };
+                let handler = match handler {
+                    Some(handler) => Some(handler),
+                    None => self.routes.read().await.find_matched(&packet.route),
+                };
+                if let Some((pattern, handler, params)) = handler {
+                    ctx.params = params;
+                    let result = match idempotency_key {
+                        Some(key) => self.invoke_once(handler.as_ref(), &pattern, ctx, key).await,
+                        None => self.invoke(handler.as_ref(), &pattern, ctx).await,
+                    };
+                    match result {
+                        Ok(_) if packet.flags.unreliable => {
+                            // At-most-once sends are never answered
+                        }
+                        Ok(response) if response.one_way => {
+                            // The handler chose not to answer
+                        }
+                        Ok(response) => {
+                            // Send response back, compressed if the response asks for it
+                            let compress = response
+                                .compress
+                                .unwrap_or(self.transport.config().enable_compression);
+                            let mut reply = Packet::new_data(packet.route, response.data, 0);
+                            reply.channel_id = channel;
+                            reply.reply_to = Some(packet.sequence);
+                            self.transport
+                                .send_reliable_packet_compressed(reply, remote_addr, compress)
+                                .await?;
+                        }
+                        Err((code, message)) => {
+                            self.send_error(&packet, code, &message, remote_addr).await?;
+                        }
+                    }
+                } else {
+                    error!("Route not found: {}", packet.route);
+                    self.record_route(metrics::UNMATCHED_ROUTE, Duration::ZERO, false).await;
+                    let message = format!("Route not found: {}", packet.route);
+                    self.send_error(&packet, RemoteErrorCode::RouteNotFound, &message, remote_addr)
+                        .await?;
+                }
+            }
+            PacketType::Ack | PacketType::Nack => {
+                // Already applied by the transport when received
+            }
+            PacketType::Heartbeat => {
+                trace!("Received heartbeat from {}", remote_addr);
+                // Send heartbeat response
+                let heartbeat = Packet::new_heartbeat();
+                self.transport.send(heartbeat, remote_addr).await?;
+            }
+            PacketType::Connect => {
+                info!("Connection request from {}", remote_addr);
+                let Some(version) = self.transport.negotiate_version(packet.version) else {
+                    warn!("Rejected connection from {} at protocol version {}", remote_addr, packet.version);
+                    let config = self.transport.config();
+                    let reason = format!(
+                        "Unsupported protocol version {}, expected {} to {}",
+                        packet.version, config.min_protocol_version, config.max_protocol_version
+                    );
+                    let reject = Packet::new_connect_reject(&reason);
+                    self.transport.send_with_version(reject, packet.version, remote_addr).await?;
+                    return Ok(());
+                };
+                let (public_key, token) = packet.connect_parts()?;
+                let early_data = packet.connect_early_data()?.map(|data| (packet.route.clone(), data));
+                if early_data.is_some() && self.transport.config().enable_encryption {
+                    // Refused before any keys are agreed, since the ConnectAck has no room for both
+                    warn!("Refusing early data from {}: encryption is enabled", remote_addr);
+                    let reject = Packet::new_connect_reject("Early data is not accepted with encryption enabled");
+                    self.transport.send_with_version(reject, version, remote_addr).await?;
+                    return Ok(());
+                }
+
+                let authenticator = self.authenticator.read().await.clone();
+                let auth = match authenticator {
+                    Some(authenticator) => {
+                        let ctx = Context {
+                            route: packet.route.clone(),
+                            payload: token,
+                            remote_addr,
+                            packet,
+                            params: HashMap::new(),
+                            auth: None,
+                            correlation_id: id.clone(),
+                            codec: self.codec,
+                            extensions: Extensions::default(),
+                        };
+                        match authenticator(ctx) {
+                            Ok(auth) => Some(auth),
+                            Err(e) => {
+                                warn!("Rejected connection from {}: {}", remote_addr, e);
+                                if self.remove_connection(remote_addr).await.is_none() {
+                                    self.transport.remove_session_crypto(remote_addr).await;
+                                }
+                                let reject = Packet::new_connect_reject(&e.to_string());
+                                self.transport.send_with_version(reject, version, remote_addr).await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let agreement = self.accept_key_exchange(&public_key, remote_addr).await?;
+                let crypto = agreement.crypto;
+                let payload = agreement.reply;
+
+                let mut connections = self.connections.write().await;
+                match connections.get_mut(&remote_addr) {
+                    Some(connection) => {
+                        connection.crypto = crypto;
+                        connection.auth = auth.clone();
+                        connection.version = version;
+                    }
+                    None => {
+                        let mut connection = Connection::new(remote_addr, crypto);
+                        connection.auth = auth.clone();
+                        connection.version = version;
+                        info!(
+                            "Connection {} established with {} at protocol version {}",
+                            connection.id, remote_addr, version
+                        );
+                        connections.insert(remote_addr, connection);
+                    }
+                }
+                drop(connections);
+
+                self.transport.set_peer_version(remote_addr, version).await;
+                self.transport.discover_path_mtu(remote_addr).await;
+                let response = match early_data {
+                    Some((route, data)) => {
+                        let reply = self.answer_early_data(route, data, auth, remote_addr, id).await;
+                        Packet::new_connect_ack_with_reply(reply)
+                    }
+                    None => Packet::new_connect_ack(payload),
+                };
+                self.transport.send(response, remote_addr).await?;
+                if let Some(ticket) = agreement.ticket {
+                    // Sealed under the new session key, so only this client can read it
+                    self.transport.send_reliable_packet(ticket, remote_addr).await?;
+                }
+            }
+            PacketType::Cancel => {
+                let sequence = packet.cancelled_sequence()?;
+                let request = (remote_addr, packet.channel_id, sequence);
+                if let Some(task) = self.running.lock().await.remove(&request) {
+                    task.abort();
+                    debug!("Cancelled request {} from {}", sequence, remote_addr);
+                }
+            }
+            PacketType::Rekey => {
+                // The transport has already switched keys on receipt
+                let crypto = self.transport.session_crypto(remote_addr).await;
+                if let Some(connection) = self.connections.write().await.get_mut(&remote_addr) {
+                    connection.crypto = crypto;
+                }
+                info!("Rotated session key with {}", remote_addr);
+            }
+            PacketType::Disconnect => {
+                info!("Disconnect from {}", remote_addr);
+                if self.remove_connection(remote_addr).await.is_none() {
+                    self.transport.remove_session_crypto(remote_addr).await;
+                }
+            }
+            _ => {
+                debug!("Unhandled packet type: {:?}", packet.packet_type);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a request with an error reply on its channel
+    ///
+    /// Unreliable requests are never answered, errors included.
+    async fn send_error(
+        &self,
+        request: &Packet,
+        code: RemoteErrorCode,
+        message: &str,
+        dest: SocketAddr,
+    ) -> Result<()> {
+        if request.flags.unreliable {
+            debug!("Not answering unreliable request to {} with: {}", request.route, message);
+            return Ok(());
+        }
+        let mut reply = Packet::new_error(request.route.clone(), code, message);
+        reply.channel_id = request.channel_id;
+        reply.reply_to = Some(request.sequence);
+        self.transport.send_reliable_packet(reply, dest).await?;
+        Ok(())
+    }
+
+    /// Run the request a `Connect` carried as early data, returning the reply for its `ConnectAck`
+    ///
+    /// Early data arrives before any key exchange, so a server with
+    /// encryption enabled rejects the whole `Connect` before getting here.
+    /// It runs behind the middleware chain like any request, but only on
+    /// routes of the default channel.
+    async fn answer_early_data(
+        &self,
+        route: String,
+        data: Bytes,
+        auth: Option<AuthInfo>,
+        remote_addr: SocketAddr,
+        id: String,
+    ) -> Packet {
+        let handler = self.routes.read().await.find_matched(&route);
+        let Some((pattern, handler, params)) = handler else {
+            error!("Route not found: {}", route);
+            self.record_route(metrics::UNMATCHED_ROUTE, Duration::ZERO, false).await;
+            let message = format!("Route not found: {}", route);
+            return Packet::new_error(route, RemoteErrorCode::RouteNotFound, &message);
+        };
+
+        let _request = self.requests.enter();
+        let ctx = Context {
+            route: route.clone(),
+            payload: data.clone(),
+            remote_addr,
+            packet: Packet::new_data(route.clone(), data, 0),
+            params,
+            auth,
+            correlation_id: id,
+            codec: self.codec_for(&route).await,
+            extensions: Extensions::default(),
+        };
+        match self.invoke(handler.as_ref(), &pattern, ctx).await {
+            Ok(response) => Packet::new_data(route, response.data, 0),
+            Err((code, message)) => Packet::new_error(route, code, &message),
+        }
+    }
+
+    /// Answer the key exchange or resumption carried by a `Connect` packet
+    ///
+    /// A `Connect` payload holding the client's X25519 public key gets a
+    /// `ConnectAck` payload of the server's public key followed by an echo of
+    /// the client's key, and the derived session key is used for that client
+    /// from then on. A client nonce followed by a valid session ticket gets
+    /// the nonce echoed and a session key derived from both, with no
+    /// exchange. Anything else, including an expired or forged ticket, gets an
+    /// empty `ConnectAck`.
+    async fn accept_key_exchange(&self, payload: &Bytes, remote_addr: SocketAddr) -> Result<KeyAgreement> {
+        if payload.len() > PUBLIC_KEY_SIZE {
+            return Ok(self.accept_resumption(payload, remote_addr).await);
+        }
+        if payload.len() != PUBLIC_KEY_SIZE {
+            return Ok(KeyAgreement::default());
+        }
+
+        let exchange = KeyExchange::new();
+        let server_public = exchange.public_key();
+        let crypto = Arc::new(exchange.derive(payload, false)?);
+        self.transport.set_session_crypto(remote_addr, crypto.clone()).await;
+        debug!("Negotiated session key with {}", remote_addr);
+
+        let mut reply = Vec::with_capacity(2 * PUBLIC_KEY_SIZE + IDENTITY_KEY_SIZE + SIGNATURE_SIZE);
+        reply.extend_from_slice(&server_public);
+        reply.extend_from_slice(payload);
+        if let Some(identity) = &self.identity {
+            reply.extend_from_slice(&identity.public_key());
+            reply.extend_from_slice(&identity.sign_handshake(&server_public, payload));
+        }
+
+        let ticket = match &self.session_tickets {
+            Some((key, lifetime)) => {
+                let expires_at = unix_time() + lifetime.as_secs();
+                let ticket = key.seal(&crypto.resumption_secret(), expires_at)?;
+                Some(Packet::new_session_ticket(*lifetime, &ticket))
+            }
+            None => None,
+        };
+        Ok(KeyAgreement {
+            reply: Bytes::from(reply),
+            crypto: Some(crypto),
+            ticket,
+        })
+    }
+
+    /// Resume a session from the client nonce and ticket a `Connect` carries
+    ///
+    /// Refused when `remote_addr` still has a session key, so a replayed or
+    /// spoofed `Connect` cannot replace it, or when the nonce was used before.
+    async fn accept_resumption(&self, payload: &Bytes, remote_addr: SocketAddr) -> KeyAgreement {
+        let Some((key, lifetime)) = &self.session_tickets else {
+            debug!("Ignoring session ticket from {}: tickets are disabled", remote_addr);
+            return KeyAgreement::default();
+        };
+        let (nonce, ticket) = payload.split_at(RESUMPTION_NONCE_SIZE);
+        let secret = match key.open(ticket, unix_time()) {
+            Ok(secret) => secret,
+            Err(e) => {
+                warn!("Refusing session ticket from {}: {}", remote_addr, e);
+                return KeyAgreement::default();
+            }
+        };
+        if self.transport.session_crypto(remote_addr).await.is_some() {
+            warn!("Refusing session ticket from {}: its session is still established", remote_addr);
+            return KeyAgreement::default();
+        }
+        if !self.claim_resumption_nonce(nonce, *lifetime).await {
+            warn!("Refusing session ticket from {}: its nonce was already used", remote_addr);
+            return KeyAgreement::default();
+        }
+
+        let crypto = Arc::new(CryptoProvider::resumed(&secret, nonce));
+        self.transport.set_session_crypto(remote_addr, crypto.clone()).await;
+        debug!("Resumed session with {}", remote_addr);
+        KeyAgreement {
+            reply: Bytes::copy_from_slice(nonce),
+            crypto: Some(crypto),
+            ticket: None,
+        }
+    }
+
+    /// Remember a resumption nonce for as long as a ticket can live, failing if it is already known
+    ///
+    /// Also fails once `MAX_RESUMPTION_NONCES` are remembered, leaving
+    /// clients to fall back to a full key exchange.
+    async fn claim_resumption_nonce(&self, nonce: &[u8], lifetime: Duration) -> bool {
+        let Ok(nonce) = <[u8; RESUMPTION_NONCE_SIZE]>::try_from(nonce) else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut nonces = self.resumption_nonces.lock().await;
+        nonces.retain(|_, forget_at| *forget_at > now);
+        if nonces.len() >= MAX_RESUMPTION_NONCES || nonces.contains_key(&nonce) {
+            return false;
+        }
+        nonces.insert(nonce, now + lifetime);
+        true
+    }
+
+    /// Snapshot of the underlying transport counters and the requests being handled
+    pub async fn stats(&self) -> TransportStats {
+        TransportStats {
+            in_flight_requests: self.requests.count.load(Ordering::SeqCst),
+            ..self.transport.stats().await
+        }
+    }
+
+    /// Get server local address
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
+
+
+/// Builder for a server wired up with everything it needs before it is shared
+#[derive(Default)]
+pub struct ServerBuilder {
+    addr: Option<SocketAddr>,
+    config: TransportConfig,
+    crypto: Option<Box<dyn Crypto>>,
+    compression: Option<Box<dyn Compression>>,
+    identity: Option<ServerIdentity>,
+    session_tickets: Option<(TicketKey, Duration)>,
 }
 
+impl ServerBuilder {
+    /// Address to listen on
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Transport configuration
+    pub fn transport_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Encryption provider
+    pub fn crypto(mut self, crypto: impl Crypto + 'static) -> Self {
+        self.crypto = Some(Box::new(crypto));
+        self
+    }
+
+    /// Compression provider
+    pub fn compression(mut self, compression: impl Compression + 'static) -> Self {
+        self.compression = Some(Box::new(compression));
+        self
+    }
+
+    /// Long-term key to sign the key exchange with, see `Server::set_identity`
+    pub fn identity(mut self, identity: ServerIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Issue session tickets for resuming sessions, see `Server::set_session_tickets`
+    pub fn session_tickets(mut self, key: TicketKey, lifetime: Duration) -> Self {
+        self.session_tickets = Some((key, lifetime));
+        self
+    }
+
+    /// Bind the socket and create the server
+    pub async fn build(self) -> Result<Server> {
+        let addr = self
+            .addr
+            .ok_or_else(|| ProtocolError::InvalidAddress("No address to bind the server to".to_string()))?;
+        let transport = Transport::bind(addr, self.config).await?;
+        if let Some(crypto) = self.crypto {
+            transport.set_crypto(crypto).await;
+        }
+        if let Some(compression) = self.compression {
+            transport.set_compression(compression).await;
+        }
+        let mut server = Server::with_transport(transport);
+        if let Some(identity) = self.identity {
+            server.set_identity(identity);
+        }
+        if let Some((key, lifetime)) = self.session_tickets {
+            server.set_session_tickets(key, lifetime);
+        }
+        Ok(server)
+    }
+}
+
+/// Session key a `Connect` settled on, the `ConnectAck` payload saying so and any ticket to follow it
+#[derive(Default)]
+struct KeyAgreement {
+    reply: Bytes,
+    crypto: Option<Arc<CryptoProvider>>,
+    ticket: Option<Packet>,
+}
+
+/// Current time in seconds since the Unix epoch
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Text of a caught panic, when it carries any
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    #[cfg(feature = "compression")]
+    use crate::compression::{CompressionAlgorithm, CompressionProvider};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Duration};
+
+    async fn start_server() -> Arc<Server> {
+        let server = Arc::new(
+            Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server
+    }
+
+    #[tokio::test]
+    async fn test_dropping_server_stops_its_tasks() {
+        let server = Arc::new(
+            Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        let listening = tokio::spawn(server.clone().listen());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // Reorder flush and idle reaper
+        assert_eq!(server.tasks.running(), 2);
+
+        // Dropping the listen future without a shutdown leaves its tasks to the server
+        listening.abort();
+        let _ = listening.await;
+        assert_eq!(server.tasks.running(), 2);
+
+        let transport = Arc::downgrade(&server.transport);
+        drop(server);
+        assert!(transport.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_packet_runs_handler_once() {
+        let server = start_server().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        server
+            .on_fn("/count", move |_ctx| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(Response::text("ok"))
+            })
+            .await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = Packet::new_data("/count".to_string(), Bytes::from("hi"), 7);
+        let data = packet.serialize().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        socket.send_to(&data, server_addr).await.unwrap();
+        socket.send_to(&data, server_addr).await.unwrap();
+
+        // Both copies are acknowledged even though only one is delivered
+        let mut acks = 0;
+        let mut buf = vec![0u8; 65536];
+        while let Ok(Ok((len, _))) =
+            timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await
+        {
+            let reply = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            if reply.packet_type == PacketType::Ack && reply.sequence == 7 {
+                acks += 1;
+            }
+        }
+
+        assert_eq!(acks, 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_packets_are_dropped() {
+        let mut server = Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+            .await
+            .unwrap();
+        server.set_max_packet_age(Some(Duration::from_secs(5)));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server.set_route_max_packet_age("/archive", Duration::MAX).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for route in ["/live", "/archive"] {
+            let tx = tx.clone();
+            server
+                .on_fn(route, move |ctx| {
+                    let _ = tx.send((ctx.route.clone(), ctx.packet.sequence));
+                    Ok(Response::text("ok"))
+                })
+                .await;
+        }
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let sixty_seconds_ago = Packet::new_data(String::new(), Bytes::new(), 0).timestamp - 60_000;
+        for (route, sequence, timestamp) in [
+            ("/live", 1, Some(sixty_seconds_ago)),
+            ("/archive", 2, Some(sixty_seconds_ago)),
+            ("/live", 3, None),
+        ] {
+            let mut packet = Packet::new_data(route.to_string(), Bytes::from("hi"), sequence);
+            if let Some(timestamp) = timestamp {
+                packet.timestamp = timestamp;
+            }
+            socket.send_to(&packet.serialize().unwrap(), server_addr).await.unwrap();
+        }
+
+        let mut handled = Vec::new();
+        while let Ok(Some(call)) = timeout(Duration::from_millis(300), rx.recv()).await {
+            handled.push(call);
+        }
+        handled.sort_by_key(|(_, sequence)| *sequence);
+        assert_eq!(handled, vec![("/archive".to_string(), 2), ("/live".to_string(), 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_get_error_replies() {
+        let mut server = Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+            .await
+            .unwrap();
+        server.set_handler_timeout(Some(Duration::from_millis(50)));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server
+            .on_async("/slow", |_ctx| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(Response::text("late"))
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let missing = client.request("/missing", Bytes::new()).await.unwrap_err();
+        assert!(matches!(missing, ProtocolError::RouteNotFound(route) if route == "/missing"));
+
+        let slow = client.request("/slow", Bytes::new()).await.unwrap_err();
+        assert!(matches!(slow, ProtocolError::Remote { code: RemoteErrorCode::Timeout, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_missing_route_returns_route_not_found() {
+        let server = start_server().await;
+
+        // The reply names the request's sequence, not one of the server's own
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = Packet::new_data("/missing".to_string(), Bytes::new(), 57);
+        socket
+            .send_to(&request.serialize().unwrap(), server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 65536];
+        let reply = loop {
+            let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+            let reply = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            if reply.packet_type != PacketType::Ack {
+                break reply;
+            }
+        };
+        assert_eq!(reply.packet_type, PacketType::Error);
+        assert_eq!(reply.reply_to, Some(57));
+        assert_ne!(reply.sequence, 57);
+        assert!(matches!(reply.remote_error().unwrap(), ProtocolError::RouteNotFound(route) if route == "/missing"));
+    }
+
+    #[tokio::test]
+    async fn test_early_data_is_rejected_with_encryption() {
+        let config = TransportConfig { enable_encryption: true, ..Default::default() };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let exchange = KeyExchange::new();
+        let connect = Packet::new_connect_with_early_data(&exchange.public_key(), b"", "/echo".to_string(), b"hi");
+        socket
+            .send_to(&connect.serialize().unwrap(), server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+        let reply = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert_eq!(reply.packet_type, PacketType::ConnectReject);
+
+        // Nothing was agreed, so no session key is left behind
+        assert!(server.transport.session_crypto(client_addr).await.is_none());
+        assert!(server.connections().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_ticket_resumption_cannot_be_replayed() {
+        async fn resume(socket: &UdpSocket, server_addr: SocketAddr, ticket: &[u8], nonce: u8) -> Bytes {
+            let nonce = [nonce; RESUMPTION_NONCE_SIZE];
+            let connect = Packet::new_connect_with(&[&nonce[..], ticket].concat(), b"");
+            socket.send_to(&connect.serialize().unwrap(), server_addr).await.unwrap();
+            let mut buf = vec![0u8; 65536];
+            let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+            let ack = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            assert_eq!(ack.packet_type, PacketType::ConnectAck);
+            ack.payload
+        }
+
+        let config = TransportConfig { enable_encryption: true, ..Default::default() };
+        let mut server = Server::new(([127, 0, 0, 1], 0), config).await.unwrap();
+        server.set_session_tickets(TicketKey::from_bytes(&[7; 32]), Duration::from_secs(60));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        let server_addr = server.local_addr().unwrap();
+
+        let ticket = TicketKey::from_bytes(&[7; 32]).seal(&[1; 32], unix_time() + 60).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+
+        let ack = resume(&socket, server_addr, &ticket, 1).await;
+        assert_eq!(ack, Bytes::copy_from_slice(&[1; RESUMPTION_NONCE_SIZE]));
+        let session = server.transport.session_crypto(client_addr).await.unwrap();
+
+        // Resuming again does not replace the established session key
+        assert!(resume(&socket, server_addr, &ticket, 2).await.is_empty());
+        assert!(Arc::ptr_eq(&server.transport.session_crypto(client_addr).await.unwrap(), &session));
+
+        // Once the session is gone, a replay of the first Connect is still refused
+        server.remove_connection(client_addr).await;
+        assert!(resume(&socket, server_addr, &ticket, 1).await.is_empty());
+        assert!(server.transport.session_crypto(client_addr).await.is_none());
+        assert!(!resume(&socket, server_addr, &ticket, 3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_none_response_sends_no_reply() {
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), TransportConfig::default()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/notify", |_ctx| Ok(Response::none())).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = Packet::new_data("/notify".to_string(), Bytes::from("event"), 9);
+        socket
+            .send_to(&request.serialize().unwrap(), server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        // The request is ACKed, then nothing else arrives
+        let mut buf = vec![0u8; 65536];
+        let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+        let ack = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert_eq!(ack.packet_type, PacketType::Ack);
+        assert!(timeout(Duration::from_millis(200), socket.recv(&mut buf)).await.is_err());
+        assert_eq!(server.stats().await.pending_acks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unreliable_send_is_neither_acked_nor_answered() {
+        let server = start_server().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/telemetry", move |ctx| {
+                let _ = tx.send(ctx.packet.flags.unreliable);
+                Ok(Response::text("ignored"))
+            })
+            .await;
+
+        let client = Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        client.send_unreliable("/telemetry", Bytes::from("42")).await.unwrap();
+
+        let unreliable = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert!(unreliable);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // No ACK was sent and the response was dropped rather than queued for delivery
+        let stats = server.stats().await;
+        assert_eq!(stats.pending_acks, 0);
+        assert_eq!(stats.acks_received, 0);
+        assert_eq!(client.stats().await.pending_acks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_route_concurrency_limit_serializes_handlers() {
+        let server = start_server().await;
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (running, most) = (active.clone(), peak.clone());
+        server
+            .on_async("/expensive", move |_ctx| {
+                let (running, most) = (running.clone(), most.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(Response::text("done"))
+                }
+            })
+            .await;
+        server.set_route_concurrency("/expensive", 1, OverflowPolicy::Queue).await;
+        server
+            .on_async("/busy", |_ctx| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(Response::text("done"))
+            })
+            .await;
+        server.set_route_concurrency("/busy", 1, OverflowPolicy::Reject).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let requests: Vec<_> = (0..3)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.request("/expensive", Bytes::new()).await })
+            })
+            .collect();
+        for request in requests {
+            assert_eq!(request.await.unwrap().unwrap(), Bytes::from("done"));
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+
+        // Over the limit of a rejecting route, one of two requests is turned away
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/busy", Bytes::new()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let second = client.request("/busy", Bytes::new()).await;
+        let results = [first.await.unwrap(), second];
+        assert_eq!(results.iter().filter(|r| matches!(r, Ok(data) if data == "done")).count(), 1);
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Err(ProtocolError::Remote { code: RemoteErrorCode::Busy, .. }))));
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_global_limit_are_shed() {
+        let mut server = Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+            .await
+            .unwrap();
+        server.set_max_concurrent_requests(Some(2));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server
+            .on_async("/slow", |_ctx| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                Ok(Response::text("done"))
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let running: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.request("/slow", Bytes::new()).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stats().await.in_flight_requests, 2);
+
+        // Replies are matched by sequence, so check the outcomes as a whole
+        let mut results = vec![client.request("/slow", Bytes::new()).await];
+        for request in running {
+            results.push(request.await.unwrap());
+        }
+        assert_eq!(results.iter().filter(|r| matches!(r, Ok(data) if data == "done")).count(), 2);
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Err(ProtocolError::Remote { code: RemoteErrorCode::Busy, .. }))));
+        timeout(Duration::from_secs(1), async {
+            while server.stats().await.in_flight_requests > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_measures_round_trip() {
+        let server = start_server().await;
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        // Loopback takes well under a millisecond; the bound leaves room for a busy machine
+        let rtt = client.ping().await.unwrap();
+        assert!(rtt > Duration::ZERO);
+        assert!(rtt < Duration::from_millis(10), "implausible loopback RTT: {:?}", rtt);
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "msgpack", feature = "cbor"))]
+    async fn test_typed_handlers_use_configured_codec() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Pair {
+            a: i32,
+            b: i32,
+        }
+
+        let mut server = Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+            .await
+            .unwrap();
+        server.set_codec(Codec::MessagePack);
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server.set_route_codec("/swap/cbor", Codec::Cbor).await;
+        for route in ["/swap", "/swap/cbor"] {
+            server.on_typed(route, |p: Pair| Ok(Pair { a: p.b, b: p.a })).await;
+        }
+        server.on_request("/negate", |n: i32| Ok(-n)).await;
+
+        let client = Arc::new(
+            Client::builder(server.local_addr().unwrap())
+                .codec(Codec::MessagePack)
+                .build()
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        for (route, codec) in [("/swap", Codec::MessagePack), ("/swap/cbor", Codec::Cbor)] {
+            let payload = codec.encode(&Pair { a: 1, b: 2 }).unwrap();
+            let reply = client.request(route, payload).await.unwrap();
+            assert_eq!(codec.decode::<Pair>(&reply).unwrap(), Pair { a: 2, b: 1 });
+        }
+
+        let request = protocol::Request::new(5);
+        let response: protocol::Response<i32> = client.request_typed("/negate", &request).await.unwrap();
+        assert_eq!(response.data, Some(-5));
+
+        // JSON is not understood on a MessagePack route
+        let err = client.request("/swap", Bytes::from(r#"{"a":1,"b":2}"#)).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::Remote { code: RemoteErrorCode::HandlerError, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_route_metrics_count_requests_and_errors() {
+        let server = start_server().await;
+        server.on_fn("/ok", |_ctx| Ok(Response::text("ok"))).await;
+        server
+            .on_fn("/fail", |_ctx| Err(ProtocolError::InvalidPayload("nope".into())))
+            .await;
+        server.on_fn("/user/:id", |_ctx| Ok(Response::text("user"))).await;
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        for _ in 0..3 {
+            client.request("/ok", Bytes::new()).await.unwrap();
+        }
+        for _ in 0..2 {
+            client.request("/fail", Bytes::new()).await.unwrap_err();
+        }
+        for id in 0..4 {
+            client.request(format!("/user/{}", id), Bytes::new()).await.unwrap();
+        }
+        client.request("/missing", Bytes::new()).await.unwrap_err();
+        client.request("/missing/too", Bytes::new()).await.unwrap_err();
+
+        // Paths are counted under the pattern they matched, so series stay bounded
+        let metrics = server.route_metrics().await;
+        assert_eq!(metrics.len(), 4);
+        assert_eq!(metrics["/user/:id"].requests, 4);
+        assert_eq!((metrics[metrics::UNMATCHED_ROUTE].requests, metrics[metrics::UNMATCHED_ROUTE].errors), (2, 2));
+        assert_eq!((metrics["/ok"].requests, metrics["/ok"].errors), (3, 0));
+        assert_eq!((metrics["/fail"].requests, metrics["/fail"].errors), (2, 2));
+        assert_eq!(metrics["/fail"].error_rate(), 1.0);
+        assert_eq!(metrics["/ok"].latency_buckets.iter().sum::<u64>(), 3);
+
+        let text = server.prometheus_metrics().await;
+        assert!(text.contains("fast_protocol_route_requests_total{route=\"/ok\"} 3\n"));
+        assert!(text.contains("fast_protocol_route_errors_total{route=\"/fail\"} 2\n"));
+        assert!(text.contains("fast_protocol_route_requests_total{route=\"<unmatched>\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_idempotency_key_returns_cached_response() {
+        let server = start_server().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        server
+            .on_fn("/charge", move |ctx| {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(Response::text(format!("charged {} (call {})", ctx.text()?, n)))
+            })
+            .await;
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let first = client.request_idempotent("/charge", "order-1", Bytes::from("$5")).await.unwrap();
+        assert_eq!(first, "charged $5 (call 1)");
+        let repeat = client.request_idempotent("/charge", "order-1", Bytes::from("$5")).await.unwrap();
+        assert_eq!(repeat, first);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let other = client.request_idempotent("/charge", "order-2", Bytes::from("$7")).await.unwrap();
+        assert_eq!(other, "charged $7 (call 2)");
+        assert!(client.request_idempotent("/charge", "", Bytes::new()).await.is_err());
+    }
+
+    #[test]
+    fn test_idempotency_cache_drops_oldest_keys() {
+        let key = |name: &str| ("peer".to_string(), "/charge".to_string(), name.to_string());
+        let ttl = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut cache = IdempotencyCache::default();
+
+        let first = cache.get_or_insert(key("a"), start, ttl, 2);
+        cache.get_or_insert(key("b"), start, ttl, 2);
+        // A repeat finds its key even when the cache is full
+        assert!(Arc::ptr_eq(&first, &cache.get_or_insert(key("a"), start, ttl, 2)));
+
+        // Full, so the oldest key makes room
+        cache.get_or_insert(key("c"), start, ttl, 2);
+        assert_eq!(cache.replies.len(), 2);
+        assert!(!cache.replies.contains_key(&key("a")));
+
+        // Expired keys go as soon as another request comes in
+        cache.get_or_insert(key("d"), start + ttl, ttl, 2);
+        assert_eq!(cache.replies.len(), 1);
+        assert_eq!(cache.order.len(), 1);
+        assert!(cache.replies.contains_key(&key("d")));
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_in_response() {
+        let server = start_server().await;
+        server
+            .on_request("/double", |n: i32| {
+                if n < 0 {
+                    Err(ProtocolError::InvalidPayload("negative".into()))
+                } else {
+                    Ok(n * 2)
+                }
+            })
+            .await;
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let request = protocol::Request { id: "trace-1".to_string(), data: 21 };
+        let response: protocol::Response<i32> = client.request_typed("/double", &request).await.unwrap();
+        assert_eq!(response.id, "trace-1");
+        assert!(response.success);
+        assert_eq!(response.data, Some(42));
+
+        let request = protocol::Request::new(-1);
+        let response: protocol::Response<i32> = client.request_typed("/double", &request).await.unwrap();
+        assert_eq!(response.id, request.id);
+        assert!(!response.success);
+        assert_eq!(response.error.as_deref(), Some("Invalid payload: negative"));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_handler_fails_only_its_request() {
+        let server = start_server().await;
+        server
+            .on_fn("/boom", |_ctx| -> Result<Response> { panic!("deliberate") })
+            .await;
+        server.on_fn("/ok", |_ctx| Ok(Response::text("fine"))).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        client.connect().await.unwrap();
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let err = client.request("/boom", Bytes::new()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::Remote { code: RemoteErrorCode::HandlerPanic, message } if message == "Handler panicked: deliberate"
+        ));
+
+        // The server keeps serving
+        let reply = client.request("/ok", Bytes::new()).await.unwrap();
+        assert_eq!(reply, Bytes::from("fine"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_negotiates_encryption() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/secret", move |ctx| {
+                let _ = tx.send((ctx.packet.flags.encrypted, ctx.payload.clone()));
+                Ok(Response::text("ok"))
+            })
+            .await;
+
+        let client = Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        client.send("/secret", Bytes::from("classified")).await.unwrap();
+
+        let (encrypted, payload) = timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(encrypted);
+        assert_eq!(payload, Bytes::from("classified"));
+
+        let connections = server.connections().await;
+        assert_eq!(connections.len(), 1);
+        assert!(server.connection(connections[0]).await.unwrap().crypto.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_server_key_is_verified() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let identity = ServerIdentity::generate();
+        let server_key = identity.public_key();
+        let server = Arc::new(
+            Server::builder()
+                .bind(([127, 0, 0, 1], 0))
+                .transport_config(config.clone())
+                .identity(identity)
+                .build()
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        let server_addr = server.local_addr().unwrap();
+
+        let trusting = Client::builder(server_addr)
+            .transport_config(config.clone())
+            .pin_server_key(server_key)
+            .build()
+            .await
+            .unwrap();
+        trusting.connect().await.unwrap();
+        assert!(trusting.is_connected());
+
+        let wrong = Client::builder(server_addr)
+            .transport_config(config.clone())
+            .pin_server_key(ServerIdentity::generate().public_key())
+            .build()
+            .await
+            .unwrap();
+        let err = wrong.connect().await.unwrap_err();
+        assert!(matches!(err, ProtocolError::AuthenticationFailed(msg) if msg.contains("not pinned")));
+        assert!(!wrong.is_connected());
+
+        // A server without an identity cannot satisfy a pin
+        let anonymous = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(anonymous.clone().listen());
+        let unsigned = Client::builder(anonymous.local_addr().unwrap())
+            .transport_config(config)
+            .pin_server_key(server_key)
+            .build()
+            .await
+            .unwrap();
+        assert!(matches!(
+            unsigned.connect().await,
+            Err(ProtocolError::AuthenticationFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handler_rejects_unencrypted_requests() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        server
+            .on_fn("/sensitive", |ctx| {
+                if !ctx.is_encrypted() {
+                    return Err(ProtocolError::Encryption("Request must be encrypted".to_string()));
+                }
+                Ok(Response::text("ok"))
+            })
+            .await;
+
+        let secure = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+                .await
+                .unwrap(),
+        );
+        secure.connect().await.unwrap();
+        tokio::spawn(secure.clone().start_recv_loop());
+        let reply = secure.request("/sensitive", Bytes::new()).await.unwrap();
+        assert_eq!(reply, Bytes::from("ok"));
+
+        // A plaintext request gets an error reply instead
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = Packet::new_data("/sensitive".to_string(), Bytes::new(), 0);
+        socket
+            .send_to(&request.serialize().unwrap(), server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 65536];
+        let error = loop {
+            let len = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await.unwrap().unwrap();
+            let reply = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            if reply.packet_type == PacketType::Error {
+                break reply.remote_error().unwrap();
+            }
+        };
+        assert!(matches!(error, ProtocolError::Remote { code: RemoteErrorCode::HandlerError, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_connect_after_recv_loop_started() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/ping", |_ctx| Ok(Response::text("pong"))).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(client.clone().start_recv_loop());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The recv loop hands the ConnectAck to the handshake instead of swallowing it
+        client.connect().await.unwrap();
+        assert!(client.is_connected());
+
+        let reply = client.request("/ping", Bytes::new()).await.unwrap();
+        assert_eq!(reply, Bytes::from("pong"));
+    }
+
+    #[tokio::test]
+    async fn test_rekey_switches_session_key() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/secret", move |ctx| {
+                let _ = tx.send(ctx.text()?);
+                Ok(Response::text("ok"))
+            })
+            .await;
+
+        let client = Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+        client.connect().await.unwrap();
+        let id = server.connections().await[0];
+        let before = server.connection(id).await.unwrap().crypto.unwrap();
+
+        client.rekey().await.unwrap();
+        client.send("/secret", Bytes::from("after rekey")).await.unwrap();
+
+        let text = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(text, "after rekey");
+        // The connection table picks up the rotated key once the rekey is handled
+        timeout(Duration::from_secs(2), async {
+            loop {
+                let after = server.connection(id).await.unwrap().crypto.unwrap();
+                if !Arc::ptr_eq(&before, &after) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_lifecycle() {
+        let server = start_server().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_disconnect(move |id, _addr| {
+                let _ = tx.send(id);
+            })
+            .await;
+
+        let server_addr = server.local_addr().unwrap();
+        let first = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        let second = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        first.connect().await.unwrap();
+        second.connect().await.unwrap();
+        assert_eq!(server.connections().await.len(), 2);
+
+        // Explicit disconnect tears the connection down
+        first.disconnect().await.unwrap();
+        let gone = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        let remaining = server.connections().await;
+        assert_eq!(remaining.len(), 1);
+        assert_ne!(remaining[0], gone);
+
+        // Silence past the idle timeout tears the other one down
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let reaped = server.reap_idle_connections(Duration::from_millis(10)).await;
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(rx.recv().await.unwrap(), reaped[0]);
+        assert!(server.connections().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reaper_evicts_connections_without_heartbeats() {
+        let mut server = Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+            .await
+            .unwrap();
+        server.set_idle_timeout(Some(Duration::from_millis(300)));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_disconnect(move |id, _addr| {
+                let _ = tx.send(id);
+            })
+            .await;
+
+        let server_addr = server.local_addr().unwrap();
+        let heartbeats = TransportConfig {
+            heartbeat_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let live = Arc::new(Client::new(([127, 0, 0, 1], 0), server_addr, heartbeats).await.unwrap());
+        live.connect().await.unwrap();
+        let live_id = server.connections().await[0];
+        tokio::spawn(live.clone().start_recv_loop());
+
+        // Without a receive loop this client never sends heartbeats
+        let silent = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        silent.connect().await.unwrap();
+        assert_eq!(server.connections().await.len(), 2);
+
+        let evicted = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_ne!(evicted, live_id);
+        assert_eq!(server.connections().await, vec![live_id]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_negotiates_protocol_version() {
+        use crate::packet::{FIXED_WIDTH_VERSION, VARINT_VERSION};
+
+        let versions = |min_protocol_version, max_protocol_version| TransportConfig {
+            min_protocol_version,
+            max_protocol_version,
+            ..Default::default()
+        };
+        let server = Arc::new(
+            Server::new(([127, 0, 0, 1], 0), versions(FIXED_WIDTH_VERSION, VARINT_VERSION))
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+        let server_addr = server.local_addr().unwrap();
+
+        // A v1 client settles on v1 and is answered in it
+        let v1 = Client::new(
+            ([127, 0, 0, 1], 0),
+            server_addr,
+            versions(FIXED_WIDTH_VERSION, FIXED_WIDTH_VERSION),
+        )
+        .await
+        .unwrap();
+        let v1 = Arc::new(v1);
+        v1.connect().await.unwrap();
+        assert_eq!(v1.protocol_version().await, FIXED_WIDTH_VERSION);
+        let connections = server.connections().await;
+        assert_eq!(server.connection(connections[0]).await.unwrap().version, FIXED_WIDTH_VERSION);
+        // v1 has no room for `reply_to`, so its replies could not be matched
+        let err = v1.request("/echo", Bytes::from("old")).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::VersionMismatch { expected: PROTOCOL_VERSION, actual: FIXED_WIDTH_VERSION }));
+
+        // A current client steps down to the server's highest version
+        let current = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        current.connect().await.unwrap();
+        assert_eq!(current.protocol_version().await, VARINT_VERSION);
+
+        // A server that dropped v1 refuses v1 clients
+        let strict = Arc::new(
+            Server::new(([127, 0, 0, 1], 0), versions(VARINT_VERSION, PROTOCOL_VERSION))
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(strict.clone().listen());
+        let refused = Client::new(
+            ([127, 0, 0, 1], 0),
+            strict.local_addr().unwrap(),
+            versions(FIXED_WIDTH_VERSION, FIXED_WIDTH_VERSION),
+        )
+        .await
+        .unwrap();
+        let err = refused.connect().await.unwrap_err();
+        assert!(matches!(err, ProtocolError::AuthenticationFailed(reason) if reason.contains("protocol version 1")));
+        assert!(strict.connections().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_admits_only_valid_tokens() {
+        let server = start_server().await;
+        server
+            .set_authenticator(|ctx| match &ctx.payload[..] {
+                b"letmein" => Ok(AuthInfo::new("alice").with_claim("role", "admin")),
+                _ => Err(ProtocolError::AuthenticationFailed("bad token".to_string())),
+            })
+            .await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/whoami", move |ctx| {
+                let _ = tx.send(ctx.auth);
+                Ok(Response::text("ok"))
+            })
+            .await;
+        let server_addr = server.local_addr().unwrap();
+
+        let mut invalid = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        invalid.set_auth_token("guess");
+        let invalid = Arc::new(invalid);
+        let rejected = invalid.connect().await.unwrap_err();
+        assert!(matches!(rejected, ProtocolError::AuthenticationFailed(reason) if reason.contains("bad token")));
+        assert!(server.connections().await.is_empty());
+
+        // Requests from the rejected peer never reach the handler
+        tokio::spawn(invalid.clone().start_recv_loop());
+        let refused = invalid.request("/whoami", Bytes::new()).await.unwrap_err();
+        assert!(matches!(refused, ProtocolError::Remote { code: RemoteErrorCode::Unauthenticated, .. }));
+
+        let mut valid = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        valid.set_auth_token("letmein");
+        valid.connect().await.unwrap();
+        assert_eq!(server.connections().await.len(), 1);
+
+        valid.send("/whoami", Bytes::new()).await.unwrap();
+        let auth = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(auth, Some(AuthInfo::new("alice").with_claim("role", "admin")));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handlers_see_the_correlation_id() {
+        let server = start_server().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/traced", move |ctx| {
+                let _ = tx.send(ctx.correlation_id);
+                Ok(Response::text("ok"))
+            })
+            .await;
+
+        let client = Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+            .await
+            .unwrap();
+        let sequence = client.send_on(3, "/traced", Bytes::new()).await.unwrap();
+        let id = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(id, format!("{}/3/{}", client.local_addr().unwrap(), sequence));
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_serves_v4_and_v6_clients() {
+        let server = Arc::new(Server::bind_dual_stack(0, TransportConfig::default()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/hello", move |ctx| {
+                let _ = tx.send(ctx.remote_addr);
+                Ok(Response::text("hi"))
+            })
+            .await;
+        let port = server.local_addr().unwrap().port();
+
+        let v4 = Client::new(([127, 0, 0, 1], 0), ([127, 0, 0, 1], port).into(), TransportConfig::default())
+            .await
+            .unwrap();
+        let v6_loopback = std::net::Ipv6Addr::LOCALHOST;
+        let v6 = Client::new((v6_loopback, 0), (v6_loopback, port).into(), TransportConfig::default())
+            .await
+            .unwrap();
+        v4.connect().await.unwrap();
+        v6.connect().await.unwrap();
+        assert_eq!(server.connections().await.len(), 2);
+
+        v4.send("/hello", Bytes::new()).await.unwrap();
+        let from = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        // The v4 client shows up as itself, not as a v4-mapped v6 address
+        assert_eq!(from, v4.local_addr().unwrap());
+
+        v6.send("/hello", Bytes::new()).await.unwrap();
+        let from = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(from, v6.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_and_push() {
+        let server = start_server().await;
+        let server_addr = server.local_addr().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut clients = Vec::new();
+        for name in ["first", "second"] {
+            let client = Arc::new(
+                Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+                    .await
+                    .unwrap(),
+            );
+            client.connect().await.unwrap();
+
+            let tx = tx.clone();
+            client
+                .on_fn("/news", move |ctx| {
+                    let _ = tx.send((name, ctx.text()?));
+                    Ok(Response::text("ok"))
+                })
+                .await;
+            tokio::spawn(client.clone().start_recv_loop());
+            clients.push(client);
+        }
+
+        let report = server.broadcast("/news", Bytes::from("hello all")).await.unwrap();
+        assert_eq!(report, BroadcastReport { sent: 2, failed: Vec::new() });
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let message = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+            received.push(message);
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            vec![("first", "hello all".to_string()), ("second", "hello all".to_string())]
+        );
+
+        // A push reaches exactly one client
+        let target = server.connections().await[0];
+        server.push(target, "/news", Bytes::from("just you")).await.unwrap();
+        let (_, text) = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(text, "just you");
+        assert!(timeout(Duration::from_millis(100), rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_does_not_wait_on_black_holed_connection() {
+        // A window of one packet makes an unacknowledged send block the next
+        let config = TransportConfig {
+            initial_congestion_window: 1,
+            ..TransportConfig::default()
+        };
+        let mut server = Server::new(([127, 0, 0, 1], 0), config).await.unwrap();
+        server.set_broadcast_timeout(Duration::from_millis(200));
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        let server_addr = server.local_addr().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let healthy = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        healthy.connect().await.unwrap();
+        healthy
+            .on_fn("/news", move |ctx| {
+                let _ = tx.send(ctx.text()?);
+                Ok(Response::text("ok"))
+            })
+            .await;
+        tokio::spawn(healthy.clone().start_recv_loop());
+
+        // Connected, but never reads again, so nothing sent to it is ACKed
+        let black_hole = Client::new(([127, 0, 0, 1], 0), server_addr, TransportConfig::default())
+            .await
+            .unwrap();
+        black_hole.connect().await.unwrap();
+        let black_hole_addr = black_hole.local_addr().unwrap();
+        let mut black_hole_id = None;
+        for id in server.connections().await {
+            if server.connection(id).await.unwrap().addr == black_hole_addr {
+                black_hole_id = Some(id);
+            }
+        }
+        let black_hole_id = black_hole_id.unwrap();
+
+        let report = server.broadcast("/news", Bytes::from("first")).await.unwrap();
+        assert_eq!(report, BroadcastReport { sent: 2, failed: Vec::new() });
+
+        let started = Instant::now();
+        let report = server.broadcast("/news", Bytes::from("second")).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(report, BroadcastReport { sent: 1, failed: vec![black_hole_id] });
+
+        for expected in ["first", "second"] {
+            let text = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+            assert_eq!(text, expected);
+        }
+
+        assert!(server.evict(black_hole_id).await);
+        assert!(!server.evict(black_hole_id).await);
+        assert_eq!(server.connections().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_response() {
+        use futures::StreamExt;
+
+        let server = start_server().await;
+        server
+            .on_stream("/count", |ctx, sink| async move {
+                let n: u32 = ctx.text()?.parse().unwrap();
+                // More chunks than the window so the sink has to wait for ACKs
+                for i in 0..n {
+                    sink.send(Bytes::from(i.to_string())).await?;
+                }
+                Ok(())
+            })
+            .await;
+        server
+            .on_stream("/fail", |_ctx, sink| async move {
+                sink.send(Bytes::from("partial")).await?;
+                Err(ProtocolError::Other("boom".to_string()))
+            })
+            .await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let stream = client.request_stream("/count", Bytes::from("100")).await.unwrap();
+        let chunks: Vec<String> = timeout(Duration::from_secs(5), stream.collect::<Vec<_>>())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect();
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        assert_eq!(chunks, expected);
+
+        let mut stream = client.request_stream("/fail", Bytes::new()).await.unwrap();
+        let first = timeout(Duration::from_secs(2), stream.next()).await.unwrap().unwrap();
+        assert_eq!(first.unwrap(), Bytes::from("partial"));
+        let second = timeout(Duration::from_secs(2), stream.next()).await.unwrap().unwrap();
+        assert!(matches!(second, Err(ProtocolError::Stream(message)) if message.contains("boom")));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_builders_wire_crypto_and_compression() {
+        let key = CryptoProvider::generate_key();
+        let config = TransportConfig {
+            enable_encryption: true,
+            enable_compression: true,
+            ..Default::default()
+        };
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0))
+            .transport_config(config.clone())
+            .crypto(CryptoProvider::new_chacha(&key))
+            .compression(CompressionProvider::new_zstd(3))
+            .build()
+            .await
+            .unwrap();
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+
+        let client = Client::builder(server.local_addr().unwrap())
+            .transport_config(config)
+            .crypto(CryptoProvider::new_chacha(&key))
+            .compression(CompressionProvider::new_lz4(4))
+            .request_timeout(Duration::from_secs(2))
+            .build()
+            .await
+            .unwrap();
+        let client = Arc::new(client);
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let payload = Bytes::from("builder ".repeat(100));
+        assert_eq!(client.request("/echo", payload.clone()).await.unwrap(), payload);
+
+        assert!(matches!(
+            Server::builder().build().await,
+            Err(ProtocolError::InvalidAddress(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_crypto_can_be_set_while_running() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        tokio::spawn(server.clone().listen());
+        server.on_fn("/echo", |ctx| Ok(Response::new(ctx.payload))).await;
+
+        let client = Arc::new(
+            Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+                .await
+                .unwrap(),
+        );
+        tokio::spawn(client.clone().start_recv_loop());
+
+        // Both transports are shared with running tasks by now
+        for key in [[1u8; 32], [2u8; 32]] {
+            server.set_crypto(CryptoProvider::new_aes(&key)).await;
+            client.set_crypto(CryptoProvider::new_aes(&key)).await;
+            let response = client.request("/echo", Bytes::from("secret")).await.unwrap();
+            assert_eq!(response, Bytes::from("secret"));
+        }
+    }
+
+    /// XOR "cipher" that also carries the AAD, to check it comes back unchanged
+    struct XorCrypto(u8);
+
+    impl Crypto for XorCrypto {
+        fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+            let mut out: Vec<u8> = data.iter().map(|b| b ^ self.0).collect();
+            out.extend_from_slice(aad);
+            Ok(Bytes::from(out))
+        }
+
+        fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Bytes> {
+            let (ciphertext, tag) = data.split_at(data.len().saturating_sub(aad.len()));
+            if tag != aad {
+                return Err(ProtocolError::Encryption("AAD mismatch".to_string()));
+            }
+            Ok(ciphertext.iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_crypto_implementation() {
+        let config = TransportConfig {
+            enable_encryption: true,
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        server.set_crypto(XorCrypto(0x5a)).await;
+        tokio::spawn(server.clone().listen());
+        server
+            .on_fn("/echo", |ctx| {
+                assert!(ctx.packet.flags.encrypted);
+                Ok(Response::new(ctx.payload))
+            })
+            .await;
+
+        let client = Client::builder(server.local_addr().unwrap())
+            .transport_config(config)
+            .crypto(XorCrypto(0x5a))
+            .build()
+            .await
+            .unwrap();
+        let client = Arc::new(client);
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let response = client.request("/echo", Bytes::from("masked")).await.unwrap();
+        assert_eq!(response, Bytes::from("masked"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_mismatched_compression_algorithms() {
+        let config = TransportConfig {
+            enable_compression: true,
+            ..Default::default()
+        };
+        let server = Server::new(([127, 0, 0, 1], 0), config.clone()).await.unwrap();
+        server.set_compression(CompressionProvider::new_lz4(4)).await;
+        let server = Arc::new(server);
+        tokio::spawn(server.clone().listen());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let server_tx = tx.clone();
+        server
+            .on_fn("/echo", move |ctx| {
+                let _ = server_tx.send(("server", ctx.packet.flags.compression, ctx.payload.clone()));
+                Ok(Response::new(ctx.payload))
+            })
+            .await;
+
+        let client = Client::new(([127, 0, 0, 1], 0), server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+        client.set_compression(CompressionProvider::new_zstd(3)).await;
+        let client = Arc::new(client);
+        // The server's reply arrives as a new data packet on the same route
+        client
+            .on_fn("/echo", move |ctx| {
+                let _ = tx.send(("client", ctx.packet.flags.compression, ctx.payload.clone()));
+                Ok(Response::text("ok"))
+            })
+            .await;
+        tokio::spawn(client.clone().start_recv_loop());
+
+        let payload = Bytes::from("compress me ".repeat(100));
+        client.send("/echo", payload.clone()).await.unwrap();
+
+        let (side, algorithm, received) = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!((side, algorithm), ("server", CompressionAlgorithm::Zstd));
+        assert_eq!(received, payload);
+
+        let (side, algorithm, received) = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!((side, algorithm), ("client", CompressionAlgorithm::Lz4));
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_route_delivers_in_sequence() {
+        let config = TransportConfig {
+            ordered_delivery_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let server = Arc::new(Server::new(([127, 0, 0, 1], 0), config).await.unwrap());
+        tokio::spawn(server.clone().listen());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server
+            .on_fn("/ordered", move |ctx| {
+                let _ = tx.send(ctx.text()?);
+                Ok(Response::text("ok"))
+            })
+            .await;
+        server.set_ordered("/ordered", true).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        for sequence in [0u32, 3, 1, 2] {
+            let packet = Packet::new_data("/ordered".to_string(), Bytes::from(sequence.to_string()), sequence);
+            socket.send_to(&packet.serialize().unwrap(), server_addr).await.unwrap();
+        }
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(order, vec!["0", "1", "2", "3"]);
+
+        // Sequence 4 never arrives, so 5 is released once the gap times out
+        let packet = Packet::new_data("/ordered".to_string(), Bytes::from("5"), 5);
+        socket.send_to(&packet.serialize().unwrap(), server_addr).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), rx.recv()).await.is_err());
+        let late = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(late, "5");
+    }
+
+    #[tokio::test]
+    async fn test_stalled_channel_does_not_block_another() {
+        let server = start_server().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let control = tx.clone();
+        server
+            .on_fn("/ordered", move |ctx| {
+                let _ = tx.send((ctx.packet.channel_id, ctx.text()?));
+                Ok(Response::text("ok"))
+            })
+            .await;
+        server
+            .on_channel(2, "/ordered", crate::middleware::FnHandler::new(move |ctx| {
+                let _ = control.send((ctx.packet.channel_id, format!("control {}", ctx.text()?)));
+                Ok(Response::text("ok"))
+            }))
+            .await;
+        server.set_ordered("/ordered", true).await;
+
+        // Channel 1 is missing its sequence 0, channel 2 numbers its packets from 0 on its own
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        for (channel, sequence) in [(1u16, 1u32), (2, 0), (2, 1)] {
+            let mut packet = Packet::new_data("/ordered".to_string(), Bytes::from(sequence.to_string()), sequence);
+            packet.channel_id = channel;
+            socket.send_to(&packet.serialize().unwrap(), server_addr).await.unwrap();
+        }
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            handled.push(timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(handled, vec![(2, "control 0".to_string()), (2, "control 1".to_string())]);
+        assert!(timeout(Duration::from_millis(100), rx.recv()).await.is_err());
+
+        // Filling the gap releases channel 1
+        let mut packet = Packet::new_data("/ordered".to_string(), Bytes::from("0"), 0);
+        packet.channel_id = 1;
+        socket.send_to(&packet.serialize().unwrap(), server_addr).await.unwrap();
+        for expected in ["0", "1"] {
+            let (channel, text) = timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+            assert_eq!((channel, text.as_str()), (1, expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_handlers() {
+        let server = Arc::new(
+            Server::new(([127, 0, 0, 1], 0), TransportConfig::default())
+                .await
+                .unwrap(),
+        );
+        let listening = tokio::spawn(server.clone().listen());
+
+        let (started_tx, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let done = finished.clone();
+        server
+            .on_async("/slow", move |_ctx| {
+                let started = started_tx.clone();
+                let done = done.clone();
+                async move {
+                    let _ = started.send(());
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    done.fetch_add(1, Ordering::SeqCst);
+                    Ok(Response::text("done"))
+                }
+            })
+            .await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = Packet::new_data("/slow".to_string(), Bytes::new(), 0);
+        socket
+            .send_to(&packet.serialize().unwrap(), server.local_addr().unwrap())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(2), started_rx.recv()).await.unwrap().unwrap();
+
+        server.shutdown();
+        timeout(Duration::from_secs(2), listening)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_typed_handler_decodes_and_encodes_json() {
+        #[derive(serde::Deserialize)]
+        struct Add {
+            a: i32,
+            b: i32,
+        }
+
+        #[derive(serde::Serialize)]
+        struct Sum {
+            sum: i32,
+        }
+
+        let server = start_server().await;
+        server
+            .on_typed("/add", |req: Add| Ok(Sum { sum: req.a + req.b }))
+            .await;
+        let (handler, _) = server.routes.read().await.find("/add").unwrap();
+
+        let packet = Packet::new_data("/add".to_string(), Bytes::from(r#"{"a":2,"b":3}"#), 0);
+        let ctx = Context {
+            route: packet.route.clone(),
+            payload: packet.payload.clone(),
+            remote_addr: "127.0.0.1:9000".parse().unwrap(),
+            packet,
+            params: HashMap::new(),
+            auth: None,
+            correlation_id: String::new(),
+            codec: Codec::Json,
+            extensions: Extensions::default(),
+        };
+        let response = handler.handle(ctx.clone()).await.unwrap();
+        assert_eq!(&response.data[..], br#"{"sum":5}"#);
+
+        let bad = Context {
+            payload: Bytes::from("not json"),
+            ..ctx
+        };
+        assert!(matches!(
+            handler.handle(bad).await,
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+//! Streaming responses
+
+use bytes::Bytes;
+use futures::Stream;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::error::*;
+use crate::packet::{Packet, StreamHeader};
+use crate::transport::Transport;
+
+/// Default number of unacknowledged chunks a stream may have in flight
+pub const DEFAULT_STREAM_WINDOW: usize = 32;
+
+/// How far ahead of the next expected chunk the client will buffer
+const MAX_REORDER_DISTANCE: u32 = DEFAULT_STREAM_WINDOW as u32 * 4;
+
+/// Writer handed to stream route handlers
+///
+/// Each chunk is sent as a reliable `Stream` packet tagged with the stream id
+/// and its position, so the client can put chunks back in order. The stream
+/// is ended by the server once the handler returns.
+#[derive(Clone)]
+pub struct StreamSink {
+    inner: Arc<SinkInner>,
+}
+
+struct SinkInner {
+    transport: Arc<Transport>,
+    dest: SocketAddr,
+    channel: u16,
+    route: String,
+    stream_id: u32,
+    window: usize,
+    state: Mutex<SinkState>,
+}
+
+#[derive(Default)]
+struct SinkState {
+    next_index: u32,
+    in_flight: VecDeque<u32>,
+    finished: bool,
+}
+
+impl StreamSink {
+    /// Create a sink for the stream answering the request with the given sequence
+    ///
+    /// Chunks are sent on the request's channel.
+    pub(crate) fn new(
+        transport: Arc<Transport>,
+        dest: SocketAddr,
+        channel: u16,
+        route: String,
+        stream_id: u32,
+        window: usize,
+    ) -> Self {
+        Self {
+            inner: Arc::new(SinkInner {
+                transport,
+                dest,
+                channel,
+                route,
+                stream_id,
+                window: window.max(1),
+                state: Mutex::new(SinkState::default()),
+            }),
+        }
+    }
+
+    /// Id of the stream, equal to the sequence of the request that opened it
+    pub fn stream_id(&self) -> u32 {
+        self.inner.stream_id
+    }
+
+    /// Send the next chunk of the stream
+    ///
+    /// Once `window` chunks are unacknowledged this waits for the oldest one
+    /// to be acknowledged, so a slow link slows the handler down instead of
+    /// queueing without bound.
+    pub async fn send(&self, chunk: Bytes) -> Result<()> {
+        let mut state = self.inner.state.lock().await;
+        if state.finished {
+            return Err(ProtocolError::Stream("Stream already finished".to_string()));
+        }
+        self.send_chunk(&mut state, &chunk, false, false).await
+    }
+
+    /// End the stream, carrying the handler's error to the client if it failed
+    pub(crate) async fn finish(&self, result: Result<()>) -> Result<()> {
+        let mut state = self.inner.state.lock().await;
+        if state.finished {
+            return Ok(());
+        }
+        state.finished = true;
+
+        match result {
+            Ok(()) => self.send_chunk(&mut state, &[], true, false).await,
+            Err(e) => {
+                let message = e.to_string();
+                self.send_chunk(&mut state, message.as_bytes(), true, true).await
+            }
+        }
+    }
+
+    async fn send_chunk(
+        &self,
+        state: &mut SinkState,
+        chunk: &[u8],
+        end: bool,
+        error: bool,
+    ) -> Result<()> {
+        while state.in_flight.len() >= self.inner.window {
+            if let Some(oldest) = state.in_flight.pop_front() {
+                self.inner.transport.wait_for_ack(self.inner.channel, oldest).await;
+            }
+        }
+
+        let header = StreamHeader {
+            stream_id: self.inner.stream_id,
+            index: state.next_index,
+            end,
+            error,
+        };
+        let mut packet = Packet::new_stream(self.inner.route.clone(), header, chunk);
+        packet.channel_id = self.inner.channel;
+        let sequence = self
+            .inner
+            .transport
+            .send_reliable_packet(packet, self.inner.dest)
+            .await?;
+
+        state.next_index += 1;
+        state.in_flight.push_back(sequence);
+        Ok(())
+    }
+}
+
+/// Puts the chunks of one incoming stream back in order
+pub(crate) struct StreamReassembler {
+    tx: mpsc::Sender<Result<Bytes>>,
+    next_index: u32,
+    buffered: BTreeMap<u32, (StreamHeader, Bytes)>,
+}
+
+impl StreamReassembler {
+    pub(crate) fn new(tx: mpsc::Sender<Result<Bytes>>) -> Self {
+        Self {
+            tx,
+            next_index: 0,
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    /// Accept a chunk, delivering every chunk that is now in order
+    ///
+    /// Returns true once the stream has ended or the reader has gone away.
+    pub(crate) async fn accept(&mut self, header: StreamHeader, chunk: Bytes) -> bool {
+        if header.index < self.next_index
+            || header.index - self.next_index >= MAX_REORDER_DISTANCE
+        {
+            return false;
+        }
+        self.buffered.insert(header.index, (header, chunk));
+
+        while let Some((header, chunk)) = self.buffered.remove(&self.next_index) {
+            self.next_index += 1;
+
+            let item = if header.error {
+                Some(Err(ProtocolError::Stream(
+                    String::from_utf8_lossy(&chunk).into_owned(),
+                )))
+            } else if header.end && chunk.is_empty() {
+                // The end marker of a successful stream carries no data
+                None
+            } else {
+                Some(Ok(chunk))
+            };
+            if let Some(item) = item {
+                if self.tx.send(item).await.is_err() {
+                    return true;
+                }
+            }
+            if header.end {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Fail the stream, e.g. because the connection was lost
+    pub(crate) async fn fail(&self, error: ProtocolError) {
+        let _ = self.tx.send(Err(error)).await;
+    }
+}
+
+/// Chunks of a streamed response, yielded in order
+///
+/// The stream ends after the last chunk, or yields an error if the server's
+/// handler failed or the connection was lost.
+pub struct ResponseStream {
+    rx: mpsc::Receiver<Result<Bytes>>,
+}
+
+impl ResponseStream {
+    pub(crate) fn new(rx: mpsc::Receiver<Result<Bytes>>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Stream for ResponseStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(index: u32, end: bool) -> StreamHeader {
+        StreamHeader {
+            stream_id: 1,
+            index,
+            end,
+            error: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reassembler_orders_chunks() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut reassembler = StreamReassembler::new(tx);
+
+        assert!(!reassembler.accept(header(2, false), Bytes::from("c")).await);
+        assert!(!reassembler.accept(header(0, false), Bytes::from("a")).await);
+        assert!(!reassembler.accept(header(0, false), Bytes::from("a")).await);
+        assert!(!reassembler.accept(header(3, true), Bytes::new()).await);
+        assert!(reassembler.accept(header(1, false), Bytes::from("b")).await);
+
+        let mut chunks = Vec::new();
+        while let Ok(chunk) = rx.try_recv() {
+            chunks.push(chunk.unwrap());
+        }
+        assert_eq!(chunks, vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Background tasks tied to the lifetime of their owner
+
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::task::AbortHandle;
+
+/// Tasks spawned on behalf of an owner and aborted when it is dropped
+///
+/// A task should only hold a `Weak` reference to its owner between steps;
+/// a strong one would keep the owner, and so the task, alive forever.
+#[derive(Default)]
+pub(crate) struct TaskSet {
+    handles: Mutex<Vec<AbortHandle>>,
+}
+
+impl TaskSet {
+    /// Spawn a task that runs until it finishes or the set is dropped
+    pub(crate) fn spawn<F>(&self, future: F) -> AbortHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(future).abort_handle();
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle.clone());
+        handle
+    }
+
+    /// Number of tasks still running
+    #[cfg(test)]
+    pub(crate) fn running(&self) -> usize {
+        self.handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count()
+    }
+}
+
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        for handle in self.handles.get_mut().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_dropping_set_aborts_tasks() {
+        let tasks = TaskSet::default();
+        let token = Arc::new(());
+        let held = token.clone();
+        tasks.spawn(async move {
+            let _held = held;
+            std::future::pending::<()>().await;
+        });
+        tasks.spawn(async {});
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(tasks.running(), 1);
+
+        drop(tasks);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(Arc::strong_count(&token), 1);
+    }
+}
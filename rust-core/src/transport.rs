@@ -1,20 +1,32 @@
 //! UDP transport layer with reliability
 
-use bytes::Bytes;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::Stream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, RwLock, Mutex};
+use tokio::sync::{Notify, RwLock, Mutex};
+use tokio::task::AbortHandle;
 use tokio::time;
-use tracing::{debug, warn, error};
+use tracing::{debug, trace, warn, error};
 
-use crate::crypto::CryptoProvider;
+use crate::clock::{SystemClock, TimeSource};
+use crate::crypto::{Crypto, CryptoProvider, DEFAULT_REKEY_GRACE};
+use crate::compression::Compression;
+#[cfg(feature = "compression")]
 use crate::compression::CompressionProvider;
-use crate::packet::{Packet, PacketType};
+use crate::packet::{FragmentHeader, Packet, PacketLimits, PacketType, DEFAULT_CHANNEL, FIXED_WIDTH_VERSION};
 use crate::error::*;
-use crate::{DEFAULT_ACK_TIMEOUT_MS, MAX_RETRANSMIT_ATTEMPTS};
+use crate::mtu::{self, PathMtu};
+use crate::pacing::TokenBucket;
+use crate::pool::RecvPool;
+use crate::tasks::TaskSet;
+use crate::{DEFAULT_ACK_TIMEOUT_MS, MAX_PACKET_SIZE, MAX_RETRANSMIT_ATTEMPTS, PROTOCOL_VERSION};
 
 /// Pending packet waiting for acknowledgment
 struct PendingPacket {
@@ -24,6 +36,171 @@ struct PendingPacket {
     attempts: u8,
 }
 
+/// Upper bound on the adaptive retransmission timeout
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Upper bound on `ack_delay`, since the delay adds to every RTT sample the sender takes
+const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// ACKs held back for a peer until they are due
+struct DelayedAcks {
+    due: Instant,
+    acks: Vec<Packet>,
+}
+
+/// Smoothed round-trip time estimate for one destination (Jacobson/Karels)
+#[derive(Debug, Clone, Copy)]
+struct RttEstimator {
+    srtt: Duration,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Start an estimate from the first RTT sample
+    fn new(sample: Duration) -> Self {
+        Self {
+            srtt: sample,
+            rttvar: sample / 2,
+        }
+    }
+
+    /// Fold a new RTT sample into the estimate
+    fn update(&mut self, sample: Duration) {
+        let delta = self.srtt.abs_diff(sample);
+        self.rttvar = (self.rttvar * 3 + delta) / 4;
+        self.srtt = (self.srtt * 7 + sample) / 8;
+    }
+
+    /// Retransmission timeout derived from the estimate
+    fn rto(&self, min_rto: Duration) -> Duration {
+        (self.srtt + self.rttvar * 4).clamp(min_rto, MAX_RTO)
+    }
+}
+
+/// Callback told about each retransmission: channel, sequence, attempt and destination
+type RetransmitHook = Arc<dyn Fn(u16, u32, u8, SocketAddr) + Send + Sync>;
+
+/// Callback told about each reliable packet given up on: channel, sequence and destination
+type DropHook = Arc<dyn Fn(u16, u32, SocketAddr) + Send + Sync>;
+
+/// Smallest congestion window, so a lossy link still makes progress
+const MIN_CWND: usize = 1;
+
+/// AIMD congestion window for one destination
+#[derive(Debug, Clone, Copy)]
+struct CongestionWindow {
+    cwnd: f64,
+    in_flight: usize,
+}
+
+impl CongestionWindow {
+    fn new(initial: usize) -> Self {
+        Self {
+            cwnd: initial.max(MIN_CWND) as f64,
+            in_flight: 0,
+        }
+    }
+
+    /// Number of reliable packets allowed in flight
+    fn size(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    /// Additive increase: roughly one extra packet per window's worth of ACKs
+    fn on_ack(&mut self, max: usize) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.cwnd = (self.cwnd + 1.0 / self.cwnd).min(max.max(MIN_CWND) as f64);
+    }
+
+    /// Multiplicative decrease on loss
+    fn on_loss(&mut self) {
+        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND as f64);
+    }
+}
+
+/// Recently received sequence numbers from one source
+#[derive(Default)]
+struct SeenWindow {
+    seen: HashSet<u32>,
+    order: VecDeque<u32>,
+}
+
+impl SeenWindow {
+    /// Record a sequence, returning false if it was already in the window
+    fn insert(&mut self, sequence: u32, capacity: usize) -> bool {
+        if !self.seen.insert(sequence) {
+            return false;
+        }
+        self.order.push_back(sequence);
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Partially received fragmented packet
+struct PartialPacket {
+    chunks: Vec<Option<Bytes>>,
+    received: usize,
+    /// Bytes held across all received chunks
+    bytes: usize,
+    first_seen: Instant,
+}
+
+/// Discard the incomplete fragmented packet that started arriving first, returning its key
+fn evict_oldest_partial(reassembly: &mut HashMap<(SocketAddr, u32), PartialPacket>) -> Option<(SocketAddr, u32)> {
+    let oldest = reassembly
+        .iter()
+        .min_by_key(|(_, partial)| partial.first_seen)
+        .map(|(key, _)| *key)?;
+    reassembly.remove(&oldest);
+    Some(oldest)
+}
+
+/// Snapshot of transport counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Datagrams written to the socket, including fragments and retransmissions
+    pub packets_sent: u64,
+    /// Datagrams read from the socket
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Reliable packets sent again after a timeout
+    pub retransmissions: u64,
+    pub acks_received: u64,
+    /// Reliable packets currently waiting for an ACK
+    pub pending_acks: usize,
+    /// Retransmitted copies of already delivered packets that were dropped
+    pub duplicates_dropped: u64,
+    /// Datagrams dropped for being larger than the receive buffer
+    pub truncated_datagrams: u64,
+    /// Smallest congestion window across destinations and channels, in packets
+    pub congestion_window: usize,
+    /// Configured send rate limit, in bytes per second
+    pub max_send_rate: Option<u64>,
+    /// Bytes that can be sent right now without waiting, when the send rate is limited
+    pub send_tokens: Option<u64>,
+    /// Requests a server is currently handling, always zero for a client
+    pub in_flight_requests: usize,
+}
+
+/// Counters behind `TransportStats`
+#[derive(Default)]
+struct StatsCounters {
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    retransmissions: AtomicU64,
+    acks_received: AtomicU64,
+    duplicates_dropped: AtomicU64,
+    truncated_datagrams: AtomicU64,
+}
+
 /// Transport configuration
 #[derive(Clone)]
 pub struct TransportConfig {
@@ -32,6 +209,76 @@ pub struct TransportConfig {
     pub heartbeat_interval: Duration,
     pub enable_encryption: bool,
     pub enable_compression: bool,
+    /// Largest datagram sent as-is; bigger packets are fragmented
+    pub max_datagram_size: usize,
+    /// Largest datagram read from the socket (None reads any datagram UDP can carry)
+    ///
+    /// A bigger datagram cannot be read whole, so it is dropped, counted in
+    /// `TransportStats::truncated_datagrams` and reported as an error.
+    pub recv_buffer_size: Option<usize>,
+    /// How long to keep an incomplete fragmented packet before discarding it
+    pub reassembly_timeout: Duration,
+    /// Incomplete fragmented packets kept at once, across all peers
+    ///
+    /// A fragment starting another packet beyond this discards the oldest one.
+    pub max_reassembly_partials: usize,
+    /// Bytes held by incomplete fragmented packets, across all peers
+    ///
+    /// Once exceeded, the oldest incomplete packets are discarded until it fits.
+    pub max_reassembly_bytes: usize,
+    /// Lower bound on the adaptive retransmission timeout
+    pub min_rto: Duration,
+    /// Number of recent sequences remembered per source to drop duplicates (0 disables)
+    pub duplicate_window: usize,
+    /// Payloads smaller than this are never compressed
+    pub compression_threshold: usize,
+    /// Fraction of the payload size compression must save to be used (e.g. 0.05 for 5%)
+    pub compression_min_savings: f64,
+    /// Reliable packets allowed in flight to a destination before any loss is seen
+    pub initial_congestion_window: usize,
+    /// Upper bound on the congestion window
+    pub max_congestion_window: usize,
+    /// Reliable packets awaiting an ACK across all destinations and channels
+    ///
+    /// Once reached, reliable sends wait until a packet is acknowledged or
+    /// given up on, so a peer that stops acking cannot grow memory unbounded.
+    pub max_pending: usize,
+    /// How long an ordered route waits for a missing sequence before skipping it
+    pub ordered_delivery_timeout: Duration,
+    /// Rotate a negotiated session key after this many bytes (None disables)
+    pub rekey_after_bytes: Option<u64>,
+    /// Rotate a negotiated session key after this long (None disables)
+    pub rekey_interval: Option<Duration>,
+    /// How long the previous session key still decrypts after a rotation
+    pub rekey_grace: Duration,
+    /// Probe connected peers for the largest datagram that gets through unfragmented
+    ///
+    /// Off by default. Only peers registered with `Transport::discover_path_mtu`,
+    /// which `Server` and `Client` do once a handshake completes, are probed.
+    pub path_mtu_discovery: bool,
+    /// Datagram size assumed for a destination before any probe succeeds
+    pub initial_path_mtu: usize,
+    /// Longest route accepted in an incoming packet
+    pub max_route_len: usize,
+    /// Largest payload accepted in an incoming packet, including reassembled ones
+    pub max_payload_len: usize,
+    /// Cap on bytes per second written to the socket, across all peers (None disables)
+    ///
+    /// Every datagram is paced, including retransmissions, ACKs and fragments,
+    /// so sends wait for their turn instead of bursting.
+    pub max_send_rate: Option<u64>,
+    /// Oldest wire format version a peer may connect with
+    pub min_protocol_version: u8,
+    /// Newest wire format version offered or accepted in the handshake
+    ///
+    /// Each connection settles on the highest version both sides support,
+    /// and packets to that peer are written in it.
+    pub max_protocol_version: u8,
+    /// Hold ACKs back this long so those for a peer go out together (None sends each at once)
+    ///
+    /// ACKs that are due together are sent as one batch datagram, roughly
+    /// halving return traffic for a stream of packets. Capped at 25ms.
+    pub ack_delay: Option<Duration>,
 }
 
 impl Default for TransportConfig {
@@ -42,216 +289,2330 @@ impl Default for TransportConfig {
             heartbeat_interval: Duration::from_secs(30),
             enable_encryption: false,
             enable_compression: false,
+            max_datagram_size: MAX_PACKET_SIZE,
+            recv_buffer_size: None,
+            reassembly_timeout: Duration::from_secs(5),
+            max_reassembly_partials: 1024,
+            max_reassembly_bytes: 64 * 1024 * 1024,
+            min_rto: Duration::from_millis(100),
+            duplicate_window: 1024,
+            compression_threshold: 64,
+            compression_min_savings: 0.05,
+            initial_congestion_window: 32,
+            max_congestion_window: 1024,
+            max_pending: 4096,
+            ordered_delivery_timeout: Duration::from_secs(3),
+            rekey_after_bytes: Some(1 << 30),
+            rekey_interval: Some(Duration::from_secs(3600)),
+            rekey_grace: DEFAULT_REKEY_GRACE,
+            path_mtu_discovery: false,
+            // Fits the IPv6 minimum MTU of 1280 after its 48 bytes of IP and UDP headers
+            initial_path_mtu: 1200,
+            max_route_len: 1024,
+            max_payload_len: 16 * 1024 * 1024,
+            max_send_rate: None,
+            min_protocol_version: FIXED_WIDTH_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            ack_delay: None,
+        }
+    }
+}
+
+/// Carries datagrams between endpoints, underneath `Transport`
+///
+/// A datagram is one encoded packet, addressed by socket address. The
+/// boundary sits below `Transport`, so its ACKs, retransmission, ordering,
+/// fragmentation and encryption work the same over every implementation, and
+/// `Server` and `Client` run over any of them through `Transport::over`.
+/// `UdpTransport` is the default; `quic::QuicTransport` (with the `quic`
+/// feature) carries datagrams over QUIC connections instead, and
+/// `impairment::ImpairedTransport` injects faults into another for testing.
+#[async_trait]
+pub trait PacketTransport: Send + Sync {
+    /// Send one datagram to `dest`
+    async fn send_to(&self, datagram: &[u8], dest: SocketAddr) -> Result<()>;
+
+    /// Wait for the next datagram, append at most `limit` bytes of it to `buf`
+    ///
+    /// Returns the datagram's length and the address it came from. A link
+    /// that knows the length of a datagram it cut short returns it in full,
+    /// above `limit`; UDP cannot tell, so `Transport` reads one byte past
+    /// its buffer size to notice.
+    async fn recv_from(&self, buf: &mut BytesMut, limit: usize) -> Result<(usize, SocketAddr)>;
+
+    /// Address the transport is bound to
+    fn local_addr(&self) -> Result<SocketAddr>;
+}
+
+/// Datagrams over a UDP socket
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// Whether the socket is IPv6, so IPv4 destinations must be sent v4-mapped
+    ipv6: bool,
+}
+
+impl UdpTransport {
+    /// Send and receive on `socket`
+    pub fn new(socket: UdpSocket) -> Result<Self> {
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        Ok(Self { socket, ipv6 })
+    }
+
+    /// Destination as the socket's address family expects it
+    fn socket_addr(&self, dest: SocketAddr) -> SocketAddr {
+        match dest {
+            SocketAddr::V4(v4) if self.ipv6 => {
+                SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0).into()
+            }
+            _ => dest,
         }
     }
 }
 
-/// UDP transport with reliability
+#[async_trait]
+impl PacketTransport for UdpTransport {
+    async fn send_to(&self, datagram: &[u8], dest: SocketAddr) -> Result<()> {
+        self.socket.send_to(datagram, self.socket_addr(dest)).await?;
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut BytesMut, limit: usize) -> Result<(usize, SocketAddr)> {
+        let (len, addr) = self.socket.recv_buf_from(&mut buf.limit(limit)).await?;
+        Ok((len, canonical_addr(addr)))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(Into::into)
+    }
+}
+
+/// Transport with reliability, over UDP unless built on another `PacketTransport`
+///
+/// Reliable packets are sequenced per channel: each channel numbers its
+/// packets from 0 on its own, and ACKs, duplicate detection and congestion
+/// windows are all keyed by channel as well as peer. A channel whose packets
+/// are being lost therefore never holds back another. The RTT estimate is a
+/// property of the path and stays shared by every channel to a peer.
 pub struct Transport {
-    socket: Arc<UdpSocket>,
+    link: Arc<dyn PacketTransport>,
     config: TransportConfig,
-    sequence: Arc<Mutex<u32>>,
-    pending_acks: Arc<RwLock<HashMap<u32, PendingPacket>>>,
-    crypto: Option<Arc<CryptoProvider>>,
-    compression: Option<Arc<CompressionProvider>>,
+    sequences: Mutex<HashMap<u16, u32>>,
+    pending_acks: Arc<RwLock<HashMap<(u16, u32), PendingPacket>>>,
+    acked: Notify,
+    stats: StatsCounters,
+    congestion: Mutex<HashMap<(SocketAddr, u16), CongestionWindow>>,
+    rtt: RwLock<HashMap<SocketAddr, RttEstimator>>,
+    fragment_id: AtomicU32,
+    reassembly: Mutex<HashMap<(SocketAddr, u32), PartialPacket>>,
+    inbox: Mutex<VecDeque<(Packet, SocketAddr)>>,
+    recv_pool: Mutex<RecvPool>,
+    /// Largest path MTU discovered to any peer, which sizes receive buffers
+    recv_mtu: AtomicUsize,
+    seen: Mutex<HashMap<(SocketAddr, u16), SeenWindow>>,
+    delayed_acks: Mutex<HashMap<SocketAddr, DelayedAcks>>,
+    /// Pre-shared crypto provider, swappable at any time
+    crypto: RwLock<Option<Arc<dyn Crypto>>>,
+    sessions: RwLock<HashMap<SocketAddr, Arc<CryptoProvider>>>,
+    /// Wire format version negotiated with each peer
+    versions: RwLock<HashMap<SocketAddr, u8>>,
+    compression: RwLock<Option<Arc<dyn Compression>>>,
+    path_mtu: Mutex<HashMap<SocketAddr, PathMtu>>,
+    pacer: Option<Mutex<TokenBucket>>,
+    on_retransmit: RwLock<Option<RetransmitHook>>,
+    on_drop: RwLock<Option<DropHook>>,
+    /// Clock retransmission and reassembly timeouts are measured on
+    clock: Arc<dyn TimeSource>,
+    /// Retransmission and heartbeat tasks, stopped when the transport is dropped
+    tasks: TaskSet,
+}
+
+/// Address with a v4-mapped IPv6 address turned back into plain IPv4
+///
+/// A dual-stack socket reports IPv4 peers as `::ffff:a.b.c.d`; the transport
+/// keys all of its per-peer state by the canonical form instead.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Let other sockets bind the same address and port
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+fn set_reuse_port(socket: &socket2::Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+/// Let other sockets bind the same address and port
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))))]
+fn set_reuse_port(_socket: &socket2::Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
 }
 
 impl Transport {
     /// Create a new transport bound to the given address
     pub async fn bind(addr: impl Into<SocketAddr>, config: TransportConfig) -> Result<Self> {
         let socket = UdpSocket::bind(addr.into()).await?;
-        
-        Ok(Self {
-            socket: Arc::new(socket),
-            config,
-            sequence: Arc::new(Mutex::new(0)),
+        Self::with_socket(socket, config)
+    }
+
+    /// Create a transport bound to `[::]:port` that also accepts IPv4 peers
+    ///
+    /// Clears `IPV6_V6ONLY` where the platform allows it; where it does not,
+    /// the socket is IPv6 only.
+    pub async fn bind_dual_stack(port: u16, config: TransportConfig) -> Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        if let Err(e) = socket.set_only_v6(false) {
+            warn!("Could not enable dual-stack, accepting IPv6 only: {}", e);
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Self::with_socket(socket, config)
+    }
+
+    /// Create a transport bound to `addr` with `SO_REUSEPORT` set, so several can share the port
+    ///
+    /// Every transport bound this way to the same address receives a share
+    /// of the incoming datagrams, letting one server scale across cores
+    /// with a transport per worker. Platform support varies:
+    ///
+    /// - Linux (3.9+) and Android balance datagrams across the sockets by a
+    ///   hash of the source address, so a peer sticks to one socket as long
+    ///   as its address does not change
+    /// - FreeBSD 12+ balances with `SO_REUSEPORT_LB`; with plain
+    ///   `SO_REUSEPORT`, as set here, macOS and the other BSDs let the
+    ///   sockets share the port but deliver to only one of them
+    /// - Windows, Solaris and illumos have no `SO_REUSEPORT`, and this fails
+    ///   with an `Unsupported` IO error
+    ///
+    /// A peer's session state lives in the transport that handled its
+    /// handshake, so sharing a port only works where the kernel keeps each
+    /// peer on one socket.
+    pub async fn bind_reuseport(addr: impl Into<SocketAddr>, config: TransportConfig) -> Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let addr = addr.into();
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        set_reuse_port(&socket)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Self::with_socket(socket, config)
+    }
+
+    fn with_socket(socket: UdpSocket, config: TransportConfig) -> Result<Self> {
+        if config.path_mtu_discovery {
+            if let Err(e) = mtu::set_dont_fragment(&socket) {
+                warn!("Could not set don't-fragment for path MTU discovery: {}", e);
+            }
+        }
+        Ok(Self::over(UdpTransport::new(socket)?, config))
+    }
+
+    /// Create a transport sending its datagrams through `link`
+    ///
+    /// Everything else, from ACKs to encryption, still happens here, so a
+    /// `Server` or `Client` built with `with_transport` works the same over
+    /// any link. Path MTU probes go through the link too; leave
+    /// `path_mtu_discovery` off where it sizes datagrams itself.
+    pub fn over(link: impl PacketTransport + 'static, config: TransportConfig) -> Self {
+        let limit = config.max_datagram_size.min(MAX_PACKET_SIZE);
+        let recv_mtu = if config.path_mtu_discovery {
+            config.initial_path_mtu.min(limit)
+        } else {
+            limit
+        };
+
+        Self {
+            link: Arc::new(link),
+            sequences: Mutex::new(HashMap::new()),
             pending_acks: Arc::new(RwLock::new(HashMap::new())),
-            crypto: None,
-            compression: None,
-        })
+            acked: Notify::new(),
+            stats: StatsCounters::default(),
+            congestion: Mutex::new(HashMap::new()),
+            rtt: RwLock::new(HashMap::new()),
+            fragment_id: AtomicU32::new(0),
+            reassembly: Mutex::new(HashMap::new()),
+            inbox: Mutex::new(VecDeque::new()),
+            recv_pool: Mutex::new(RecvPool::new()),
+            recv_mtu: AtomicUsize::new(recv_mtu),
+            seen: Mutex::new(HashMap::new()),
+            delayed_acks: Mutex::new(HashMap::new()),
+            crypto: RwLock::new(None),
+            sessions: RwLock::new(HashMap::new()),
+            versions: RwLock::new(HashMap::new()),
+            compression: RwLock::new(None),
+            path_mtu: Mutex::new(HashMap::new()),
+            pacer: config.max_send_rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            on_retransmit: RwLock::new(None),
+            on_drop: RwLock::new(None),
+            clock: Arc::new(SystemClock),
+            tasks: TaskSet::default(),
+            config,
+        }
+    }
+
+    /// Set the clock retransmission and reassembly timeouts are measured on
+    ///
+    /// The system clock by default; tests can set a `MockClock` to decide
+    /// exactly when a packet is due for retransmission.
+    pub fn set_time_source(&mut self, clock: Arc<dyn TimeSource>) {
+        self.clock = clock;
     }
 
     /// Set encryption provider
-    pub fn set_crypto(&mut self, crypto: CryptoProvider) {
-        self.crypto = Some(Arc::new(crypto));
+    ///
+    /// Replaces any provider already set; packets in flight keep the one
+    /// they were encrypted with.
+    pub async fn set_crypto(&self, crypto: impl Crypto + 'static) {
+        *self.crypto.write().await = Some(Arc::new(crypto));
     }
 
     /// Set compression provider
-    pub fn set_compression(&mut self, compression: CompressionProvider) {
-        self.compression = Some(Arc::new(compression));
+    pub async fn set_compression(&self, compression: impl Compression + 'static) {
+        *self.compression.write().await = Some(Arc::new(compression));
+    }
+
+    /// Register a callback invoked for every retransmission
+    ///
+    /// It receives the channel, sequence, attempt number (1 for the first
+    /// retransmission) and destination, and runs on the retransmitting task,
+    /// so it should return quickly.
+    pub async fn on_retransmit<F>(&self, callback: F)
+    where
+        F: Fn(u16, u32, u8, SocketAddr) + Send + Sync + 'static,
+    {
+        *self.on_retransmit.write().await = Some(Arc::new(callback));
+    }
+
+    /// Register a callback invoked when a reliable packet is given up on
+    ///
+    /// It receives the channel, sequence and destination of a packet that
+    /// used up its retransmit attempts without being acknowledged.
+    pub async fn on_drop<F>(&self, callback: F)
+    where
+        F: Fn(u16, u32, SocketAddr) + Send + Sync + 'static,
+    {
+        *self.on_drop.write().await = Some(Arc::new(callback));
+    }
+
+    /// Tell the retransmit callback, if any, about a retransmission
+    async fn notify_retransmit(&self, channel: u16, sequence: u32, attempt: u8, dest: SocketAddr) {
+        if let Some(callback) = self.on_retransmit.read().await.as_ref() {
+            callback(channel, sequence, attempt, dest);
+        }
+    }
+
+    /// Tell the drop callback, if any, about a packet given up on
+    async fn notify_drop(&self, channel: u16, sequence: u32, dest: SocketAddr) {
+        if let Some(callback) = self.on_drop.read().await.as_ref() {
+            callback(channel, sequence, dest);
+        }
+    }
+
+    /// Whether a pre-shared crypto provider is configured
+    pub async fn has_crypto(&self) -> bool {
+        self.crypto.read().await.is_some()
+    }
+
+    /// Get the transport configuration
+    pub fn config(&self) -> &TransportConfig {
+        &self.config
+    }
+
+    /// Use a negotiated crypto provider for traffic with one peer
+    ///
+    /// Session providers take precedence over the pre-shared provider.
+    pub async fn set_session_crypto(&self, peer: SocketAddr, crypto: Arc<CryptoProvider>) {
+        self.sessions.write().await.insert(peer, crypto);
+    }
+
+    /// Forget the negotiated crypto provider for a peer
+    pub async fn remove_session_crypto(&self, peer: SocketAddr) {
+        self.sessions.write().await.remove(&peer);
+    }
+
+    /// Negotiated crypto provider for a peer, if any
+    pub async fn session_crypto(&self, peer: SocketAddr) -> Option<Arc<CryptoProvider>> {
+        self.sessions.read().await.get(&peer).cloned()
+    }
+
+    /// Drop everything kept for a peer that is gone
+    ///
+    /// Clears its session key, negotiated version, RTT estimate, path MTU,
+    /// duplicate windows, partial fragments and congestion windows, and gives
+    /// up on reliable packets still waiting for its ACK.
+    pub async fn forget_peer(&self, peer: SocketAddr) {
+        self.sessions.write().await.remove(&peer);
+        self.versions.write().await.remove(&peer);
+        self.rtt.write().await.remove(&peer);
+        self.path_mtu.lock().await.remove(&peer);
+        self.seen.lock().await.retain(|(addr, _), _| *addr != peer);
+        self.delayed_acks.lock().await.remove(&peer);
+        self.reassembly.lock().await.retain(|(addr, _), _| *addr != peer);
+        self.pending_acks.write().await.retain(|_, pending| pending.dest != peer);
+        self.congestion.lock().await.retain(|(addr, _), _| *addr != peer);
+        self.acked.notify_waiters();
+    }
+
+    /// Highest wire format version both sides support, given the newest one a peer offers
+    ///
+    /// `None` when the peer only speaks versions older than `min_protocol_version`.
+    pub fn negotiate_version(&self, offered: u8) -> Option<u8> {
+        let version = offered.min(self.config.max_protocol_version);
+        (version >= self.config.min_protocol_version).then_some(version)
+    }
+
+    /// Write packets to a peer in a negotiated wire format version
+    pub async fn set_peer_version(&self, peer: SocketAddr, version: u8) {
+        self.versions.write().await.insert(peer, version);
+    }
+
+    /// Forget the negotiated wire format version for a peer
+    pub async fn remove_peer_version(&self, peer: SocketAddr) {
+        self.versions.write().await.remove(&peer);
+    }
+
+    /// Wire format version packets to a peer are written in
+    ///
+    /// `max_protocol_version` until a version has been negotiated.
+    pub async fn peer_version(&self, peer: SocketAddr) -> u8 {
+        match self.versions.read().await.get(&peer) {
+            Some(&version) => version,
+            None => self.config.max_protocol_version,
+        }
+    }
+
+    /// Rotate the negotiated session key for a peer
+    ///
+    /// The next key is derived from the current one and `salt`, and the
+    /// current key keeps decrypting for the configured grace window.
+    pub async fn rekey_session(&self, peer: SocketAddr, salt: &[u8]) -> Result<Arc<CryptoProvider>> {
+        let mut sessions = self.sessions.write().await;
+        let current = sessions.get(&peer).ok_or_else(|| {
+            ProtocolError::Encryption(format!("No session key to rotate for {}", peer))
+        })?;
+
+        let mut next = current.rotated(&current.next_key(salt));
+        next.set_rekey_grace(self.config.rekey_grace);
+        let next = Arc::new(next);
+        sessions.insert(peer, next.clone());
+
+        debug!("Rotated session key for {}", peer);
+        Ok(next)
+    }
+
+    /// Crypto provider to use for a peer
+    async fn crypto_for(&self, peer: SocketAddr) -> Option<Arc<dyn Crypto>> {
+        match self.sessions.read().await.get(&peer) {
+            Some(crypto) => Some(crypto.clone() as Arc<dyn Crypto>),
+            None => self.crypto.read().await.clone(),
+        }
     }
 
-    /// Get next sequence number
-    async fn next_sequence(&self) -> u32 {
-        let mut seq = self.sequence.lock().await;
+    /// Get next sequence number on a channel
+    async fn next_sequence(&self, channel: u16) -> u32 {
+        let mut sequences = self.sequences.lock().await;
+        let seq = sequences.entry(channel).or_insert(0);
         let current = *seq;
         *seq = seq.wrapping_add(1);
         current
     }
 
-    /// Send a packet with reliability
+    /// Send a packet with reliability on the default channel
     pub async fn send_reliable(
         &self,
         route: String,
         payload: Bytes,
         dest: SocketAddr,
     ) -> Result<u32> {
-        let sequence = self.next_sequence().await;
-        let mut packet = Packet::new_data(route, payload, sequence);
+        self.send_reliable_on(DEFAULT_CHANNEL, route, payload, dest).await
+    }
 
-        // Apply compression if enabled
-        if self.config.enable_compression {
-            if let Some(comp) = &self.compression {
-                packet.payload = comp.compress(&packet.payload)?;
-                packet.flags.compressed = true;
+    /// Send a packet with reliability on a channel
+    pub async fn send_reliable_on(
+        &self,
+        channel: u16,
+        route: String,
+        payload: Bytes,
+        dest: SocketAddr,
+    ) -> Result<u32> {
+        let mut packet = Packet::new_data(route, payload, 0);
+        packet.channel_id = channel;
+        self.send_reliable_packet(packet, dest).await
+    }
+
+    /// Send a data packet at most once on a channel
+    ///
+    /// The packet is sequenced, compressed and encrypted like a reliable one,
+    /// but it is marked `unreliable` instead of requiring an ACK: it is never
+    /// retransmitted, does not take a congestion window slot, and the peer
+    /// neither ACKs nor answers it. A lost packet is simply gone, which suits
+    /// real-time data that is worthless once late.
+    pub async fn send_unreliable_on(
+        &self,
+        channel: u16,
+        route: String,
+        payload: Bytes,
+        dest: SocketAddr,
+    ) -> Result<u32> {
+        let mut packet = Packet::new_data(route, payload, 0);
+        packet.channel_id = channel;
+        packet.sequence = self.next_sequence(channel).await;
+        packet.flags.requires_ack = false;
+        packet.flags.unreliable = true;
+        packet.version = self.peer_version(dest).await;
+        self.seal_payload(&mut packet, dest, self.config.enable_compression).await?;
+
+        let sequence = packet.sequence;
+        let data = packet.serialize()?;
+        self.send_datagram(data, dest, packet.version).await?;
+        Ok(sequence)
+    }
+
+    /// Send an arbitrary packet with reliability
+    ///
+    /// The packet is given the next sequence number of its channel and marked
+    /// as requiring an ACK.
+    ///
+    /// Waits for room in the channel's congestion window to the destination first.
+    pub async fn send_reliable_packet(&self, packet: Packet, dest: SocketAddr) -> Result<u32> {
+        self.send_reliable_packet_compressed(packet, dest, self.config.enable_compression)
+            .await
+    }
+
+    /// Send a packet with reliability, choosing whether to compress it
+    ///
+    /// Like `send_reliable_packet`, but `compress` takes the place of
+    /// `enable_compression` for this packet. Compressing still needs a
+    /// provider, and is still skipped when it does not pay off.
+    pub async fn send_reliable_packet_compressed(
+        &self,
+        packet: Packet,
+        dest: SocketAddr,
+        compress: bool,
+    ) -> Result<u32> {
+        self.send_reliable_sequenced(packet, dest, compress, |_, _| async {}).await
+    }
+
+    /// Like `send_reliable_packet`, calling `on_sequenced` with the packet's
+    /// channel and sequence before the packet is sent
+    ///
+    /// Lets a caller get ready for the answer to a packet before it can arrive.
+    pub async fn send_reliable_packet_with<F, Fut>(
+        &self,
+        packet: Packet,
+        dest: SocketAddr,
+        on_sequenced: F,
+    ) -> Result<u32>
+    where
+        F: FnOnce(u16, u32) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.send_reliable_sequenced(packet, dest, self.config.enable_compression, on_sequenced)
+            .await
+    }
+
+    /// Send a packet with reliability, calling `on_sequenced` once it has a sequence
+    async fn send_reliable_sequenced<F, Fut>(
+        &self,
+        packet: Packet,
+        dest: SocketAddr,
+        compress: bool,
+        on_sequenced: F,
+    ) -> Result<u32>
+    where
+        F: FnOnce(u16, u32) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let channel = packet.channel_id;
+        self.acquire_window(dest, channel).await;
+        let result = self.transmit_reliable(packet, dest, compress, on_sequenced).await;
+        if result.is_err() {
+            self.release_window(dest, channel).await;
+        }
+        result
+    }
+
+    /// Send several packets with reliability, packing them into as few datagrams as possible
+    ///
+    /// Each packet is sequenced, acknowledged and retransmitted on its own,
+    /// as if sent with `send_reliable_packet`; only the first transmission is
+    /// batched. Whenever a congestion window is full, the packets prepared so
+    /// far are sent before waiting for room. Returns the sequences in order.
+    pub async fn send_reliable_batch(&self, packets: Vec<Packet>, dest: SocketAddr) -> Result<Vec<u32>> {
+        self.send_reliable_batch_with(packets, dest, |_, _| async {}).await
+    }
+
+    /// Like `send_reliable_batch`, calling `on_sequenced` with each packet's
+    /// channel and sequence before the packet is sent
+    ///
+    /// Lets a caller get ready for the answer to a packet before it can arrive.
+    pub async fn send_reliable_batch_with<F, Fut>(
+        &self,
+        packets: Vec<Packet>,
+        dest: SocketAddr,
+        mut on_sequenced: F,
+    ) -> Result<Vec<u32>>
+    where
+        F: FnMut(u16, u32) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut sequences = Vec::with_capacity(packets.len());
+        let mut prepared = Vec::new();
+        let mut unsent = Vec::new();
+
+        for packet in packets {
+            let channel = packet.channel_id;
+            if !self.try_acquire_window(dest, channel).await {
+                self.send_prepared(std::mem::take(&mut prepared), &mut unsent, dest).await?;
+                self.acquire_window(dest, channel).await;
+            }
+            match self.prepare_reliable(packet, dest, self.config.enable_compression).await {
+                Ok(packet) => {
+                    on_sequenced(channel, packet.sequence).await;
+                    sequences.push(packet.sequence);
+                    unsent.push((channel, packet.sequence));
+                    prepared.push(packet);
+                }
+                Err(e) => {
+                    self.release_window(dest, channel).await;
+                    for (channel, sequence) in unsent {
+                        self.cancel_reliable(channel, sequence).await;
+                    }
+                    return Err(e);
+                }
             }
         }
 
-        // Apply encryption if enabled
-        if self.config.enable_encryption {
-            if let Some(crypto) = &self.crypto {
-                packet.payload = crypto.encrypt(&packet.payload)?;
-                packet.flags.encrypted = true;
+        self.send_prepared(prepared, &mut unsent, dest).await?;
+        Ok(sequences)
+    }
+
+    /// Send packets readied by `send_reliable_batch`, cancelling them all if that fails
+    async fn send_prepared(
+        &self,
+        prepared: Vec<Packet>,
+        unsent: &mut Vec<(u16, u32)>,
+        dest: SocketAddr,
+    ) -> Result<()> {
+        if let Err(e) = self.send_batch(prepared, dest).await {
+            for (channel, sequence) in unsent.drain(..) {
+                self.cancel_reliable(channel, sequence).await;
             }
+            return Err(e);
         }
+        unsent.clear();
+        Ok(())
+    }
 
-        // Serialize and send
+    /// Sequence, encode and send a reliable packet that already holds a window slot
+    async fn transmit_reliable<F, Fut>(
+        &self,
+        packet: Packet,
+        dest: SocketAddr,
+        compress: bool,
+        on_sequenced: F,
+    ) -> Result<u32>
+    where
+        F: FnOnce(u16, u32) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let packet = self.prepare_reliable(packet, dest, compress).await?;
+        let (channel, sequence) = (packet.channel_id, packet.sequence);
+        on_sequenced(channel, sequence).await;
         let data = packet.serialize()?;
-        self.socket.send_to(&data, dest).await?;
+        if let Err(e) = self.send_datagram(data, dest, packet.version).await {
+            self.pending_acks.write().await.remove(&(channel, sequence));
+            return Err(e);
+        }
+
+        trace!("Sent packet with sequence {} on channel {}", sequence, channel);
+        Ok(sequence)
+    }
+
+    /// Sequence and encode a reliable packet and store it for retransmission
+    ///
+    /// Storing it before it is sent means a fast ACK cannot miss it.
+    async fn prepare_reliable(&self, mut packet: Packet, dest: SocketAddr, compress: bool) -> Result<Packet> {
+        let channel = packet.channel_id;
+        let sequence = self.next_sequence(channel).await;
+        packet.sequence = sequence;
+        packet.flags.requires_ack = true;
+        packet.version = self.peer_version(dest).await;
+        self.seal_payload(&mut packet, dest, compress).await?;
 
-        // Store for retransmission
         let pending = PendingPacket {
-            packet,
+            packet: packet.clone(),
             dest,
-            sent_at: Instant::now(),
+            sent_at: self.clock.now(),
             attempts: 0,
         };
-        self.pending_acks.write().await.insert(sequence, pending);
+        self.pending_acks.write().await.insert((channel, sequence), pending);
+        Ok(packet)
+    }
 
-        debug!("Sent packet with sequence {}", sequence);
-        Ok(sequence)
+    /// Compress and encrypt a packet's payload as configured for `dest`
+    async fn seal_payload(&self, packet: &mut Packet, dest: SocketAddr, compress: bool) -> Result<()> {
+        // Apply compression if enabled
+        if compress {
+            self.compress_payload(packet).await?;
+        }
+
+        // Apply encryption if enabled
+        if self.config.enable_encryption {
+            if let Some(crypto) = self.crypto_for(dest).await {
+                packet.payload = crypto.encrypt(&packet.payload, packet.route.as_bytes())?;
+                packet.flags.encrypted = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until the channel's congestion window to a destination has room, then take a slot
+    ///
+    /// Also waits while `max_pending` packets are in flight in total.
+    async fn acquire_window(&self, dest: SocketAddr, channel: u16) {
+        loop {
+            // Register interest before checking so an ACK in between is not missed
+            let acked = self.acked.notified();
+            if self.try_acquire_window(dest, channel).await {
+                return;
+            }
+            acked.await;
+        }
+    }
+
+    /// Take a window slot if one is free right now
+    async fn try_acquire_window(&self, dest: SocketAddr, channel: u16) -> bool {
+        let mut windows = self.congestion.lock().await;
+        let pending: usize = windows.values().map(|window| window.in_flight).sum();
+        if pending >= self.config.max_pending {
+            return false;
+        }
+        let window = windows
+            .entry((dest, channel))
+            .or_insert_with(|| CongestionWindow::new(self.config.initial_congestion_window));
+        if window.in_flight < window.size() {
+            window.in_flight += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Give back a window slot without counting it as delivered or lost
+    async fn release_window(&self, dest: SocketAddr, channel: u16) {
+        if let Some(window) = self.congestion.lock().await.get_mut(&(dest, channel)) {
+            window.in_flight = window.in_flight.saturating_sub(1);
+        }
+        self.acked.notify_waiters();
+    }
+
+    /// Current congestion window for a channel to a destination, in packets
+    pub async fn congestion_window(&self, dest: SocketAddr, channel: u16) -> usize {
+        match self.congestion.lock().await.get(&(dest, channel)) {
+            Some(window) => window.size(),
+            None => self.config.initial_congestion_window.max(MIN_CWND),
+        }
+    }
+
+    /// Compress a packet's payload when it is large enough and compression pays off
+    ///
+    /// The payload is only replaced, and the compressed flag set, if the
+    /// compressed form saves at least `compression_min_savings` of its size.
+    async fn compress_payload(&self, packet: &mut Packet) -> Result<()> {
+        let Some(comp) = self.compression.read().await.clone() else {
+            return Ok(());
+        };
+        if packet.payload.len() < self.config.compression_threshold {
+            return Ok(());
+        }
+
+        let compressed = comp.compress(&packet.payload)?;
+        let max_len = packet.payload.len() as f64 * (1.0 - self.config.compression_min_savings);
+        if (compressed.len() as f64) <= max_len {
+            packet.payload = compressed;
+            packet.flags.compressed = true;
+            packet.flags.compression = comp.algorithm();
+        } else {
+            trace!(
+                "Sending {} bytes uncompressed, compression only reached {}",
+                packet.payload.len(),
+                compressed.len()
+            );
+        }
+        Ok(())
     }
 
     /// Send a packet without reliability
+    ///
+    /// The packet is written in the wire format version negotiated with `dest`.
     pub async fn send(&self, packet: Packet, dest: SocketAddr) -> Result<()> {
+        let version = self.peer_version(dest).await;
+        self.send_with_version(packet, version, dest).await
+    }
+
+    /// Send a packet without reliability, written in a given wire format version
+    ///
+    /// For replies to a peer no version has been negotiated with, such as
+    /// refusing a connection in the version it was requested in.
+    pub async fn send_with_version(&self, mut packet: Packet, version: u8, dest: SocketAddr) -> Result<()> {
+        packet.version = version;
         let data = packet.serialize()?;
-        self.socket.send_to(&data, dest).await?;
-        Ok(())
+        self.send_datagram(data, dest, version).await
     }
 
-    /// Receive a packet
-    pub async fn recv(&self) -> Result<(Packet, SocketAddr)> {
-        let mut buf = vec![0u8; 65536];
-        let (len, addr) = self.socket.recv_from(&mut buf).await?;
-        buf.truncate(len);
+    /// Send several packets without reliability, packing them into as few datagrams as possible
+    ///
+    /// Packets are grouped into `Batch` packets that never exceed the configured
+    /// datagram size. A packet too large to share a datagram is sent on its own.
+    pub async fn send_batch(&self, packets: Vec<Packet>, dest: SocketAddr) -> Result<()> {
+        let limit = self.datagram_limit(dest).await;
+        let version = self.peer_version(dest).await;
+        let overhead = Packet {
+            version,
+            ..Packet::new_batch(&[])?
+        }
+        .encoded_len();
 
-        let mut packet = Packet::deserialize(Bytes::from(buf))?;
+        let mut group: Vec<Packet> = Vec::new();
+        let mut group_len = overhead;
 
-        // Decrypt if needed
-        if packet.flags.encrypted {
-            if let Some(crypto) = &self.crypto {
-                packet.payload = crypto.decrypt(&packet.payload)?;
-            } else {
-                return Err(ProtocolError::Encryption(
-                    "Received encrypted packet but no crypto provider".to_string(),
-                ));
+        for mut packet in packets {
+            packet.version = version;
+            let entry_len = packet.batch_entry_len();
+            if overhead + entry_len > limit {
+                self.send(packet, dest).await?;
+                continue;
+            }
+            if group_len + entry_len > limit {
+                self.flush_batch(std::mem::take(&mut group), dest).await?;
+                group_len = overhead;
             }
+            group_len += entry_len;
+            group.push(packet);
         }
 
-        // Decompress if needed
-        if packet.flags.compressed {
-            if let Some(comp) = &self.compression {
-                packet.payload = comp.decompress(&packet.payload)?;
-            } else {
-                return Err(ProtocolError::Compression(
-                    "Received compressed packet but no compression provider".to_string(),
-                ));
+        self.flush_batch(group, dest).await
+    }
+
+    /// Send one group of packets collected by `send_batch`
+    async fn flush_batch(&self, mut group: Vec<Packet>, dest: SocketAddr) -> Result<()> {
+        match group.len() {
+            0 => Ok(()),
+            1 => self.send(group.remove(0), dest).await,
+            count => {
+                trace!("Sending batch of {} packets", count);
+                self.send(Packet::new_batch(&group)?, dest).await
             }
         }
+    }
 
-        // Send ACK if required
-        if packet.flags.requires_ack && packet.packet_type == PacketType::Data {
-            let ack = Packet::new_ack(packet.sequence);
-            let _ = self.send(ack, addr).await;
+    /// Write one datagram to the link, once the send rate allows it
+    async fn send_to(&self, data: &[u8], dest: SocketAddr) -> Result<()> {
+        if let Some(pacer) = &self.pacer {
+            let wait = pacer.lock().await.reserve(data.len(), Instant::now());
+            if !wait.is_zero() {
+                time::sleep(wait).await;
+            }
         }
-
-        Ok((packet, addr))
+        self.link.send_to(data, dest).await?;
+        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats.bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
     }
 
-    /// Handle acknowledgment
-    pub async fn handle_ack(&self, sequence: u32) {
-        self.pending_acks.write().await.remove(&sequence);
-        debug!("Received ACK for sequence {}", sequence);
+    /// Largest datagram to send to a destination: the configured size, capped by the path MTU
+    ///
+    /// Destinations not being probed get `initial_path_mtu`.
+    async fn datagram_limit(&self, dest: SocketAddr) -> usize {
+        let limit = self.config.max_datagram_size.min(MAX_PACKET_SIZE);
+        if !self.config.path_mtu_discovery {
+            return limit;
+        }
+        let mtu = self
+            .path_mtu
+            .lock()
+            .await
+            .get(&dest)
+            .map_or(self.config.initial_path_mtu, PathMtu::mtu);
+        mtu.min(limit)
     }
 
-    /// Handle negative acknowledgment
-    pub async fn handle_nack(&self, sequence: u32) {
-        if let Some(pending) = self.pending_acks.write().await.get_mut(&sequence) {
-            pending.attempts += 1;
-            pending.sent_at = Instant::now();
-            debug!("Received NACK for sequence {}, retransmitting", sequence);
+    /// Start probing the path MTU to a peer with an established connection
+    ///
+    /// Does nothing when discovery is disabled. Only registered peers are
+    /// probed or have their probe replies trusted, so traffic from unknown or
+    /// spoofed addresses never adds to the state kept here; `forget_peer`
+    /// unregisters a peer again.
+    pub async fn discover_path_mtu(&self, peer: SocketAddr) {
+        if !self.config.path_mtu_discovery {
+            return;
         }
+        let limit = self.config.max_datagram_size.min(MAX_PACKET_SIZE);
+        let initial = self.config.initial_path_mtu.min(limit);
+        self.path_mtu
+            .lock()
+            .await
+            .entry(peer)
+            .or_insert_with(|| PathMtu::new(initial));
     }
 
-    /// Start retransmission task
-    pub async fn start_retransmission_task(self: Arc<Self>) {
-        let transport = self.clone();
-        tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_millis(100));
-            loop {
-                interval.tick().await;
+    /// Largest datagram currently known to reach a destination without IP fragmentation
+    ///
+    /// Packets bigger than this are split with application-level fragmentation.
+    /// Returns `None` when discovery is disabled or `dest` is not being probed.
+    pub async fn path_mtu(&self, dest: SocketAddr) -> Option<usize> {
+        if !self.config.path_mtu_discovery {
+            return None;
+        }
+        let limit = self.config.max_datagram_size.min(MAX_PACKET_SIZE);
+        self.path_mtu.lock().await.get(&dest).map(|path| path.mtu().min(limit))
+    }
 
-                let now = Instant::now();
-                let mut to_retransmit = Vec::new();
-                let mut to_remove = Vec::new();
-
-                {
-                    let mut pending = transport.pending_acks.write().await;
-                    for (seq, packet) in pending.iter_mut() {
-                        if now.duration_since(packet.sent_at) > transport.config.ack_timeout {
-                            if packet.attempts >= transport.config.max_retransmit {
-                                warn!("Max retransmit attempts reached for sequence {}", seq);
-                                to_remove.push(*seq);
-                            } else {
-                                packet.attempts += 1;
-                                packet.sent_at = now;
-                                to_retransmit.push((packet.packet.clone(), packet.dest));
-                            }
-                        }
-                    }
+    /// Send whichever path MTU probes are due
+    async fn probe_path_mtu(&self) {
+        let limit = self.config.max_datagram_size.min(MAX_PACKET_SIZE);
+        let due: Vec<(SocketAddr, usize)> = self
+            .path_mtu
+            .lock()
+            .await
+            .iter_mut()
+            .filter_map(|(dest, path)| {
+                path.next_probe(self.config.ack_timeout, limit)
+                    .map(|size| (*dest, size))
+            })
+            .collect();
 
-                    for seq in to_remove {
-                        pending.remove(&seq);
-                    }
+        for (dest, size) in due {
+            let version = self.peer_version(dest).await;
+            let probe = match Packet::new_probe_in(size, version).serialize() {
+                Ok(probe) => probe,
+                Err(e) => {
+                    warn!("Failed to build path MTU probe: {}", e);
+                    continue;
                 }
-
-                for (packet, dest) in to_retransmit {
-                    if let Err(e) = transport.send(packet, dest).await {
-                        error!("Retransmission failed: {}", e);
+            };
+            match self.send_to(&probe, dest).await {
+                Err(ProtocolError::Io(e)) if mtu::is_too_large(&e) => {
+                    debug!("Path MTU probe of {} bytes to {} refused locally", size, dest);
+                    if let Some(path) = self.path_mtu.lock().await.get_mut(&dest) {
+                        path.fail(size);
                     }
                 }
+                Err(e) => debug!("Failed to send path MTU probe to {}: {}", dest, e),
+                Ok(()) => {}
             }
-        });
+        }
     }
 
-    /// Start heartbeat task
-    pub async fn start_heartbeat_task(self: Arc<Self>, dest: SocketAddr) {
-        let transport = self.clone();
-        tokio::spawn(async move {
-            let mut interval = time::interval(transport.config.heartbeat_interval);
+    /// Send serialized packet bytes, fragmenting them if they exceed the datagram size
+    ///
+    /// Fragments are written in wire format `version`.
+    async fn send_datagram(&self, data: Bytes, dest: SocketAddr, version: u8) -> Result<()> {
+        let limit = self.datagram_limit(dest).await;
+        if data.len() <= limit {
+            return self.send_to(&data, dest).await;
+        }
+
+        let new_fragment = |header, chunk: &[u8]| Packet {
+            version,
+            ..Packet::new_fragment(header, chunk)
+        };
+        let overhead = new_fragment(FragmentHeader::default(), &[]).encoded_len();
+        if limit <= overhead {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Datagram size {} too small for fragmentation",
+                limit
+            )));
+        }
+        let chunk_size = limit - overhead;
+        let fragment_count = data.len().div_ceil(chunk_size);
+        if fragment_count > u16::MAX as usize {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Packet of {} bytes needs too many fragments",
+                data.len()
+            )));
+        }
+
+        let fragment_id = self.fragment_id.fetch_add(1, Ordering::Relaxed);
+        for (index, chunk) in data.chunks(chunk_size).enumerate() {
+            let header = FragmentHeader {
+                fragment_id,
+                fragment_index: index as u16,
+                fragment_count: fragment_count as u16,
+            };
+            let fragment = new_fragment(header, chunk).serialize()?;
+            self.send_to(&fragment, dest).await?;
+        }
+
+        trace!("Sent {} bytes as {} fragments", data.len(), fragment_count);
+        Ok(())
+    }
+
+    /// Store a fragment, returning the original packet once all fragments have arrived
+    async fn reassemble(&self, fragment: Packet, addr: SocketAddr) -> Result<Option<Packet>> {
+        let (header, chunk) = fragment.fragment_parts()?;
+        let count = header.fragment_count as usize;
+        let now = self.clock.now();
+
+        let mut reassembly = self.reassembly.lock().await;
+
+        // Drop incomplete packets whose fragments never all arrived
+        let timeout = self.config.reassembly_timeout;
+        reassembly.retain(|(from, id), partial| {
+            let alive = now.duration_since(partial.first_seen) < timeout;
+            if !alive {
+                warn!("Discarding incomplete fragmented packet {} from {}", id, from);
+            }
+            alive
+        });
+
+        let key = (addr, header.fragment_id);
+        if !reassembly.contains_key(&key) && reassembly.len() >= self.config.max_reassembly_partials {
+            if let Some((from, id)) = evict_oldest_partial(&mut reassembly) {
+                warn!("Too many incomplete fragmented packets, discarding {} from {}", id, from);
+            }
+        }
+        let partial = reassembly.entry(key).or_insert_with(|| PartialPacket {
+            chunks: vec![None; count],
+            received: 0,
+            bytes: 0,
+            first_seen: now,
+        });
+
+        if partial.chunks.len() != count {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Fragment count mismatch for fragment id {}",
+                header.fragment_id
+            )));
+        }
+
+        let slot = &mut partial.chunks[header.fragment_index as usize];
+        if slot.is_none() {
+            partial.bytes += chunk.len();
+            *slot = Some(chunk);
+            partial.received += 1;
+        }
+
+        let limit = self.packet_limits().max_packet_len();
+        if partial.bytes > limit {
+            reassembly.remove(&key);
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Fragmented packet {} exceeds limit of {} bytes",
+                header.fragment_id, limit
+            )));
+        }
+
+        let complete = partial.received == count;
+
+        // Bound what all incomplete packets hold together, discarding the oldest first
+        let budget = self.config.max_reassembly_bytes;
+        while reassembly.values().map(|partial| partial.bytes).sum::<usize>() > budget {
+            let Some(oldest) = evict_oldest_partial(&mut reassembly) else {
+                break;
+            };
+            if oldest == key {
+                return Err(ProtocolError::InvalidPacket(format!(
+                    "Fragmented packet {} does not fit the reassembly budget of {} bytes",
+                    header.fragment_id, budget
+                )));
+            }
+            warn!("Reassembly budget exceeded, discarding fragmented packet {} from {}", oldest.1, oldest.0);
+        }
+
+        if !complete {
+            return Ok(None);
+        }
+
+        let partial = reassembly.remove(&key).expect("entry exists");
+        drop(reassembly);
+
+        let total = partial.chunks.iter().flatten().map(|c| c.len()).sum();
+        let mut data = Vec::with_capacity(total);
+        for chunk in partial.chunks.iter().flatten() {
+            data.extend_from_slice(chunk);
+        }
+
+        trace!("Reassembled {} fragments from {}", count, addr);
+        Packet::deserialize_with_limits(Bytes::from(data), &self.packet_limits()).map(Some)
+    }
+
+    /// Read one datagram into a pooled receive buffer
+    ///
+    /// The datagram is split off the buffer without copying, so the packet
+    /// payload deserialized from it is a slice of the same allocation. One
+    /// byte more than the receive buffer size is read, so a datagram that
+    /// fills it is known to be truncated rather than an exact fit.
+    async fn recv_datagram(&self) -> Result<(Bytes, SocketAddr)> {
+        let size = self.config.recv_buffer_size.unwrap_or(MAX_PACKET_SIZE).min(MAX_PACKET_SIZE);
+        let mut pool = self.recv_pool.lock().await;
+        let buf = pool.buffer(self.recv_mtu.load(Ordering::Relaxed));
+        let (len, addr) = self.link.recv_from(buf, size + 1).await?;
+        let datagram = buf.split().freeze();
+        if len > size {
+            self.stats.truncated_datagrams.fetch_add(1, Ordering::Relaxed);
+            warn!("Dropping datagram from {} larger than the {} byte receive buffer", addr, size);
+            return Err(ProtocolError::InvalidPacket(format!(
+                "Datagram larger than the {} byte receive buffer",
+                size
+            )));
+        }
+        Ok((datagram, addr))
+    }
+
+    /// Bounds incoming packets are checked against
+    fn packet_limits(&self) -> PacketLimits {
+        PacketLimits {
+            max_route_len: self.config.max_route_len,
+            max_payload_len: self.config.max_payload_len,
+        }
+    }
+
+    /// Reject packets in a wire format version outside the configured range
+    ///
+    /// Connection requests always pass, so the handshake can negotiate a
+    /// version or refuse the peer.
+    fn check_version(&self, packet: &Packet) -> Result<()> {
+        let range = self.config.min_protocol_version..=self.config.max_protocol_version;
+        if packet.packet_type == PacketType::Connect || range.contains(&packet.version) {
+            return Ok(());
+        }
+        Err(ProtocolError::VersionMismatch {
+            expected: self.config.max_protocol_version,
+            actual: packet.version,
+        })
+    }
+
+    /// Read the next complete packet, reassembling fragments and unpacking batches
+    async fn recv_packet(&self) -> Result<(Packet, SocketAddr)> {
+        if let Some(queued) = self.inbox.lock().await.pop_front() {
+            return Ok(queued);
+        }
+
+        loop {
+            let (datagram, addr) = match self.next_ack_due().await {
+                Some(due) if due <= Instant::now() => {
+                    self.flush_acks().await;
+                    continue;
+                }
+                // Wake up for held-back ACKs even if nothing else arrives
+                Some(due) => tokio::select! {
+                    received = self.recv_datagram() => received?,
+                    _ = time::sleep_until(due.into()) => continue,
+                },
+                None => self.recv_datagram().await?,
+            };
+            self.stats.packets_received.fetch_add(1, Ordering::Relaxed);
+            self.stats.bytes_received.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+
+            let mut packet = Packet::deserialize_with_limits(datagram, &self.packet_limits())?;
+            self.check_version(&packet)?;
+            if packet.packet_type == PacketType::Fragment {
+                match self.reassemble(packet, addr).await? {
+                    Some(complete) => packet = complete,
+                    None => continue,
+                }
+            }
+
+            if packet.packet_type == PacketType::Batch {
+                let mut packets = packet.split_batch_with_limits(&self.packet_limits())?.into_iter();
+                let Some(first) = packets.next() else {
+                    continue;
+                };
+                self.inbox.lock().await.extend(packets.map(|p| (p, addr)));
+                return Ok((first, addr));
+            }
+
+            return Ok((packet, addr));
+        }
+    }
+
+    /// Send an ACK at once, or hold it back to go out with others when `ack_delay` is set
+    async fn send_ack(&self, ack: Packet, dest: SocketAddr) {
+        let Some(delay) = self.config.ack_delay else {
+            let _ = self.send(ack, dest).await;
+            return;
+        };
+        self.delayed_acks
+            .lock()
+            .await
+            .entry(dest)
+            .or_insert_with(|| DelayedAcks {
+                due: Instant::now() + delay.min(MAX_ACK_DELAY),
+                acks: Vec::new(),
+            })
+            .acks
+            .push(ack);
+    }
+
+    /// When the earliest held-back ACK is due
+    async fn next_ack_due(&self) -> Option<Instant> {
+        self.delayed_acks.lock().await.values().map(|delayed| delayed.due).min()
+    }
+
+    /// Send the held-back ACKs that are due, batched per peer
+    async fn flush_acks(&self) {
+        let now = Instant::now();
+        let due: HashMap<SocketAddr, DelayedAcks> = {
+            let mut delayed = self.delayed_acks.lock().await;
+            let (due, waiting) = std::mem::take(&mut *delayed)
+                .into_iter()
+                .partition(|(_, acks)| acks.due <= now);
+            *delayed = waiting;
+            due
+        };
+
+        for (peer, delayed) in due {
+            if let Err(e) = self.send_batch(delayed.acks, peer).await {
+                debug!("Failed to send delayed ACKs to {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Record a reliable sequence from a source's channel, returning false for duplicates
+    async fn mark_seen(&self, addr: SocketAddr, channel: u16, sequence: u32) -> bool {
+        if self.config.duplicate_window == 0 {
+            return true;
+        }
+        self.seen
+            .lock()
+            .await
+            .entry((addr, channel))
+            .or_default()
+            .insert(sequence, self.config.duplicate_window)
+    }
+
+    /// Receive a packet
+    ///
+    /// ACKs and NACKs for this transport's reliable sends are applied before
+    /// they are returned, so acknowledged packets give back their congestion
+    /// window slots whoever drives the receive loop. Retransmitted copies of
+    /// reliable packets that were already delivered
+    /// are acknowledged again and dropped. A packet is only recorded as seen
+    /// once it has been decrypted, so a copy that could not be decrypted yet
+    /// (e.g. one sent just after a key rotation) is accepted when retransmitted.
+    pub async fn recv(&self) -> Result<(Packet, SocketAddr)> {
+        loop {
+            let (mut packet, addr) = self.recv_packet().await?;
+
+            // Path MTU probes and pings are answered and consumed here
+            match packet.packet_type {
+                PacketType::Probe => {
+                    let _ = self.send(Packet::new_probe_ack(packet.encoded_len()), addr).await;
+                    continue;
+                }
+                PacketType::Ping => {
+                    let _ = self.send(Packet::new_pong(&packet), addr).await;
+                    continue;
+                }
+                PacketType::ProbeAck => {
+                    let size = packet.probed_size()?;
+                    if let Some(path) = self.path_mtu.lock().await.get_mut(&addr) {
+                        if path.confirm(size) {
+                            self.recv_mtu.fetch_max(path.mtu(), Ordering::Relaxed);
+                            debug!("Path MTU to {} is at least {} bytes", addr, size);
+                        } else {
+                            debug!("Ignoring ProbeAck from {} for an unprobed size of {} bytes", addr, size);
+                        }
+                    }
+                    continue;
+                }
+                PacketType::Ack => self.handle_ack(packet.channel_id, packet.sequence).await,
+                PacketType::Nack => self.handle_nack(packet.channel_id, packet.sequence).await,
+                _ => {}
+            }
+
+            // Decrypt if needed
+            if packet.flags.encrypted {
+                if let Some(crypto) = self.crypto_for(addr).await {
+                    packet.payload = crypto.decrypt(&packet.payload, packet.route.as_bytes())?;
+                } else {
+                    return Err(ProtocolError::Encryption(
+                        "Received encrypted packet but no crypto provider".to_string(),
+                    ));
+                }
+            }
+
+            // Decompress with whichever algorithm the sender used, through our own
+            // provider when it matches so a shared dictionary applies
+            if packet.flags.compressed {
+                let own = self.compression.read().await.clone();
+                packet.payload = match own {
+                    Some(own) if own.algorithm() == packet.flags.compression => {
+                        own.decompress(&packet.payload)?
+                    }
+                    #[cfg(feature = "compression")]
+                    _ => CompressionProvider::decompress_with(packet.flags.compression, &packet.payload)?,
+                    #[cfg(not(feature = "compression"))]
+                    _ => {
+                        return Err(ProtocolError::Compression(format!(
+                            "No codec for {:?} compression",
+                            packet.flags.compression
+                        )))
+                    }
+                };
+            }
+
+            // Send ACK if required
+            if packet.flags.requires_ack {
+                let mut ack = Packet::new_ack(packet.sequence);
+                ack.channel_id = packet.channel_id;
+                self.send_ack(ack, addr).await;
+
+                if !self.mark_seen(addr, packet.channel_id, packet.sequence).await {
+                    debug!(
+                        "Dropping duplicate sequence {} on channel {} from {}",
+                        packet.sequence, packet.channel_id, addr
+                    );
+                    self.stats.duplicates_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+
+            // Switch keys here rather than in a handler so the very next packet already uses the new key
+            if packet.packet_type == PacketType::Rekey {
+                self.rekey_session(addr, &packet.payload).await?;
+            }
+
+            return Ok((packet, addr));
+        }
+    }
+
+    /// Stream of received packets, for callers that drive their own receive loop
+    ///
+    /// Yields what `recv` would. Receive errors are yielded and the stream
+    /// carries on after them.
+    pub fn incoming(&self) -> impl Stream<Item = Result<(Packet, SocketAddr)>> + '_ {
+        futures::stream::unfold(self, |transport| async move { Some((transport.recv().await, transport)) })
+    }
+
+    /// Handle acknowledgment of a sequence on a channel
+    pub async fn handle_ack(&self, channel: u16, sequence: u32) {
+        let acked = self.pending_acks.write().await.remove(&(channel, sequence));
+        trace!("Received ACK for sequence {} on channel {}", sequence, channel);
+        self.stats.acks_received.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(pending) = &acked {
+            if let Some(window) = self.congestion.lock().await.get_mut(&(pending.dest, channel)) {
+                window.on_ack(self.config.max_congestion_window);
+            }
+        }
+        self.acked.notify_waiters();
+
+        // Only sample packets that were never retransmitted (Karn's algorithm)
+        if let Some(pending) = acked {
+            if pending.attempts == 0 {
+                let rtt = self.clock.now().saturating_duration_since(pending.sent_at);
+                self.record_rtt(pending.dest, rtt).await;
+            }
+        }
+    }
+
+    /// Stop retransmitting a reliable packet that is no longer wanted
+    pub async fn cancel_reliable(&self, channel: u16, sequence: u32) {
+        let cancelled = self.pending_acks.write().await.remove(&(channel, sequence));
+        if let Some(pending) = cancelled {
+            trace!("Cancelled retransmission of sequence {} on channel {}", sequence, channel);
+            self.release_window(pending.dest, channel).await;
+        }
+    }
+
+    /// Wait until a reliable packet is acknowledged or given up on
+    pub async fn wait_for_ack(&self, channel: u16, sequence: u32) {
+        loop {
+            // Register interest before checking so an ACK in between is not missed
+            let acked = self.acked.notified();
+            if !self.pending_acks.read().await.contains_key(&(channel, sequence)) {
+                return;
+            }
+            acked.await;
+        }
+    }
+
+    /// Wait until every reliable packet to `dest` is acknowledged or given up on
+    pub async fn flush(&self, dest: SocketAddr) {
+        loop {
+            // Register interest before checking so an ACK in between is not missed
+            let acked = self.acked.notified();
+            if !self.pending_acks.read().await.values().any(|pending| pending.dest == dest) {
+                return;
+            }
+            acked.await;
+        }
+    }
+
+    /// Fold an RTT sample for a destination into its estimate
+    async fn record_rtt(&self, dest: SocketAddr, sample: Duration) {
+        let mut rtt = self.rtt.write().await;
+        match rtt.get_mut(&dest) {
+            Some(estimate) => estimate.update(sample),
+            None => {
+                rtt.insert(dest, RttEstimator::new(sample));
+            }
+        }
+    }
+
+    /// Current smoothed RTT estimate for a destination, if any ACKs have been seen
+    pub async fn rtt_estimate(&self, dest: SocketAddr) -> Option<Duration> {
+        self.rtt.read().await.get(&dest).map(|estimate| estimate.srtt)
+    }
+
+    /// Retransmission timeout for a destination
+    ///
+    /// Falls back to the configured `ack_timeout` until an RTT sample exists.
+    pub async fn retransmission_timeout(&self, dest: SocketAddr) -> Duration {
+        match self.rtt.read().await.get(&dest) {
+            Some(estimate) => estimate.rto(self.config.min_rto),
+            None => self.config.ack_timeout,
+        }
+    }
+
+    /// Handle negative acknowledgment of a sequence on a channel
+    ///
+    /// The packet is retransmitted right away rather than on the next
+    /// retransmission tick, and counts as a loss for the channel's congestion
+    /// window. It keeps the window slot it already holds, unless it has used
+    /// up its retransmit attempts and is given up on.
+    pub async fn handle_nack(&self, channel: u16, sequence: u32) {
+        let key = (channel, sequence);
+        let mut pending = self.pending_acks.write().await;
+        let Some(entry) = pending.get_mut(&key) else {
+            return;
+        };
+        let dest = entry.dest;
+        let resend = if entry.attempts >= self.config.max_retransmit {
+            warn!("Max retransmit attempts reached for sequence {} on channel {}", sequence, channel);
+            pending.remove(&key);
+            None
+        } else {
+            entry.attempts += 1;
+            entry.sent_at = self.clock.now();
+            Some((entry.packet.clone(), entry.attempts))
+        };
+        drop(pending);
+
+        if let Some(window) = self.congestion.lock().await.get_mut(&(dest, channel)) {
+            window.on_loss();
+            if resend.is_none() {
+                window.in_flight = window.in_flight.saturating_sub(1);
+            }
+        }
+
+        match resend {
+            Some((packet, attempt)) => {
+                trace!("Received NACK for sequence {} on channel {}, retransmitting", sequence, channel);
+                self.stats.retransmissions.fetch_add(1, Ordering::Relaxed);
+                self.notify_retransmit(channel, sequence, attempt, dest).await;
+                if let Err(e) = self.send(packet, dest).await {
+                    error!("Retransmission failed: {}", e);
+                }
+            }
+            None => {
+                self.acked.notify_waiters();
+                self.notify_drop(channel, sequence, dest).await;
+            }
+        }
+    }
+
+    /// Start retransmission task
+    ///
+    /// Runs until the transport is dropped.
+    pub async fn start_retransmission_task(self: Arc<Self>) {
+        let weak = Arc::downgrade(&self);
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                let Some(transport) = weak.upgrade() else {
+                    break;
+                };
+                if transport.config.path_mtu_discovery {
+                    transport.probe_path_mtu().await;
+                }
+
+                transport.retransmit_expired().await;
+            }
+        });
+    }
+
+    /// Retransmit every reliable packet whose timeout has passed, and give up on those out of attempts
+    async fn retransmit_expired(&self) {
+        let now = self.clock.now();
+        let mut to_retransmit = Vec::new();
+        let mut to_remove = Vec::new();
+        let mut lossy = HashSet::new();
+
+        {
+            let rtt = self.rtt.read().await.clone();
+            let mut pending = self.pending_acks.write().await;
+            for (&(channel, seq), packet) in pending.iter_mut() {
+                let rto = match rtt.get(&packet.dest) {
+                    Some(estimate) => estimate.rto(self.config.min_rto),
+                    None => self.config.ack_timeout,
+                };
+                if now.duration_since(packet.sent_at) > rto {
+                    lossy.insert((packet.dest, channel));
+                    if packet.attempts >= self.config.max_retransmit {
+                        warn!("Max retransmit attempts reached for sequence {} on channel {}", seq, channel);
+                        to_remove.push((channel, seq));
+                    } else {
+                        packet.attempts += 1;
+                        packet.sent_at = now;
+                        to_retransmit.push((packet.packet.clone(), packet.dest, packet.attempts));
+                    }
+                }
+            }
+
+            let removed: Vec<(SocketAddr, u16, u32)> = to_remove
+                .iter()
+                .filter_map(|key| pending.remove(key).map(|packet| (packet.dest, key.0, key.1)))
+                .collect();
+            drop(pending);
+
+            // Halve each lossy channel's window once per round, and free the slots given up on
+            if !lossy.is_empty() {
+                let mut windows = self.congestion.lock().await;
+                for key in &lossy {
+                    if let Some(window) = windows.get_mut(key) {
+                        window.on_loss();
+                    }
+                }
+                for &(dest, channel, _) in &removed {
+                    if let Some(window) = windows.get_mut(&(dest, channel)) {
+                        window.in_flight = window.in_flight.saturating_sub(1);
+                    }
+                }
+            }
+            if !to_remove.is_empty() {
+                self.acked.notify_waiters();
+            }
+            for (dest, channel, seq) in removed {
+                self.notify_drop(channel, seq, dest).await;
+            }
+        }
+
+        for (packet, dest, attempt) in to_retransmit {
+            self.stats.retransmissions.fetch_add(1, Ordering::Relaxed);
+            self.notify_retransmit(packet.channel_id, packet.sequence, attempt, dest).await;
+            if let Err(e) = self.send(packet, dest).await {
+                error!("Retransmission failed: {}", e);
+            }
+        }
+    }
+
+    /// Start heartbeat task, sending a heartbeat to `dest` every `period`
+    ///
+    /// Runs until aborted through the returned handle or the transport is dropped.
+    pub async fn start_heartbeat_task(self: Arc<Self>, dest: SocketAddr, period: Duration) -> AbortHandle {
+        let weak = Arc::downgrade(&self);
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(period);
             loop {
                 interval.tick().await;
+                let Some(transport) = weak.upgrade() else {
+                    break;
+                };
                 let heartbeat = Packet::new_heartbeat();
                 if let Err(e) = transport.send(heartbeat, dest).await {
                     error!("Heartbeat send failed: {}", e);
                 }
             }
-        });
+        })
+    }
+
+    /// Snapshot of the transport counters
+    pub async fn stats(&self) -> TransportStats {
+        TransportStats {
+            packets_sent: self.stats.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.stats.packets_received.load(Ordering::Relaxed),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.stats.bytes_received.load(Ordering::Relaxed),
+            retransmissions: self.stats.retransmissions.load(Ordering::Relaxed),
+            acks_received: self.stats.acks_received.load(Ordering::Relaxed),
+            pending_acks: self.pending_acks.read().await.len(),
+            duplicates_dropped: self.stats.duplicates_dropped.load(Ordering::Relaxed),
+            truncated_datagrams: self.stats.truncated_datagrams.load(Ordering::Relaxed),
+            congestion_window: self
+                .congestion
+                .lock()
+                .await
+                .values()
+                .map(CongestionWindow::size)
+                .min()
+                .unwrap_or(self.config.initial_congestion_window.max(MIN_CWND)),
+            max_send_rate: self.config.max_send_rate,
+            send_tokens: match &self.pacer {
+                Some(pacer) => Some(pacer.lock().await.available(Instant::now())),
+                None => None,
+            },
+            in_flight_requests: 0,
+        }
     }
 
     /// Get local address
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr().map_err(Into::into)
+        self.link.local_addr()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionAlgorithm;
+
+    async fn loopback_pair(config: TransportConfig) -> (Arc<Transport>, Arc<Transport>) {
+        let a = Transport::bind(([127, 0, 0, 1], 0), config.clone()).await.unwrap();
+        let b = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        (Arc::new(a), Arc::new(b))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fragmented_roundtrip() {
+        let config = TransportConfig {
+            max_datagram_size: 8192,
+            ..Default::default()
+        };
+        let sender = Arc::new(Transport::bind(([127, 0, 0, 1], 0), config.clone()).await.unwrap());
+        // Room for every fragment of the burst, however late the receiver reads it
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+        socket.set_recv_buffer_size(1 << 20).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        let socket = UdpSocket::from_std(socket.into()).unwrap();
+        let receiver = Arc::new(Transport::over(UdpTransport::new(socket).unwrap(), config));
+        let dest = receiver.local_addr().unwrap();
+
+        let payload: Bytes = (0..200 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>().into();
+
+        let recv_task = tokio::spawn({
+            let receiver = receiver.clone();
+            async move { receiver.recv().await }
+        });
+        sender.send_reliable("/big".to_string(), payload.clone(), dest).await.unwrap();
+
+        let (packet, _) = recv_task.await.unwrap().unwrap();
+        assert_eq!(packet.packet_type, PacketType::Data);
+        assert_eq!(packet.route, "/big");
+        assert_eq!(packet.payload, payload);
+    }
+
+    #[tokio::test]
+    async fn test_lost_fragments_are_recovered_by_retransmission() {
+        use crate::clock::SeededRng;
+        use crate::impairment::{ImpairedTransport, ImpairmentConfig};
+
+        let config = TransportConfig {
+            max_datagram_size: 8192,
+            ack_timeout: Duration::from_millis(50),
+            min_rto: Duration::from_millis(20),
+            max_retransmit: 30,
+            ..Default::default()
+        };
+        // Losing any fragment loses the whole packet, so it must be sent again
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let impairment = ImpairmentConfig { loss: 0.2, ..Default::default() };
+        let link = ImpairedTransport::new(UdpTransport::new(socket).unwrap(), impairment, SeededRng::new(3)).unwrap();
+        let sender = Arc::new(Transport::over(link, config.clone()));
+        let receiver = Arc::new(Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap());
+        let dest = receiver.local_addr().unwrap();
+        sender.clone().start_retransmission_task().await;
+
+        let payload: Bytes = (0..48 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>().into();
+        let recv_task = tokio::spawn({
+            let receiver = receiver.clone();
+            async move { receiver.recv().await }
+        });
+        tokio::spawn({
+            let sender = sender.clone();
+            async move { while sender.recv().await.is_ok() {} }
+        });
+        sender.send_reliable("/big".to_string(), payload.clone(), dest).await.unwrap();
+
+        let (packet, _) = time::timeout(Duration::from_secs(10), recv_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.payload, payload);
+        assert!(sender.stats().await.retransmissions > 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_fragmented_packet_is_rejected() {
+        let config = TransportConfig {
+            max_route_len: 16,
+            max_payload_len: 1000,
+            ..Default::default()
+        };
+        let (_, receiver) = loopback_pair(config).await;
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        // Fragments claiming a packet far past the payload limit
+        let header = FragmentHeader {
+            fragment_id: 1,
+            fragment_index: 0,
+            fragment_count: 100,
+        };
+        let chunk = vec![0u8; 600];
+        let first = Packet::new_fragment(header, &chunk);
+        assert!(receiver.reassemble(first, from).await.unwrap().is_none());
+
+        let second = Packet::new_fragment(FragmentHeader { fragment_index: 1, ..header }, &chunk);
+        let err = receiver.reassemble(second, from).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::InvalidPacket(msg) if msg.contains("exceeds limit")));
+        assert!(receiver.reassembly.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reassembly_evicts_oldest_partials() {
+        let config = TransportConfig {
+            max_reassembly_partials: 2,
+            max_reassembly_bytes: 1000,
+            ..Default::default()
+        };
+        let (_, receiver) = loopback_pair(config).await;
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let fragment = |fragment_id, len| {
+            let header = FragmentHeader {
+                fragment_id,
+                fragment_index: 0,
+                fragment_count: 2,
+            };
+            Packet::new_fragment(header, &vec![0u8; len])
+        };
+
+        // A third packet pushes out the first
+        for id in 1..=3 {
+            assert!(receiver.reassemble(fragment(id, 100), from).await.unwrap().is_none());
+            time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut ids: Vec<_> = receiver.reassembly.lock().await.keys().map(|(_, id)| *id).collect();
+        ids.sort();
+        assert_eq!(ids, [2, 3]);
+
+        // Going over the byte budget discards the oldest until it fits
+        assert!(receiver.reassemble(fragment(4, 950), from).await.unwrap().is_none());
+        let reassembly = receiver.reassembly.lock().await;
+        assert_eq!(reassembly.len(), 1);
+        assert!(reassembly.contains_key(&(from, 4)));
+        drop(reassembly);
+
+        // A packet that cannot fit even alone is rejected
+        let err = receiver.reassemble(fragment(5, 1200), from).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::InvalidPacket(msg) if msg.contains("reassembly budget")));
+        assert!(receiver.reassembly.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_fragments_expire() {
+        let config = TransportConfig {
+            reassembly_timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let (_, receiver) = loopback_pair(config).await;
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        let header = FragmentHeader {
+            fragment_id: 1,
+            fragment_index: 0,
+            fragment_count: 2,
+        };
+        let first = Packet::new_fragment(header, b"half");
+        assert!(receiver.reassemble(first, from).await.unwrap().is_none());
+        assert_eq!(receiver.reassembly.lock().await.len(), 1);
+
+        time::sleep(Duration::from_millis(40)).await;
+
+        let other = Packet::new_fragment(FragmentHeader { fragment_id: 2, ..header }, b"x");
+        assert!(receiver.reassemble(other, from).await.unwrap().is_none());
+
+        let reassembly = receiver.reassembly.lock().await;
+        assert_eq!(reassembly.len(), 1);
+        assert!(!reassembly.contains_key(&(from, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_send_batch() {
+        let config = TransportConfig {
+            max_datagram_size: 256,
+            ..Default::default()
+        };
+        let (sender, receiver) = loopback_pair(config).await;
+        let dest = receiver.local_addr().unwrap();
+
+        let packets: Vec<Packet> = (0..10)
+            .map(|i| Packet::new_data(format!("/item/{}", i), Bytes::from(vec![i as u8; 40]), i))
+            .collect();
+        sender.send_batch(packets, dest).await.unwrap();
+
+        for i in 0..10 {
+            let (packet, _) = receiver.recv().await.unwrap();
+            assert_eq!(packet.route, format!("/item/{}", i));
+            assert_eq!(packet.sequence, i);
+        }
+
+        // Every packet was delivered from the batches, nothing is left on the socket
+        let mut raw = BytesMut::with_capacity(512);
+        let empty = time::timeout(Duration::from_millis(50), receiver.link.recv_from(&mut raw, 512)).await;
+        assert!(empty.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delayed_acks_are_batched() {
+        let config = TransportConfig {
+            ack_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let receiver = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        let dest = receiver.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        for sequence in 0..5 {
+            let packet = Packet::new_data("/stream".to_string(), Bytes::from("x"), sequence);
+            sender.send_to(&packet.serialize().unwrap(), dest).await.unwrap();
+        }
+        tokio::spawn(async move { while receiver.recv().await.is_ok() {} });
+
+        let mut datagrams = 0;
+        let mut acked = Vec::new();
+        let mut buf = vec![0u8; 65536];
+        while let Ok(Ok(len)) = time::timeout(Duration::from_millis(200), sender.recv(&mut buf)).await {
+            datagrams += 1;
+            let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            let acks = match packet.packet_type {
+                PacketType::Batch => packet.split_batch().unwrap(),
+                _ => vec![packet],
+            };
+            acked.extend(acks.iter().filter(|p| p.packet_type == PacketType::Ack).map(|p| p.sequence));
+        }
+
+        acked.sort_unstable();
+        assert_eq!(acked, vec![0, 1, 2, 3, 4]);
+        assert!(datagrams < 5);
+    }
+
+    #[test]
+    fn test_seen_window_evicts_oldest() {
+        let mut window = SeenWindow::default();
+        assert!(window.insert(1, 2));
+        assert!(!window.insert(1, 2));
+        assert!(window.insert(2, 2));
+        assert!(window.insert(3, 2));
+        // 1 fell out of the window and is accepted again
+        assert!(window.insert(1, 2));
+    }
+
+    #[test]
+    fn test_rtt_estimator_tracks_slow_link() {
+        let link = Duration::from_millis(300);
+        let mut estimate = RttEstimator::new(link);
+        for _ in 0..20 {
+            estimate.update(link);
+        }
+
+        let rto = estimate.rto(Duration::from_millis(100));
+        assert!(rto >= link);
+        assert!(rto < Duration::from_millis(400), "rto was {:?}", rto);
+
+        // A stable 300ms link no longer needs the conservative 1s default
+        assert!(rto < Duration::from_millis(DEFAULT_ACK_TIMEOUT_MS));
+    }
+
+    #[tokio::test]
+    async fn test_ack_updates_rtt_estimate() {
+        let (sender, receiver) = loopback_pair(TransportConfig::default()).await;
+        let dest = receiver.local_addr().unwrap();
+        assert!(sender.rtt_estimate(dest).await.is_none());
+
+        let sequence = sender
+            .send_reliable("/rtt".to_string(), Bytes::from("ping"), dest)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        sender.handle_ack(DEFAULT_CHANNEL, sequence).await;
+
+        let srtt = sender.rtt_estimate(dest).await.unwrap();
+        assert!(srtt >= Duration::from_millis(20));
+        assert!(sender.retransmission_timeout(dest).await >= srtt);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compression_skipped_when_it_does_not_help() {
+        let config = TransportConfig {
+            enable_compression: true,
+            ..Default::default()
+        };
+        let sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        sender.set_compression(CompressionProvider::new_zstd(3)).await;
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = receiver.local_addr().unwrap();
+
+        let random: Bytes = (0..4096).map(|_| rand::random::<u8>()).collect::<Vec<_>>().into();
+        let repetitive = Bytes::from(vec![b'a'; 4096]);
+        sender.send_reliable("/r".to_string(), random.clone(), dest).await.unwrap();
+        sender.send_reliable("/a".to_string(), repetitive.clone(), dest).await.unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+        let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert!(!packet.flags.compressed);
+        assert_eq!(packet.payload, random);
+
+        let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+        let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert!(packet.flags.compressed);
+        assert!(packet.payload.len() < repetitive.len());
+    }
+
+    /// Codec that leaves data as it is, counting how often it runs
+    struct IdentityCompression(Arc<AtomicUsize>);
+
+    impl Compression for IdentityCompression {
+        fn compress(&self, data: &[u8]) -> Result<Bytes> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::copy_from_slice(data))
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Bytes> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::copy_from_slice(data))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_compression_implementation() {
+        let config = TransportConfig {
+            enable_compression: true,
+            compression_min_savings: 0.0,
+            ..Default::default()
+        };
+        let (sender, receiver) = loopback_pair(config).await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        sender.set_compression(IdentityCompression(calls.clone())).await;
+        receiver.set_compression(IdentityCompression(calls.clone())).await;
+
+        let payload = Bytes::from(vec![b'x'; 256]);
+        let dest = receiver.local_addr().unwrap();
+        sender.send_reliable("/plain".to_string(), payload.clone(), dest).await.unwrap();
+
+        let (packet, _) = receiver.recv().await.unwrap();
+        assert!(packet.flags.compressed);
+        assert_eq!(packet.flags.compression, CompressionAlgorithm::Custom);
+        assert_eq!(packet.payload, payload);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stats_track_traffic() {
+        let (sender, receiver) = loopback_pair(TransportConfig::default()).await;
+        let dest = receiver.local_addr().unwrap();
+
+        for _ in 0..3 {
+            sender.send_reliable("/s".to_string(), Bytes::from("ping"), dest).await.unwrap();
+        }
+        assert_eq!(sender.stats().await.pending_acks, 3);
+
+        for _ in 0..3 {
+            receiver.recv().await.unwrap();
+            let (ack, _) = sender.recv().await.unwrap();
+            assert_eq!(ack.packet_type, PacketType::Ack);
+        }
+
+        let sent = sender.stats().await;
+        assert_eq!(sent.packets_sent, 3);
+        assert_eq!(sent.acks_received, 3);
+        assert_eq!(sent.pending_acks, 0);
+        assert!(sent.bytes_sent > 0);
+
+        let received = receiver.stats().await;
+        assert_eq!(received.packets_received, 3);
+        assert_eq!(received.packets_sent, 3);
+        assert_eq!(received.bytes_received, sent.bytes_sent);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_datagram_is_reported() {
+        let config = TransportConfig {
+            recv_buffer_size: Some(256),
+            ..Default::default()
+        };
+        let receiver = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        let dest = receiver.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let large = Packet::new_data("/big".to_string(), Bytes::from(vec![7; 1000]), 0);
+        sender.send_to(&large.serialize().unwrap(), dest).await.unwrap();
+        let small = Packet::new_data("/small".to_string(), Bytes::from("x"), 1);
+        sender.send_to(&small.serialize().unwrap(), dest).await.unwrap();
+
+        assert!(matches!(receiver.recv().await, Err(ProtocolError::InvalidPacket(_))));
+        assert_eq!(receiver.stats().await.truncated_datagrams, 1);
+        let (packet, _) = receiver.recv().await.unwrap();
+        assert_eq!(packet.route, "/small");
+    }
+
+    #[tokio::test]
+    async fn test_incoming_stream_applies_acks() {
+        use futures::StreamExt;
+
+        let (sender, receiver) = loopback_pair(TransportConfig::default()).await;
+        let dest = receiver.local_addr().unwrap();
+        sender.send_reliable("/s".to_string(), Bytes::from("ping"), dest).await.unwrap();
+
+        let incoming = receiver.incoming();
+        tokio::pin!(incoming);
+        let (packet, from) = incoming.next().await.unwrap().unwrap();
+        assert_eq!(packet.route, "/s");
+        assert_eq!(from, sender.local_addr().unwrap());
+
+        // The sender's stream applies the ACK without being told to
+        let acks = sender.incoming();
+        tokio::pin!(acks);
+        let (ack, _) = acks.next().await.unwrap().unwrap();
+        assert_eq!(ack.packet_type, PacketType::Ack);
+        let stats = sender.stats().await;
+        assert_eq!((stats.acks_received, stats.pending_acks), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_nack_retransmits_immediately() {
+        let config = TransportConfig {
+            max_retransmit: 1,
+            ..Default::default()
+        };
+        let sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+        let mut buf = vec![0u8; 65536];
+
+        let sequence = sender.send_reliable("/r".to_string(), Bytes::from("x"), dest).await.unwrap();
+        sink.recv_from(&mut buf).await.unwrap();
+
+        // No retransmission task is running, so only the NACK can resend it
+        sender.handle_nack(DEFAULT_CHANNEL, sequence).await;
+        let (len, _) = time::timeout(Duration::from_millis(20), sink.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let resent = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert_eq!(resent.sequence, sequence);
+        assert_eq!(sender.stats().await.retransmissions, 1);
+
+        // Past the retransmit cap the packet is given up on instead
+        sender.handle_nack(DEFAULT_CHANNEL, sequence).await;
+        assert!(time::timeout(Duration::from_millis(50), sink.recv_from(&mut buf)).await.is_err());
+        assert_eq!(sender.stats().await.pending_acks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_rate_is_paced() {
+        let config = TransportConfig {
+            max_send_rate: Some(100_000),
+            ..Default::default()
+        };
+        let sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        let packet = Packet::new_data("/paced".to_string(), Bytes::from(vec![7u8; 1000]), 0);
+        let start = Instant::now();
+        for _ in 0..50 {
+            sender.send(packet.clone(), dest).await.unwrap();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let stats = sender.stats().await;
+        let expected = stats.bytes_sent as f64 / 100_000.0;
+        assert!(elapsed > expected * 0.8 && elapsed < expected * 1.5, "took {}s, expected {}s", elapsed, expected);
+        assert_eq!(stats.max_send_rate, Some(100_000));
+        assert!(stats.send_tokens.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_loss_shrinks_congestion_window() {
+        let config = TransportConfig {
+            ack_timeout: Duration::from_millis(20),
+            max_retransmit: 10,
+            ..Default::default()
+        };
+        let sender = Arc::new(Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap());
+        // Nothing on the other side ever ACKs, so every packet is lost
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        let initial = sender.congestion_window(dest, DEFAULT_CHANNEL).await;
+        for _ in 0..4 {
+            sender.send_reliable("/lossy".to_string(), Bytes::from("x"), dest).await.unwrap();
+        }
+        sender.clone().start_retransmission_task().await;
+
+        time::timeout(Duration::from_secs(2), async {
+            while sender.congestion_window(dest, DEFAULT_CHANNEL).await > initial / 4 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(sender.stats().await.congestion_window < initial);
+        assert!(sender.stats().await.retransmissions > 0);
+    }
+
+    #[tokio::test]
+    async fn test_acks_free_the_window_without_retransmission_task() {
+        let (sender, receiver) = loopback_pair(TransportConfig::default()).await;
+        let dest = receiver.local_addr().unwrap();
+        tokio::spawn(async move { while receiver.recv().await.is_ok() {} });
+        // A plain receive loop, with no retransmission task and no explicit handle_ack
+        let reader = sender.clone();
+        tokio::spawn(async move { while reader.recv().await.is_ok() {} });
+
+        let initial = sender.congestion_window(dest, DEFAULT_CHANNEL).await;
+        time::timeout(Duration::from_secs(2), async {
+            for _ in 0..initial * 4 {
+                sender.send_reliable("/w".to_string(), Bytes::from("x"), dest).await.unwrap();
+            }
+            sender.flush(dest).await;
+        })
+        .await
+        .unwrap();
+        assert!(sender.congestion_window(dest, DEFAULT_CHANNEL).await > initial);
+    }
+
+    #[tokio::test]
+    async fn test_unreliable_send_is_never_retransmitted() {
+        let config = TransportConfig {
+            ack_timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let sender = Arc::new(Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap());
+        // Nothing on the other side ever ACKs
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        sender.clone().start_retransmission_task().await;
+        sender
+            .send_unreliable_on(DEFAULT_CHANNEL, "/telemetry".to_string(), Bytes::from("x"), dest)
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        let len = time::timeout(Duration::from_secs(1), sink.recv(&mut buf)).await.unwrap().unwrap();
+        let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert!(packet.flags.unreliable);
+        assert!(!packet.flags.requires_ack);
+
+        // Well past several ACK timeouts, nothing else arrives
+        assert!(time::timeout(Duration::from_millis(200), sink.recv(&mut buf)).await.is_err());
+        let stats = sender.stats().await;
+        assert_eq!(stats.retransmissions, 0);
+        assert_eq!(stats.pending_acks, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuseport_sockets_share_traffic() {
+        let first = Transport::bind_reuseport(([127, 0, 0, 1], 0), TransportConfig::default()).await.unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = Transport::bind_reuseport(addr, TransportConfig::default()).await.unwrap();
+        // A plain bind still cannot take the port
+        assert!(Transport::bind(addr, TransportConfig::default()).await.is_err());
+
+        let received = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+        for (i, transport) in [first, second].into_iter().enumerate() {
+            let received = received.clone();
+            tokio::spawn(async move {
+                while transport.recv().await.is_ok() {
+                    received[i].fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+
+        // The kernel picks a socket per source address, so send from many
+        for _ in 0..32 {
+            let source = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let packet = Packet::new_data("/spread".to_string(), Bytes::from("x"), 0);
+            source.send_to(&packet.serialize().unwrap(), addr).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(received[0].load(Ordering::SeqCst) + received[1].load(Ordering::SeqCst), 32);
+        assert!(received[0].load(Ordering::SeqCst) > 0);
+        assert!(received[1].load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_drives_retransmission() {
+        let config = TransportConfig {
+            ack_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let mut sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        sender.set_time_source(clock.clone());
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        sender.send_reliable("/r".to_string(), Bytes::from("x"), dest).await.unwrap();
+        let mut buf = vec![0u8; 65536];
+        sink.recv(&mut buf).await.unwrap();
+
+        // However long the test really takes, nothing is due until the clock says so
+        sender.retransmit_expired().await;
+        assert_eq!(sender.stats().await.retransmissions, 0);
+
+        clock.advance(Duration::from_millis(501));
+        sender.retransmit_expired().await;
+        assert_eq!(sender.stats().await.retransmissions, 1);
+        let len = sink.recv(&mut buf).await.unwrap();
+        let packet = Packet::deserialize(Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert_eq!(packet.route, "/r");
+
+        // The timeout restarts from the retransmission
+        clock.advance(Duration::from_millis(250));
+        sender.retransmit_expired().await;
+        assert_eq!(sender.stats().await.retransmissions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_channel_does_not_block_another() {
+        let config = TransportConfig {
+            initial_congestion_window: 1,
+            ..Default::default()
+        };
+        let sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        // Nothing on the other side ever ACKs, so channel 1 fills its window and stalls
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        let first = sender.send_reliable_on(1, "/bulk".to_string(), Bytes::from("x"), dest).await.unwrap();
+        let stalled = time::timeout(
+            Duration::from_millis(100),
+            sender.send_reliable_on(1, "/bulk".to_string(), Bytes::from("x"), dest),
+        )
+        .await;
+        assert!(stalled.is_err());
+
+        // Channel 2 has its own window and its own sequence space
+        let other = time::timeout(
+            Duration::from_millis(100),
+            sender.send_reliable_on(2, "/control".to_string(), Bytes::from("x"), dest),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!((first, other), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_drop_callback_fires_after_max_attempts() {
+        let config = TransportConfig {
+            ack_timeout: Duration::from_millis(10),
+            max_retransmit: 2,
+            ..Default::default()
+        };
+        let sender = Arc::new(Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap());
+        // Nothing listens at the destination once its socket is gone
+        let dest = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let retransmits = events.clone();
+        sender
+            .on_retransmit(move |channel, seq, attempt, to| {
+                retransmits.lock().unwrap().push((channel, seq, Some(attempt), to));
+            })
+            .await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let drops = events.clone();
+        sender
+            .on_drop(move |channel, seq, to| {
+                drops.lock().unwrap().push((channel, seq, None, to));
+                let _ = tx.send(());
+            })
+            .await;
+        sender.clone().start_retransmission_task().await;
+
+        sender.send_reliable_on(5, "/lost".to_string(), Bytes::from("x"), dest).await.unwrap();
+        time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [(5, 0, Some(1), dest), (5, 0, Some(2), dest), (5, 0, None, dest)]
+        );
+        assert_eq!(sender.stats().await.pending_acks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_applies_backpressure() {
+        let config = TransportConfig {
+            max_pending: 2,
+            ..Default::default()
+        };
+        let sender = Transport::bind(([127, 0, 0, 1], 0), config).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = sink.local_addr().unwrap();
+
+        // The cap spans channels, even though each has room in its own window
+        sender.send_reliable_on(1, "/a".to_string(), Bytes::from("x"), dest).await.unwrap();
+        sender.send_reliable_on(2, "/b".to_string(), Bytes::from("x"), dest).await.unwrap();
+        let blocked = time::timeout(
+            Duration::from_millis(100),
+            sender.send_reliable_on(3, "/c".to_string(), Bytes::from("x"), dest),
+        )
+        .await;
+        assert!(blocked.is_err());
+        assert_eq!(sender.stats().await.pending_acks, 2);
+
+        // An ACK frees room for the waiting send
+        let waiting = sender.send_reliable_on(3, "/c".to_string(), Bytes::from("x"), dest);
+        let (_, sent) = tokio::join!(
+            async {
+                time::sleep(Duration::from_millis(20)).await;
+                sender.handle_ack(1, 0).await;
+            },
+            time::timeout(Duration::from_secs(1), waiting),
+        );
+        assert_eq!(sent.unwrap().unwrap(), 0);
+        assert_eq!(sender.stats().await.pending_acks, 2);
+    }
+
+    #[tokio::test]
+    async fn test_path_mtu_probes_upward() {
+        let config = TransportConfig {
+            max_datagram_size: 8972,
+            path_mtu_discovery: true,
+            ..Default::default()
+        };
+        let (sender, receiver) = loopback_pair(config).await;
+        let dest = receiver.local_addr().unwrap();
+        for transport in [&sender, &receiver] {
+            let transport = transport.clone();
+            tokio::spawn(async move { while transport.recv().await.is_ok() {} });
+        }
+
+        // Sending alone does not start probing; only registered peers are tracked
+        sender.send(Packet::new_heartbeat(), dest).await.unwrap();
+        assert_eq!(sender.path_mtu(dest).await, None);
+        sender.discover_path_mtu(dest).await;
+        assert_eq!(sender.path_mtu(dest).await, Some(1200));
+
+        // Loopback carries large datagrams, so probing climbs to the configured limit
+        sender.clone().start_retransmission_task().await;
+        time::timeout(Duration::from_secs(5), async {
+            while sender.path_mtu(dest).await != Some(8972) {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
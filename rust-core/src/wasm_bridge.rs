@@ -3,26 +3,130 @@
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 #[cfg(feature = "wasm")]
-use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::JsFuture;
 #[cfg(feature = "wasm")]
 use js_sys::{Promise, Uint8Array};
 #[cfg(feature = "wasm")]
-use web_sys::{WebSocket, MessageEvent};
+use web_sys::{WebSocket, MessageEvent, CloseEvent};
 #[cfg(feature = "wasm")]
-use std::sync::Arc;
+use std::cell::{Cell, RefCell};
 #[cfg(feature = "wasm")]
-use tokio::sync::RwLock;
+use std::rc::Rc;
 #[cfg(feature = "wasm")]
 use bytes::Bytes;
 #[cfg(feature = "wasm")]
 use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use crate::{
+    error::ProtocolError,
+    packet::{Packet, PacketType},
+};
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn console_error(value: &JsValue);
+}
+
+/// Resolve and reject functions of a request's promise
+#[cfg(feature = "wasm")]
+struct PendingReply {
+    resolve: js_sys::Function,
+    reject: js_sys::Function,
+}
+
+/// State shared between a client and its WebSocket callbacks
+#[cfg(feature = "wasm")]
+#[derive(Default)]
+struct Shared {
+    handlers: RefCell<HashMap<String, js_sys::Function>>,
+    pending: RefCell<HashMap<u32, PendingReply>>,
+}
+
+#[cfg(feature = "wasm")]
+impl Shared {
+    /// Handle one binary frame received from the server
+    fn handle_frame(&self, ws: &WebSocket, data: Vec<u8>) -> Result<(), JsValue> {
+        let packet = Packet::deserialize(Bytes::from(data)).map_err(to_js_error)?;
+        self.handle_packet(ws, packet)
+    }
+
+    fn handle_packet(&self, ws: &WebSocket, packet: Packet) -> Result<(), JsValue> {
+        match packet.packet_type {
+            PacketType::Data | PacketType::Error => {}
+            PacketType::Batch => {
+                for packet in packet.split_batch().map_err(to_js_error)? {
+                    self.handle_packet(ws, packet)?;
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+
+        if packet.flags.requires_ack {
+            let mut ack = Packet::new_ack(packet.sequence);
+            ack.channel_id = packet.channel_id;
+            send_packet(ws, &ack)?;
+        }
+
+        if packet.packet_type == PacketType::Error {
+            let error = packet.remote_error().map_err(to_js_error)?;
+            let pending = packet.answered_sequence().and_then(|request| self.pending.borrow_mut().remove(&request));
+            if let Some(pending) = pending {
+                pending.reject.call1(&JsValue::NULL, &to_js_error(error))?;
+            }
+            return Ok(());
+        }
+
+        let payload = Uint8Array::from(&packet.payload[..]);
+        let pending = packet.answered_sequence().and_then(|request| self.pending.borrow_mut().remove(&request));
+        if let Some(pending) = pending {
+            pending.resolve.call1(&JsValue::NULL, &payload)?;
+            return Ok(());
+        }
+
+        // Otherwise it is an unsolicited push from the server
+        let handler = self.handlers.borrow().get(&packet.route).cloned();
+        if let Some(handler) = handler {
+            handler.call2(&JsValue::NULL, &payload, &JsValue::from_str(&packet.route))?;
+        }
+        Ok(())
+    }
+
+    /// Fail every request still waiting for a reply
+    fn reject_all(&self, reason: &str) {
+        let reason = JsValue::from_str(reason);
+        let pending: Vec<_> = self.pending.borrow_mut().drain().map(|(_, p)| p).collect();
+        for pending in pending {
+            let _ = pending.reject.call1(&JsValue::NULL, &reason);
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn to_js_error(err: ProtocolError) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+/// Serialize a packet and send it as one binary frame
+#[cfg(feature = "wasm")]
+fn send_packet(ws: &WebSocket, packet: &Packet) -> Result<(), JsValue> {
+    let data = packet.serialize().map_err(to_js_error)?;
+    ws.send_with_u8_array(&data)
+}
 
 /// WASM Client for browser
+///
+/// Requests are sent as protocol packets over WebSocket binary frames and
+/// matched to their replies by the request sequence each reply echoes. Packets that answer no
+/// request are passed to the handler registered for their route.
 #[cfg(feature = "wasm")]
 #[wasm_bindgen]
 pub struct WasmClient {
     ws: Option<WebSocket>,
-    handlers: Arc<RwLock<HashMap<String, js_sys::Function>>>,
+    shared: Rc<Shared>,
+    next_sequence: Cell<u32>,
 }
 
 #[cfg(feature = "wasm")]
@@ -32,51 +136,130 @@ impl WasmClient {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
         console_error_panic_hook::set_once();
-        
+
         Self {
             ws: None,
-            handlers: Arc::new(RwLock::new(HashMap::new())),
+            shared: Rc::new(Shared::default()),
+            next_sequence: Cell::new(1),
         }
     }
 
-    /// Connect to server via WebSocket
+    /// Connect to server via WebSocket, resolving once the socket is open
     pub async fn connect(&mut self, url: String) -> Result<(), JsValue> {
         let ws = WebSocket::new(&url)?;
         ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-        
+
+        let opened = Promise::new(&mut |resolve, reject| {
+            ws.set_onopen(Some(&resolve));
+            ws.set_onerror(Some(&reject));
+        });
+        JsFuture::from(opened).await?;
+        ws.set_onopen(None);
+        ws.set_onerror(None);
+
+        let shared = self.shared.clone();
+        let socket = ws.clone();
+        let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
+            if let Ok(arraybuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                let data = Uint8Array::new(&arraybuf).to_vec();
+                if let Err(e) = shared.handle_frame(&socket, data) {
+                    console_error(&e);
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+
+        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        onmessage_callback.forget();
+
+        let shared = self.shared.clone();
+        let onclose_callback = Closure::wrap(Box::new(move |_: CloseEvent| {
+            shared.reject_all("Connection closed");
+        }) as Box<dyn FnMut(CloseEvent)>);
+
+        ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        onclose_callback.forget();
+
         self.ws = Some(ws);
         Ok(())
     }
 
-    /// Register a route handler
-    pub async fn on(&self, route: String, handler: js_sys::Function) {
-        self.handlers.write().await.insert(route, handler);
+    /// Register a handler for packets the server pushes to a route
+    ///
+    /// The handler is called with the payload as a `Uint8Array` and the route.
+    pub fn on(&self, route: String, handler: js_sys::Function) {
+        self.shared.handlers.borrow_mut().insert(route, handler);
     }
 
-    /// Send a request
+    /// Send a request and wait for the response payload
     pub async fn request(&self, route: String, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
-        if let Some(ws) = &self.ws {
-            // In a full implementation, this would send via WebSocket
-            // and wait for response
-            ws.send_with_u8_array(&data)?;
-        }
-        
-        Ok(vec![])
+        let reply = JsFuture::from(self.request_promise(route, data)?).await?;
+        Ok(Uint8Array::new(&reply).to_vec())
+    }
+
+    /// Send data without waiting for response
+    pub fn send(&self, route: String, data: Vec<u8>) -> Result<(), JsValue> {
+        let packet = Packet::new_data(route, Bytes::from(data), self.next_sequence());
+        send_packet(self.socket()?, &packet)
     }
 
     /// Disconnect
+    ///
+    /// Nothing needs flushing first: the WebSocket delivers every message
+    /// already sent before it closes, and requests still waiting for a
+    /// response are rejected.
     pub fn disconnect(&mut self) -> Result<(), JsValue> {
         if let Some(ws) = &self.ws {
             ws.close()?;
         }
         self.ws = None;
+        self.shared.reject_all("Disconnected");
         Ok(())
     }
 }
 
+#[cfg(feature = "wasm")]
+impl WasmClient {
+    /// Send a request, returning a promise of the response payload
+    ///
+    /// The promise resolves with a `Uint8Array`, or rejects if the connection
+    /// closes first.
+    pub fn request_promise(&self, route: String, data: Vec<u8>) -> Result<Promise, JsValue> {
+        let ws = self.socket()?;
+        let sequence = self.next_sequence();
+        let packet = Packet::new_data(route, Bytes::from(data), sequence);
+
+        let pending = &self.shared.pending;
+        let promise = Promise::new(&mut |resolve, reject| {
+            pending.borrow_mut().insert(sequence, PendingReply { resolve, reject });
+        });
+
+        if let Err(e) = send_packet(ws, &packet) {
+            self.shared.pending.borrow_mut().remove(&sequence);
+            return Err(e);
+        }
+        Ok(promise)
+    }
+
+    fn socket(&self) -> Result<&WebSocket, JsValue> {
+        self.ws.as_ref().ok_or_else(|| JsValue::from_str("Not connected"))
+    }
+
+    fn next_sequence(&self) -> u32 {
+        let sequence = self.next_sequence.get();
+        self.next_sequence.set(sequence.wrapping_add(1));
+        sequence
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Default for WasmClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(feature = "wasm")]
 #[wasm_bindgen]
 pub fn init_logging() {
     console_error_panic_hook::set_once();
 }
-
@@ -1,13 +1,8 @@
 //! WASM bindings for browser usage
 
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::JsFuture;
-use web_sys::{WebSocket, MessageEvent, ErrorEvent, CloseEvent};
-use js_sys::{Uint8Array, Promise};
-use std::sync::Arc;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::collections::HashMap;
+use js_sys::Promise;
+use fast_protocol::wasm_bridge::WasmClient;
 
 /// Initialize panic hook for better error messages
 #[wasm_bindgen(start)]
@@ -26,10 +21,12 @@ extern "C" {
 type Handler = js_sys::Function;
 
 /// Protocol client for browser
+///
+/// Speaks the native packet format over WebSocket binary frames, matching
+/// each response to its request by sequence number.
 #[wasm_bindgen]
 pub struct ProtocolClient {
-    ws: Option<WebSocket>,
-    handlers: Rc<RefCell<HashMap<String, Handler>>>,
+    inner: WasmClient,
     connected: bool,
 }
 
@@ -39,8 +36,7 @@ impl ProtocolClient {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
         Self {
-            ws: None,
-            handlers: Rc::new(RefCell::new(HashMap::new())),
+            inner: WasmClient::new(),
             connected: false,
         }
     }
@@ -49,72 +45,27 @@ impl ProtocolClient {
     pub async fn connect(&mut self, url: String) -> Result<(), JsValue> {
         log(&format!("Connecting to {}...", url));
         
-        let ws = WebSocket::new(&url)?;
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-        
-        // Setup event handlers
-        let handlers = self.handlers.clone();
-        
-        let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
-            if let Ok(arraybuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
-                let array = Uint8Array::new(&arraybuf);
-                let data = array.to_vec();
-                
-                // Parse message and call handler
-                // In production, this would parse the protocol packet
-                log(&format!("Received {} bytes", data.len()));
-            }
-        }) as Box<dyn FnMut(MessageEvent)>);
-        
-        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        onmessage_callback.forget();
-        
-        let onerror_callback = Closure::wrap(Box::new(move |e: ErrorEvent| {
-            log(&format!("WebSocket error: {:?}", e));
-        }) as Box<dyn FnMut(ErrorEvent)>);
-        
-        ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-        onerror_callback.forget();
-        
-        let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
-            log(&format!("WebSocket closed: {}", e.code()));
-        }) as Box<dyn FnMut(CloseEvent)>);
-        
-        ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
-        onclose_callback.forget();
-        
-        self.ws = Some(ws);
+        self.inner.connect(url).await?;
         self.connected = true;
         
         log("Connected!");
         Ok(())
     }
 
-    /// Register a route handler
+    /// Register a handler for packets the server pushes to a route
     pub fn on(&mut self, route: String, handler: Handler) {
         log(&format!("Registered handler for {}", route));
-        self.handlers.borrow_mut().insert(route, handler);
+        self.inner.on(route, handler);
     }
 
-    /// Send a request
+    /// Send a request, resolving with the response payload as a `Uint8Array`
     pub fn request(&self, route: String, data: Vec<u8>) -> Result<Promise, JsValue> {
         if !self.connected {
             return Err(JsValue::from_str("Not connected"));
         }
         
         log(&format!("Sending request to {} ({} bytes)", route, data.len()));
-        
-        if let Some(ws) = &self.ws {
-            ws.send_with_u8_array(&data)?;
-        }
-        
-        // Create a promise that resolves with the response
-        let promise = Promise::new(&mut |resolve, reject| {
-            // In production, this would wait for the actual response
-            resolve.call1(&JsValue::NULL, &JsValue::from_str("Response")).unwrap();
-        });
-        
-        Ok(promise)
+        self.inner.request_promise(route, data)
     }
 
     /// Send data without waiting for response
@@ -123,19 +74,12 @@ impl ProtocolClient {
             return Err(JsValue::from_str("Not connected"));
         }
         
-        if let Some(ws) = &self.ws {
-            ws.send_with_u8_array(&data)?;
-        }
-        
-        Ok(())
+        self.inner.send(route, data)
     }
 
     /// Disconnect
     pub fn disconnect(&mut self) -> Result<(), JsValue> {
-        if let Some(ws) = &self.ws {
-            ws.close()?;
-        }
-        self.ws = None;
+        self.inner.disconnect()?;
         self.connected = false;
         log("Disconnected");
         Ok(())
@@ -147,6 +91,12 @@ impl ProtocolClient {
     }
 }
 
+impl Default for ProtocolClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Helper function to encode string to bytes
 #[wasm_bindgen]
 pub fn encode_string(s: String) -> Vec<u8> {